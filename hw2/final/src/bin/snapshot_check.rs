@@ -0,0 +1,225 @@
+// Reads the --json snapshot output of every process and checks that each snapshot is consistent
+
+use part1::snapshot::{ChannelKind, SavedRecord, SnapshotEvent, SnapshotId};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+use std::process;
+
+/// The local state in one process's "complete" record
+struct Recorded {
+    has_token: bool,
+    receipts: Option<usize>,
+}
+
+/// What one snapshot looks like once every file in the directory has been read
+#[derive(Default)]
+struct Collected {
+    states: BTreeMap<u32, Recorded>, // from each "complete" record, by process
+    channels: BTreeMap<String, Vec<usize>>, // token count of every report of a channel
+    ring: BTreeSet<(u32, u32)>, // (from, to) of every channel reported as kind "ring"
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let dir = match args.as_slice() {
+        [_, dir] => dir,
+        _ => {
+            eprintln!("Usage: {} <dir>", args[0]);
+            process::exit(2);
+        }
+    };
+
+    let snapshots = match collect(Path::new(dir)) {
+        Ok(snapshots) => snapshots,
+        Err(e) => {
+            eprintln!("Error reading {}: {}", dir, e);
+            process::exit(2);
+        }
+    };
+    if snapshots.is_empty() {
+        eprintln!("No snapshot records found in {}", dir);
+        process::exit(2);
+    }
+
+    let mut failed = 0;
+    for (id, snapshot) in &snapshots {
+        let problems = check(snapshot);
+        if problems.is_empty() {
+            println!("snapshot {}: PASS", id);
+        } else {
+            failed += 1;
+            println!("snapshot {}: FAIL", id);
+            for (expected, found) in problems {
+                println!("  - expected {}", expected);
+                println!("  + found {}", found);
+            }
+        }
+    }
+    println!("{} of {} snapshots consistent", snapshots.len() - failed, snapshots.len());
+    if failed > 0 {
+        process::exit(1);
+    }
+}
+
+/// Group the records of every file in `dir` by snapshot id. Lines that are not snapshot records are skipped
+fn collect(dir: &Path) -> std::io::Result<BTreeMap<SnapshotId, Collected>> {
+    let mut snapshots: BTreeMap<SnapshotId, Collected> = BTreeMap::new();
+    let mut paths: Vec<_> = fs::read_dir(dir)?.map(|entry| entry.map(|e| e.path())).collect::<Result<_, _>>()?;
+    paths.sort();
+    for path in paths.into_iter().filter(|p| p.is_file()) {
+        for line in fs::read_to_string(&path)?.lines() {
+            let Some(record) = SavedRecord::parse_line(line) else { continue };
+            let snapshot = snapshots.entry(record.snapshot_id).or_default();
+            match record.event {
+                SnapshotEvent::Complete { has_token, receipts, .. } => {
+                    snapshot.states.insert(record.proc_id, Recorded { has_token, receipts });
+                }
+                SnapshotEvent::ChannelClosed { channel, kind, tokens, .. } => {
+                    if kind == ChannelKind::Ring {
+                        if let Some(edge) = parse_channel(&channel) {
+                            snapshot.ring.insert(edge);
+                        }
+                    }
+                    snapshot.channels.entry(channel).or_default().push(tokens);
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(snapshots)
+}
+
+/// Every (expected, found) mismatch in one snapshot, empty if it is consistent
+fn check(snapshot: &Collected) -> Vec<(String, String)> {
+    let mut problems = Vec::new();
+    let ids: Vec<u32> = snapshot.states.keys().copied().collect();
+    if ids.is_empty() {
+        problems.push(("a complete record from every process".to_string(), "none".to_string()));
+        return problems;
+    }
+
+    // Exactly one token: held by a process or recorded in flight on a channel
+    let holders: Vec<String> =
+        snapshot.states.iter().filter(|(_, recorded)| recorded.has_token).map(|(id, _)| id.to_string()).collect();
+    let in_flight: Vec<String> = snapshot
+        .channels
+        .iter()
+        .flat_map(|(channel, reports)| reports.iter().filter(|t| **t > 0).map(move |t| format!("{} in {}", t, channel)))
+        .collect();
+    let tokens = holders.len() + snapshot.channels.values().flatten().sum::<usize>();
+    if tokens != 1 {
+        problems.push((
+            "1 token".to_string(),
+            format!("{} (held by [{}], in flight [{}])", tokens, holders.join(", "), in_flight.join(", ")),
+        ));
+    }
+
+    // Every directed channel between the processes reported exactly once
+    let expected: BTreeSet<String> = ids
+        .iter()
+        .flat_map(|from| ids.iter().filter(move |to| *to != from).map(move |to| format!("{}-{}", from, to)))
+        .collect();
+    for channel in &expected {
+        match snapshot.channels.get(channel).map_or(0, Vec::len) {
+            1 => {}
+            0 => problems.push((format!("channel {} closed once", channel), "never".to_string())),
+            n => problems.push((format!("channel {} closed once", channel), format!("{} times", n))),
+        }
+    }
+    for (channel, reports) in &snapshot.channels {
+        if !expected.contains(channel) {
+            problems.push((
+                "only channels between reporting processes".to_string(),
+                format!("channel {} ({} reports)", channel, reports.len()),
+            ));
+        }
+    }
+
+    // Along each ring channel p-q, p passed the token on once per receipt, plus once more if
+    // it started the token, less the one it still holds. All of that went to q, so q's receipts
+    // and what is left in the channel have to add up to it, or the recorded states and channel
+    // queues don't describe the same cut. With --bounce-at the token comes in over two ring
+    // channels and a process's sends can't be split between them, so this is skipped there.
+    let bounced = snapshot.ring.iter().map(|&(_, to)| to).collect::<BTreeSet<_>>().len() < snapshot.ring.len();
+    for &(from, to) in snapshot.ring.iter().filter(|_| !bounced) {
+        let (Some(sender), Some(receiver)) = (snapshot.states.get(&from), snapshot.states.get(&to)) else { continue };
+        let (Some(sent), Some(taken)) = (sender.receipts, receiver.receipts) else { continue };
+        let channel = format!("{}-{}", from, to);
+        let in_flight: usize = snapshot.channels.get(&channel).map_or(0, |reports| reports.iter().sum());
+        let held = usize::from(sender.has_token);
+        let accounted = taken + in_flight + held;
+        if accounted != sent && accounted != sent + 1 {
+            problems.push((
+                format!(
+                    "process {} receipts + tokens in {} + token held by {} = {} or {} (receipts of {}, plus 1 if it started the token)",
+                    to, channel, from, sent, sent + 1, from
+                ),
+                format!("{} + {} + {} = {}", taken, in_flight, held, accounted),
+            ));
+        }
+    }
+    for (id, recorded) in &snapshot.states {
+        if recorded.receipts.is_none() {
+            problems.push((
+                format!("process {} complete record with receipts", id),
+                "none, from output older than the receipt count".to_string(),
+            ));
+        }
+    }
+
+    problems
+}
+
+/// "<from>-<to>" as printed in channel closed records
+fn parse_channel(channel: &str) -> Option<(u32, u32)> {
+    let (from, to) = channel.split_once('-')?;
+    Some((from.parse().ok()?, to.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Snapshot of the ring 1 -> 2 -> 3 -> 1 with every process's (receipts, has_token)
+    /// and the tokens found on each channel, 0 for channels not listed
+    fn ring(states: [(usize, bool); 3], tokens: &[(&str, usize)]) -> Collected {
+        let mut snapshot = Collected::default();
+        for (id, (receipts, has_token)) in (1..).zip(states) {
+            snapshot.states.insert(id, Recorded { has_token, receipts: Some(receipts) });
+        }
+        for from in 1..=3 {
+            for to in (1..=3).filter(|&to| to != from) {
+                let channel = format!("{}-{}", from, to);
+                let found = tokens.iter().find(|(c, _)| *c == channel).map_or(0, |&(_, n)| n);
+                snapshot.channels.insert(channel, vec![found]);
+            }
+        }
+        snapshot.ring = [(1, 2), (2, 3), (3, 1)].into_iter().collect();
+        snapshot
+    }
+
+    #[test]
+    fn consistent_cut_passes() {
+        // Process 1 started the token, got it back once and passed it on again
+        assert_eq!(check(&ring([(1, false), (1, false), (1, false)], &[("1-2", 1)])), []);
+        // Same round, with process 2 holding it
+        assert_eq!(check(&ring([(1, false), (2, true), (1, false)], &[])), []);
+    }
+
+    #[test]
+    fn receipts_ahead_of_the_sender_fail() {
+        // Still one token, but process 2 recorded receipts process 1 never sent
+        let problems = check(&ring([(1, false), (3, true), (1, false)], &[]));
+        assert!(problems.iter().any(|(expected, _)| expected.contains("tokens in 1-2")), "{:?}", problems);
+        assert!(problems.iter().any(|(expected, _)| expected.contains("tokens in 2-3")), "{:?}", problems);
+        assert!(!problems.iter().any(|(expected, _)| expected == "1 token"), "{:?}", problems);
+    }
+
+    #[test]
+    fn token_held_and_in_transit_fails() {
+        // Process 2 holds the token that is also recorded on its way there
+        let problems = check(&ring([(1, false), (2, true), (1, false)], &[("1-2", 1)]));
+        assert!(problems.iter().any(|(expected, _)| expected == "1 token"), "{:?}", problems);
+    }
+}
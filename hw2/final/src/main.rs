@@ -1,19 +1,57 @@
-use hostname;
 use std::env;
+use std::fmt;
 use std::fs::File;
 use std::io::{self, BufRead, BufReader, Write};
 use std::net::{SocketAddr, ToSocketAddrs, UdpSocket, TcpListener, TcpStream};
 use std::path::Path;
-use std::time::Duration;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::thread;
 use std::process;
-use std::sync::{Arc, Mutex};
-use std::collections::{HashMap, HashSet};
-use std::sync::atomic::{AtomicBool, Ordering, AtomicUsize};
+use std::sync::{mpsc, Arc, Mutex};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use part1::snapshot::{ChannelKind, SavedRecord, SnapshotEvent, SnapshotId, SnapshotRecord};
+use std::sync::atomic::{AtomicBool, Ordering};
 
+/// Set from --quiet, silences log!
+static QUIET: AtomicBool = AtomicBool::new(false);
 
-const UDP_PORT: &str = "8888";
-const TOKEN_PORT: u32 = 8889;
+/// Protocol events (token, state, READY and snapshot lines). These are the graded output
+/// and the only thing allowed on stdout.
+macro_rules! emit {
+    ($($arg:tt)*) => { println!($($arg)*) };
+}
+
+/// Diagnostics, on stderr so they never mix with protocol events. Dropped with --quiet.
+macro_rules! log {
+    ($($arg:tt)*) => {
+        if !QUIET.load(Ordering::Relaxed) {
+            eprintln!($($arg)*)
+        }
+    };
+}
+
+
+const DEFAULT_UDP_PORT: u16 = 8888;
+const DEFAULT_TOKEN_PORT: u16 = 8889;
+// How often blocked marker readers wake up to check for shutdown
+const READ_POLL_INTERVAL: Duration = Duration::from_millis(500);
+// Bounded retry when forwarding the token to the next hop fails
+const FORWARD_RETRIES: u32 = 5;
+const FORWARD_RETRY_DELAY: Duration = Duration::from_millis(200);
+// How long to wait for the predecessor to reconnect after its stream drops
+const RECONNECT_WINDOW: Duration = Duration::from_secs(2);
+const DEFAULT_STARTUP_TIMEOUT_SECS: u64 = 60;
+// How often failsafe_startup reports which peers it is still waiting for
+const STARTUP_PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+// Exit code when some peer never came up during failsafe_startup
+const EXIT_STARTUP_TIMEOUT: i32 = 2;
+// How long a snapshot initiator waits for every process to report done
+const DEFAULT_DONE_TIMEOUT_SECS: u64 = 30;
+// How long exit paths wait for the marker listener and its readers to finish
+const MESH_SHUTDOWN_WAIT: Duration = Duration::from_secs(2);
+// Unanswered --keepalive pings before the ring is treated as broken
+const DEFAULT_KEEPALIVE_MISSES: u32 = 3;
 
 #[derive(Debug, Clone)]
 struct UserInfo {
@@ -21,17 +59,727 @@ struct UserInfo {
     id: u32,
 }
 
+/// Which way the token travels around the ring
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Direction {
+    Cw,  // towards the successor
+    Ccw, // towards the predecessor
+}
+
+impl Direction {
+    fn flip(self) -> Self {
+        match self {
+            Direction::Cw => Direction::Ccw,
+            Direction::Ccw => Direction::Cw,
+        }
+    }
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Cw => write!(f, "cw"),
+            Direction::Ccw => write!(f, "ccw"),
+        }
+    }
+}
+
+impl FromStr for Direction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cw" => Ok(Direction::Cw),
+            "ccw" => Ok(Direction::Ccw),
+            other => Err(format!("unknown direction '{}'", other)),
+        }
+    }
+}
+
+/// A token line: "token:<sender>[:<dir>[:<sent_ms>:<born_ms>]]". The timestamps are epoch
+/// millis of the last send and of the initiator's latest injection, for latency stats.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct TokenMsg {
+    sender: u32,
+    direction: Option<Direction>,
+    sent_ms: Option<u64>,
+    born_ms: Option<u64>,
+}
+
+/// min/avg/max over a series of millisecond samples
+#[derive(Debug, Default)]
+struct Latency {
+    count: u64,
+    sum: u64,
+    min: u64,
+    max: u64,
+}
+
+impl Latency {
+    fn record(&mut self, ms: u64) {
+        if self.count == 0 || ms < self.min {
+            self.min = ms;
+        }
+        self.max = self.max.max(ms);
+        self.sum += ms;
+        self.count += 1;
+    }
+}
+
+impl fmt::Display for Latency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.count == 0 {
+            return write!(f, "{{}}");
+        }
+        let avg = self.sum as f64 / self.count as f64;
+        write!(f, "{{min:{}, avg:{:.1}, max:{}}}", self.min, avg, self.max)
+    }
+}
+
+/// Token latency seen by one process. Hops are measured against the sender's clock,
+/// so on separate hosts they include any clock skew.
+#[derive(Debug, Default)]
+struct TokenStats {
+    rounds: u64, // token receipts
+    hop: Latency,
+    round: Latency, // initiator only
+}
+
+/// Token counters printed instead of the per-hop lines with --quiet-tokens
+#[derive(Debug)]
+struct Throughput {
+    started: Instant,
+    tokens: u64, // token receipts
+    rounds: u64, // receipts that started a new round for this process
+}
+
+impl Throughput {
+    fn new() -> Self {
+        Throughput { started: Instant::now(), tokens: 0, rounds: 0 }
+    }
+
+    fn record(&mut self, new_round: bool) {
+        self.tokens += 1;
+        if new_round {
+            self.rounds += 1;
+        }
+    }
+}
+
+/// Where the ring reader and mesh readers hand token lines to the token loop.
+/// The ring reader clears it when it hangs up, so the loop ends even while mesh readers live on.
+type TokenInbox = Arc<Mutex<Option<mpsc::Sender<Delayed>>>>;
+
+/// A line read off a channel and the instant it may be delivered
+type Delayed = (Instant, String);
+
+/// --channel-delay <from>:<to>:<seconds>
+#[derive(Debug, Clone, PartialEq)]
+struct ChannelDelay {
+    from: u32,
+    to: u32,
+    seconds: f64,
+}
+
+impl FromStr for ChannelDelay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        if parts.len() != 3 {
+            return Err("expected <from>:<to>:<seconds>".to_string());
+        }
+        let seconds: f64 = parts[2].parse().map_err(|e| format!("bad seconds: {}", e))?;
+        if !(seconds >= 0.0 && seconds.is_finite()) {
+            return Err("seconds must be a non-negative number".to_string());
+        }
+        Ok(ChannelDelay {
+            from: parts[0].parse().map_err(|e| format!("bad from id: {}", e))?,
+            to: parts[1].parse().map_err(|e| format!("bad to id: {}", e))?,
+            seconds,
+        })
+    }
+}
+
+/// The process state the token updates, shared by the token loop and snapshot recording.
+/// Everything sits behind one lock so a snapshot never sees a torn counter/has_token pair.
+#[derive(Debug)]
+struct ProcessState {
+    counter: usize,
+    has_token: bool,
+    receipts: usize, // token receipts in this run, which snapshot_check lines up across the ring
+}
+
+type SharedState = Arc<Mutex<ProcessState>>;
+
+impl ProcessState {
+    fn shared(counter: usize, has_token: bool) -> SharedState {
+        Arc::new(Mutex::new(ProcessState { counter, has_token, receipts: 0 }))
+    }
+
+    /// Count a token receipt and print the new state while still holding the lock
+    fn increment_and_log(&mut self, id: u32) {
+        self.counter += 1;
+        self.receipts += 1;
+        emit!("{{id: {}, state: {}}}", id, self.counter);
+    }
+
+    /// Count a token receipt and print the new state unless --quiet-tokens is on
+    fn increment(&mut self, id: u32, quiet_tokens: bool) {
+        if quiet_tokens {
+            self.counter += 1;
+            self.receipts += 1;
+        } else {
+            self.increment_and_log(id);
+        }
+    }
+}
+
+/// Local state captured when a process joins a snapshot
+#[derive(Debug, Clone, Default)]
+struct LocalState {
+    state: usize,
+    has_token: bool,
+    receipts: usize,
+    sent: BTreeMap<u32, u64>,     // chatter messages sent, by receiver
+    received: BTreeMap<u32, u64>, // chatter messages received, by sender
+}
+
+/// Chandy-Lamport bookkeeping for a single initiation on this process
+#[derive(Debug, Default)]
+struct Initiation {
+    recorded: Option<LocalState>,
+    markers_sent: bool,                // false until markers went out after recording
+    closed: HashSet<u32>,              // incoming channels closed, by sender id
+    queues: HashMap<u32, Vec<String>>, // messages recorded per incoming channel
+    complete: bool,
+    channel_tokens: usize, // tokens found in the queues of closed channels
+    done: BTreeSet<u32>, // initiator only: processes that reported completion
+    reported: bool,      // initiator only: global outcome already printed
+}
+
+/// Application-level "msg:<from>:<seq>" traffic for --chatter
+#[derive(Debug, Default)]
+struct Chatter {
+    next_seq: u64,
+    sent: BTreeMap<u32, u64>,
+    received: BTreeMap<u32, u64>,
+}
+
+/// Everything the marker threads share with the token loop
+#[derive(Clone)]
+struct SnapshotContext {
+    my_id: u32,
+    ring_channels: Vec<u32>, // peers whose channel can carry the token to us
+    process_ids: Vec<u32>, // every process in the hostsfile, self included
+    total_channels: usize,
+    marker_delay: f64,
+    done_timeout: Duration,
+    json: bool,
+    state: SharedState,
+    chatter: Arc<Mutex<Chatter>>,
+    initiations: Arc<Mutex<HashMap<SnapshotId, Initiation>>>,
+    marker_connections: Arc<Mutex<HashMap<u32, TcpStream>>>,
+}
+
+impl SnapshotContext {
+    /// Start snapshot `id` from this process. Used by the -p timer and the control socket.
+    fn initiate(&self, id: SnapshotId) {
+        emit_snapshot(self.json, self.my_id, id, &SnapshotEvent::Started);
+        let recorded = {
+            let mut initiations = self.initiations.lock().unwrap();
+            self.record_local_state(id, initiations.entry(id).or_default())
+        };
+        if let Some(recorded) = recorded {
+            self.broadcast_markers_later(id, recorded);
+        }
+
+        // Give up on stragglers after --done-timeout
+        let snapshots = self.clone();
+        thread::spawn(move || {
+            thread::sleep(snapshots.done_timeout);
+            let mut initiations = snapshots.initiations.lock().unwrap();
+            let Some(initiation) = initiations.get_mut(&id) else { return };
+            if initiation.reported {
+                return;
+            }
+            initiation.reported = true;
+            let missing = snapshots.process_ids.iter().copied().filter(|p| !initiation.done.contains(p)).collect();
+            emit_snapshot(snapshots.json, snapshots.my_id, id, &SnapshotEvent::GloballyIncomplete {
+                processes: initiation.done.iter().copied().collect(),
+                missing,
+            });
+        });
+    }
+
+    /// Whether this process has already seen snapshot `id`, started here or by a marker
+    fn knows(&self, id: SnapshotId) -> bool {
+        self.initiations.lock().unwrap().contains_key(&id)
+    }
+
+    /// Snapshots that recorded local state here but have not closed every channel yet
+    fn in_progress(&self) -> Vec<SnapshotId> {
+        let initiations = self.initiations.lock().unwrap();
+        let mut ids: Vec<SnapshotId> = initiations
+            .iter()
+            .filter(|(_, i)| i.recorded.is_some() && !i.complete)
+            .map(|(&id, _)| id)
+            .collect();
+        ids.sort_by_key(|id| (id.initiator, id.seq));
+        ids
+    }
+
+    /// A marker for `id` arrived on the channel from `sender`
+    fn handle_marker(&self, sender: u32, id: SnapshotId) {
+        // Ignore marker from self
+        if sender == self.my_id {
+            return;
+        }
+
+        let recorded = {
+            let mut initiations = self.initiations.lock().unwrap();
+            let initiation = initiations.entry(id).or_default();
+            if initiation.closed.contains(&sender) {
+                return;
+            }
+
+            // The first marker records our state, which leaves this channel empty
+            let recorded = self.record_local_state(id, initiation);
+            initiation.closed.insert(sender);
+            let queue = initiation.queues.remove(&sender).unwrap_or_default();
+            let tokens = queue.iter().filter(|m| *m == "token").count();
+            initiation.channel_tokens += tokens;
+            emit_snapshot(self.json, self.my_id, id, &SnapshotEvent::ChannelClosed {
+                channel: format!("{}-{}", sender, self.my_id),
+                kind: if self.ring_channels.contains(&sender) { ChannelKind::Ring } else { ChannelKind::Marker },
+                tokens,
+                queue,
+            });
+            self.check_complete(id, initiation);
+            recorded
+        };
+
+        if let Some(recorded) = recorded {
+            self.broadcast_markers_later(id, recorded);
+        }
+    }
+
+    /// Initiator side: process `sender` finished its part of snapshot `id`
+    fn handle_done(&self, sender: u32, id: SnapshotId) {
+        if id.initiator != self.my_id {
+            log!("Ignoring done for snapshot {} from {}, not the initiator", id, sender);
+            return;
+        }
+        let mut initiations = self.initiations.lock().unwrap();
+        let initiation = initiations.entry(id).or_default();
+        initiation.done.insert(sender);
+        self.check_global(id, initiation);
+    }
+
+    fn check_global(&self, id: SnapshotId, initiation: &mut Initiation) {
+        if initiation.reported || initiation.done.len() < self.process_ids.len() {
+            return;
+        }
+        initiation.reported = true;
+        emit_snapshot(self.json, self.my_id, id, &SnapshotEvent::GloballyComplete {
+            processes: initiation.done.iter().copied().collect(),
+        });
+    }
+
+    /// Tell the initiator of `id` that this process finished, or count ourselves if we are it
+    fn report_done(&self, id: SnapshotId, initiation: &mut Initiation) {
+        if id.initiator == self.my_id {
+            initiation.done.insert(self.my_id);
+            self.check_global(id, initiation);
+            return;
+        }
+        let done_msg = format!("done:{}:{}\n", self.my_id, id);
+        let connections = self.marker_connections.lock().unwrap();
+        let Some(stream) = connections.get(&id.initiator) else {
+            log!("No connection to initiator {} of snapshot {}", id.initiator, id);
+            return;
+        };
+        let mut writer: &TcpStream = stream;
+        if let Err(e) = writer.write_all(done_msg.as_bytes()).and_then(|_| writer.flush()) {
+            log!("Error sending done to initiator {}: {}", id.initiator, e);
+        }
+    }
+
+    /// Write one line to `peer_id` over the mesh, in order with the markers sent there
+    fn send_on_mesh(&self, peer_id: u32, msg: &str) -> io::Result<()> {
+        let connections = self.marker_connections.lock().unwrap();
+        let stream = connections.get(&peer_id).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotConnected, format!("no mesh connection to peer {}", peer_id))
+        })?;
+        let mut writer: &TcpStream = stream;
+        writer.write_all(msg.as_bytes())?;
+        writer.flush()
+    }
+
+    /// Take the token from `sender`: record it on every snapshot still listening to that channel,
+    /// set has_token and count the receipt, all under one lock so a marker never records the
+    /// token as held and in transit at once. Returns the new counter.
+    fn receive_token(&self, sender: u32, quiet_tokens: bool) -> usize {
+        let mut initiations = self.initiations.lock().unwrap();
+        Self::record_in_transit(&mut initiations, sender, "token");
+        let mut state = self.state.lock().unwrap();
+        state.has_token = true;
+        state.increment(self.my_id, quiet_tokens);
+        state.counter
+    }
+
+    /// Count a chatter message from `sender` and record it if it was in transit.
+    /// Both happen under one lock so a message is never in the tally and a queue at once.
+    fn deliver_chatter(&self, sender: u32, msg: &str) {
+        let mut initiations = self.initiations.lock().unwrap();
+        Self::record_in_transit(&mut initiations, sender, msg);
+        *self.chatter.lock().unwrap().received.entry(sender).or_default() += 1;
+    }
+
+    fn record_in_transit(initiations: &mut HashMap<SnapshotId, Initiation>, sender: u32, msg: &str) {
+        for initiation in initiations.values_mut() {
+            if initiation.recorded.is_some() && !initiation.complete && !initiation.closed.contains(&sender) {
+                initiation.queues.entry(sender).or_default().push(msg.to_string());
+            }
+        }
+    }
+
+    /// Send one chatter message to a random peer. Skipped while a snapshot has recorded
+    /// state but not sent its markers yet, since nothing may overtake the markers.
+    fn send_chatter(&self, rng: &mut XorShift) {
+        let initiations = self.initiations.lock().unwrap();
+        if initiations.values().any(|i| i.recorded.is_some() && !i.markers_sent) {
+            return;
+        }
+
+        let connections = self.marker_connections.lock().unwrap();
+        let peer_ids: Vec<u32> = connections.keys().copied().collect();
+        if peer_ids.is_empty() {
+            return;
+        }
+        let peer_id = peer_ids[rng.next_below(peer_ids.len())];
+
+        let mut chatter = self.chatter.lock().unwrap();
+        chatter.next_seq += 1;
+        let msg = format!("msg:{}:{}\n", self.my_id, chatter.next_seq);
+        let mut writer: &TcpStream = &connections[&peer_id];
+        match writer.write_all(msg.as_bytes()).and_then(|_| writer.flush()) {
+            Ok(()) => *chatter.sent.entry(peer_id).or_default() += 1,
+            Err(e) => log!("Error sending chatter to peer {}: {}", peer_id, e),
+        }
+    }
+
+    /// Record local state unless already done, returning it if this call recorded it
+    fn record_local_state(&self, id: SnapshotId, initiation: &mut Initiation) -> Option<LocalState> {
+        if initiation.recorded.is_some() {
+            return None;
+        }
+        let recorded = {
+            let chatter = self.chatter.lock().unwrap();
+            let state = self.state.lock().unwrap();
+            LocalState {
+                state: state.counter,
+                has_token: state.has_token,
+                receipts: state.receipts,
+                sent: chatter.sent.clone(),
+                received: chatter.received.clone(),
+            }
+        };
+        initiation.recorded = Some(recorded.clone());
+        self.check_complete(id, initiation);
+        Some(recorded)
+    }
+
+    fn check_complete(&self, id: SnapshotId, initiation: &mut Initiation) {
+        if initiation.complete || initiation.closed.len() < self.total_channels {
+            return;
+        }
+        if let Some(recorded) = &initiation.recorded {
+            initiation.complete = true;
+            emit_snapshot(self.json, self.my_id, id, &SnapshotEvent::Complete {
+                state: recorded.state,
+                has_token: recorded.has_token,
+                receipts: Some(recorded.receipts),
+                channel_tokens: initiation.channel_tokens,
+                sent: recorded.sent.clone(),
+                received: recorded.received.clone(),
+            });
+            self.report_done(id, initiation);
+        }
+    }
+
+    /// Send markers for `id` to all peers after marker_delay, without blocking the caller
+    fn broadcast_markers_later(&self, id: SnapshotId, recorded: LocalState) {
+        let snapshots = self.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_secs_f64(snapshots.marker_delay));
+            snapshots.broadcast_markers(id, recorded.state, recorded.has_token);
+            if let Some(initiation) = snapshots.initiations.lock().unwrap().get_mut(&id) {
+                initiation.markers_sent = true;
+            }
+        });
+    }
+
+    fn broadcast_markers(&self, id: SnapshotId, state: usize, has_token: bool) {
+        let marker_msg = format!("marker:{}:{}\n", self.my_id, id);
+        let connections = self.marker_connections.lock().unwrap();
+        for (&peer_id, stream) in connections.iter() {
+            let mut writer: &TcpStream = stream;
+            
+            if let Err(e) = writer.write_all(marker_msg.as_bytes()) {
+                log!("Error sending marker to peer {}: {}", peer_id, e);
+                continue;
+            }
+            
+            if let Err(e) = writer.flush() {
+                log!("Error flushing marker to peer {}: {}", peer_id, e);
+                continue;
+            }
+            
+            emit_snapshot(self.json, self.my_id, id, &SnapshotEvent::MarkerSent {
+                sender: self.my_id,
+                receiver: peer_id,
+                state,
+                has_token,
+            });
+        }
+    }
+}
+
+/// Small xorshift generator for picking chatter targets, good enough for test traffic
+struct XorShift(u64);
+
+impl XorShift {
+    fn seeded(salt: u64) -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        // xorshift must never be seeded with zero
+        XorShift((nanos ^ salt.wrapping_mul(0x9E37_79B9_7F4A_7C15)) | 1)
+    }
+
+    fn next_below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n as u64) as usize
+    }
+}
+
+/// Command-line configuration
+#[derive(Debug, Clone)]
+struct Config {
+    hostsfile: String,
+    state: usize,
+    token_delay: f64,
+    marker_delay: f64,
+    snapshot_start: u64, // seconds to wait before initiating snapshot, and between repeated -p
+    is_initiator: bool,
+    snapshot_ids: Vec<u64>, // one initiation per -p, in order
+    udp_port: u16,
+    token_port: u16,
+    marker_port: Option<u16>, // fallback if token_port + 1 can't be bound
+    startup_timeout: u64,     // seconds failsafe_startup waits for every peer
+    json: bool,               // print snapshot events as JSON lines
+    channel_delays: Vec<ChannelDelay>,
+    reverse: bool, // pass the token to the predecessor instead of the successor
+    chatter: f64,  // application messages per second, 0 disables
+    done_timeout: u64, // seconds the initiator waits for every process to report done
+    bounce_at: Option<u32>, // process that reverses the token's direction
+    crash_after_tokens: Option<u64>, // exit(1) on this token receipt, for fault-injection runs
+    crash_holding: bool,             // crash before forwarding instead of after
+    quiet: bool,                     // drop diagnostics from stderr
+    stats_every: u64,                // print token latency stats every n receipts, 0 only at exit
+    keepalive: f64,                  // seconds between pings to the next hop, 0 disables
+    keepalive_misses: u32,           // unanswered pings before the ring counts as broken
+    control_port: Option<u16>,       // listener for "snapshot <seq>" and "status" commands
+    restore: Option<String>,         // --json snapshot output to take the starting state from
+    quiet_tokens: Option<u64>,       // replace per-hop token lines with a summary every n rounds
+    snapshot_every: Option<usize>,   // initiate a snapshot whenever the state reaches a multiple of this
+}
+
+impl Config {
+    /// Whether the crash injected with --crash-after-tokens is due on this receipt
+    fn crash_due(&self, receipts: u64) -> bool {
+        self.crash_after_tokens == Some(receipts)
+    }
+
+    /// Marker ports to try in order: token_port + 1, then the explicit fallback
+    fn marker_port_candidates(&self) -> Vec<u16> {
+        let mut ports: Vec<u16> = self.token_port.checked_add(1).into_iter().collect();
+        if let Some(port) = self.marker_port {
+            if !ports.contains(&port) {
+                ports.push(port);
+            }
+        }
+        ports
+    }
+}
+
+/// Why the command line or the hostsfile was rejected
+#[derive(Debug, PartialEq)]
+enum ParseError {
+    MissingValue(String),
+    InvalidValue { flag: String, value: String, reason: String },
+    UnknownOption(String),
+    MissingHostsfile,
+    HostsfileNotFound(String),
+    SamePorts(u16),
+    NoMarkerPort(u16),
+    NeedsMarkers(&'static str),
+    CrashHoldingWithoutCount,
+    SnapshotEveryBase(usize),
+    Hostname(String),
+    HostsfileIo { path: String, reason: String },
+    HostsfileLine { line: usize, reason: String },
+    EmptyHostsfile(String),
+    HostNotInHostsfile { host: String, path: String },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::MissingValue(flag) => write!(f, "Missing argument for {}", flag),
+            ParseError::InvalidValue { flag, value, reason } => {
+                write!(f, "Invalid argument for {}: '{}' ({})", flag, value, reason)
+            }
+            ParseError::UnknownOption(opt) => write!(f, "Unknown option: {}", opt),
+            ParseError::MissingHostsfile => write!(f, "Missing hostsfile path (-h)"),
+            ParseError::HostsfileNotFound(path) => write!(f, "Hostsfile not found: {}", path),
+            ParseError::SamePorts(port) => {
+                write!(f, "--udp-port and --token-port must differ (both are {})", port)
+            }
+            ParseError::NoMarkerPort(port) => {
+                write!(f, "--token-port {} leaves no room for the marker port, pass --marker-port", port)
+            }
+            ParseError::NeedsMarkers(flag) => write!(f, "{} needs a non-zero -m marker delay", flag),
+            ParseError::CrashHoldingWithoutCount => write!(f, "--crash-holding needs --crash-after-tokens"),
+            ParseError::SnapshotEveryBase(count) => {
+                write!(f, "--snapshot-every needs exactly one -p as the base snapshot id, got {}", count)
+            }
+            ParseError::Hostname(reason) => write!(f, "Failed to get host name: {}", reason),
+            ParseError::HostsfileIo { path, reason } => {
+                write!(f, "Failed to read hostsfile {}: {}", path, reason)
+            }
+            ParseError::HostsfileLine { line, reason } => {
+                write!(f, "Failed to read hostsfile line {}: {}", line, reason)
+            }
+            ParseError::EmptyHostsfile(path) => write!(f, "Hostsfile {} has no hosts", path),
+            ParseError::HostNotInHostsfile { host, path } => {
+                write!(f, "This host '{}' is not listed in hostsfile {}", host, path)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Why --restore could not load a snapshot
+#[derive(Debug)]
+enum RestoreError {
+    Io { path: String, reason: String },
+    NoCompleteRecord(String),
+    WrongProcess { path: String, proc_id: u32, my_id: u32 },
+}
+
+impl fmt::Display for RestoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RestoreError::Io { path, reason } => write!(f, "Failed to read snapshot file {}: {}", path, reason),
+            RestoreError::NoCompleteRecord(path) => {
+                write!(f, "Snapshot file {} has no \"complete\" record to restore from", path)
+            }
+            RestoreError::WrongProcess { path, proc_id, my_id } => write!(
+                f,
+                "Snapshot file {} was recorded by process {}, refusing to restore it on process {}",
+                path, proc_id, my_id
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RestoreError {}
+
+/// failsafe_startup gave up before every peer answered
+#[derive(Debug)]
+struct StartupTimeout {
+    missing: Vec<String>,
+}
+
+impl fmt::Display for StartupTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Startup timed out waiting for peers: {}", self.missing.join(", "))
+    }
+}
+
+impl std::error::Error for StartupTimeout {}
+
 fn main() {
     thread::sleep(Duration::from_secs_f64(1.0));
 
-    if let Err(e) = run() {
+    let args: Vec<String> = env::args().collect();
+    let config = match parse_args(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            eprintln!("{}", usage(&args[0]));
+            process::exit(1);
+        }
+    };
+
+    QUIET.store(config.quiet, Ordering::Relaxed);
+
+    let hosts = hostname::get()
+        .map_err(|e| ParseError::Hostname(e.to_string()))
+        .and_then(|name| {
+            name.into_string()
+                .map_err(|_| ParseError::Hostname("host name is not valid UTF-8".to_string()))
+        })
+        .and_then(|my_name| parse_hostfile(&config.hostsfile, &my_name));
+    let (my_user, full_list_of_peers) = match hosts {
+        Ok(hosts) => hosts,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+    };
+
+    if let Err(e) = run(config, my_user, full_list_of_peers) {
         eprintln!("Fatal error: {}", e);
-        process::exit(1);
+        let startup_timed_out = e
+            .get_ref()
+            .is_some_and(|inner| inner.downcast_ref::<StartupTimeout>().is_some());
+        process::exit(if startup_timed_out { EXIT_STARTUP_TIMEOUT } else { 1 });
     }
 }
 
-fn parse_args() -> (String, usize, f64, f64, u64, bool, Option<u64>) {
-    let args: Vec<String> = env::args().collect();
+fn usage(program: &str) -> String {
+    format!(
+        "Usage: {} -h <hostsfile> [-x] [-t <token_delay>] [-m <marker_delay>] [-s <snapshot_start>] [-p <snapshot_id>]... [--udp-port <port>] [--token-port <port>] [--marker-port <port>] [--startup-timeout <seconds>] [--json] [--channel-delay <from>:<to>:<seconds>]... [--reverse] [--chatter <msgs_per_sec>] [--done-timeout <secs>] [--bounce-at <id>] [--crash-after-tokens <n> [--crash-holding]] [--quiet] [--stats-every <n>] [--keepalive <secs> [--keepalive-misses <m>]] [--control-port <port>] [--restore <file>] [--quiet-tokens <n>] [--snapshot-every <s>]",
+        program
+    )
+}
+
+/// Parse the value following `flag` at args[*i], advancing past it
+fn flag_value<T>(args: &[String], i: &mut usize, flag: &str) -> Result<T, ParseError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let value = args
+        .get(*i + 1)
+        .ok_or_else(|| ParseError::MissingValue(flag.to_string()))?;
+    *i += 1;
+    value.parse::<T>().map_err(|e| ParseError::InvalidValue {
+        flag: flag.to_string(),
+        value: value.clone(),
+        reason: e.to_string(),
+    })
+}
+
+/// Parse command-line arguments (args[0] is the program name)
+fn parse_args(args: &[String]) -> Result<Config, ParseError> {
     let mut hostsfile: Option<String> = None;
     let mut state: usize = 0;
     let mut token_delay: f64 = 1.0;
@@ -39,164 +787,201 @@ fn parse_args() -> (String, usize, f64, f64, u64, bool, Option<u64>) {
     let mut snapshot_start: u64 = 0;
     let mut i = 1;
     let mut is_initiator = false;
-    let mut snapshot_id: Option<u64> = None;
+    let mut snapshot_ids: Vec<u64> = Vec::new();
+    let mut udp_port: u16 = DEFAULT_UDP_PORT;
+    let mut token_port: u16 = DEFAULT_TOKEN_PORT;
+    let mut marker_port: Option<u16> = None;
+    let mut startup_timeout: u64 = DEFAULT_STARTUP_TIMEOUT_SECS;
+    let mut json = false;
+    let mut channel_delays: Vec<ChannelDelay> = Vec::new();
+    let mut reverse = false;
+    let mut chatter: f64 = 0.0;
+    let mut done_timeout: u64 = DEFAULT_DONE_TIMEOUT_SECS;
+    let mut bounce_at: Option<u32> = None;
+    let mut crash_after_tokens: Option<u64> = None;
+    let mut crash_holding = false;
+    let mut quiet = false;
+    let mut stats_every: u64 = 0;
+    let mut keepalive: f64 = 0.0;
+    let mut keepalive_misses: u32 = DEFAULT_KEEPALIVE_MISSES;
+    let mut control_port: Option<u16> = None;
+    let mut restore: Option<String> = None;
+    let mut quiet_tokens: Option<u64> = None;
+    let mut snapshot_every: Option<usize> = None;
 
     while i < args.len() {
         match args[i].as_str() {
-            "-h" => {
-                if i + 1 < args.len() {
-                    hostsfile = Some(args[i + 1].clone());
-                    i += 1;
-                } else {
-                    eprintln!("Error: Missing argument for -h");
-                    process::exit(1);
-                }
-            }
+            "-h" => hostsfile = Some(flag_value(args, &mut i, "-h")?),
             "-x" => {
                 state = 1;
                 is_initiator = true;
             }
-            "-t" => {
-                if i + 1 < args.len() {
-                    match args[i + 1].parse::<f64>() {
-                        Ok(val) => token_delay = val,
-                        Err(e) => {
-                            eprintln!("Error: Invalid argument for -t: {}", e);
-                            process::exit(1);
-                        }
-                    }
-                    i += 1;
-                } else {
-                    eprintln!("Error: Missing argument for -t");
-                    process::exit(1);
-                }
-            }
-            "-m" => {
-                if i + 1 < args.len() {
-                    match args[i + 1].parse::<f64>() {
-                        Ok(val) => marker_delay = val,
-                        Err(e) => {
-                            eprintln!("Error: Invalid argument for -m: {}", e);
-                            process::exit(1);
-                        }
-                    }
-                    i += 1;
-                } else {
-                    eprintln!("Error: Missing argument for -m");
-                    process::exit(1);
-                }
-            }
-            "-s" => {
-                if i + 1 < args.len() {
-                    match args[i + 1].parse::<u64>() {
-                        Ok(val) => snapshot_start = val,
-                        Err(e) => {
-                            eprintln!("Error: Invalid argument for -s: {}", e);
-                            process::exit(1);
-                        }
-                    }
-                    i += 1;
-                } else {
-                    eprintln!("Error: Missing argument for -s");
-                    process::exit(1);
-                }
-            }
+            "-t" => token_delay = flag_value(args, &mut i, "-t")?,
+            "-m" => marker_delay = flag_value(args, &mut i, "-m")?,
+            "-s" => snapshot_start = flag_value(args, &mut i, "-s")?,
             "-p" => {
-                if i + 1 < args.len() {
-                    match args[i + 1].parse::<u64>() {
-                        Ok(val) => snapshot_id = Some(val),
-                        Err(e) => {
-                            eprintln!("Error: Invalid argument for -p: {}", e);
-                            process::exit(1);
-                        }
-                    }
-                    i += 1;
-                } else {
-                    eprintln!("Error: Missing argument for -p");
-                    process::exit(1);
+                let seq: u64 = flag_value(args, &mut i, "-p")?;
+                if snapshot_ids.contains(&seq) {
+                    return Err(ParseError::InvalidValue {
+                        flag: "-p".to_string(),
+                        value: seq.to_string(),
+                        reason: "snapshot id given more than once".to_string(),
+                    });
                 }
+                snapshot_ids.push(seq);
             }
-            other => {
-                eprintln!("Unknown option: {}", other);
-                process::exit(1);
+            "--udp-port" => udp_port = flag_value(args, &mut i, "--udp-port")?,
+            "--token-port" => token_port = flag_value(args, &mut i, "--token-port")?,
+            "--marker-port" => marker_port = Some(flag_value(args, &mut i, "--marker-port")?),
+            "--startup-timeout" => startup_timeout = flag_value(args, &mut i, "--startup-timeout")?,
+            "--json" => json = true,
+            "--reverse" => reverse = true,
+            "--chatter" => chatter = flag_value(args, &mut i, "--chatter")?,
+            "--done-timeout" => done_timeout = flag_value(args, &mut i, "--done-timeout")?,
+            "--bounce-at" => bounce_at = Some(flag_value(args, &mut i, "--bounce-at")?),
+            "--crash-after-tokens" => crash_after_tokens = Some(flag_value(args, &mut i, "--crash-after-tokens")?),
+            "--crash-holding" => crash_holding = true,
+            "--quiet" => quiet = true,
+            "--stats-every" => stats_every = flag_value(args, &mut i, "--stats-every")?,
+            "--keepalive" => keepalive = flag_value(args, &mut i, "--keepalive")?,
+            "--keepalive-misses" => keepalive_misses = flag_value(args, &mut i, "--keepalive-misses")?,
+            "--control-port" => control_port = Some(flag_value(args, &mut i, "--control-port")?),
+            "--restore" => restore = Some(flag_value(args, &mut i, "--restore")?),
+            "--quiet-tokens" => quiet_tokens = Some(flag_value(args, &mut i, "--quiet-tokens")?),
+            "--snapshot-every" => {
+                let every: usize = flag_value(args, &mut i, "--snapshot-every")?;
+                if every == 0 {
+                    return Err(ParseError::InvalidValue {
+                        flag: "--snapshot-every".to_string(),
+                        value: every.to_string(),
+                        reason: "must be at least 1".to_string(),
+                    });
+                }
+                snapshot_every = Some(every);
             }
+            "--channel-delay" => channel_delays.push(flag_value(args, &mut i, "--channel-delay")?),
+            other => return Err(ParseError::UnknownOption(other.to_string())),
         }
         i += 1;
     }
 
-    let hostsfile = match hostsfile {
-        Some(h) => h,
-        None => {
-            eprintln!(
-                "Error: Missing hostsfile path. Usage: {} -h <hostsfile> [-x] [-t <token_delay>] [-m <marker_delay>] [-s <snapshot_start>]",
-                args[0]
-            );
-            process::exit(1);
-        }
-    };
+    let hostsfile = hostsfile.ok_or(ParseError::MissingHostsfile)?;
 
     if !Path::new(&hostsfile).exists() {
-        eprintln!("Error: Hostsfile not found: {}", hostsfile);
-        process::exit(1);
+        return Err(ParseError::HostsfileNotFound(hostsfile));
     }
 
-    (hostsfile, state, token_delay, marker_delay, snapshot_start, is_initiator, snapshot_id)
-}
+    if udp_port == token_port {
+        return Err(ParseError::SamePorts(udp_port));
+    }
 
-/// Parse hostsfile, returns current user and list of peers 
-fn parse_hostfile(hostsfile: &String) -> (UserInfo, Vec<UserInfo>) {
-    let my_name = match hostname::get() {
-        Ok(my_name) => my_name.into_string().unwrap_or_else(|_| "unknown".to_string()),
-        Err(e) => {
-            eprintln!("parse_hostfile error: Failed to get host name: {}", e);
-            process::exit(1);
-        }
-    };
+    if token_port.checked_add(1).is_none() && marker_port.is_none() {
+        return Err(ParseError::NoMarkerPort(token_port));
+    }
 
-    let file = File::open(&hostsfile).unwrap_or_else(|e| {
-        eprintln!("parse_hostfile error: Failed to open file: {}", e);
-        process::exit(1);
-    });
+    // The bounced leg and control-socket snapshots need the marker connections, which only exist with -m
+    if bounce_at.is_some() && marker_delay == 0.0 {
+        return Err(ParseError::NeedsMarkers("--bounce-at"));
+    }
+    if control_port.is_some() && marker_delay == 0.0 {
+        return Err(ParseError::NeedsMarkers("--control-port"));
+    }
+
+    if snapshot_every.is_some() && marker_delay == 0.0 {
+        return Err(ParseError::NeedsMarkers("--snapshot-every"));
+    }
+    if snapshot_every.is_some() && snapshot_ids.len() != 1 {
+        return Err(ParseError::SnapshotEveryBase(snapshot_ids.len()));
+    }
+
+    if crash_holding && crash_after_tokens.is_none() {
+        return Err(ParseError::CrashHoldingWithoutCount);
+    }
+
+    Ok(Config {
+        hostsfile,
+        state,
+        token_delay,
+        marker_delay,
+        snapshot_start,
+        is_initiator,
+        snapshot_ids,
+        udp_port,
+        token_port,
+        marker_port,
+        startup_timeout,
+        json,
+        channel_delays,
+        reverse,
+        chatter,
+        done_timeout,
+        bounce_at,
+        crash_after_tokens,
+        crash_holding,
+        quiet,
+        stats_every,
+        keepalive,
+        keepalive_misses,
+        control_port,
+        restore,
+        quiet_tokens,
+        snapshot_every,
+    })
+}
+
+/// Parse hostsfile, returns current user and list of peers.
+/// Blank lines are skipped and ids are assigned in order of appearance starting at 1.
+fn parse_hostfile(hostsfile: &str, my_name: &str) -> Result<(UserInfo, Vec<UserInfo>), ParseError> {
+    let file = File::open(hostsfile).map_err(|e| ParseError::HostsfileIo {
+        path: hostsfile.to_string(),
+        reason: e.to_string(),
+    })?;
 
     let reader = BufReader::new(file);
     let mut peers: Vec<UserInfo> = Vec::new();
-    let mut my_user_id = 0;
+    let mut my_user_id = None;
 
     for (i, line) in reader.lines().enumerate() {
-        match line {
-            Ok(l) => {
-                let trimmed = l.trim();
-                if trimmed.is_empty() {
-                    continue;
-                }
-                let user = UserInfo {
-                    name: trimmed.to_string(),
-                    id: (i + 1) as u32,
-                };
-                
-                if user.name == my_name {
-                    my_user_id = user.id;
-                }
+        let l = line.map_err(|e| ParseError::HostsfileLine {
+            line: i + 1,
+            reason: e.to_string(),
+        })?;
+        let trimmed = l.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+        let user = UserInfo {
+            name: trimmed.to_string(),
+            id: peers.len() as u32 + 1,
+        };
 
-                peers.push(user);
-            }
-            Err(e) => {
-                eprintln!("parse_hostfile error: Failed to read line: {}", e);
-                process::exit(1);
-            }
+        if user.name == my_name {
+            my_user_id = Some(user.id);
         }
+
+        peers.push(user);
+    }
+
+    if peers.is_empty() {
+        return Err(ParseError::EmptyHostsfile(hostsfile.to_string()));
     }
 
+    // Without this the id would silently be 0 and break get_predecessor later
+    let my_user_id = my_user_id.ok_or_else(|| ParseError::HostNotInHostsfile {
+        host: my_name.to_string(),
+        path: hostsfile.to_string(),
+    })?;
+
     let my_user = UserInfo {
-        name: my_name,
-        id: my_user_id, // If my_name isn't found, id will be 0.
+        name: my_name.to_string(),
+        id: my_user_id,
     };
 
-    (my_user, peers)
+    Ok((my_user, peers))
 }
 
 // Given a user and a list of peers, return the user's predecessor
-fn get_predecessor(my_user: &UserInfo, peers: &Vec<UserInfo>) -> UserInfo {
+fn get_predecessor(my_user: &UserInfo, peers: &[UserInfo]) -> UserInfo {
     let my_id = my_user.id;
     let peer_count = peers.len() as u32;
     let predecessor_id = if my_id == 1 { peer_count } else { my_id - 1 };
@@ -208,7 +993,7 @@ fn get_predecessor(my_user: &UserInfo, peers: &Vec<UserInfo>) -> UserInfo {
 }
 
 // Given a user and a list of peers, return the user's successor
-fn get_successor(my_user: &UserInfo, peers: &Vec<UserInfo>) -> UserInfo {
+fn get_successor(my_user: &UserInfo, peers: &[UserInfo]) -> UserInfo {
     let my_id = my_user.id;
     let peer_count = peers.len() as u32;
     let successor_id = if my_id == peer_count { 1 } else { my_id + 1 };
@@ -219,70 +1004,156 @@ fn get_successor(my_user: &UserInfo, peers: &Vec<UserInfo>) -> UserInfo {
     successor.clone()
 }
 
-fn run() -> io::Result<()> {
-    // Parse command-line arguments
-    let (hostsfile, mut state, token_delay, marker_delay, snapshot_start, is_initiator, snapshot_id) = parse_args();
-    let (my_user, full_list_of_peers) = parse_hostfile(&hostsfile);
+// Where this process sends the token: the successor, or the predecessor when reversed.
+// The incoming token connection is accepted from whichever neighbor connects.
+fn get_next_hop(my_user: &UserInfo, peers: &[UserInfo], reverse: bool) -> UserInfo {
+    if reverse {
+        get_predecessor(my_user, peers)
+    } else {
+        get_successor(my_user, peers)
+    }
+}
+
+fn run(config: Config, my_user: UserInfo, full_list_of_peers: Vec<UserInfo>) -> io::Result<()> {
 
     // ========== Project 1 ========== //
 
-    // Create and bind a UDP socket on UDP_PORT 8888.
-    let socket = UdpSocket::bind(format!("0.0.0.0:{}", UDP_PORT))?;
+    // Create and bind a UDP socket on the UDP port (8888 by default).
+    let socket = UdpSocket::bind(format!("0.0.0.0:{}", config.udp_port))?;
     // Set a short read timeout (100 ms)
     socket.set_read_timeout(Some(Duration::from_millis(100)))?;
 
     // Hand off to the failsafe_startup loop.
     let peers: Vec<String> = full_list_of_peers.iter().map(|u| u.name.clone()).collect();
     let my_name = my_user.name.clone();
-    let _ = failsafe_startup(&socket, &peers, &my_name);
+    failsafe_startup(
+        &socket,
+        &peers,
+        &my_name,
+        config.udp_port,
+        Duration::from_secs(config.startup_timeout),
+    )?;
 
     // ========== Project 2 ========== //
     let predecessor = get_predecessor(&my_user, &full_list_of_peers).id;
     let successor = get_successor(&my_user, &full_list_of_peers).id;
 
-    // Print our ID, state, predecessor, and successor.
-    println!(
-        "{{id: {}, state: {}, predecessor: {}, successor: {}}}",
-        my_user.id, state, predecessor, successor
-    );
-    io::stdout().flush().unwrap();
+    // Resume the counter from a recorded snapshot instead of 0 or 1
+    let (start_state, restored) = match &config.restore {
+        Some(path) => {
+            let (state, id) = load_restore(path, my_user.id)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            (state, format!(", restored_from: {}", id))
+        }
+        None => (config.state, String::new()),
+    };
+
+    // Both loops share one ProcessState, snapshot recording reads it through the same lock
+    let state = ProcessState::shared(start_state, config.is_initiator);
+
+    if config.marker_delay == 0.0 {
+        // Print our ID, state, predecessor, successor, and the ports in use.
+        emit!(
+            "{{id: {}, state: {}, predecessor: {}, successor: {}, udp_port: {}, token_port: {}{}}}",
+            my_user.id, start_state, predecessor, successor, config.udp_port, config.token_port, restored
+        );
+        io::stdout().flush().unwrap();
 
-    if marker_delay == 0.0 {
         // TEST CASE 1: Token passing in a loop once if no -m argument is provided
-        token_loop(my_user, full_list_of_peers, &mut state, token_delay, is_initiator)?;
+        token_loop(my_user, full_list_of_peers, state, &config)?;
     } else {
+        // Bind the marker listener up front so the startup line reports the port actually in use
+        let (marker_listener, marker_port) = bind_marker_listener(&config)?;
+
+        emit!(
+            "{{id: {}, state: {}, predecessor: {}, successor: {}, udp_port: {}, token_port: {}, marker_port: {}{}}}",
+            my_user.id, start_state, predecessor, successor, config.udp_port, config.token_port, marker_port, restored
+        );
+        io::stdout().flush().unwrap();
+
         // TEST CASE 2: Modified version of test case 1 with Chandy Lamport snapshot algorithm
-        let state_arc = Arc::new(Mutex::new(state));
-        token_snapshot_loop(my_user, full_list_of_peers, state_arc, token_delay, marker_delay, snapshot_start, snapshot_id, is_initiator)?;
+        token_snapshot_loop(my_user, full_list_of_peers, state, &config, marker_listener, marker_port)?;
     }
     
-    return Ok(());
+    Ok(())
+}
+
+/// Read the state recorded by the last "complete" record in a file of --json snapshot output.
+/// Other lines are skipped.
+fn load_restore(path: &str, my_id: u32) -> Result<(usize, SnapshotId), RestoreError> {
+    let io_error = |e: io::Error| RestoreError::Io { path: path.to_string(), reason: e.to_string() };
+    let file = File::open(path).map_err(io_error)?;
+
+    let mut last = None;
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(io_error)?;
+        let Some(record) = SavedRecord::parse_line(&line) else { continue };
+        if let SnapshotEvent::Complete { state, .. } = record.event {
+            last = Some((record.proc_id, state, record.snapshot_id));
+        }
+    }
+
+    match last {
+        None => Err(RestoreError::NoCompleteRecord(path.to_string())),
+        Some((proc_id, _, _)) if proc_id != my_id => Err(RestoreError::WrongProcess {
+            path: path.to_string(),
+            proc_id,
+            my_id,
+        }),
+        Some((_, state, id)) => Ok((state, id)),
+    }
+}
+
+/// Bind the marker listener on token_port + 1, falling back to --marker-port if that fails
+fn bind_marker_listener(config: &Config) -> io::Result<(TcpListener, u16)> {
+    let mut last_err = None;
+    for port in config.marker_port_candidates() {
+        match TcpListener::bind(format!("0.0.0.0:{}", port)) {
+            Ok(listener) => return Ok((listener, port)),
+            Err(e) => {
+                log!("Could not bind marker port {}: {}", port, e);
+                last_err = Some(e);
+            }
+        }
+    }
+    Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, "no marker port available")))
 }
 
 fn token_snapshot_loop(
     my_user: UserInfo,
     full_list_of_peers: Vec<UserInfo>,
-    state: Arc<Mutex<usize>>,
-    token_delay: f64,
-    marker_delay: f64,
-    snapshot_start: u64,  // seconds to wait before initiating snapshot
-    snapshot_id: Option<u64>, 
-    is_initiator: bool
+    state: SharedState,
+    config: &Config,
+    marker_listener: TcpListener,
+    marker_port: u16,
 ) -> io::Result<()> {
+    let token_delay = config.token_delay;
+    let quiet_tokens = config.quiet_tokens.is_some();
+    let marker_delay = config.marker_delay;
+    let snapshot_start = config.snapshot_start;
+    let is_initiator = config.is_initiator;
+
+    // The direction tokens start out in, and our neighbors in each direction
+    let initial_direction = if config.reverse { Direction::Ccw } else { Direction::Cw };
+    let successor = get_successor(&my_user, &full_list_of_peers);
+    let predecessor = get_predecessor(&my_user, &full_list_of_peers);
+
     // 1. Bind a TCP listener for incoming connections
-    let listener_addr = format!("0.0.0.0:{}", TOKEN_PORT);
+    let listener_addr = format!("0.0.0.0:{}", config.token_port);
     let listener = TcpListener::bind(&listener_addr)?;
     
     // 2. First, establish the TOKEN RING connection 
-    // Connect to our successor in the ring
-    let successor = get_successor(&my_user, &full_list_of_peers);
-    let successor_addr = format!("{}:{}", successor.name, TOKEN_PORT);
+    // Connect to the next hop in the ring (our successor, or predecessor with --reverse)
+    let next_hop = get_next_hop(&my_user, &full_list_of_peers, config.reverse);
+    let next_addr = format!("{}:{}", next_hop.name, config.token_port);
     let mut outgoing: Option<TcpStream> = None;
     
     // Try to connect multiple times
     for _ in 0..10 {
-        match TcpStream::connect(&successor_addr) {
+        match TcpStream::connect(&next_addr) {
             Ok(stream) => {
+                // Nagle would otherwise hold small token writes back
+                stream.set_nodelay(true)?;
                 outgoing = Some(stream);
                 break;
             }
@@ -292,380 +1163,826 @@ fn token_snapshot_loop(
     
     if outgoing.is_none() {
         return Err(io::Error::new(io::ErrorKind::ConnectionRefused, 
-                                 "Could not connect to successor"));
+                                 "Could not connect to next hop"));
     }
     
-    let mut successor_stream = outgoing.unwrap();
+    // Shared with the keepalive thread, which pings over the same stream
+    let next_stream = Arc::new(Mutex::new(outgoing.unwrap()));
     
-    // Accept a connection from our predecessor
+    // Accept a connection from our predecessor. The listener stays open so the
+    // predecessor can reconnect if its forward fails.
+    let accept_listener = listener.try_clone()?;
     let incoming_handle = thread::spawn(move || -> io::Result<TcpStream> {
-        let (stream, _) = listener.accept()?;
+        let (stream, _) = accept_listener.accept()?;
         Ok(stream)
     });
     
     let predecessor_stream = incoming_handle.join().expect("Thread panicked")?;
-    let mut predecessor_reader = BufReader::new(predecessor_stream.try_clone()?);
-    
-    // 3. Set up shared state for snapshot tracking
-    let snapshot_started = Arc::new(AtomicBool::new(false));
-    let snapshot_record = Arc::new(Mutex::new(Vec::<String>::new()));
-    let closed_channels = Arc::new(Mutex::new(HashSet::<String>::new()));
-    let closed_channels_count = Arc::new(AtomicUsize::new(0));
-    let total_channels = full_list_of_peers.len() - 1; // All peers except self
-    let snapshot_id_val = snapshot_id.unwrap_or(1);
+    let predecessor_reader = BufReader::new(predecessor_stream.try_clone()?);
+
+    // Artificial delays on channels into this process, by sender
+    let delays_to_me: Arc<HashMap<u32, Duration>> = Arc::new(
+        config
+            .channel_delays
+            .iter()
+            .filter(|d| d.to == my_user.id)
+            .map(|d| (d.from, Duration::from_secs_f64(d.seconds)))
+            .collect(),
+    );
     
-    // 4. Add has_token flag to track token possession
-    let has_token = Arc::new(AtomicBool::new(is_initiator));
+    // Set by the termination path so the marker listener and its readers can exit
+    let shutdown = Arc::new(AtomicBool::new(false));
     
-    // 5. Set up TCP connections to all peers for markers
+    // 4. Set up TCP connections to all peers for markers
     let mut marker_connections: HashMap<u32, TcpStream> = HashMap::new();
     
-    // Create a new listener just for marker connections (best I can do)
-    let marker_listener = TcpListener::bind(format!("0.0.0.0:{}", TOKEN_PORT + 1))?;
-    marker_listener.set_nonblocking(true)?;
+    // The marker listener was bound by run(), peers may be on the fallback port
+    let marker_ports = config.marker_port_candidates();
     
     // Connect to all other peers (except self) for markers
     for peer in &full_list_of_peers {
         if peer.id != my_user.id {
-            let peer_addr = format!("{}:{}", peer.name, TOKEN_PORT + 1);
-            
-            for attempt in 1..=5 {
-                match TcpStream::connect(&peer_addr) {
-                    Ok(stream) => {
-                        marker_connections.insert(peer.id, stream);
-                        break;
+            'attempts: for attempt in 1..=5 {
+                for &port in &marker_ports {
+                    let peer_addr = format!("{}:{}", peer.name, port);
+                    match TcpStream::connect(&peer_addr) {
+                        Ok(stream) => {
+                            // Bounced tokens travel here too
+                            stream.set_nodelay(true)?;
+                            marker_connections.insert(peer.id, stream);
+                            break 'attempts;
+                        }
+                        Err(e) if attempt == 5 => {
+                            log!("Failed to establish marker connection to peer {} on port {} after 5 attempts: {}", peer.id, port, e);
+                        }
+                        Err(_) => {}
                     }
-                    Err(_) if attempt < 5 => {
-                        thread::sleep(Duration::from_millis(1000));
+                }
+                if attempt < 5 {
+                    thread::sleep(Duration::from_millis(1000));
+                }
+            }
+        }
+    }
+    
+    // 5. Shared snapshot bookkeeping, kept separately for every initiation
+    let snapshots = SnapshotContext {
+        my_id: my_user.id,
+        // With --bounce-at the token also comes back the other way
+        ring_channels: if config.bounce_at.is_some() {
+            vec![predecessor.id, successor.id]
+        } else {
+            vec![get_next_hop(&my_user, &full_list_of_peers, !config.reverse).id]
+        },
+        process_ids: full_list_of_peers.iter().map(|p| p.id).collect(),
+        total_channels: full_list_of_peers.len() - 1, // All peers except self
+        marker_delay,
+        done_timeout: Duration::from_secs(config.done_timeout),
+        json: config.json,
+        state: Arc::clone(&state),
+        chatter: Arc::new(Mutex::new(Chatter::default())),
+        initiations: Arc::new(Mutex::new(HashMap::new())),
+        marker_connections: Arc::new(Mutex::new(marker_connections)),
+    };
+    
+    // Token lines from the ring reader and from the mesh (bounced tokens) meet here
+    let (incoming_tx, incoming) = mpsc::channel();
+    let inbox: TokenInbox = Arc::new(Mutex::new(Some(incoming_tx)));
+
+    // 6. Start accepting marker connections from other peers. The acceptor joins its
+    // readers once shutdown is set, and every exit path below waits for it via stop_mesh.
+    let acceptor = spawn_mesh_acceptor(
+        marker_listener,
+        snapshots.clone(),
+        Arc::clone(&shutdown),
+        Arc::clone(&delays_to_me),
+        Arc::clone(&inbox),
+    );
+    
+    // Background application traffic so channel recordings hold more than the token
+    if config.chatter > 0.0 {
+        let snapshots = snapshots.clone();
+        let shutdown = Arc::clone(&shutdown);
+        let interval = Duration::from_secs_f64(1.0 / config.chatter);
+
+        thread::spawn(move || {
+            let mut rng = XorShift::seeded(snapshots.my_id as u64);
+            while !shutdown.load(Ordering::SeqCst) {
+                thread::sleep(interval);
+                snapshots.send_chatter(&mut rng);
+            }
+        });
+    }
+
+    // 7. If this process is the token initiator, send the initial token
+    if is_initiator {
+        let now = epoch_millis();
+        let token_msg = format!("token:{}:{}:{}:{}\n", my_user.id, initial_direction, now, now);
+        forward_token(&mut next_stream.lock().unwrap(), &next_addr, &token_msg)?;
+        if !quiet_tokens {
+            emit!("{{id: {}, sender: {}, receiver: {}, message:\"token\", direction:\"{}\"}}",
+                     my_user.id, my_user.id, next_hop.id, initial_direction);
+        }
+        
+        // Set has_token to false after sending
+        state.lock().unwrap().has_token = false;
+    }
+    
+    // 8. Set up snapshot initiation if needed. The id is composite so several
+    // processes can initiate at once without their snapshots mixing, and each
+    // initiation has its own bookkeeping so repeated -p need no reset in between.
+    // With --snapshot-every the token loop starts them instead, from the -p base.
+    if !config.snapshot_ids.is_empty() && config.snapshot_every.is_none() {
+        let snapshots = snapshots.clone();
+        let ids: Vec<SnapshotId> = config
+            .snapshot_ids
+            .iter()
+            .map(|&seq| SnapshotId { initiator: my_user.id, seq })
+            .collect();
+        
+        thread::spawn(move || {
+            for id in ids {
+                // Wait before starting each snapshot
+                thread::sleep(Duration::from_secs(snapshot_start));
+                snapshots.initiate(id);
+            }
+        });
+    }
+    
+    // Snapshots on demand: "snapshot <seq>" or "status", one command per line
+    if let Some(port) = config.control_port {
+        let control_listener = TcpListener::bind(format!("0.0.0.0:{}", port))?;
+        let snapshots = snapshots.clone();
+        thread::spawn(move || {
+            for stream in control_listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let snapshots = snapshots.clone();
+                        thread::spawn(move || serve_control(stream, snapshots));
                     }
-                    Err(e) => {
-                        println!("Failed to establish marker connection to peer {} after 5 attempts: {}", peer.id, e);
+                    Err(e) => log!("Error accepting control connection: {}", e),
+                }
+            }
+        });
+    }
+    
+    // 9. Read the predecessor on its own thread. Lines go through a delay queue
+    // so --channel-delay can hold a message in transit before we process it.
+    spawn_token_reader(predecessor_reader, listener, Arc::clone(&delays_to_me), Arc::clone(&inbox));
+
+    if config.keepalive > 0.0 {
+        spawn_keepalive(
+            my_user.id,
+            Arc::clone(&next_stream),
+            Arc::clone(&state),
+            inbox,
+            Duration::from_secs_f64(config.keepalive),
+            config.keepalive_misses,
+        );
+    }
+    
+    // 10. MAIN LOOP: Process token messages from predecessor
+    let mut stats = TokenStats::default();
+    let mut throughput = Throughput::new();
+    let mut next_snapshot = SnapshotId { initiator: my_user.id, seq: config.snapshot_ids.first().copied().unwrap_or(0) };
+    while let Some(line) = recv_delayed(&incoming) {
+        let line = line.trim_end();
+        
+        if line.starts_with("token:") {
+            // Process token message
+            let Some(token) = parse_token(line) else {
+                log!("Invalid token format: {}", line);
+                continue;
+            };
+            let sender_id = token.sender;
+            let direction = token.direction.unwrap_or(initial_direction);
+
+            // The initiator closes a round and starts the next one
+            let now = epoch_millis();
+            if let Some(sent) = token.sent_ms {
+                stats.hop.record(now.saturating_sub(sent));
+            }
+            let born = match token.born_ms {
+                Some(born) if is_initiator => {
+                    stats.round.record(now.saturating_sub(born));
+                    now
+                }
+                Some(born) => born,
+                None => now,
+            };
+            
+            // With --bounce-at the token passes processes twice, once each way, so only
+            // receipts in the starting direction count as rounds
+            let new_round = direction == initial_direction;
+            throughput.record(new_round);
+            if !quiet_tokens {
+                emit!("{{id: {}, sender: {}, receiver: {}, message:\"token\", direction:\"{}\"}}",
+                    my_user.id, sender_id, my_user.id, direction);
+            }
+            
+            // Take the token, record it on snapshots still listening to the channel and update state
+            let counter = snapshots.receive_token(sender_id, quiet_tokens);
+            stats.rounds += 1;
+            if config.stats_every > 0 && stats.rounds % config.stats_every == 0 {
+                emit_stats(my_user.id, &stats);
+            }
+            if let Some(every) = config.quiet_tokens {
+                if new_round && every > 0 && throughput.rounds % every == 0 {
+                    emit_throughput(my_user.id, &throughput);
+                }
+            }
+
+            // Periodic snapshot while holding the token. Ids already taken over the
+            // control port are skipped so the two never share an initiation.
+            if let Some(every) = config.snapshot_every {
+                if counter % every == 0 {
+                    while snapshots.knows(next_snapshot) {
+                        next_snapshot.seq += 1;
                     }
+                    snapshots.initiate(next_snapshot);
+                    next_snapshot.seq += 1;
+                }
+            }
+            
+            // Sleep before forwarding token, skipped entirely for -t 0
+            if token_delay > 0.0 {
+                thread::sleep(Duration::from_secs_f64(token_delay));
+            }
+            
+            if config.crash_holding && config.crash_due(stats.rounds) {
+                crash(my_user.id, stats.rounds, true);
+            }
+            
+            // Forward token onwards, reversing at the --bounce-at process
+            let direction = if config.bounce_at == Some(my_user.id) { direction.flip() } else { direction };
+            let target = match direction {
+                Direction::Cw => &successor,
+                Direction::Ccw => &predecessor,
+            };
+            if !quiet_tokens {
+                emit!("{{id: {}, sender: {}, receiver: {}, message:\"token\", direction:\"{}\"}}",
+                    my_user.id, my_user.id, target.id, direction);
+            }
+            
+            // has_token stays true while retrying so a snapshot in that window records it.
+            // Against the ring direction there is no token stream, so the mesh carries it.
+            let token_msg = format!("token:{}:{}:{}:{}\n", my_user.id, direction, epoch_millis(), born);
+            let sent = if target.id == next_hop.id {
+                forward_token(&mut next_stream.lock().unwrap(), &next_addr, &token_msg)
+            } else {
+                snapshots.send_on_mesh(target.id, &token_msg)
+            };
+            if let Err(e) = sent {
+                log!("Fatal: could not forward token to {}: {}", target.id, e);
+                stop_mesh(&shutdown, marker_port, acceptor);
+                return Err(e);
+            }
+            
+            // Set has_token to false after sending
+            state.lock().unwrap().has_token = false;
+
+            if config.crash_due(stats.rounds) {
+                crash(my_user.id, stats.rounds, false);
+            }
+        } else if line.starts_with("broken:") {
+            // Posted by the keepalive thread, handled like a failed forward
+            log!("Fatal: next hop {} stopped answering keepalive pings", next_hop.id);
+            stop_mesh(&shutdown, marker_port, acceptor);
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "next hop stopped answering keepalive pings"));
+        } else if line.starts_with("marker:") {
+            // Handle marker on the token channel
+            // This code ensures backward compatibility if needed
+            let parts: Vec<&str> = line.splitn(3, ':').collect();
+            if parts.len() == 3 {
+                log!("Received marker on token channel, ignoring");
+            }
+        } else {
+            log!("Unknown message received: {}", line);
+        }
+    }
+
+    emit_stats(my_user.id, &stats);
+    if quiet_tokens {
+        emit_throughput(my_user.id, &throughput);
+    }
+    stop_mesh(&shutdown, marker_port, acceptor);
+
+    Ok(())
+}
+
+/// Accept marker connections and read each one on its own thread until `shutdown` is set,
+/// then join those readers before returning
+fn spawn_mesh_acceptor(
+    marker_listener: TcpListener,
+    snapshots: SnapshotContext,
+    shutdown: Arc<AtomicBool>,
+    delays: Arc<HashMap<u32, Duration>>,
+    inbox: TokenInbox,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut readers: Vec<thread::JoinHandle<()>> = Vec::new();
+        loop {
+            let accepted = marker_listener.accept();
+            if shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+            match accepted {
+                Ok((stream, _)) => {
+                    let snapshots = snapshots.clone();
+                    let shutdown = Arc::clone(&shutdown);
+                    let delays = Arc::clone(&delays);
+                    let inbox = Arc::clone(&inbox);
+                    readers.push(thread::spawn(move || read_mesh(stream, snapshots, shutdown, delays, inbox)));
+                }
+                Err(e) => {
+                    log!("Error accepting marker connection: {}", e);
+                    break;
+                }
+            }
+        }
+        for reader in readers {
+            let _ = reader.join();
+        }
+    })
+}
+
+/// Read the token channel from the predecessor and queue each line with its delivery time.
+/// If the stream drops, give the predecessor a chance to reconnect before hanging up.
+fn spawn_token_reader(
+    mut reader: BufReader<TcpStream>,
+    listener: TcpListener,
+    delays: Arc<HashMap<u32, Duration>>,
+    inbox: TokenInbox,
+) {
+    thread::spawn(move || {
+        read_token_channel(&mut reader, &listener, &delays, &inbox);
+        // Dropping the sender ends the token loop once queued lines are drained
+        inbox.lock().unwrap().take();
+    });
+}
+
+fn read_token_channel(
+    reader: &mut BufReader<TcpStream>,
+    listener: &TcpListener,
+    delays: &HashMap<u32, Duration>,
+    inbox: &TokenInbox,
+) {
+    loop {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) => {
+                // Connection closed, give the predecessor a chance to reconnect
+                match accept_reconnect(listener, RECONNECT_WINDOW) {
+                    Some(stream) => *reader = BufReader::new(stream),
+                    None => break,
+                }
+            }
+            Ok(_) if line.trim_end() == "ping" => {
+                // Keepalive from the predecessor, answered on the same stream and never queued
+                let mut writer: &TcpStream = reader.get_ref();
+                if let Err(e) = writer.write_all(b"pong\n") {
+                    log!("Error answering keepalive ping: {}", e);
+                }
+            }
+            Ok(_) => {
+                let due = deliver_at(delays, &line);
+                let delivered = inbox.lock().unwrap().as_ref().is_some_and(|tx| tx.send((due, line)).is_ok());
+                if !delivered {
+                    break;
+                }
+            }
+            Err(e) => {
+                log!("Error reading from predecessor: {}", e);
+                match accept_reconnect(listener, RECONNECT_WINDOW) {
+                    Some(stream) => *reader = BufReader::new(stream),
+                    None => break,
                 }
             }
         }
     }
-    
-    // Create a shareable version of the marker connections
-    let marker_connections = Arc::new(Mutex::new(marker_connections));
-    
-    // 6. Start accepting marker connections from other peers (a lot of clone cause Rust borrowing cry)
-    let marker_listener_clone = marker_listener.try_clone()?;
-    let closed_channels_clone = Arc::clone(&closed_channels);
-    let closed_channels_count_clone = Arc::clone(&closed_channels_count);
-    let snapshot_started_clone = Arc::clone(&snapshot_started);
-    let snapshot_record_clone = Arc::clone(&snapshot_record);
-    let marker_connections_clone = Arc::clone(&marker_connections);
-    let state_clone = Arc::clone(&state);
-    let has_token_clone = Arc::clone(&has_token);
-    let my_id = my_user.id;
-    
+}
+
+/// Answer control commands on one connection until the client hangs up
+fn serve_control(stream: TcpStream, snapshots: SnapshotContext) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(e) => {
+            log!("Error setting up control connection: {}", e);
+            return;
+        }
+    };
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else { break };
+        let reply = control_command(line.trim(), &snapshots);
+        if writeln!(writer, "{}", reply).is_err() {
+            break;
+        }
+    }
+}
+
+fn control_command(command: &str, snapshots: &SnapshotContext) -> String {
+    let mut words = command.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some("snapshot"), Some(seq), None) => {
+            let Ok(seq) = seq.parse::<u64>() else {
+                return format!("error: invalid snapshot id '{}'", seq);
+            };
+            let id = SnapshotId { initiator: snapshots.my_id, seq };
+            if snapshots.knows(id) {
+                return format!("error: snapshot {} already started", id);
+            }
+            snapshots.initiate(id);
+            format!("ok: snapshot {} started", id)
+        }
+        (Some("status"), None, None) => {
+            let (counter, has_token) = {
+                let state = snapshots.state.lock().unwrap();
+                (state.counter, state.has_token)
+            };
+            let in_progress: Vec<String> = snapshots.in_progress().iter().map(|id| id.to_string()).collect();
+            format!(
+                "{{id: {}, state: {}, has_token:\"{}\", snapshots_in_progress:[{}]}}",
+                snapshots.my_id, counter, if has_token { "YES" } else { "NO" }, in_progress.join(", ")
+            )
+        }
+        _ => format!("error: unknown command '{}', expected \"snapshot <id>\" or \"status\"", command),
+    }
+}
+
+/// Ping the next hop every `interval` while we don't hold the token, and expect "pong" back on
+/// the same stream. After `max_misses` unanswered pings in a row, post "broken:<id>" so the token
+/// loop gives up the same way it does when a forward fails.
+fn spawn_keepalive(
+    my_id: u32,
+    next_stream: Arc<Mutex<TcpStream>>,
+    state: SharedState,
+    inbox: TokenInbox,
+    interval: Duration,
+    max_misses: u32,
+) {
     thread::spawn(move || {
+        let mut misses = 0;
         loop {
-            match marker_listener_clone.accept() {
-                Ok((stream, _)) => {
-                    let closed_channels = Arc::clone(&closed_channels_clone);
-                    let closed_channels_count = Arc::clone(&closed_channels_count_clone);
-                    let snapshot_started = Arc::clone(&snapshot_started_clone);
-                    let snapshot_record = Arc::clone(&snapshot_record_clone);
-                    let marker_connections = Arc::clone(&marker_connections_clone);
-                    let state = Arc::clone(&state_clone);
-                    let has_token = Arc::clone(&has_token_clone);
-                    let my_id = my_id;
-                    
-                    thread::spawn(move || {
-                        let mut reader = BufReader::new(stream);
-                        
-                        loop {
-                            let mut line = String::new();
-                            match reader.read_line(&mut line) {
-                                Ok(0) => break, // Connection closed
-                                Ok(_) => {
-                                    let line = line.trim_end();
-                                    
-                                    if line.starts_with("marker:") {
-                                        let parts: Vec<&str> = line.splitn(3, ':').collect();
-                                        if parts.len() == 3 {
-                                            let marker_sender: u32 = parts[1].parse().unwrap_or(0);
-                                            let marker_snapshot_id: u64 = parts[2].parse().unwrap_or(0);
-                                            
-                                            // Ignore marker from self
-                                            if marker_sender != my_id {
-                                                // Check if channel is already closed
-                                                let channel_id = format!("{}-{}", marker_sender, my_id);
-                                                let is_first_marker;
-                                                let channel_already_closed;
-                                                
-                                                {
-                                                    let mut closed = closed_channels.lock().unwrap();
-                                                    channel_already_closed = closed.contains(&channel_id);
-                                                    
-                                                    if !channel_already_closed {
-                                                        closed.insert(channel_id.clone());
-                                                        
-                                                        // Check if this is the first marker received
-                                                        is_first_marker = !snapshot_started.load(Ordering::SeqCst);
-                                                    } else {
-                                                        is_first_marker = false;
-                                                    }
-                                                }
-                                                
-                                                if channel_already_closed {
-                                                    continue;
-                                                }
-                                                
-                                                // If this is the first marker received, start participating in the snapshot
-                                                if is_first_marker {
-                                                    snapshot_started.store(true, Ordering::SeqCst);
-                                                    
-                                                    // Current state
-                                                    let current_state = *state.lock().unwrap();
-                                                    
-                                                    // Check if we currently have the token
-                                                    let has_token_value = has_token.load(Ordering::SeqCst);
-                                                    let has_token_str = if has_token_value { "YES" } else { "NO" };
-                                                    
-                                                    let marker_connections_clone = Arc::clone(&marker_connections);
-                                                    
-                                                    thread::spawn(move || {
-                                                        thread::sleep(Duration::from_secs_f64(marker_delay));
-                                                        
-                                                        // Send markers to ALL other peers
-                                                        let connections = marker_connections_clone.lock().unwrap();
-                                                        for (&peer_id, stream) in connections.iter() {
-                                                            if let Ok(mut stream_clone) = stream.try_clone() {
-                                                                let marker_msg = format!("marker:{}:{}\n", my_id, marker_snapshot_id);
-                                                                
-                                                                if let Err(e) = stream_clone.write_all(marker_msg.as_bytes()) {
-                                                                    eprintln!("Error sending marker to peer {}: {}", peer_id, e);
-                                                                    continue;
-                                                                }
-                                                                
-                                                                if let Err(e) = stream_clone.flush() {
-                                                                    eprintln!("Error flushing marker to peer {}: {}", peer_id, e);
-                                                                    continue;
-                                                                }
-                                                                
-                                                                println!("{{proc_id:{}, snapshot_id:{}, sender:{}, receiver:{}, message:\"marker\", state:{}, has_token:\"{}\"}}",
-                                                                    my_id, marker_snapshot_id, my_id, peer_id, current_state, has_token_str);
-                                                            }
-                                                        }
-                                                    });
-                                                }
-                                                
-                                                // Get recorded messages for this channel
-                                                let tokens = {
-                                                    let mut record = snapshot_record.lock().unwrap();
-                                                    std::mem::take(&mut *record)
-                                                };
-                                                
-                                                let token_list = if tokens.is_empty() {
-                                                    String::new()
-                                                } else {
-                                                    tokens.join(", ")
-                                                };
-                                                
-                                                println!("{{proc_id:{}, snapshot_id:{}, snapshot:\"channel closed\", channel:\"{}\", queue:[{}]}}",
-                                                    my_id, marker_snapshot_id, channel_id, token_list);
-                                                
-                                                // Increment closed channels count
-                                                closed_channels_count.fetch_add(1, Ordering::SeqCst);
-                                            }
-                                        }
-                                    }
-                                }
-                                Err(e) => {
-                                    eprintln!("Error reading from marker connection: {}", e);
-                                    break;
-                                }
-                            }
-                        }
-                    });
-                }
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    thread::sleep(Duration::from_millis(100));
-                }
-                Err(e) => {
-                    eprintln!("Error accepting marker connection: {}", e);
-                    break;
-                }
+            thread::sleep(interval);
+            if state.lock().unwrap().has_token {
+                continue;
+            }
+
+            // Cloned each time so a stream replaced by forward_token's reconnect is picked up,
+            // and pinged after the lock is released so forward_token isn't kept waiting
+            let probe = next_stream.lock().unwrap().try_clone();
+            let answered = probe
+                .and_then(|stream| ping_once(stream, interval))
+                .unwrap_or(false);
+            misses = if answered { 0 } else { misses + 1 };
+            if misses < max_misses {
+                continue;
+            }
+
+            let broken = format!("broken:{}\n", my_id);
+            if let Some(tx) = inbox.lock().unwrap().as_ref() {
+                let _ = tx.send((Instant::now(), broken));
             }
+            return;
         }
     });
-    
-    // 7. If this process is the token initiator, send the initial token
-    if is_initiator {
-        let token_msg = format!("token:{}\n", my_user.id);
-        successor_stream.write_all(token_msg.as_bytes())?;
-        successor_stream.flush()?;
-        println!("{{id: {}, sender: {}, receiver: {}, message:\"token\"}}", 
-                 my_user.id, my_user.id, successor.id);
-        
-        // Set has_token to false after sending
-        has_token.store(false, Ordering::SeqCst);
+}
+
+/// Send one "ping" and wait up to `timeout` for the "pong"
+fn ping_once(mut stream: TcpStream, timeout: Duration) -> io::Result<bool> {
+    stream.set_read_timeout(Some(timeout))?;
+    stream.write_all(b"ping\n")?;
+    stream.flush()?;
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match reader.read_line(&mut line) {
+            Ok(0) => return Ok(false),
+            Ok(_) if line.trim_end() == "pong" => return Ok(true),
+            Ok(_) => continue,
+            Err(ref e) if is_timeout(e) => return Ok(false),
+            Err(e) => return Err(e),
+        }
     }
-    
-    // 8. Set up snapshot initiation if needed
-    if snapshot_id.is_some() {
-        let snapshot_id_val = snapshot_id.unwrap();
-        let my_user_clone = my_user.clone();
-        let marker_connections_clone = Arc::clone(&marker_connections);
-        let snapshot_started_clone = Arc::clone(&snapshot_started);
-        let state_clone = Arc::clone(&state);
-        let has_token_clone = Arc::clone(&has_token);
-        
-        thread::spawn(move || {
-            // Wait before starting snapshot
-            thread::sleep(Duration::from_secs(snapshot_start));
-            
-            // Mark snapshot as started
-            snapshot_started_clone.store(true, Ordering::SeqCst);
-            println!("{{proc_id:{}, snapshot_id:{}, snapshot:\"started\"}}", 
-                    my_user_clone.id, snapshot_id_val);
-            
-            thread::sleep(Duration::from_secs_f64(marker_delay));
-            
-            // Get current state
-            let current_state = *state_clone.lock().unwrap();
-            
-            // Check if we currently have the token
-            let has_token_value = has_token_clone.load(Ordering::SeqCst);
-            let has_token_str = if has_token_value { "YES" } else { "NO" };
-            
-            // Send markers to ALL peers
-            let connections = marker_connections_clone.lock().unwrap();
-            for (&peer_id, stream) in connections.iter() {
-                if let Ok(mut stream_clone) = stream.try_clone() {
-                    let marker_msg = format!("marker:{}:{}\n", my_user_clone.id, snapshot_id_val);
-                    
-                    if let Err(e) = stream_clone.write_all(marker_msg.as_bytes()) {
-                        eprintln!("Error sending marker to peer {}: {}", peer_id, e);
-                        continue;
-                    }
-                    
-                    if let Err(e) = stream_clone.flush() {
-                        eprintln!("Error flushing marker to peer {}: {}", peer_id, e);
-                        continue;
-                    }
-                    
-                    println!("{{proc_id:{}, snapshot_id:{}, sender:{}, receiver:{}, message:\"marker\", state:{}, has_token:\"{}\"}}",
-                        my_user_clone.id, snapshot_id_val, my_user_clone.id, peer_id, current_state, has_token_str);
+}
+
+/// When a line read just now may be delivered, per the --channel-delay of its sender
+fn deliver_at(delays: &HashMap<u32, Duration>, line: &str) -> Instant {
+    // Every message starts with "<kind>:<sender>"
+    let sender = line.trim_end().split(':').nth(1).and_then(|s| s.parse::<u32>().ok());
+    let delay = sender.and_then(|s| delays.get(&s)).copied().unwrap_or_default();
+    Instant::now() + delay
+}
+
+/// Wait for the next queued line and hold it until it is due. None once the reader hung up.
+fn recv_delayed(rx: &mpsc::Receiver<Delayed>) -> Option<String> {
+    let (due, line) = rx.recv().ok()?;
+    let now = Instant::now();
+    if due > now {
+        thread::sleep(due - now);
+    }
+    Some(line)
+}
+
+/// Read "marker:<sender>:<snapshot_id>", "msg:<sender>:<seq>", "done:<sender>:<snapshot_id>"
+/// and bounced "token:<sender>:<dir>" lines from one peer until it disconnects or we shut down
+fn read_mesh(
+    stream: TcpStream,
+    snapshots: SnapshotContext,
+    shutdown: Arc<AtomicBool>,
+    delays: Arc<HashMap<u32, Duration>>,
+    inbox: TokenInbox,
+) {
+    // Wake up periodically so the shutdown flag is noticed
+    if let Err(e) = stream.set_read_timeout(Some(READ_POLL_INTERVAL)) {
+        log!("Error setting marker connection timeout: {}", e);
+        return;
+    }
+    let mut reader = BufReader::new(stream);
+    // Kept across timeouts so a partially received line isn't lost
+    let mut buffer = String::new();
+
+    // Mesh messages pass through the delay queue like tokens do
+    let (tx, rx) = mpsc::channel();
+    let processor = thread::spawn(move || {
+        while let Some(line) = recv_delayed(&rx) {
+            let line = line.trim_end();
+            if line.starts_with("token:") {
+                // Already held for its channel delay, hand it over as due now
+                if let Some(tx) = inbox.lock().unwrap().as_ref() {
+                    let _ = tx.send((Instant::now(), line.to_string()));
                 }
+                continue;
             }
-        });
-    }
-    
-    // 9. Set up monitoring thread
-    {
-        let closed_channels_count_clone = Arc::clone(&closed_channels_count);
-        let total_channels_clone = total_channels;
-        let my_user_clone = my_user.clone();
-        let snapshot_id_val = snapshot_id_val;
-        
-        thread::spawn(move || {
-            loop {
-                let closed = closed_channels_count_clone.load(Ordering::SeqCst);
-                
-                if closed == total_channels_clone {
-                    println!("{{proc_id:{}, snapshot_id:{}, snapshot:\"complete\"}}", 
-                        my_user_clone.id, snapshot_id_val);
-                    break;
+            if line.starts_with("msg:") {
+                match line.split(':').nth(1).and_then(|s| s.parse::<u32>().ok()) {
+                    Some(sender) => snapshots.deliver_chatter(sender, line),
+                    None => log!("Invalid chatter message: {}", line),
                 }
-                thread::sleep(Duration::from_millis(500));
+                continue;
             }
-        });
-    }
+            if let Some(rest) = line.strip_prefix("done:") {
+                match parse_sender_and_id(rest) {
+                    Some((sender, id)) => snapshots.handle_done(sender, id),
+                    None => log!("Invalid done message: {}", line),
+                }
+                continue;
+            }
+            match line.strip_prefix("marker:").and_then(parse_sender_and_id) {
+                Some((sender, id)) => snapshots.handle_marker(sender, id),
+                None => log!("Invalid marker message: {}", line),
+            }
+        }
+    });
     
-    // 10. MAIN LOOP: Process token messages from predecessor
     loop {
-        let mut line = String::new();
-        match predecessor_reader.read_line(&mut line) {
+        if shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+        match reader.read_line(&mut buffer) {
             Ok(0) => break, // Connection closed
             Ok(_) => {
-                let line = line.trim_end();
-                
-                if line.starts_with("token:") {
-                    // Process token message
-                    let parts: Vec<&str> = line.splitn(2, ':').collect();
-                    if parts.len() != 2 {
-                        eprintln!("Invalid token format: {}", line);
-                        continue;
-                    }
-                    let sender_id: u32 = parts[1].parse().unwrap_or(0);
-                    
-                    println!("{{id: {}, sender: {}, receiver: {}, message:\"token\"}}", 
-                        my_user.id, sender_id, my_user.id);
-                    
-                    // Set has_token to true when receiving token
-                    has_token.store(true, Ordering::SeqCst);
-                    
-                    // Record token for snapshot if active
-                    if snapshot_started.load(Ordering::SeqCst) {
-                        let mut record = snapshot_record.lock().unwrap();
-                        record.push("token".to_string());
-                    }
-                    
-                    // Update state
-                    {
-                        let mut s = state.lock().unwrap();
-                        *s += 1;
-                        println!("{{id: {}, state: {}}}", my_user.id, *s);
-                    }
-                    
-                    // Sleep before forwarding token
-                    thread::sleep(Duration::from_secs_f64(token_delay));
-                    
-                    // Forward token to successor
-                    println!("{{id: {}, sender: {}, receiver: {}, message:\"token\"}}", 
-                        my_user.id, my_user.id, successor.id);
-                    
-                    let token_msg = format!("token:{}\n", my_user.id);
-                    match successor_stream.write_all(token_msg.as_bytes()) {
-                        Ok(_) => {
-                            if let Err(e) = successor_stream.flush() {
-                                eprintln!("Error flushing token to successor: {}", e);
-                                break;
-                            }
-                            
-                            // Set has_token to false after sending
-                            has_token.store(false, Ordering::SeqCst);
-                        }
-                        Err(e) => {
-                            eprintln!("Error sending token to successor: {}", e);
-                            break;
-                        }
-                    }
-                } else if line.starts_with("marker:") {
-                    // Handle marker on the token channel
-                    // This code ensures backward compatibility if needed
-                    let parts: Vec<&str> = line.splitn(3, ':').collect();
-                    if parts.len() == 3 {
-                        eprintln!("Received marker on token channel, ignoring");
-                    }
-                } else {
-                    eprintln!("Unknown message received: {}", line);
+                let line = std::mem::take(&mut buffer);
+                if tx.send((deliver_at(&delays, &line), line)).is_err() {
+                    break;
                 }
             }
+            Err(ref e) if is_timeout(e) => continue,
+            // Peers dropping their end on exit is expected, not worth a log line
+            Err(ref e) if is_broken_stream(e) => break,
             Err(e) => {
-                eprintln!("Error reading from predecessor: {}", e);
+                log!("Error reading from marker connection: {}", e);
                 break;
             }
         }
     }
 
-    Ok(())
+    // Hanging up the queue lets the processor drain what is left and finish
+    drop(tx);
+    let _ = processor.join();
+}
+
+/// Parse "token:<sender>", "token:<sender>:<dir>" or "token:<sender>:<dir>:<sent_ms>:<born_ms>"
+fn parse_token(line: &str) -> Option<TokenMsg> {
+    let fields: Vec<&str> = line.strip_prefix("token:")?.split(':').collect();
+    let (direction, sent_ms, born_ms) = match fields.len() {
+        1 => (None, None, None),
+        2 => (Some(fields[1].parse().ok()?), None, None),
+        4 => (
+            Some(fields[1].parse().ok()?),
+            Some(fields[2].parse().ok()?),
+            Some(fields[3].parse().ok()?),
+        ),
+        _ => return None,
+    };
+    Some(TokenMsg { sender: fields[0].parse().ok()?, direction, sent_ms, born_ms })
+}
+
+fn epoch_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// "{id: 1, rounds: 12, hop_ms:{min:0, avg:0.4, max:2}, round_ms:{...}}"
+fn emit_stats(id: u32, stats: &TokenStats) {
+    emit!(
+        "{{id: {}, rounds: {}, hop_ms:{}, round_ms:{}}}",
+        id, stats.rounds, stats.hop, stats.round
+    );
+}
+
+/// "{id: 1, tokens: 5000, rounds: 5000, elapsed_ms: 812, tokens_per_sec: 6157.6}"
+fn emit_throughput(id: u32, throughput: &Throughput) {
+    let elapsed = throughput.started.elapsed();
+    let per_sec = if elapsed.is_zero() { 0.0 } else { throughput.tokens as f64 / elapsed.as_secs_f64() };
+    emit!(
+        "{{id: {}, tokens: {}, rounds: {}, elapsed_ms: {}, tokens_per_sec: {:.1}}}",
+        id, throughput.tokens, throughput.rounds, elapsed.as_millis(), per_sec
+    );
+}
+
+/// Parse "<sender>:<initiator>.<seq>", the body of marker and done messages
+fn parse_sender_and_id(rest: &str) -> Option<(u32, SnapshotId)> {
+    let (sender, id) = rest.split_once(':')?;
+    Some((sender.parse().ok()?, id.parse().ok()?))
+}
+
+/// Print a snapshot event on stdout, as JSON with --json or in the legacy format otherwise.
+/// Every snapshot print goes through here so both formats stay in sync.
+fn emit_snapshot(json: bool, proc_id: u32, snapshot_id: SnapshotId, event: &SnapshotEvent) {
+    if json {
+        let record = SnapshotRecord { proc_id, snapshot_id, event };
+        match serde_json::to_string(&record) {
+            Ok(line) => emit!("{}", line),
+            Err(e) => log!("Error serializing snapshot event: {}", e),
+        }
+        return;
+    }
+
+    match event {
+        SnapshotEvent::Started => {
+            emit!("{{proc_id:{}, snapshot_id:{}, snapshot:\"started\"}}", proc_id, snapshot_id);
+        }
+        SnapshotEvent::MarkerSent { sender, receiver, state, has_token } => {
+            emit!(
+                "{{proc_id:{}, snapshot_id:{}, sender:{}, receiver:{}, message:\"marker\", state:{}, has_token:\"{}\"}}",
+                proc_id, snapshot_id, sender, receiver, state, if *has_token { "YES" } else { "NO" }
+            );
+        }
+        SnapshotEvent::ChannelClosed { channel, kind, tokens, queue } => {
+            emit!(
+                "{{proc_id:{}, snapshot_id:{}, snapshot:\"channel closed\", channel:\"{}\", kind:\"{}\", tokens:{}, queue:[{}]}}",
+                proc_id, snapshot_id, channel, kind, tokens, queue.join(", ")
+            );
+        }
+        SnapshotEvent::Complete { has_token, channel_tokens, sent, received, .. } => {
+            let chatter = if sent.is_empty() && received.is_empty() {
+                String::new()
+            } else {
+                format!(", sent:{{{}}}, received:{{{}}}", format_tally(sent), format_tally(received))
+            };
+            emit!(
+                "{{proc_id:{}, snapshot_id:{}, snapshot:\"complete\", has_token:\"{}\", channel_tokens:{}{}}}",
+                proc_id, snapshot_id, if *has_token { "YES" } else { "NO" }, channel_tokens, chatter
+            );
+        }
+        SnapshotEvent::GloballyComplete { processes } => {
+            emit!(
+                "{{snapshot_id:{}, snapshot:\"globally complete\", processes:[{}]}}",
+                snapshot_id, join_ids(processes)
+            );
+        }
+        SnapshotEvent::GloballyIncomplete { processes, missing } => {
+            emit!(
+                "{{snapshot_id:{}, snapshot:\"globally incomplete\", processes:[{}], missing:[{}]}}",
+                snapshot_id, join_ids(processes), join_ids(missing)
+            );
+        }
+    }
+}
+
+fn join_ids(ids: &[u32]) -> String {
+    ids.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+/// "2:5, 3:1" for the legacy output of a chatter tally
+fn format_tally(tally: &BTreeMap<u32, u64>) -> String {
+    tally.iter().map(|(id, n)| format!("{}:{}", id, n)).collect::<Vec<_>>().join(", ")
+}
+
+/// Write the token to the next hop, retrying a bounded number of times and
+/// reconnecting first if the stream is broken
+fn forward_token(stream: &mut TcpStream, next_addr: &str, token_msg: &str) -> io::Result<()> {
+    for attempt in 1..=FORWARD_RETRIES {
+        let result = stream.write_all(token_msg.as_bytes()).and_then(|_| stream.flush());
+        let e = match result {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        log!("Error forwarding token (attempt {}/{}): {}", attempt, FORWARD_RETRIES, e);
+        thread::sleep(FORWARD_RETRY_DELAY);
+
+        if is_broken_stream(&e) {
+            match TcpStream::connect(next_addr).and_then(|s| s.set_nodelay(true).map(|_| s)) {
+                Ok(new_stream) => *stream = new_stream,
+                Err(e) => log!("Error reconnecting to next hop: {}", e),
+            }
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        format!("gave up after {} attempts", FORWARD_RETRIES),
+    ))
+}
+
+/// Wait up to `window` for the predecessor to reconnect to the token listener
+fn accept_reconnect(listener: &TcpListener, window: Duration) -> Option<TcpStream> {
+    if listener.set_nonblocking(true).is_err() {
+        return None;
+    }
+    let deadline = Instant::now() + window;
+    let mut accepted = None;
+
+    while Instant::now() < deadline {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                accepted = Some(stream);
+                break;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(100));
+            }
+            Err(e) => {
+                log!("Error accepting predecessor reconnect: {}", e);
+                break;
+            }
+        }
+    }
+
+    let _ = listener.set_nonblocking(false);
+    let stream = accepted?;
+    // Accepted sockets can inherit the listener's nonblocking mode on some platforms
+    stream.set_nonblocking(false).ok()?;
+    log!("Predecessor reconnected");
+    Some(stream)
+}
+
+fn is_broken_stream(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+    )
+}
+
+/// Flags the marker threads to exit and wakes the blocking accept by connecting to ourselves once.
+/// Returns whether they all finished within MESH_SHUTDOWN_WAIT.
+fn stop_mesh(shutdown: &AtomicBool, marker_port: u16, acceptor: thread::JoinHandle<()>) -> bool {
+    shutdown.store(true, Ordering::SeqCst);
+    let _ = TcpStream::connect(format!("127.0.0.1:{}", marker_port));
+    let joined = join_within(acceptor, MESH_SHUTDOWN_WAIT);
+    if !joined {
+        log!("Marker threads still busy after {:?}, exiting anyway", MESH_SHUTDOWN_WAIT);
+    }
+    joined
+}
+
+/// Join `handle` if it finishes within `limit`, otherwise leave it detached
+fn join_within(handle: thread::JoinHandle<()>, limit: Duration) -> bool {
+    let deadline = Instant::now() + limit;
+    while !handle.is_finished() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    handle.join().is_ok()
+}
+
+// Read timeouts surface as WouldBlock on Unix and TimedOut on Windows
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
 }
 
 /// Send and receive tokens in a loop
 fn token_loop(
     my_user: UserInfo,
     full_list_of_peers: Vec<UserInfo>,
-    state: &mut usize,
-    token_delay: f64,
-    is_initiator: bool
+    state: SharedState,
+    config: &Config,
 ) -> io::Result<()> {
+    let token_delay = config.token_delay;
+    let quiet_tokens = config.quiet_tokens.is_some();
+    let is_initiator = config.is_initiator;
+
     // 1. Bind a TCP listener to accept a connection from our predecessor.
-    let listener_addr = format!("0.0.0.0:{}", TOKEN_PORT);
+    let listener_addr = format!("0.0.0.0:{}", config.token_port);
     let listener = TcpListener::bind(&listener_addr)?;
 
     // Spawn a thread to accept the connection from our predecessor.
@@ -674,29 +1991,28 @@ fn token_loop(
         Ok(stream)
     });
 
-    // 2. Connect to our successor’s TCP listener.
-    let successor = get_successor(&my_user, &full_list_of_peers);
+    // 2. Connect to the next hop's TCP listener (our successor, or predecessor with --reverse).
+    let next_hop = get_next_hop(&my_user, &full_list_of_peers, config.reverse);
 
-    let successor_addr = format!("{}:{}", successor.name, TOKEN_PORT);
-    let mut outgoing: Option<TcpStream> = None;
-    loop {
-        match TcpStream::connect(&successor_addr) {
+    let next_addr = format!("{}:{}", next_hop.name, config.token_port);
+    let mut outgoing = loop {
+        match TcpStream::connect(&next_addr) {
             Ok(stream) => {
-                outgoing = Some(stream);
-                break;
+                stream.set_nodelay(true)?;
+                break stream;
             }
             Err(_) => {
                 thread::sleep(Duration::from_millis(500));
             }
         }
-    }
-    let mut outgoing = outgoing.unwrap();
+    };
 
     // 3. Get the incoming connection from our predecessor.
     let incoming = incoming_handle.join().expect("Listener thread panicked")?;
     let mut reader = BufReader::new(incoming);
 
     // Token message format: "token:<sender_id>"
+    let mut throughput = Throughput::new();
 
     // If this process is the designated token initiator, send the initial token.
     if is_initiator {
@@ -705,51 +2021,111 @@ fn token_loop(
         outgoing.write_all(b"\n")?;
         outgoing.flush()?;
         // Print token sending log.
-        println!("{{id: {}, sender: {}, receiver: {}, message:\"token\"}}", my_user.id, my_user.id, successor.id);
+        if !quiet_tokens {
+            emit!("{{id: {}, sender: {}, receiver: {}, message:\"token\"}}", my_user.id, my_user.id, next_hop.id);
+        }
+        state.lock().unwrap().has_token = false;
     }
 
     // Then wait to receive the token back from our predecessor.
-    loop {
-        let mut token_line = String::new();
-        reader.read_line(&mut token_line)?;
-        let token_line = token_line.trim_end();
-        let parts: Vec<&str> = token_line.splitn(2, ':').collect();
-        if parts.len() != 2 {
-            eprintln!("Process {}: Invalid token format received: '{}'", my_user.id, token_line);
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid token format"));
-        }
-        let sender_id: usize = parts[1].parse().unwrap_or(0);
-        // Print token receipt log.
-        println!("{{id: {}, sender: {}, receiver: {}, message:\"token\"}}", my_user.id, sender_id, my_user.id);
-        // Process the token.
-        *state += 1;
-        println!("{{id: {}, state: {}}}", my_user.id, *state);
+    let mut token_line = String::new();
+    reader.read_line(&mut token_line)?;
+    let token_line = token_line.trim_end();
+    let parts: Vec<&str> = token_line.splitn(2, ':').collect();
+    if parts.len() != 2 {
+        log!("Process {}: Invalid token format received: '{}'", my_user.id, token_line);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid token format"));
+    }
+    let sender_id: usize = parts[1].parse().unwrap_or(0);
+    // Print token receipt log.
+    throughput.record(true);
+    if !quiet_tokens {
+        emit!("{{id: {}, sender: {}, receiver: {}, message:\"token\"}}", my_user.id, sender_id, my_user.id);
+    }
+    // Process the token.
+    {
+        let mut state = state.lock().unwrap();
+        state.has_token = true;
+        state.increment(my_user.id, quiet_tokens);
+    }
+    if token_delay > 0.0 {
         thread::sleep(Duration::from_secs_f64(token_delay));
+    }
 
-        // Forward the token to the successor if we are not the initiator.
-        if !is_initiator {
-            let token_msg = format!("token:{}", my_user.id);
-            outgoing.write_all(token_msg.as_bytes())?;
-            outgoing.write_all(b"\n")?;
-            outgoing.flush()?;
-            // Print token sending log.
-            println!("{{id: {}, sender: {}, receiver: {}, message:\"token\"}}", my_user.id, my_user.id, successor.id);
-            break;
-        } else {
-            break;
+    // This loop only ever sees one receipt
+    if config.crash_holding && config.crash_due(1) {
+        crash(my_user.id, 1, true);
+    }
+
+    // Forward the token to the next hop if we are not the initiator.
+    if !is_initiator {
+        let token_msg = format!("token:{}", my_user.id);
+        outgoing.write_all(token_msg.as_bytes())?;
+        outgoing.write_all(b"\n")?;
+        outgoing.flush()?;
+        // Print token sending log.
+        if !quiet_tokens {
+            emit!("{{id: {}, sender: {}, receiver: {}, message:\"token\"}}", my_user.id, my_user.id, next_hop.id);
         }
+        state.lock().unwrap().has_token = false;
+    }
+
+    if config.crash_due(1) {
+        crash(my_user.id, 1, false);
+    }
+
+    if quiet_tokens {
+        emit_throughput(my_user.id, &throughput);
     }
 
     Ok(())
 }
 
+/// Exit on purpose for --crash-after-tokens, announcing it first so traces show where it happened
+fn crash(id: u32, receipts: u64, holding_token: bool) -> ! {
+    emit!(
+        "{{id: {}, crash:\"injected\", tokens: {}, holding_token:\"{}\"}}",
+        id, receipts, if holding_token { "YES" } else { "NO" }
+    );
+    io::stdout().flush().ok();
+    process::exit(1);
+}
+
 /// Keeps pinging until all peers are online, then prints "READY"
 /// When all peers are online, run another round of pinging to check if all peers have printed "READY"
-fn failsafe_startup(socket: &UdpSocket, peers: &[String], my_name: &str) -> io::Result<()> {
+fn failsafe_startup(
+    socket: &UdpSocket,
+    peers: &[String],
+    my_name: &str,
+    udp_port: u16,
+    startup_timeout: Duration,
+) -> io::Result<()> {
     let peer_count = peers.len();
     let mut online = vec![false; peer_count];
+    let started = Instant::now();
+    let mut last_progress = started;
 
     loop {
+        let missing = || -> Vec<String> {
+            peers.iter().zip(&online).filter(|(_, &up)| !up).map(|(p, _)| p.clone()).collect()
+        };
+
+        if started.elapsed() >= startup_timeout {
+            let missing = missing();
+            emit!(
+                "{{startup:\"timeout\", seconds:{}, missing:[{}]}}",
+                startup_timeout.as_secs(), missing.join(", ")
+            );
+            io::stdout().flush().unwrap();
+            return Err(io::Error::new(io::ErrorKind::TimedOut, StartupTimeout { missing }));
+        }
+
+        if last_progress.elapsed() >= STARTUP_PROGRESS_INTERVAL {
+            let missing = missing();
+            log!("Waiting for {}/{} peers: {}", missing.len(), peer_count, missing.join(", "));
+            last_progress = Instant::now();
+        }
+
         // Send "ping:<my_name>" to every peer not yet marked online, except ourselves
         for (i, peer) in peers.iter().enumerate() {
             if online[i] {
@@ -760,7 +2136,7 @@ fn failsafe_startup(socket: &UdpSocket, peers: &[String], my_name: &str) -> io::
                 continue;
             }
 
-            let addr_str = format!("{}:{}", peer, UDP_PORT);
+            let addr_str = format!("{}:{}", peer, udp_port);
             let socket_addrs: io::Result<Vec<SocketAddr>> =
                 addr_str.to_socket_addrs().map(|iter| iter.collect());
             if let Ok(addrs) = socket_addrs {
@@ -775,7 +2151,7 @@ fn failsafe_startup(socket: &UdpSocket, peers: &[String], my_name: &str) -> io::
                     }
                 }
                 if !sent_ok {
-                    println!("Failed to send ping to {}", peer);
+                    log!("Failed to send ping to {}", peer);
                     io::stdout().flush().unwrap();
                 }
             }
@@ -788,7 +2164,7 @@ fn failsafe_startup(socket: &UdpSocket, peers: &[String], my_name: &str) -> io::
                 let msg = match std::str::from_utf8(&buffer[..received]) {
                     Ok(m) => m,
                     Err(e) => {
-                        eprintln!("Invalid UTF-8 message: {}", e);
+                        log!("Invalid UTF-8 message: {}", e);
                         continue;
                     }
                 };
@@ -796,17 +2172,16 @@ fn failsafe_startup(socket: &UdpSocket, peers: &[String], my_name: &str) -> io::
                 if msg.starts_with("ping:") {
                     let reply = format!("pong:{}", my_name);
                     if let Err(e) = socket.send_to(reply.as_bytes(), sender_addr) {
-                        eprintln!("sendto (pong) failed: {}", e);
+                        log!("sendto (pong) failed: {}", e);
                     }
-                } else if msg.starts_with("pong:") {
-                    let their_name = &msg[5..];
+                } else if let Some(their_name) = msg.strip_prefix("pong:") {
                     for (i, peer) in peers.iter().enumerate() {
                         if peer == their_name {
                             online[i] = true;
                         }
                     }
                 } else {
-                    println!("Got unknown message: {}", msg);
+                    log!("Got unknown message: {}", msg);
                     io::stdout().flush().unwrap();
                 }
             }
@@ -817,13 +2192,13 @@ fn failsafe_startup(socket: &UdpSocket, peers: &[String], my_name: &str) -> io::
                 // Source: https://users.rust-lang.org/t/udpsocket-recv-from-always-getting-resource-temporarily-unavailable-error/92451
             }
             Err(e) => {
-                eprintln!("recv_from error: {}", e);
+                log!("recv_from error: {}", e);
             }
         }
 
         // Check if all peers are online.
         if online.iter().all(|&b| b) {
-            println!("READY");
+            emit!("READY");
             io::stdout().flush().unwrap();
 
             // Wait for 2 seconds then return Ok
@@ -834,4 +2209,178 @@ fn failsafe_startup(socket: &UdpSocket, peers: &[String], my_name: &str) -> io::
 
         thread::sleep(Duration::from_millis(100)); // (100 milliseconds)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        std::iter::once("prj2").chain(list.iter().copied()).map(String::from).collect()
+    }
+
+    /// Writes `contents` to a hostsfile of its own under the temp dir and returns its path
+    fn hostsfile(name: &str, contents: &str) -> String {
+        let path = env::temp_dir().join(format!("prj2-test-{}-{}.txt", name, process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    /// Snapshot bookkeeping for process `my_id` with no mesh connections yet
+    fn context(my_id: u32, state: SharedState, total_channels: usize) -> SnapshotContext {
+        SnapshotContext {
+            my_id,
+            ring_channels: Vec::new(),
+            process_ids: vec![my_id],
+            total_channels,
+            marker_delay: 0.0,
+            done_timeout: Duration::from_secs(60),
+            json: false,
+            state,
+            chatter: Arc::new(Mutex::new(Chatter::default())),
+            initiations: Arc::new(Mutex::new(HashMap::new())),
+            marker_connections: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    #[test]
+    fn token_receipts_and_snapshots_agree_under_concurrency() {
+        const RECEIPTS: usize = 200;
+        let snapshots = context(2, ProcessState::shared(0, false), 1);
+
+        // Snapshots start on another thread while the token keeps coming in from process 1
+        let initiator = {
+            let snapshots = snapshots.clone();
+            thread::spawn(move || {
+                for seq in 0..50 {
+                    snapshots.initiate(SnapshotId { initiator: 2, seq });
+                    thread::sleep(Duration::from_micros(100));
+                }
+            })
+        };
+        // receive_token returns the state it just printed
+        let printed: Vec<usize> = (0..RECEIPTS)
+            .map(|_| {
+                let counter = snapshots.receive_token(1, false);
+                snapshots.state.lock().unwrap().has_token = false;
+                counter
+            })
+            .collect();
+        initiator.join().unwrap();
+
+        assert_eq!(printed, (1..=RECEIPTS).collect::<Vec<_>>());
+        // Each receipt is either in the recorded state or queued on the channel, never both or neither
+        for (id, initiation) in snapshots.initiations.lock().unwrap().iter() {
+            let recorded = initiation.recorded.as_ref().unwrap();
+            let in_transit = initiation.queues.get(&1).map_or(0, Vec::len);
+            assert_eq!(recorded.receipts + in_transit, RECEIPTS, "snapshot {}", id);
+            assert_eq!(recorded.state, recorded.receipts, "snapshot {}", id);
+        }
+    }
+
+    #[test]
+    fn forward_token_survives_the_successor_dropping_its_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let next_addr = listener.local_addr().unwrap().to_string();
+        let mut stream = TcpStream::connect(&next_addr).unwrap();
+
+        // The successor goes away with a ping unread, which resets the connection, and its
+        // listener stays down for a moment before it comes back on the same port
+        stream.write_all(b"ping\n").unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        thread::sleep(Duration::from_millis(100));
+        drop(accepted);
+        drop(listener);
+        let successor = {
+            let next_addr = next_addr.clone();
+            thread::spawn(move || {
+                thread::sleep(FORWARD_RETRY_DELAY * 2);
+                let listener = TcpListener::bind(&next_addr).unwrap();
+                let (stream, _) = listener.accept().unwrap();
+                let mut line = String::new();
+                BufReader::new(stream).read_line(&mut line).unwrap();
+                line
+            })
+        };
+        thread::sleep(Duration::from_millis(100));
+
+        forward_token(&mut stream, &next_addr, "token:1:cw:0:0\n").unwrap();
+        assert_eq!(successor.join().unwrap(), "token:1:cw:0:0\n");
+    }
+
+    #[test]
+    fn stop_mesh_joins_readers_of_peers_that_stay_connected() {
+        let marker_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let marker_port = marker_listener.local_addr().unwrap().port();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let (tx, _incoming) = mpsc::channel();
+        let acceptor = spawn_mesh_acceptor(
+            marker_listener,
+            context(1, ProcessState::shared(0, false), 2),
+            Arc::clone(&shutdown),
+            Arc::new(HashMap::new()),
+            Arc::new(Mutex::new(Some(tx))),
+        );
+
+        // Two peers that never send anything or hang up, so their readers sit in read_line
+        let peers: Vec<TcpStream> =
+            (0..2).map(|_| TcpStream::connect(("127.0.0.1", marker_port)).unwrap()).collect();
+        thread::sleep(Duration::from_millis(200));
+
+        let started = Instant::now();
+        assert!(stop_mesh(&shutdown, marker_port, acceptor));
+        assert!(started.elapsed() < Duration::from_secs(2), "took {:?}", started.elapsed());
+        drop(peers);
+    }
+
+    #[test]
+    fn missing_hostsfile_flag() {
+        assert_eq!(parse_args(&args(&["-t", "1"])).err(), Some(ParseError::MissingHostsfile));
+    }
+
+    #[test]
+    fn bad_token_delay() {
+        match parse_args(&args(&["-h", "hostsfile.txt", "-t", "soon"])) {
+            Err(ParseError::InvalidValue { flag, value, .. }) => {
+                assert_eq!(flag, "-t");
+                assert_eq!(value, "soon");
+            }
+            other => panic!("expected an invalid -t, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn token_delay_without_value() {
+        assert_eq!(
+            parse_args(&args(&["-h", "hostsfile.txt", "-t"])).err(),
+            Some(ParseError::MissingValue("-t".to_string()))
+        );
+    }
+
+    #[test]
+    fn hostsfile_blank_lines_are_skipped() {
+        let path = hostsfile("blank-lines", "\npeer1\n\n  \npeer2\npeer3\n\n");
+        let (me, peers) = parse_hostfile(&path, "peer2").unwrap();
+        assert_eq!(me.id, 2);
+        let names: Vec<(&str, u32)> = peers.iter().map(|p| (p.name.as_str(), p.id)).collect();
+        assert_eq!(names, [("peer1", 1), ("peer2", 2), ("peer3", 3)]);
+    }
+
+    #[test]
+    fn hostsfile_with_only_blank_lines() {
+        let path = hostsfile("only-blank", "\n   \n\n");
+        assert_eq!(
+            parse_hostfile(&path, "peer1").err(),
+            Some(ParseError::EmptyHostsfile(path))
+        );
+    }
+
+    #[test]
+    fn host_not_in_hostsfile() {
+        let path = hostsfile("not-listed", "peer1\npeer2\n");
+        assert_eq!(
+            parse_hostfile(&path, "peer9").err(),
+            Some(ParseError::HostNotInHostsfile { host: "peer9".to_string(), path })
+        );
+    }
+}
@@ -0,0 +1,123 @@
+//! Snapshot events as printed by the peers, shared with the snapshot_check binary
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Snapshot progress on one process. Serialized with the "snapshot" field naming the event.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "snapshot")]
+pub enum SnapshotEvent {
+    #[serde(rename = "started")]
+    Started,
+    #[serde(rename = "marker sent")]
+    MarkerSent { sender: u32, receiver: u32, state: usize, has_token: bool },
+    #[serde(rename = "channel closed")]
+    ChannelClosed { channel: String, kind: ChannelKind, tokens: usize, queue: Vec<String> },
+    #[serde(rename = "complete")]
+    Complete {
+        state: usize,
+        has_token: bool,
+        // Token receipts since the process started, so the checker can line up the
+        // processes along the ring. Missing from output older than this field.
+        #[serde(default)]
+        receipts: Option<usize>,
+        channel_tokens: usize, // tokens recorded across all incoming channels
+        // --chatter message tallies by peer id, omitted when no chatter was exchanged
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        sent: BTreeMap<u32, u64>,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        received: BTreeMap<u32, u64>,
+    },
+    // Only printed by the initiator, once every process reported done
+    #[serde(rename = "globally complete")]
+    GloballyComplete { processes: Vec<u32> },
+    // Only printed by the initiator when --done-timeout ran out first
+    #[serde(rename = "globally incomplete")]
+    GloballyIncomplete { processes: Vec<u32>, missing: Vec<u32> },
+}
+
+/// Whether an incoming channel carries the token or only markers and chatter
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChannelKind {
+    Ring,
+    Marker,
+}
+
+impl fmt::Display for ChannelKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChannelKind::Ring => write!(f, "ring"),
+            ChannelKind::Marker => write!(f, "marker"),
+        }
+    }
+}
+
+/// Identifies one snapshot initiation, written "<initiator_id>.<seq>" on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SnapshotId {
+    pub initiator: u32,
+    pub seq: u64,
+}
+
+impl fmt::Display for SnapshotId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.initiator, self.seq)
+    }
+}
+
+impl FromStr for SnapshotId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (initiator, seq) = s
+            .split_once('.')
+            .ok_or_else(|| format!("snapshot id '{}' is not <initiator>.<seq>", s))?;
+        Ok(SnapshotId {
+            initiator: initiator.parse().map_err(|e| format!("bad initiator in '{}': {}", s, e))?,
+            seq: seq.parse().map_err(|e| format!("bad sequence in '{}': {}", s, e))?,
+        })
+    }
+}
+
+impl Serialize for SnapshotId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for SnapshotId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// A SnapshotEvent tagged with the process and snapshot it belongs to
+#[derive(Debug, Serialize)]
+pub struct SnapshotRecord<'a> {
+    pub proc_id: u32,
+    pub snapshot_id: SnapshotId,
+    #[serde(flatten)]
+    pub event: &'a SnapshotEvent,
+}
+
+/// A line of --json snapshot output read back, by --restore and the snapshot checker
+#[derive(Debug, Deserialize)]
+pub struct SavedRecord {
+    pub proc_id: u32,
+    pub snapshot_id: SnapshotId,
+    #[serde(flatten)]
+    pub event: SnapshotEvent,
+}
+
+impl SavedRecord {
+    /// Parse one line of --json output. Text before the first '{' is skipped so lines
+    /// copied from docker compose ("peer3  | {...}") work too.
+    pub fn parse_line(line: &str) -> Option<SavedRecord> {
+        let start = line.find('{')?;
+        serde_json::from_str(&line[start..]).ok()
+    }
+}
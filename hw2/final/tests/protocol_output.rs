@@ -0,0 +1,106 @@
+// Runs the peer as a one-process ring on this host and checks that stdout carries nothing but
+// protocol events, since diagnostics belong on stderr
+
+use std::io::Read;
+use std::process::{self, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const RUN_LIMIT: Duration = Duration::from_secs(30);
+
+/// Every line the peer may print on stdout. `*` stands for a value without commas and `%`
+/// for anything, which covers lists.
+const SHAPES: &[&str] = &[
+    "READY",
+    "{id: *, state: *, predecessor: *, successor: *, udp_port: *, token_port: *}",
+    "{id: *, state: *, predecessor: *, successor: *, udp_port: *, token_port: *, marker_port: *}",
+    "{id: *, sender: *, receiver: *, message:\"token\"}",
+    "{id: *, sender: *, receiver: *, message:\"token\", direction:\"*\"}",
+    "{id: *, state: *}",
+    "{id: *, crash:\"injected\", tokens: *, holding_token:\"*\"}",
+    "{id: *, rounds: *, hop_ms:%, round_ms:%}",
+    "{proc_id:*, snapshot_id:*, snapshot:\"started\"}",
+    "{proc_id:*, snapshot_id:*, sender:*, receiver:*, message:\"marker\", state:*, has_token:\"*\"}",
+    "{proc_id:*, snapshot_id:*, snapshot:\"channel closed\", channel:\"*\", kind:\"*\", tokens:*, queue:[%]}",
+    "{proc_id:*, snapshot_id:*, snapshot:\"complete\", has_token:\"*\", channel_tokens:*}",
+    "{snapshot_id:*, snapshot:\"globally complete\", processes:[%]}",
+];
+
+fn matches(pattern: &str, line: &str) -> bool {
+    match pattern.chars().next() {
+        None => line.is_empty(),
+        Some('*') => (0..=line.len())
+            .take_while(|&i| !line[..i].contains(','))
+            .any(|i| matches(&pattern[1..], &line[i..])),
+        Some('%') => (0..=line.len()).any(|i| matches(&pattern[1..], &line[i..])),
+        Some(c) => line.starts_with(c) && matches(&pattern[c.len_utf8()..], &line[c.len_utf8()..]),
+    }
+}
+
+/// Run the peer with a hostsfile naming only this host and return its exit code and stdout
+fn run_alone(name: &str, args: &[&str]) -> (Option<i32>, String) {
+    let host = hostname::get().unwrap().into_string().unwrap();
+    let hostsfile = std::env::temp_dir().join(format!("prj2-output-{}-{}.txt", name, process::id()));
+    std::fs::write(&hostsfile, format!("{}\n", host)).unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_part1"))
+        .arg("-h")
+        .arg(&hostsfile)
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+    let reader = thread::spawn(move || {
+        let mut output = String::new();
+        stdout.read_to_string(&mut output).unwrap();
+        output
+    });
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if started.elapsed() > RUN_LIMIT {
+            child.kill().unwrap();
+            panic!("peer still running after {:?}", RUN_LIMIT);
+        }
+        thread::sleep(Duration::from_millis(100));
+    };
+    (status.code(), reader.join().unwrap())
+}
+
+/// Ports of their own for each run, so the tests don't collide when run in parallel
+fn ports(run: u32) -> (String, String) {
+    let base = 20000 + (process::id() % 1000) * 10 + run * 4;
+    (base.to_string(), (base + 1).to_string())
+}
+
+fn assert_protocol_only(output: &str) {
+    assert!(!output.is_empty(), "no output");
+    for line in output.lines() {
+        assert!(SHAPES.iter().any(|shape| matches(shape, line)), "not a protocol event: {}", line);
+    }
+}
+
+#[test]
+fn token_round_prints_only_protocol_events() {
+    let (udp, token) = ports(0);
+    let (code, output) = run_alone("round", &["-x", "-t", "0", "--udp-port", &udp, "--token-port", &token]);
+    assert_eq!(code, Some(0), "{}", output);
+    assert_protocol_only(&output);
+    assert!(output.lines().any(|line| line == "{id: 1, state: 2}"), "{}", output);
+}
+
+#[test]
+fn snapshot_run_prints_only_protocol_events() {
+    let (udp, token) = ports(1);
+    let args = ["-x", "-t", "0.05", "-m", "0.1", "-s", "0", "-p", "1", "--crash-after-tokens", "10"];
+    let (code, output) = run_alone("snapshot", &[&args[..], &["--udp-port", &udp, "--token-port", &token]].concat());
+    // --crash-after-tokens ends the run with exit code 1 after printing the crash event
+    assert_eq!(code, Some(1), "{}", output);
+    assert_protocol_only(&output);
+    assert!(output.contains("snapshot:\"complete\""), "{}", output);
+}
@@ -0,0 +1,92 @@
+use std::env;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+// Port a proposer started with --client-port 8890 listens on
+const DEFAULT_CLIENT_PORT: u16 = 8890;
+// The proposer may still be starting up, so connecting is tried this many times
+const CONNECT_ATTEMPTS: u32 = 20;
+const CONNECT_BACKOFF: Duration = Duration::from_millis(250);
+
+/// Submits a value to a proposer's --client-port and prints the decision.
+/// Expected flags: -p <proposer-host>[:<port>] (port defaults to 8890), -v <value>
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (proposer, value) = args.chunks(2).fold((None, None), |(proposer, value), pair| match pair {
+        [key, arg] => match key.as_str() {
+            "-p" => (Some(arg.clone()), value),
+            "-v" => (proposer, Some(arg.clone())),
+            other => {
+                eprintln!("init error: Unknown flag: {}", other);
+                process::exit(1);
+            }
+        },
+        _ => {
+            eprintln!("init error: Invalid arguments format");
+            process::exit(1);
+        }
+    });
+    let Some(proposer) = proposer else {
+        eprintln!("init error: Missing proposer argument (-p)");
+        process::exit(1);
+    };
+    let value = match value {
+        Some(value) if !value.is_empty() && !value.contains('\n') => value,
+        _ => {
+            eprintln!("init error: -v expects a non-empty value on one line");
+            process::exit(1);
+        }
+    };
+    let addr = if proposer.contains(':') { proposer } else { format!("{}:{}", proposer, DEFAULT_CLIENT_PORT) };
+
+    let stream = connect(&addr);
+    let mut writer = stream.try_clone().unwrap_or_else(|e| {
+        eprintln!("Failed to clone stream to {}: {}", addr, e);
+        process::exit(1);
+    });
+    if let Err(e) = writeln!(writer, "SUBMIT:{}", value) {
+        eprintln!("Failed to submit to {}: {}", addr, e);
+        process::exit(1);
+    }
+    eprintln!("Submitted {} to {}", value, addr);
+
+    let mut reply = String::new();
+    match BufReader::new(stream).read_line(&mut reply) {
+        Ok(0) => {
+            eprintln!("{} closed the connection before a decision", addr);
+            process::exit(1);
+        }
+        Ok(_) => {}
+        Err(e) => {
+            eprintln!("Failed to read the decision from {}: {}", addr, e);
+            process::exit(1);
+        }
+    }
+    let reply = reply.trim_end();
+    println!("{}", reply);
+    if !reply.starts_with("DECIDED:") {
+        process::exit(1);
+    }
+}
+
+/// Connects to the proposer, retrying while it is not listening yet
+fn connect(addr: &str) -> TcpStream {
+    let mut attempt = 1;
+    loop {
+        match TcpStream::connect(addr) {
+            Ok(stream) => return stream,
+            Err(e) if attempt < CONNECT_ATTEMPTS => {
+                eprintln!("Connecting to {} failed (attempt {}): {}", addr, attempt, e);
+                attempt += 1;
+                thread::sleep(CONNECT_BACKOFF);
+            }
+            Err(e) => {
+                eprintln!("Failed to connect to {}: {}", addr, e);
+                process::exit(1);
+            }
+        }
+    }
+}
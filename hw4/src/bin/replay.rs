@@ -0,0 +1,39 @@
+use std::env;
+use std::fs;
+use std::process;
+
+/// Merges the --log files of several nodes into one global order by timestamp and prints
+/// the events as JSON lines. Events with the same timestamp keep their order within a
+/// file, and a send comes before a receive.
+/// Expected arguments: <log> [<log> ...]
+fn main() {
+    let paths: Vec<String> = env::args().skip(1).collect();
+    if paths.is_empty() {
+        eprintln!("init error: expects one or more --log files");
+        process::exit(1);
+    }
+    let mut events = Vec::new();
+    for path in &paths {
+        let content = fs::read_to_string(path).unwrap_or_else(|e| {
+            eprintln!("Error reading {}: {}", path, e);
+            process::exit(1);
+        });
+        for (number, line) in content.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<serde_json::Value>(line) {
+                Ok(event) => match event["ts"].as_u64() {
+                    Some(ts) => events.push((ts, event["dir"] != "send", event)),
+                    None => eprintln!("{}:{}: event without a ts, skipped", path, number + 1),
+                },
+                Err(e) => eprintln!("{}:{}: {}, skipped", path, number + 1, e),
+            }
+        }
+    }
+    // A stable sort keeps the order of each file for equal keys
+    events.sort_by_key(|(ts, receive, _)| (*ts, *receive));
+    for (_, _, event) in events {
+        println!("{}", event);
+    }
+}
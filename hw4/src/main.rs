@@ -1,18 +1,48 @@
-use hostname;
 use serde::{Deserialize, Serialize};
-use serde_json;
+use std::collections::{btree_map, BTreeMap, BTreeSet, HashMap, HashSet};
 use std::env;
+use std::fmt;
 use std::fs;
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
 use std::process;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-const TCP_PORT: &str = "8889";
+// Listen port, and the port of every hostsfile entry that doesn't give its own
+const DEFAULT_PORT: u16 = 8889;
+// How long a proposer waits on a connect, write or reply before counting the acceptor as missing
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+// Extra attempts per message when an acceptor is unreachable or slow
+const DEFAULT_RETRIES: u32 = 4;
+const RETRY_BACKOFF: Duration = Duration::from_millis(250);
+const DEFAULT_MAX_ROUNDS: u64 = 10;
+// Exit code of a proposer that gave up on a slot after --max-rounds rounds
+const EXIT_NO_QUORUM: i32 = 3;
+// Backoff between failed rounds doubles from the base up to the max
+const DEFAULT_BACKOFF_BASE: Duration = Duration::from_millis(1000);
+const DEFAULT_BACKOFF_MAX: Duration = Duration::from_millis(8000);
+// Acceptors and learners stop after this long without messages once they have a value
+const DEFAULT_QUIET: Duration = Duration::from_secs(30);
+const DEFAULT_RESULT_FILE: &str = "accepted.json";
+// With --failover-ms, how often a passive proposer pings the others, and how long it waits
+// for each answer
+const PING_INTERVAL: Duration = Duration::from_millis(500);
+// How often a proposer pings each of its acceptors to see which are up
+const DEFAULT_HEARTBEAT: Duration = Duration::from_millis(1000);
+// With --pull-only and no --poll-ms, how often a learner asks the acceptors what they accepted
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(1000);
+// A decided value starting with this names the acceptors of the group from the next slot on
+const RECONFIGURE_PREFIX: &str = "reconfigure:";
+// How long a partial batch waits for more values before it is proposed
+const DEFAULT_BATCH_WINDOW: Duration = Duration::from_millis(200);
+// Flags that take no value
+const SWITCHES: [&str; 4] = ["--distinguished-learner", "--metrics", "--pull-only", "--unsafe-quorum"];
 
-pub enum Role {
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+enum Role {
     Learner,
     Acceptor,
     Proposer,
@@ -23,393 +53,3959 @@ struct UserInfo {
     id: u32,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 struct PaxosMessage {
     peer_id: u32,
     action: String,
     message_type: String,
     message_value: String,
-    proposal_num: u32,
+    proposal_num: u64,
+    // The ballot proposal_num stands for, as "round" and "proposer". Messages that only give
+    // proposal_num, e.g. written by hand, are read with the receiver's hostsfile, see ballot
+    #[serde(flatten)]
+    ballot: Option<Ballot>,
+    // Paxos group, the <n> of the proposer<n> that started the exchange
+    #[serde(default)]
+    group: u32,
+    // Paxos instance the message belongs to, 0 unless the proposer runs a sequence
+    #[serde(default)]
+    slot: u64,
+    // Only set in key-value mode: the key the slot belongs to, see -k. Every key has
+    // Paxos state of its own, apart from every other key and from the plain slots.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    // Only set on prepare_ack: what the acceptor already accepted, if anything
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    accepted_proposal: Option<Ballot>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    accepted_value: Option<String>,
+    // Only set on prepare_ack: the acceptor accepted something in a later slot of the group
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    accepted_later: bool,
+    // Only set on reject_prepare and reject_accept: the acceptor's promised proposal
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    promised: Option<Ballot>,
+    // Only set on the chosen message for a proposer's last slot
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    last: bool,
+    // Only set on messages a proposer sends: grows with each of them in the group, and
+    // stays the same when the message is sent again after a timeout
+    #[serde(default, skip_serializing_if = "is_zero")]
+    message_id: u64,
+}
+
+impl PaxosMessage {
+    fn instance(&self) -> Instance {
+        Instance { group: self.group, slot: self.slot }
+    }
+
+    /// The message's ballot, or the one its proposal_num stands for with `total_peers`
+    /// lines in the hostsfile if it has none
+    fn ballot(&self, total_peers: u32) -> Ballot {
+        self.ballot.unwrap_or_else(|| Ballot::from_number(self.proposal_num, total_peers))
+    }
+}
+
+/// A proposal's ballot: the proposer's round, then its id to tell proposers apart. Ballots
+/// compare by round first and proposer second, so two proposers never share one and a
+/// proposer's next round beats all of its earlier ones. Ballot::default() is below every
+/// proposal and stands for none.
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Default, Debug)]
+struct Ballot {
+    round: u64,
+    proposer: u32,
+}
+
+impl Ballot {
+    /// The proposal_num printed for this ballot: round * total_peers + proposer. The ids
+    /// are line numbers in 1..=total_peers, so with one hostsfile the numbers order the
+    /// same way as the ballots.
+    fn number(self, total_peers: u32) -> u64 {
+        let num = self.round.checked_mul(total_peers as u64).and_then(|n| n.checked_add(self.proposer as u64));
+        no_overflow(num, format_args!("proposal_num of round {} proposer {}", self.round, self.proposer))
+    }
+
+    /// The ballot a proposal_num stands for with `total_peers` lines in the hostsfile
+    fn from_number(num: u64, total_peers: u32) -> Ballot {
+        match num.checked_sub(1) {
+            Some(n) => Ballot { round: n / total_peers as u64, proposer: (n % total_peers as u64) as u32 + 1 },
+            None => Ballot::default(),
+        }
+    }
+}
+
+/// The result of checked arithmetic on rounds and proposal numbers. Wrapping around would
+/// put a new ballot below the old ones without any sign, so the node stops instead.
+fn no_overflow(n: Option<u64>, what: fmt::Arguments) -> u64 {
+    n.unwrap_or_else(|| {
+        eprintln!("Overflow computing {}, past {}", what, u64::MAX);
+        process::exit(1);
+    })
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+/// A ballot and the value accepted under it
+type Accepted = (Ballot, String);
+
+/// One consensus instance: a slot of one group's sequence. Groups never share state,
+/// even on a node that belongs to several of them.
+#[derive(PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Copy, Debug)]
+struct Instance {
+    group: u32,
+    slot: u64,
+}
+
+/// How logs name a slot: its instance, after its key in key-value mode
+fn slot_name(key: Option<&str>, instance: Instance) -> String {
+    match key {
+        Some(key) => format!("key {} {}", key, instance),
+        None => instance.to_string(),
+    }
+}
+
+impl fmt::Display for Instance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "group {} slot {}", self.group, self.slot)
+    }
+}
+
+/// Peers a role deals with, by group number
+type Groups = BTreeMap<u32, Vec<String>>;
+
+/// What parse_hosts returns for a node
+type Hosts = (UserInfo, HashMap<Role, Groups>, u32, BTreeMap<u32, usize>, Groups, Groups);
+
+/// One hostsfile line: a peer, the port it listens on and its roles with their groups
+struct HostEntry<'a> {
+    name: &'a str,
+    port: u16,
+    roles: Vec<(Role, u32)>,
 }
 
+/// Acceptor state of one Paxos instance
+#[derive(Default)]
 struct PaxosState {
-    promised_proposal: u32,
-    accepted_proposal: Option<u32>,
+    promised_proposal: Ballot,
+    promised_onward: Ballot, // promise made by a prepare for this slot, which also covers every later slot
+    accepted_proposal: Option<Ballot>,
     accepted_value: Option<String>,
+    decided: Option<Accepted>, // what a proposer announced as chosen in this slot
 }
 
-fn main() {
-    // Record the program start time to calculate proposal_num
-    let program_start = Instant::now();
+/// A slot's value and ballot as an acceptor hands them out: the decided ones to a joining
+/// acceptor, the accepted ones to a polling learner
+#[derive(Serialize, Deserialize)]
+struct SlotValue {
+    slot: u64,
+    #[serde(flatten)]
+    ballot: Ballot,
+    value: String,
+}
 
-    let (hostsfile, proposed_val, delay_time) = init();
-    let (user, role, target_peers) = parse_hostfile(&hostsfile);
+/// One slot of an acceptor's status reply, with ballots given as proposal numbers
+#[derive(Serialize)]
+struct SlotStatus {
+    group: u32,
+    slot: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    promised: u64, // highest proposal promised for the slot, 0 for none
+    accepted_proposal: Option<u64>,
+    accepted_value: Option<String>,
+    decided_value: Option<String>,
+}
 
-    // Create a shared state for Paxos that both roles will use.
-    let state = Arc::new(Mutex::new(PaxosState {
-        promised_proposal: 0,
-        accepted_proposal: None,
-        accepted_value: None,
-    }));
-
-    match role {
-        Role::Proposer => {
-            let initial_proposal = match proposed_val {
-                Some(m) => m, 
-                None => {
-                    eprintln!("Proposer must have a proposed value; check arguments.");
-                    process::exit(1);
-                }
-            };
+/// Acceptor state of every instance seen so far
+type PaxosLog = BTreeMap<Instance, PaxosState>;
 
-            if let Some(t) = delay_time {
-                thread::sleep(Duration::from_secs(t as u64));
-            }
-
-            // Design decision: proposal_num as elapsed seconds.
-            let proposal_num = program_start.elapsed().as_secs() as u32;
-            
-            // --- Phase 1: Prepare ---
-            let mut prepared_peers = Vec::new();
-            let mut chosen_value = initial_proposal.clone();
-            let mut highest_accepted = 0; // highest proposal number among responses with an accepted value.
-
-            for peer in &target_peers {
-                let addr = format!("{}:{}", peer, TCP_PORT);
-                let mut connected = false;
-                let mut retries = 0;
-                while !connected && retries < 5 {
-                    match TcpStream::connect(&addr) {
-                        Ok(mut stream) => {
-                            let prepare_msg = PaxosMessage {
-                                peer_id: user.id,
-                                action: "sent".to_string(),
-                                message_type: "prepare".to_string(),
-                                message_value: initial_proposal.clone(),
-                                proposal_num,
-                            };
-                            let msg_json = serde_json::to_string(&prepare_msg).unwrap();
-                            stream.write(msg_json.as_bytes()).unwrap();
-                            eprintln!("{}", msg_json);
-
-                            let mut buffer = [0; 512];
-                            if let Ok(n) = stream.read(&mut buffer) {
-                                let reply_str = String::from_utf8_lossy(&buffer[..n]);
-                                eprintln!("{}", reply_str);
-                                let reply: PaxosMessage = serde_json::from_str(&reply_str).unwrap();
-                                if reply.message_type == "prepare_ack" {
-                                    prepared_peers.push(peer.clone());
-                                    if !reply.message_value.is_empty() && reply.proposal_num > highest_accepted {
-                                        highest_accepted = reply.proposal_num;
-                                        chosen_value = reply.message_value;
-                                    }
-                                }
-                                connected = true;
-                            }
-                        }
-                        Err(e) => {
-                            thread::sleep(Duration::from_secs(1));
-                            retries += 1;
-                        }
-                    }
-                }
-                if !connected {
-                    eprintln!("Unable to connect to {} after retries.", addr);
-                }
-            }
+/// An acceptor's PaxosLog for the plain slots, under None, and one more for each key of
+/// key-value mode, so no promise or accepted value crosses from one key to another
+type KeyedLogs = BTreeMap<Option<String>, PaxosLog>;
 
-            // --- Phase 2: Accept ---
-            for peer in &prepared_peers {
-                let addr = format!("{}:{}", peer, TCP_PORT);
-                match TcpStream::connect(&addr) {
-                    Ok(mut stream) => {
-                        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
-                        let accept_msg = PaxosMessage {
-                            peer_id: user.id,
-                            action: "sent".to_string(),
-                            message_type: "accept".to_string(),
-                            message_value: chosen_value.clone(),
-                            proposal_num,
-                        };
-                        let msg_json = serde_json::to_string(&accept_msg).unwrap();
-                        stream.write(msg_json.as_bytes()).unwrap();
-                        eprintln!("{}", msg_json);
-
-                        let mut buffer = [0; 512];
-                        match stream.read(&mut buffer) {
-                            Ok(n) => {
-                                let reply_str = String::from_utf8_lossy(&buffer[..n]);
-                                eprintln!("{}", reply_str);
-                                let reply: PaxosMessage = serde_json::from_str(&reply_str).unwrap();
-                                if reply.message_type == "accept_ack" {
-                                    let mut s = state.lock().unwrap();
-                                    if s.accepted_proposal.is_none() || reply.proposal_num > s.accepted_proposal.unwrap() {
-                                        s.accepted_proposal = Some(reply.proposal_num);
-                                        s.accepted_value = Some(reply.message_value.clone());
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                eprintln!("Timeout or error reading from {}: {}", addr, e);
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("Failed to connect to {}: {}", addr, e);
-                    }
-                }
-            }
+/// What the --port listener serves on this node
+struct Node {
+    id: u32,
+    total_peers: u32, // lines in the hostsfile, to read messages that only give proposal_num
+    address: String, // this node's <name>:<port>
+    port: u16,       // --port, for acceptor addresses given without one
+    state: Arc<Mutex<KeyedLogs>>,
+    learners: Option<Groups>, // Some if this node is an acceptor: learners to report to in each group
+    learner: Option<Mutex<Learner>>, // Some if this node is a learner
+    pull_only: bool,                 // --pull-only: the learner ignores learn messages
+    reply_delay: Duration,
+    learner_links: Mutex<HashMap<String, TcpStream>>, // open connections to learners, by address
+    distinguished_learner: bool, // --distinguished-learner: acceptors only report to the first learner of their group
+    crash_after: Option<String>, // --crash-after: reply type after which the acceptor exits
+    metrics: bool,               // --metrics: log how long each message took to handle
+    proposers: BTreeMap<u32, usize>,                   // proposers in each group this node accepts for
+    finished_proposers: Mutex<HashSet<(u32, u32)>>,    // (group, id) of those that sent a last chosen message
+    last_replies: Mutex<HashMap<(u32, u32), LastReply>>, // by (proposer id, group), see handle_once
+    join: Option<String>, // --join: acceptor to copy the decided log from once this node is added to a group
+    active_proposers: Arc<Mutex<HashSet<u32>>>, // groups this node is the active proposer of, see Failover
+    last_message: Mutex<Instant>,
+    started: Instant, // for the uptime in status replies
+    last_query: Mutex<Option<Instant>>, // when a polling learner last asked what this acceptor accepted
+    events: Option<Arc<EventLog>>,      // --log
+    network: Option<Arc<Network>>,      // --in-process: the other nodes, reached without TCP
+}
 
-            let final_state = state.lock().unwrap();
-            if let Some(ref val) = final_state.accepted_value {
-                eprintln!("State updated: accepted_value = {}", val);
-            } else {
-                eprintln!("No value accepted.");
-            }
+/// The id of the last message from a proposer this acceptor handled, with its reply
+type LastReply = Arc<Mutex<Option<(u64, PaxosMessage)>>>;
 
-            let chosen_msg = PaxosMessage {
-                peer_id: user.id,
-                action: "chose".to_string(),
-                message_type: "chose".to_string(),
-                message_value: chosen_value.clone(),
-                proposal_num,
-            };
-            eprintln!("{}", serde_json::to_string(&chosen_msg).unwrap());
-        }
-        Role::Acceptor => {
-            let addr = format!("0.0.0.0:{}", TCP_PORT);
-            let listener = TcpListener::bind(&addr).unwrap_or_else(|e| {
-                eprintln!("Failed to bind to {}: {}", addr, e);
-                process::exit(1);
-            });
+/// Learn messages heard so far by a learner
+struct Learner {
+    quorums: BTreeMap<u32, usize>, // majority of each group's acceptors
+    reports: HashMap<(Option<String>, Instance, Accepted), HashSet<u32>>, // acceptor ids that reported each key and instance's accepted proposal
+    learned: HashMap<(Option<String>, Instance), String>, // value learned in each key and instance
+    reconfigured: BTreeMap<Instance, Vec<String>>, // acceptors in effect after each decided reconfiguration
+    acceptors: Groups, // each group's acceptors in the hostsfile, polled with --poll-ms
+    followers: Groups, // with --distinguished-learner, the other learners of each group this node is the first learner of
+}
 
-            for stream in listener.incoming() {
-                match stream {
-                    Ok(stream) => {
-                        let state_clone = Arc::clone(&state);
-                        let local_id = user.id;
-                        thread::spawn(move || {
-                            handle_client(stream, local_id, state_clone);
-                        });
-                    }
-                    Err(e) => {
-                        eprintln!("Error accepting connection: {}", e);
-                    }
-                }
-            }
-            let final_state = state.lock().unwrap();
-            if let Some(ref val) = final_state.accepted_value {
-                eprintln!("State updated: accepted_value = {}", val);
-            } else {
-                eprintln!("No value accepted.");
+/// Command-line configuration
+struct Config {
+    hostsfile: String,
+    proposed_val: Option<String>,
+    keys: Vec<(String, String)>, // -k with the -v after it: key-value mode, one instance per key
+    delay_time: Option<u32>,
+    seed: Option<(u64, String)>, // -a: acceptor starts out having accepted this proposal_num in slot 0
+    max_rounds: u64,        // proposer gives up on a slot after this many failed rounds
+    instances: Option<u64>, // -n: propose the -v value in this many consecutive slots
+    values_file: Option<String>, // -f: propose each line of this file in its own slot
+    port: u16,              // --port: where acceptors and learners listen
+    timeout: Duration,      // --timeout-ms: per connect, write and reply
+    connect_timeout: Option<Duration>, // --connect-timeout-ms: overrides --timeout-ms for connects
+    read_timeout: Option<Duration>,    // --read-timeout-ms: overrides --timeout-ms for writes and replies
+    retries: u32,           // --retries: extra attempts per message to one acceptor
+    reply_delay: Duration,  // --reply-delay-ms: acceptor sits on each message this long
+    backoff_base: Duration, // --backoff-base-ms: wait cap after the first failed round, 0 disables backoff
+    backoff_max: Duration,  // --backoff-max-ms: the cap stops doubling here
+    quiet: Duration,        // --quiet-secs: idle time after which a node with a value stops
+    result_file: String,    // --result-file: where an acceptor writes what it accepted
+    out: Option<String>,    // --out: where a proposer records its decisions, default chosen_<id>.json
+    distinguished_learner: bool, // --distinguished-learner: one learner per group counts accept_acks
+    crash_after: Option<String>, // --crash-after: exit right after sending the first reply of this type
+    metrics: bool,               // --metrics: print phase and round-trip times
+    batch_size: usize,           // --batch-size: values proposed together in one slot
+    batch_window: Duration,      // --batch-ms: longest wait for a batch to fill up
+    client_port: Option<u16>,    // --client-port: proposer takes SUBMIT lines from clients here
+    join: Option<String>,        // --join: acceptor a new acceptor copies the decided log from
+    failover: Option<Duration>,  // --failover-ms: one proposer per group at a time, taking over after this long
+    log: Option<String>,         // --log: event log of every message sent and received
+    poll: Option<Duration>,      // --poll-ms: a learner asks the acceptors what they accepted this often
+    pull_only: bool,             // --pull-only: a learner ignores learn messages and only polls
+    drop_rate: f64,              // --drop-rate: chance that a proposer's message, or its reply, is lost
+    delay_max: Duration,         // --delay-ms-max: a proposer's messages are held back up to this long
+    fault_seed: Option<u64>,     // --seed: makes the injected faults reproducible
+    heartbeat: Duration,         // --heartbeat-ms: how often a proposer pings its acceptors, 0 disables
+    delay_before_prepare: Duration, // --delay-before-prepare: proposer waits this long before each prepare phase
+    delay_before_accept: Duration,  // --delay-before-accept: proposer waits this long before each accept phase
+    quorum: Option<usize>,          // --quorum: acceptors a proposer needs in each phase instead of a majority
+    unsafe_quorum: bool,            // --unsafe-quorum: allow a --quorum of half the acceptors or fewer
+}
+
+/// --log: the node's event log, one JSON line per message it sent or received, e.g.
+/// {"dir":"recv","msg":{..},"node_id":2,"peer":"10.0.0.2:41234","ts":<µs since the Unix epoch>}.
+/// `peer` is the address the message went to or came from, and `msg` the message that was
+/// sent, or a string if it wasn't JSON. src/bin/replay.rs merges the logs of several nodes.
+struct EventLog {
+    node_id: u32,
+    file: Mutex<fs::File>,
+}
+
+/// --failover-ms: what a proposer needs to take turns with the other proposers of its group.
+/// Only the active one proposes; the others ping it and wait.
+struct Failover {
+    window: Duration,
+    peers: Vec<String>, // the group's other proposers, in hostsfile order
+    earlier: usize,     // how many of `peers` come before this proposer in the hostsfile
+    active: Arc<Mutex<HashSet<u32>>>, // groups this node is the active proposer of, for pongs
+}
+
+/// A value waiting to be proposed. Values from a client carry the channel its connection
+/// waits on for the slot the value was chosen in.
+struct Submission {
+    value: String,
+    client: Option<mpsc::Sender<u64>>,
+}
+
+/// How a proposer reaches its acceptors, kept behind a trait so the phases
+/// don't depend on TCP. Phases talk to all acceptors at once, from one thread each.
+trait Transport: Sync {
+    /// Sends `msg` to the acceptor at `addr` and returns its reply
+    fn send_and_receive(&self, addr: &str, msg: &PaxosMessage) -> Result<PaxosMessage, SendError>;
+
+    /// Round-trip times of the exchanges that got a reply since the last call, by acceptor
+    /// address. Empty unless the transport records them (--metrics).
+    fn take_round_trips(&self) -> BTreeMap<String, Vec<Duration>> {
+        BTreeMap::new()
+    }
+
+    /// Gives up on a send_and_receive to `addr` that is still running, so it returns
+    /// SendError::Aborted right away. Does nothing if none is running.
+    fn abort(&self, _addr: &str) {}
+
+    /// The acceptors of `addrs` that are up as far as the transport knows. All of them
+    /// unless it tracks liveness (--heartbeat-ms).
+    fn up(&self, addrs: &[String]) -> Vec<String> {
+        addrs.to_vec()
+    }
+}
+
+/// One persistent TCP connection per acceptor, used for every slot and round, with the
+/// timeout and --retries settings
+struct TcpTransport {
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    retries: u32,
+    connections: Mutex<HashMap<String, BufReader<TcpStream>>>, // idle connections, taken out while in use
+    in_flight: Mutex<InFlight>,
+    aborted_signal: Condvar, // wakes exchanges waiting to retry when one is aborted
+    round_trips: Option<Mutex<BTreeMap<String, Vec<Duration>>>>, // Some with --metrics
+    last_message_id: AtomicU64, // id of the last message sent, see PaxosMessage::message_id
+    events: Option<Arc<EventLog>>, // --log
+    heartbeat: Option<Arc<Heartbeat>>, // Some for a proposer with --heartbeat-ms above 0
+}
+
+/// The exchanges a TcpTransport is running, so a phase can abort the ones it no longer needs
+#[derive(Default)]
+struct InFlight {
+    streams: HashMap<String, Option<TcpStream>>, // a handle on each running exchange's socket, once connected
+    aborted: HashSet<String>,
+}
+
+impl Transport for TcpTransport {
+    fn send_and_receive(&self, addr: &str, msg: &PaxosMessage) -> Result<PaxosMessage, SendError> {
+        // Every retry of this message carries the same id, so an acceptor that already
+        // handled it can tell
+        let msg = PaxosMessage { message_id: self.last_message_id.fetch_add(1, Ordering::Relaxed) + 1, ..msg.clone() };
+        self.in_flight.lock().unwrap().streams.insert(addr.to_string(), None);
+        let result = self.send_with_retries(addr, &msg);
+        let mut in_flight = self.in_flight.lock().unwrap();
+        in_flight.streams.remove(addr);
+        in_flight.aborted.remove(addr);
+        result
+    }
+
+    fn take_round_trips(&self) -> BTreeMap<String, Vec<Duration>> {
+        self.round_trips.as_ref().map(|round_trips| std::mem::take(&mut *round_trips.lock().unwrap())).unwrap_or_default()
+    }
+
+    fn up(&self, addrs: &[String]) -> Vec<String> {
+        self.heartbeat.as_ref().map_or_else(|| addrs.to_vec(), |heartbeat| heartbeat.up(addrs))
+    }
+
+    fn abort(&self, addr: &str) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let InFlight { streams, aborted } = &mut *in_flight;
+        if let Some(stream) = streams.get(addr) {
+            // Unblocks a pending read or write at once
+            if let Some(stream) = stream {
+                let _ = stream.shutdown(Shutdown::Both);
             }
+            aborted.insert(addr.to_string());
+            self.aborted_signal.notify_all();
         }
-        Role::Learner => {
-            // Learner does nothing
+    }
+}
+
+/// Why a message to an acceptor got no usable reply
+#[derive(Debug)]
+enum SendError {
+    Unreachable(io::Error), // could not connect, or the connection broke
+    TimedOut,               // connected, but the write or the reply took too long
+    Malformed(String),      // a reply came back that is not a PaxosMessage
+    Aborted,                // the phase stopped waiting for this acceptor
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SendError::Unreachable(e) => write!(f, "unreachable: {}", e),
+            SendError::TimedOut => write!(f, "timed out"),
+            SendError::Malformed(reply) => write!(f, "malformed reply: {:?}", reply),
+            SendError::Aborted => write!(f, "aborted"),
         }
     }
 }
 
-/// Initializes the application from command-line arguments.
-/// Expected flags: -h <hostsfile>, -v <proposed_value>, -t <delay_time>
-fn init() -> (String, Option<String>, Option<u32>) {
+/// Outcome of one prepare round
+struct Prepared {
+    peers: Vec<String>, // acceptors that promised
+    responded: Vec<String>, // acceptors that sent any reply
+    rejected: bool,     // some acceptor had already promised a higher proposal
+    highest_promised: Option<Ballot>, // highest promised proposal reported by a rejection
+    highest_accepted: Option<Accepted>, // from the prepare_ack with the highest accepted proposal
+    accepted_later: bool, // some acceptor that promised already accepted a value in a later slot
+    majority_rejected: bool, // a majority rejected, so the other requests were aborted
+}
+
+/// Outcome of one accept round
+struct AcceptResult {
+    peers: Vec<String>, // acceptors that accepted
+    responded: Vec<String>, // acceptors that sent any reply
+    rejected: bool, // some acceptor had promised a higher proposal in the meantime
+    highest_promised: Option<Ballot>, // highest promised proposal reported by a rejection
+    majority_rejected: bool,       // a majority rejected, so the other requests were aborted
+}
+
+/// Small xorshift generator for retry backoff, seeded per proposer so two proposers
+/// started together don't back off in lockstep
+struct XorShift(u64);
+
+impl XorShift {
+    fn seeded(salt: u32) -> Self {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+        XorShift(((nanos as u64) << 32 | salt as u64) | 1)
+    }
+
+    /// A generator that gives the same numbers for the same `seed`
+    fn from_seed(seed: u64) -> Self {
+        XorShift(if seed == 0 { 1 } else { seed })
+    }
+
+    fn next_below(&mut self, n: u64) -> u64 {
+        if n == 0 {
+            return 0;
+        }
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+
+    /// True with probability `p`
+    fn chance(&mut self, p: f64) -> bool {
+        self.next_below(1_000_000) < (p * 1_000_000.0) as u64
+    }
+}
+
+fn main() {
     let args: Vec<String> = env::args().skip(1).collect();
-    
-    let (hostsfile, proposed_val, delay_time) = args.chunks(2).fold(
-        (None, None, None),
-        |(hf, pv, dt), pair| {
-            match pair {
-                [key, value] => match key.as_str() {
-                    "-h" => (Some(value.clone()), pv, dt),
-                    "-v" => (hf, Some(value.clone()), dt),
-                    "-t" => (hf, pv, value.parse().ok()),
-                    other => {
-                        eprintln!("init error: Unknown flag: {}", other);
-                        process::exit(1);
-                    }
-                },
-                _ => {
-                    eprintln!("init error: Invalid arguments format");
-                    process::exit(1);
-                }
-            }
-        },
-    );
-    
-    let hostsfile = match hostsfile {
-        Some(h) => h,
-        None => {
-            eprintln!("init error: Missing hostsfile argument (-h)");
+    if args.first().map(String::as_str) == Some("--in-process") {
+        run_in_process(&args[1..]);
+    }
+    if args.first().map(String::as_str) == Some("--status") {
+        query_status(&args[1..]);
+    }
+    let config = init(args);
+    let hosts = parse_hostfile(&config.hostsfile, config.port).unwrap_or_else(|e| {
+        eprintln!("parse_hostfile error: {}", e);
+        process::exit(1);
+    });
+    let events = config.log.as_ref().map(|path| Arc::new(EventLog::open(path, hosts.0.id)));
+
+    // Acceptor and learner traffic share the single --port listener, on its own thread
+    // so a proposer on the same node can run alongside it. It is bound before the
+    // proposer starts, so the proposer can also reach the acceptor on its own node.
+    // With --failover-ms a proposer listens too, to answer the other proposers' pings.
+    let active_proposers = Arc::new(Mutex::new(HashSet::new()));
+    let node = build_node(&config, &hosts, events.clone(), Arc::clone(&active_proposers), None);
+    if let Some(ref node) = node {
+        let addr = format!("0.0.0.0:{}", config.port);
+        let listener = TcpListener::bind(&addr).unwrap_or_else(|e| {
+            eprintln!("Failed to bind to {}: {}", addr, e);
             process::exit(1);
+        });
+        let serving = Arc::clone(node);
+        thread::spawn(move || serve(listener, serving));
+        let poll = config.poll.or(config.pull_only.then_some(DEFAULT_POLL_INTERVAL));
+        if let (Some(interval), Some(_)) = (poll, &node.learner) {
+            let polling = Arc::clone(node);
+            thread::spawn(move || poll_acceptors(&polling, interval));
         }
-    };
-    
-    (hostsfile, proposed_val, delay_time)
-}
+    }
 
-/// Parses the hostsfile to return the current user's info, role, and target peers.
-/// The UserInfo includes the name and the line number (id) where the peer appears.
-fn parse_hostfile(hostsfile: &String) -> (UserInfo, Role, Vec<String>) {
-    let raw_name = match hostname::get() {
-        Ok(name) => name.into_string().unwrap_or_else(|_| "unknown".to_string()),
-        Err(e) => {
-            eprintln!("parse_hostfile error: Failed to get host name: {}", e);
+    // A proposer in several groups runs them side by side. Their decisions all go to one
+    // file, started afresh for this run.
+    let (user, roles, total_peers, _, _, fellow_proposers) = hosts;
+    if let Some(groups) = roles.get(&Role::Proposer) {
+        start_decision_file(&config, user.id);
+        if config.client_port.is_some() && groups.len() > 1 {
+            eprintln!("init error: --client-port needs a node that proposes in a single group");
             process::exit(1);
         }
-    };
+        thread::scope(|scope| {
+            for (group, acceptors) in groups {
+                let config = &config;
+                let failover = config.failover.map(|window| {
+                    let me = format!("{}:{}", user.name, config.port);
+                    let proposers = fellow_proposers.get(group).cloned().unwrap_or_default();
+                    Failover {
+                        window,
+                        earlier: proposers.iter().position(|addr| *addr == me).unwrap_or(0),
+                        peers: proposers.into_iter().filter(|addr| *addr != me).collect(),
+                        active: Arc::clone(&active_proposers),
+                    }
+                });
+                let events = events.clone();
+                let user = &user;
+                scope.spawn(move || {
+                    // Connections to the acceptors stay open across all slots
+                    let mut transport = TcpTransport::new(
+                        config.connect_timeout.unwrap_or(config.timeout),
+                        config.read_timeout.unwrap_or(config.timeout),
+                        config.retries,
+                        config.metrics,
+                        events,
+                    );
+                    transport.heartbeat = Heartbeat::start(config, user.id, *group, acceptors);
+                    let transport = FaultyTransport::new(transport, config, user.id, *group);
+                    run_proposer(config, &transport, user.id, *group, acceptors, total_peers, failover)
+                });
+            }
+        });
+    }
 
-    let content = fs::read_to_string(hostsfile).unwrap_or_else(|err| {
-        eprintln!("Error reading {}: {}", hostsfile, err);
-        process::exit(1);
-    });
+    // Acceptors and learners keep serving after their own proposal finished
+    if let Some(node) = node {
+        while !node_done(&node, config.quiet) {
+            thread::sleep(Duration::from_millis(100));
+        }
+        finish(&node, &config.result_file);
+    }
+}
 
-    let mut my_roles: Vec<String> = Vec::new();
-    let mut my_id: Option<u32> = None;
-    let mut non_empty_line_count: u32 = 0;
+/// The Node serving a node's acceptor and learner roles, or None if it has neither and
+/// doesn't listen. A proposer with --failover-ms listens too, to answer pings. `network`
+/// is Some for a node run with --in-process, see Network.
+fn build_node(
+    config: &Config,
+    hosts: &Hosts,
+    events: Option<Arc<EventLog>>,
+    active_proposers: Arc<Mutex<HashSet<u32>>>,
+    network: Option<Arc<Network>>,
+) -> Option<Arc<Node>> {
+    let (user, roles, total_peers, proposers, learners, _) = hosts;
+    let total_peers = *total_peers;
+    if config.join.is_some() && !roles.contains_key(&Role::Acceptor) {
+        eprintln!("init error: --join needs a node with an acceptor role");
+        process::exit(1);
+    }
+    // Two quorums of the same group must share an acceptor, or two values can be chosen
+    for (group, acceptors) in roles.get(&Role::Proposer).into_iter().flatten() {
+        match config.quorum {
+            Some(n) if n > acceptors.len() => {
+                eprintln!("init error: --quorum {} is more than the {} acceptors of group {}", n, acceptors.len(), group);
+                process::exit(1);
+            }
+            Some(n) if n * 2 <= acceptors.len() && !config.unsafe_quorum => {
+                eprintln!(
+                    "init error: --quorum {} of the {} acceptors of group {} lets two quorums miss each other, add --unsafe-quorum to allow it",
+                    n, acceptors.len(), group
+                );
+                process::exit(1);
+            }
+            _ => {}
+        }
+    }
+    let failover_proposer = config.failover.is_some() && roles.contains_key(&Role::Proposer);
+    if !roles.contains_key(&Role::Acceptor) && !roles.contains_key(&Role::Learner) && !failover_proposer {
+        return None;
+    }
 
-    for line in content.lines() {
-        let line = line.trim();
-        if line.is_empty() {
-            continue;
+    // Create a shared state for Paxos that both roles will use.
+    // -a pre-seeds an acceptor as if it had already accepted a proposal in slot 0 of
+    // each of its groups.
+    let mut log = PaxosLog::new();
+    if let (Some((proposal, value)), Some(groups)) = (config.seed.clone(), roles.get(&Role::Acceptor)) {
+        let proposal = Ballot::from_number(proposal, total_peers);
+        for group in groups.keys() {
+            log.insert(Instance { group: *group, slot: 0 }, PaxosState {
+                promised_proposal: proposal,
+                promised_onward: Ballot::default(),
+                accepted_proposal: Some(proposal),
+                accepted_value: Some(value.clone()),
+                decided: None,
+            });
         }
-        non_empty_line_count += 1;
-        if let Some((peer, roles_str)) = line.split_once(':') {
-            if peer.trim() == raw_name {
-                my_id = Some(non_empty_line_count);
-                for role in roles_str.split(',') {
-                    my_roles.push(role.trim().to_string());
-                }
-                break;
+    }
+
+    let join = config.join.as_ref().map(|acceptor| {
+        acceptor_address(acceptor, config.port).unwrap_or_else(|| {
+            eprintln!("init error: --join expects <name>[:<port>], got {}", acceptor);
+            process::exit(1);
+        })
+    });
+    Some(Arc::new(Node {
+        id: user.id,
+        total_peers,
+        address: format!("{}:{}", user.name, config.port),
+        port: config.port,
+        state: Arc::new(Mutex::new(KeyedLogs::from([(None, log)]))),
+        learners: roles.get(&Role::Acceptor).cloned(),
+        learner: roles.get(&Role::Learner).map(|groups| {
+            let quorums = groups.iter().map(|(group, acceptors)| (*group, majority(acceptors.len()))).collect();
+            // The first learner of a group in the hostsfile is its distinguished learner
+            let my_addr = format!("{}:{}", user.name, config.port);
+            let followers = learners
+                .iter()
+                .filter(|(_, peers)| config.distinguished_learner && peers.first() == Some(&my_addr))
+                .map(|(group, peers)| (*group, peers[1..].to_vec()))
+                .collect();
+            Mutex::new(Learner {
+                quorums,
+                reports: HashMap::new(),
+                learned: HashMap::new(),
+                reconfigured: BTreeMap::new(),
+                acceptors: groups.clone(),
+                followers,
+            })
+        }),
+        pull_only: config.pull_only,
+        reply_delay: config.reply_delay,
+        learner_links: Mutex::new(HashMap::new()),
+        distinguished_learner: config.distinguished_learner,
+        crash_after: config.crash_after.clone(),
+        metrics: config.metrics,
+        proposers: proposers.clone(),
+        finished_proposers: Mutex::new(HashSet::new()),
+        last_replies: Mutex::new(HashMap::new()),
+        join,
+        active_proposers,
+        last_message: Mutex::new(Instant::now()),
+        started: Instant::now(),
+        last_query: Mutex::new(None),
+        events,
+        network,
+    }))
+}
+
+/// --status <host>[:<port>] ...: asks each node for its status, see status_message, and
+/// prints each answer as one JSON line on stdout. Exits 1 if a node didn't answer.
+fn query_status(addrs: &[String]) -> ! {
+    if addrs.is_empty() {
+        eprintln!("init error: --status expects <host>[:<port>] of at least one node");
+        process::exit(1);
+    }
+    let status_msg = PaxosMessage {
+        action: "sent".to_string(),
+        message_type: "status".to_string(),
+        ..Default::default()
+    };
+    let mut all_answered = true;
+    for addr in addrs {
+        let addr = if addr.contains(':') { addr.clone() } else { format!("{}:{}", addr, DEFAULT_PORT) };
+        match exchange_quietly(&addr, &status_msg, DEFAULT_TIMEOUT) {
+            Some(reply) if reply.message_type == "status_reply" => println!("{}", reply.message_value),
+            Some(reply) => {
+                eprintln!("Unexpected {} reply from {}: {}", reply.message_type, addr, reply.message_value);
+                all_answered = false;
+            }
+            None => {
+                eprintln!("No status from {}", addr);
+                all_answered = false;
             }
         }
     }
+    process::exit(if all_answered { 0 } else { 1 });
+}
 
-    let my_id = my_id.unwrap_or(0);
-    let my_info = UserInfo { name: raw_name, id: my_id };
+/// --in-process <hostsfile> ["<flags of node 1>"] ["<flags of node 2>"] ...: runs every
+/// node of the hostsfile as threads of this process, without docker, host names or
+/// ports. Node n takes the flags in the nth argument, split at spaces, e.g. "-v X" for a
+/// proposer, and proposers reach acceptors, and acceptors learners, through Network. The
+/// proposers' --seed, --drop-rate and --delay-ms-max decide which messages are lost and in
+/// what order the rest arrive. Once every proposer finished, every slot's value has been
+/// announced, so it checks that the acceptors, learners and proposers that know a slot's
+/// value all have the same one, and that it was proposed or given with -a. Exits 1 if not.
+fn run_in_process(args: &[String]) -> ! {
+    let Some((hostsfile, node_args)) = args.split_first() else {
+        eprintln!("init error: --in-process expects <hostsfile> and the flags of each node");
+        process::exit(1);
+    };
+    let content = fs::read_to_string(hostsfile).unwrap_or_else(|e| {
+        eprintln!("parse_hostfile error: Error reading {}: {}", hostsfile, e);
+        process::exit(1);
+    });
+    let lines: Vec<&str> = content.lines().map(str::trim).filter(|line| !line.is_empty()).collect();
+    if node_args.len() > lines.len() {
+        eprintln!("init error: flags for {} nodes, but {} lists {}", node_args.len(), hostsfile, lines.len());
+        process::exit(1);
+    }
 
-    let mut proposer_nums: Vec<String> = Vec::new();
-    let mut acceptor_nums: Vec<String> = Vec::new();
-    for role in &my_roles {
-        if role.starts_with("proposer") {
-            let num = role.trim_start_matches("proposer");
-            if !num.is_empty() {
-                proposer_nums.push(num.to_string());
+    let network = Arc::new(Network::default());
+    let mut nodes = Vec::new();
+    for (n, line) in lines.iter().enumerate() {
+        let mut args = vec!["-h".to_string(), hostsfile.clone()];
+        args.extend(node_args.get(n).map_or("", String::as_str).split_whitespace().map(String::from));
+        let config = init(args);
+        let unsupported = [
+            (config.failover.is_some(), "--failover-ms"),
+            (config.poll.is_some() || config.pull_only, "--poll-ms and --pull-only"),
+            (config.join.is_some(), "--join"),
+            (config.client_port.is_some(), "--client-port"),
+            (config.crash_after.is_some(), "--crash-after"),
+        ];
+        if let Some((_, flag)) = unsupported.iter().find(|(given, _)| *given) {
+            eprintln!("init error: {} can't be used with --in-process", flag);
+            process::exit(1);
+        }
+        let name = parse_entry(line, config.port).map(|entry| entry.name.to_string()).unwrap_or_default();
+        let hosts = match parse_hosts(&content, &name, config.port) {
+            Ok(hosts) if hosts.0.id == n as u32 + 1 => hosts,
+            Ok(_) => {
+                eprintln!("parse_hostfile error: {}: line {} lists {}:{} again", hostsfile, n + 1, name, config.port);
+                process::exit(1);
             }
-        } else if role.starts_with("acceptor") {
-            let num = role.trim_start_matches("acceptor");
-            if !num.is_empty() {
-                acceptor_nums.push(num.to_string());
+            Err(e) => {
+                eprintln!("parse_hostfile error: {}: {}", hostsfile, e);
+                process::exit(1);
             }
+        };
+        let events = config.log.as_ref().map(|path| Arc::new(EventLog::open(path, hosts.0.id)));
+        let node = build_node(&config, &hosts, events.clone(), Arc::default(), Some(Arc::clone(&network)));
+        if let Some(ref node) = node {
+            network.nodes.lock().unwrap().insert(node.address.clone(), Arc::clone(node));
         }
+        nodes.push((config, hosts, node, events));
     }
 
-    let mut result_peers: Vec<String> = Vec::new();
-    let my_role = if !proposer_nums.is_empty() {
-        for line in content.lines() {
-            let line = line.trim();
-            if line.is_empty() {
-                continue;
+    thread::scope(|scope| {
+        for (config, (user, roles, total_peers, ..), _, events) in &nodes {
+            let Some(groups) = roles.get(&Role::Proposer) else { continue };
+            start_decision_file(config, user.id);
+            for (group, acceptors) in groups {
+                let from = format!("{}:{}", user.name, config.port);
+                let transport = MemoryTransport::new(Arc::clone(&network), from, events.clone());
+                scope.spawn(move || {
+                    let transport = FaultyTransport::new(transport, config, user.id, *group);
+                    run_proposer(config, &transport, user.id, *group, acceptors, *total_peers, None)
+                });
             }
-            if let Some((peer, roles_str)) = line.split_once(':') {
-                if peer.trim() == my_info.name {
-                    continue;
-                }
-                let roles: Vec<&str> = roles_str.split(',').map(|r| r.trim()).collect();
-                for num in &proposer_nums {
-                    let target_role = format!("acceptor{}", num);
-                    if roles.iter().any(|&r| r == target_role) {
-                        result_peers.push(peer.trim().to_string());
-                        break;
+        }
+    });
+
+    // What each node has for each slot, and every value that could rightly be chosen
+    let mut values: BTreeMap<(Option<String>, Instance), BTreeMap<String, Vec<String>>> = BTreeMap::new();
+    let mut proposed = HashSet::new();
+    for (config, (user, roles, ..), node, _) in &nodes {
+        let mut record = |key: Option<String>, instance: Instance, value: String, who: String| {
+            values.entry((key, instance)).or_default().entry(value).or_default().push(who);
+        };
+        if let Some(node) = node {
+            for (key, log) in node.state.lock().unwrap().iter() {
+                for (instance, s) in log {
+                    if let Some((_, ref value)) = s.decided {
+                        record(key.clone(), *instance, value.clone(), format!("acceptor {}", user.id));
                     }
                 }
             }
-        }
-        Role::Proposer
-    } else if !acceptor_nums.is_empty() {
-        for line in content.lines() {
-            let line = line.trim();
-            if line.is_empty() {
-                continue;
+            if let Some(ref learner) = node.learner {
+                for ((key, instance), value) in learner.lock().unwrap().learned.iter() {
+                    record(key.clone(), *instance, value.clone(), format!("learner {}", user.id));
+                }
             }
-            if let Some((peer, roles_str)) = line.split_once(':') {
-                if peer.trim() == my_info.name {
+        }
+        if roles.contains_key(&Role::Proposer) {
+            let out = decision_file(config, user.id);
+            for line in fs::read_to_string(&out).unwrap_or_default().lines() {
+                let Ok(decision) = serde_json::from_str::<serde_json::Value>(line) else { continue };
+                let (Some(group), Some(slot), Some(value)) =
+                    (decision["group"].as_u64(), decision["slot"].as_u64(), decision["value"].as_str())
+                else {
                     continue;
-                }
-                let roles: Vec<&str> = roles_str.split(',').map(|r| r.trim()).collect();
-                for num in &acceptor_nums {
-                    let target_role = format!("proposer{}", num);
-                    if roles.iter().any(|&r| r == target_role) {
-                        result_peers.push(peer.trim().to_string());
-                        break;
+                };
+                let key = decision["key"].as_str().map(String::from);
+                record(key, Instance { group: group as u32, slot }, value.to_string(), format!("proposer {}", user.id));
+            }
+            proposed.extend(config.proposed_val.clone().map(|value| (None, value)));
+            if let Some(ref path) = config.values_file {
+                let file = fs::read_to_string(path).unwrap_or_default();
+                proposed.extend(file.lines().map(str::trim).filter(|line| !line.is_empty()).map(|line| (None, line.to_string())));
+            }
+            proposed.extend(config.keys.iter().map(|(key, value)| (Some(key.clone()), value.clone())));
+        }
+        proposed.extend(config.seed.clone().map(|(_, value)| (None, value)));
+    }
+
+    // A key's value must have been proposed for that key
+    let mut failed = false;
+    for ((key, instance), by_value) in &values {
+        let slot = slot_name(key.as_deref(), *instance);
+        if by_value.len() > 1 {
+            let held: Vec<String> = by_value.iter().map(|(value, who)| format!("{:?} at {}", value, who.join(", "))).collect();
+            eprintln!("Disagreement in {}: {}", slot, held.join("; "));
+            failed = true;
+        }
+        for value in by_value.keys() {
+            // A batch is a JSON array of proposed values
+            let batch = serde_json::from_str::<Vec<String>>(value).ok();
+            let was_proposed = |value: &str| proposed.contains(&(key.clone(), value.to_string()));
+            let valid = was_proposed(value) || batch.is_some_and(|batch| batch.iter().all(|v| was_proposed(v)));
+            if !valid {
+                eprintln!("Invalid value in {}: {:?} was never proposed", slot, value);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+    eprintln!("In-process run agreed on {} slot{}, every value proposed", values.len(), if values.len() == 1 { "" } else { "s" });
+    process::exit(0);
+}
+
+/// Whether every listening role of the node is done. An acceptor is done once every
+/// proposer of each of its groups sent the chosen message for its last slot, a learner once it
+/// learned a value. An acceptor a learner polls also waits for one query after its last
+/// message, so the learner sees the last slots. Either also stops after --quiet-secs
+/// without messages once it accepted or learned something, in case a proposer never finishes.
+fn node_done(node: &Node, quiet: Duration) -> bool {
+    let last_message = *node.last_message.lock().unwrap();
+    let idle = last_message.elapsed() >= quiet;
+    let polled_since = node.last_query.lock().unwrap().map_or(true, |query| query > last_message);
+    let acceptor_done = node.learners.is_none() || {
+        let accepted = node.state.lock().unwrap().values().flat_map(|log| log.values()).any(|s| s.accepted_value.is_some());
+        let finished = node.finished_proposers.lock().unwrap();
+        let all_finished = node
+            .proposers
+            .iter()
+            .all(|(group, count)| finished.iter().filter(|(g, _)| g == group).count() >= *count);
+        (all_finished && polled_since) || (accepted && idle)
+    };
+    let learner_done = match node.learner {
+        Some(ref learner) => !learner.lock().unwrap().learned.is_empty() && idle,
+        None => true,
+    };
+    acceptor_done && learner_done
+}
+
+/// Writes what the acceptor accepted in every instance to `result_file`, one JSON object
+/// per line, prints the final values and exits
+fn finish(node: &Node, result_file: &str) -> ! {
+    if node.learners.is_some() {
+        let logs = node.state.lock().unwrap();
+        let mut lines = String::new();
+        for (key, log) in logs.iter() {
+            for (instance, s) in log.iter() {
+                if let Some(ref val) = s.accepted_value {
+                    let mut entry = serde_json::json!({
+                        "group": instance.group,
+                        "slot": instance.slot,
+                        "accepted_proposal": s.accepted_proposal.map(|ballot| ballot.number(node.total_peers)),
+                        "accepted_value": val,
+                    });
+                    if let Some(key) = key {
+                        entry["key"] = key.clone().into();
                     }
+                    lines.push_str(&format!("{}\n", entry));
+                    eprintln!("Final accepted value: {} accepted_value = {}", slot_name(key.as_deref(), *instance), val);
                 }
             }
         }
-        Role::Acceptor
-    } else {
-        result_peers = Vec::new();
-        Role::Learner
-    };
+        if lines.is_empty() {
+            eprintln!("No value accepted.");
+        }
+        if let Err(e) = fs::write(result_file, lines) {
+            eprintln!("Failed to write {}: {}", result_file, e);
+            process::exit(1);
+        }
+    }
+    process::exit(0);
+}
 
-    result_peers.sort();
-    (my_info, my_role, result_peers)
-}
-
-/// Handles an incoming TCP connection (used by both acceptors and, indirectly, by a node acting as both).
-fn handle_client(mut stream: TcpStream, my_id: u32, state: Arc<Mutex<PaxosState>>) {
-    let mut buffer = [0; 512];
-    let n = stream.read(&mut buffer).unwrap();
-    let received_str = String::from_utf8_lossy(&buffer[..n]);
-    eprintln!("{}", received_str);
-
-    let msg: PaxosMessage = serde_json::from_str(&received_str).unwrap();
-    let reply_type: String;
-    {
-        let mut s = state.lock().unwrap();
-        if msg.message_type == "prepare" {
-            if msg.proposal_num >= s.promised_proposal {
-                s.promised_proposal = msg.proposal_num;
-                reply_type = "prepare_ack".to_string();
-            } else {
-                reply_type = "reject_prepare".to_string();
-            }
-        } else if msg.message_type == "accept" {
-            if msg.proposal_num >= s.promised_proposal {
-                s.promised_proposal = msg.proposal_num;
-                s.accepted_proposal = Some(msg.proposal_num);
-                s.accepted_value = Some(msg.message_value.clone());
-                reply_type = "accept_ack".to_string();
-            } else {
-                reply_type = "reject_accept".to_string();
+/// Runs one Paxos instance per proposed value in `group`, in consecutive slots starting at 0
+fn run_proposer(
+    config: &Config,
+    transport: &impl Transport,
+    my_id: u32,
+    group: u32,
+    acceptors: &[String],
+    total_peers: u32,
+    failover: Option<Failover>,
+) {
+    if let Some(n) = config.quorum {
+        eprintln!("Proposer {} needs {} of {} acceptors in group {} (--quorum)", my_id, n, acceptors.len(), group);
+    }
+    if !config.keys.is_empty() {
+        if let Some(t) = config.delay_time {
+            thread::sleep(Duration::from_secs(t as u64));
+        }
+        propose_keys(config, transport, my_id, group, acceptors, total_peers);
+        return;
+    }
+    let values = proposer_values(config);
+    let mut acceptors = acceptors.to_vec();
+
+    if let Some(t) = config.delay_time {
+        thread::sleep(Duration::from_secs(t as u64));
+    }
+    // Ballot whose phase 1 covers the next slot too, while no one else competes
+    let mut leader = None;
+    if let Some(ref failover) = failover {
+        if failover.earlier > 0 {
+            eprintln!("Proposer {} passive in group {}, waiting on {}", my_id, group, failover.peers[..failover.earlier].join(","));
+            wait_for_turn(failover, my_id, group);
+        } else {
+            failover.active.lock().unwrap().insert(group);
+            eprintln!("Proposer {} activating in group {}: first proposer of the group", my_id, group);
+        }
+    }
+    let mut batch = next_batch(&values, config);
+    if batch.is_empty() {
+        eprintln!("{} has no values to propose", config.values_file.as_deref().unwrap_or("-v"));
+        process::exit(1);
+    }
+    let mut slot = 0;
+    while !batch.is_empty() {
+        let instance = Instance { group, slot };
+        let value = batch_value(&batch, config);
+        let (ballot, chosen) =
+            propose(config, transport, my_id, &acceptors, total_peers, instance, None, &value, &mut leader, failover.as_ref());
+        if config.batch_size > 1 {
+            print_batch(instance, &chosen);
+        }
+        // A batch, or a value a client waits for, that lost its slot to another proposer's
+        // value goes again, intact, in the next slot
+        let from_client = batch.iter().any(|submission| submission.client.is_some());
+        let again = chosen != value && (config.batch_size > 1 || from_client);
+        if chosen == value {
+            for submission in &batch {
+                if let Some(ref client) = submission.client {
+                    let _ = client.send(slot);
+                }
+            }
+        } else if again {
+            let what = if config.batch_size > 1 { "Batch" } else { "Value" };
+            eprintln!("{} lost {} to another value, proposing it again in the next slot", what, instance);
+        }
+        // A decided reconfiguration takes effect from the next slot. Its own slot is announced
+        // to the old and the new acceptors, so the new ones learn they were added.
+        let reconfigured = parse_reconfiguration(&chosen, config.port);
+        let mut announce_to = acceptors.clone();
+        if let Some(ref new_acceptors) = reconfigured {
+            announce_to.extend(new_acceptors.iter().filter(|addr| !acceptors.contains(addr)).cloned());
+        }
+        // The slot is the last one once the source has nothing more to give. Clients can
+        // always submit more, so then there is no last slot and the announcement can't
+        // wait for the next value.
+        if config.client_port.is_some() {
+            announce_chosen(transport, &announce_to, ballot, total_peers, chosen, instance, None, false);
+            if !again {
+                batch = next_batch(&values, config);
             }
         } else {
-            reply_type = "unknown".to_string();
+            if !again {
+                batch = next_batch(&values, config);
+            }
+            announce_chosen(transport, &announce_to, ballot, total_peers, chosen, instance, None, batch.is_empty());
         }
-        if let Some(ref val) = s.accepted_value {
-            eprintln!("State updated: accepted_value = {}", val);
+        if let Some(new_acceptors) = reconfigured {
+            eprintln!(
+                "Group {} reconfigured in slot {}: acceptors {} from slot {} on, majority {}",
+                group, slot, new_acceptors.join(","), slot + 1, majority(new_acceptors.len())
+            );
+            acceptors = new_acceptors;
+            // Phase 1 ran on the old acceptors, so the next slot starts with a full round
+            leader = None;
         }
+        slot += 1;
     }
+}
 
-    let reply_value: String = {
-        let s = state.lock().unwrap();
-        if let Some(ref val) = s.accepted_value {
-            val.clone()
-        } else if msg.message_type == "prepare" {
-            msg.message_value.clone()
-        } else {
-            "".to_string()
+/// Key-value mode (-k): decides each key's value in slot 0 of that key, one key after the
+/// other, and announces it. Acceptors keep every key's promises and accepted values apart,
+/// so each key starts over at round 0 and never leads with another key's ballot.
+fn propose_keys(config: &Config, transport: &impl Transport, my_id: u32, group: u32, acceptors: &[String], total_peers: u32) {
+    for (n, (key, value)) in config.keys.iter().enumerate() {
+        let instance = Instance { group, slot: 0 };
+        let (ballot, chosen) = propose(config, transport, my_id, acceptors, total_peers, instance, Some(key), value, &mut None, None);
+        let last = n + 1 == config.keys.len();
+        announce_chosen(transport, acceptors, ballot, total_peers, chosen, instance, Some(key), last);
+    }
+}
+
+/// Up to --batch-size values for the next slot: waits for the first one, then takes more
+/// until the batch is full, --batch-ms passed or the source ran out. Empty once the
+/// source is used up.
+fn next_batch(values: &mpsc::Receiver<Submission>, config: &Config) -> Vec<Submission> {
+    let Ok(first) = values.recv() else { return Vec::new() };
+    let mut batch = vec![first];
+    let deadline = Instant::now() + config.batch_window;
+    while batch.len() < config.batch_size {
+        match values.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(value) => batch.push(value),
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                eprintln!("Batch of {} values flushed after {} ms", batch.len(), config.batch_window.as_millis());
+                break;
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
         }
-    };
+    }
+    batch
+}
 
-    let reply = PaxosMessage {
-        peer_id: my_id,
-        action: "sent".to_string(),
-        message_type: reply_type,
-        message_value: reply_value,
-        proposal_num: msg.proposal_num,
-    };
+/// The value proposed for a batch: the value itself without batching, otherwise a JSON
+/// array of the batch that acceptors and learners pass along like any other value
+fn batch_value(batch: &[Submission], config: &Config) -> String {
+    if config.batch_size == 1 {
+        return batch[0].value.clone();
+    }
+    let values: Vec<&str> = batch.iter().map(|submission| submission.value.as_str()).collect();
+    serde_json::to_string(&values).unwrap_or_else(|e| {
+        eprintln!("Failed to encode batch: {}", e);
+        process::exit(1);
+    })
+}
 
-    let reply_str = serde_json::to_string(&reply).unwrap();
-    stream.write(reply_str.as_bytes()).unwrap();
-    eprintln!("{}", reply_str);
+/// Prints each value of a chosen batch with its position. A value adopted from a
+/// proposer that doesn't batch is not an array and is left as it is.
+fn print_batch(instance: Instance, chosen: &str) {
+    if let Ok(values) = serde_json::from_str::<Vec<String>>(chosen) {
+        for (position, value) in values.iter().enumerate() {
+            eprintln!("Chosen in {} position {}: {}", instance, position, value);
+        }
+    }
+}
+
+/// The acceptors a `reconfigure:<acceptor>,<acceptor>,...` value names, as sorted
+/// `<name>:<port>` addresses. None for any other value, or if an acceptor isn't
+/// `<name>[:<port>]`, in which case the value is just a value.
+fn parse_reconfiguration(value: &str, default_port: u16) -> Option<Vec<String>> {
+    let list = value.strip_prefix(RECONFIGURE_PREFIX)?;
+    let mut acceptors = Vec::new();
+    for acceptor in list.split(',') {
+        let Some(addr) = acceptor_address(acceptor.trim(), default_port) else {
+            eprintln!("{:?} is not a reconfiguration: {:?} is not <name>[:<port>]", value, acceptor);
+            return None;
+        };
+        if !acceptors.contains(&addr) {
+            acceptors.push(addr);
+        }
+    }
+    acceptors.sort();
+    Some(acceptors)
+}
+
+/// `<name>[:<port>]` as a `<name>:<port>` address, taking `default_port` if it has none
+fn acceptor_address(acceptor: &str, default_port: u16) -> Option<String> {
+    match acceptor.split_once(':') {
+        None if !acceptor.is_empty() => Some(format!("{}:{}", acceptor, default_port)),
+        Some((name, port)) if !name.is_empty() && matches!(port.parse::<u16>(), Ok(p) if p > 0) => {
+            Some(acceptor.to_string())
+        }
+        _ => None,
+    }
+}
+
+/// Tells every acceptor of the group which value was chosen in `instance`. `last` marks the
+/// proposer's final slot, after which acceptors no longer wait for it.
+#[allow(clippy::too_many_arguments)]
+fn announce_chosen(
+    transport: &impl Transport,
+    acceptors: &[String],
+    ballot: Ballot,
+    total_peers: u32,
+    value: String,
+    instance: Instance,
+    key: Option<&str>,
+    last: bool,
+) {
+    let chosen_msg = PaxosMessage {
+        peer_id: ballot.proposer,
+        action: "sent".to_string(),
+        message_type: "chosen".to_string(),
+        message_value: value,
+        proposal_num: ballot.number(total_peers),
+        ballot: Some(ballot),
+        group: instance.group,
+        slot: instance.slot,
+        key: key.map(String::from),
+        last,
+        ..Default::default()
+    };
+    for addr in acceptors {
+        if let Err(e) = transport.send_and_receive(addr, &chosen_msg) {
+            eprintln!("Could not announce the chosen value to {}: {}", addr, e);
+        }
+    }
+}
+
+/// The values to propose, as they become available: the non-empty lines of -f, read as
+/// they are written so a pipe can feed them over time, or the -v value once or -n times.
+/// With --client-port, values submitted by clients come after those and the source never
+/// runs out.
+fn proposer_values(config: &Config) -> mpsc::Receiver<Submission> {
+    let (values_tx, values) = mpsc::channel();
+    if let Some(port) = config.client_port {
+        let addr = format!("0.0.0.0:{}", port);
+        let listener = TcpListener::bind(&addr).unwrap_or_else(|e| {
+            eprintln!("Failed to bind to {}: {}", addr, e);
+            process::exit(1);
+        });
+        let submissions = values_tx.clone();
+        thread::spawn(move || serve_submissions(listener, submissions));
+    }
+    if let Some(ref path) = config.values_file {
+        let file = fs::File::open(path).unwrap_or_else(|err| {
+            eprintln!("Error reading {}: {}", path, err);
+            process::exit(1);
+        });
+        let path = path.clone();
+        thread::spawn(move || {
+            for line in BufReader::new(file).lines() {
+                match line {
+                    Ok(line) if line.trim().is_empty() => {}
+                    Ok(line) => {
+                        let submission = Submission { value: line.trim().to_string(), client: None };
+                        if values_tx.send(submission).is_err() {
+                            return;
+                        }
+                    }
+                    Err(err) => {
+                        eprintln!("Error reading {}: {}", path, err);
+                        return;
+                    }
+                }
+            }
+        });
+        return values;
+    }
+    match &config.proposed_val {
+        Some(m) => {
+            for _ in 0..config.instances.unwrap_or(1) {
+                let _ = values_tx.send(Submission { value: m.clone(), client: None });
+            }
+            values
+        }
+        None if config.client_port.is_some() => values,
+        None => {
+            eprintln!("Proposer must have a proposed value; check arguments.");
+            process::exit(1);
+        }
+    }
+}
+
+/// Accepts client connections on --client-port and handles each on its own thread
+fn serve_submissions(listener: TcpListener, values: mpsc::Sender<Submission>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let values = values.clone();
+                thread::spawn(move || handle_submissions(stream, values));
+            }
+            Err(e) => {
+                eprintln!("Error accepting client connection: {}", e);
+            }
+        }
+    }
+}
+
+/// Queues each "SUBMIT:<value>" line of a client connection for proposing and answers it
+/// with "DECIDED:<slot>:<value>" once the value is chosen. Lines are handled one at a time,
+/// so a client waits for each decision before its next submission is queued.
+fn handle_submissions(stream: TcpStream, values: mpsc::Sender<Submission>) {
+    let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+    let mut writer = match stream.try_clone() {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Failed to clone client stream: {}", e);
+            return;
+        }
+    };
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else { return };
+        let reply = match line.trim().strip_prefix("SUBMIT:") {
+            Some(value) if !value.is_empty() => {
+                eprintln!("Client {} submitted {}", peer, value);
+                let (decided_tx, decided) = mpsc::channel();
+                let submission = Submission { value: value.to_string(), client: Some(decided_tx) };
+                if values.send(submission).is_err() {
+                    return;
+                }
+                match decided.recv() {
+                    Ok(slot) => format!("DECIDED:{}:{}", slot, value),
+                    Err(_) => return,
+                }
+            }
+            _ => "ERROR:expected SUBMIT:<value>".to_string(),
+        };
+        if writeln!(writer, "{}", reply).is_err() {
+            return;
+        }
+    }
+}
+
+/// The values a proposer deals with in one slot, kept apart so every log line can say
+/// which one it prints
+struct SlotValues {
+    my_value: String,              // what this proposer was given to propose
+    adopted_value: Option<String>, // accepted by some acceptor, as the last phase 1 reported
+    decided_value: Option<String>, // accepted by a majority in phase 2, so chosen
+}
+
+impl SlotValues {
+    /// What phase 2 proposes: the adopted value if phase 1 reported one, else my_value
+    fn to_propose(&self) -> &str {
+        self.adopted_value.as_deref().unwrap_or(&self.my_value)
+    }
+
+    fn log_decided(&self, instance: Instance) {
+        let decided = self.decided_value.as_deref().unwrap_or_default();
+        match self.adopted_value {
+            Some(_) if decided != self.my_value => {
+                eprintln!("State updated: {} decided_value = {} (adopted, my_value = {})", instance, decided, self.my_value)
+            }
+            _ => eprintln!("State updated: {} decided_value = {}", instance, decided),
+        }
+    }
+}
+
+/// Runs rounds of prepare and accept for one slot until a value is chosen there and
+/// returns it with its ballot, exiting the process once --max-rounds rounds failed.
+/// A promise covers every later slot of the group too, so a proposer holding `leader` goes
+/// straight to the accept phase with that ballot. It falls back to full rounds
+/// if that fails, and keeps the leadership for the next slot as long as it succeeds.
+#[allow(clippy::too_many_arguments)]
+fn propose(
+    config: &Config,
+    transport: &impl Transport,
+    my_id: u32,
+    acceptors: &[String],
+    total_peers: u32,
+    instance: Instance,
+    key: Option<&str>,
+    my_value: &str,
+    leader: &mut Option<Ballot>,
+    failover: Option<&Failover>,
+) -> Accepted {
+    let quorum = quorum_size(config, acceptors.len());
+    let mut rng = XorShift::seeded(my_id);
+    let mut values = SlotValues { my_value: my_value.to_string(), adopted_value: None, decided_value: None };
+    // --metrics: time spent in each phase over all rounds of this slot
+    let started = Instant::now();
+    let (mut phase1, mut phase2) = (Duration::ZERO, Duration::ZERO);
+    transport.take_round_trips();
+    // Every acceptor that answered at all, for the failure record if no round succeeds
+    let mut responded = BTreeSet::new();
+
+    let mut round = 0;
+    if let Some(ballot) = leader.take() {
+        let proposal_num = ballot.number(total_peers);
+        eprintln!("Group {} slot {}: leading with proposal_num = {}, skipping prepare", instance.group, instance.slot, proposal_num);
+        pause(config.delay_before_accept, "accept", instance);
+        let phase_start = Instant::now();
+        let accepted = accept_phase(transport, &up_acceptors(transport, acceptors, quorum), quorum, &values.my_value, ballot, proposal_num, instance, key);
+        phase2 += phase_start.elapsed();
+        responded.extend(accepted.responded.iter().cloned());
+        if !accepted.rejected && accepted.peers.len() >= quorum {
+            values.decided_value = Some(values.my_value.clone());
+            values.log_decided(instance);
+            *leader = Some(ballot);
+            let chosen = (ballot, values.my_value);
+            return report_chosen(config, transport, total_peers, instance, key, chosen, &accepted.peers, started, (phase1, phase2));
+        }
+        eprintln!(
+            "Lost leadership in {}: {} of {} acceptors accepted proposal {}{}",
+            instance, accepted.peers.len(), acceptors.len(), proposal_num,
+            if accepted.rejected { ", rejected" } else { "" }
+        );
+        round = next_round(ballot.round, accepted.highest_promised, my_id, total_peers);
+        if let (Some(failover), true) = (failover, accepted.rejected) {
+            step_back(failover, my_id, instance.group, accepted.highest_promised.map(|p| p.number(total_peers)));
+        }
+    }
+
+    // Each attempt retries phase 1 from scratch with a larger proposal number. A round a
+    // majority rejected is retried at once, since the rejections already said what number
+    // to beat. Only when that retry fails too does the proposer back off.
+    let mut fast_retried = false;
+    for attempt in 0..config.max_rounds {
+        // Ballots are unique per proposer and grow with the round.
+        let ballot = Ballot { round, proposer: my_id };
+        let proposal_num = ballot.number(total_peers);
+        eprintln!("Group {} slot {} round {}: proposal_num = {}", instance.group, instance.slot, round, proposal_num);
+
+        // --- Phase 1: Prepare ---
+        pause(config.delay_before_prepare, "prepare", instance);
+        let phase_start = Instant::now();
+        let prepared = prepare_phase(transport, &up_acceptors(transport, acceptors, quorum), quorum, &values.my_value, ballot, proposal_num, instance, key);
+        phase1 += phase_start.elapsed();
+        responded.extend(prepared.responded.iter().cloned());
+
+        // Only a majority of promises guarantees no other proposal can be chosen in between
+        if prepared.rejected || prepared.peers.len() < quorum {
+            eprintln!(
+                "Group {} slot {} round {} failed: {} of {} acceptors promised proposal {}, need {}{}",
+                instance.group, instance.slot, round, prepared.peers.len(), acceptors.len(), proposal_num, quorum,
+                if prepared.rejected { ", rejected" } else { "" }
+            );
+            round = next_round(round, prepared.highest_promised, my_id, total_peers);
+            let promised = prepared.highest_promised.map(|p| p.number(total_peers));
+            if let (Some(failover), true) = (failover, prepared.rejected) {
+                step_back(failover, my_id, instance.group, promised);
+                fast_retried = false;
+            } else if prepared.majority_rejected && !fast_retried {
+                eprintln!("Retrying at once above promised proposal {}", promised.unwrap_or(0));
+                fast_retried = true;
+            } else {
+                let yielding_to = prepared.highest_promised.filter(|p| *p > ballot).map(|p| p.number(total_peers));
+                backoff(config, &mut rng, attempt, yielding_to);
+                fast_retried = false;
+            }
+            continue;
+        }
+
+        // Paxos safety: a value some acceptor already accepted must be carried forward.
+        // Only with no accepted value in any reply is the proposer free to use its own.
+        values.adopted_value = prepared.highest_accepted.map(|(n, value)| {
+            eprintln!(
+                "Adopting adopted_value = {} accepted under proposal {} in place of my_value = {}",
+                value, n.number(total_peers), values.my_value
+            );
+            value
+        });
+
+        // --- Phase 2: Accept ---
+        pause(config.delay_before_accept, "accept", instance);
+        let phase_start = Instant::now();
+        let accepted = accept_phase(transport, &prepared.peers, quorum, values.to_propose(), ballot, proposal_num, instance, key);
+        phase2 += phase_start.elapsed();
+        responded.extend(accepted.responded.iter().cloned());
+
+        // The value is chosen once a majority accepted it, not before
+        if accepted.rejected || accepted.peers.len() < quorum {
+            eprintln!(
+                "Group {} slot {} round {} failed: {} of {} acceptors accepted proposal {}, need {}{}",
+                instance.group, instance.slot, round, accepted.peers.len(), acceptors.len(), proposal_num, quorum,
+                if accepted.rejected { ", rejected" } else { "" }
+            );
+            round = next_round(round, accepted.highest_promised, my_id, total_peers);
+            let promised = accepted.highest_promised.map(|p| p.number(total_peers));
+            if let (Some(failover), true) = (failover, accepted.rejected) {
+                step_back(failover, my_id, instance.group, promised);
+                fast_retried = false;
+            } else if accepted.majority_rejected && !fast_retried {
+                eprintln!("Retrying at once above promised proposal {}", promised.unwrap_or(0));
+                fast_retried = true;
+            } else {
+                let yielding_to = accepted.highest_promised.filter(|p| *p > ballot).map(|p| p.number(total_peers));
+                backoff(config, &mut rng, attempt, yielding_to);
+                fast_retried = false;
+            }
+            continue;
+        }
+
+        // No promise reported anything accepted further on, so later slots are free for
+        // this proposal number until some acceptor rejects it
+        if !prepared.accepted_later {
+            eprintln!("Leading group {} with proposal {}, later slots skip prepare", instance.group, proposal_num);
+            *leader = Some(ballot);
+        }
+        values.decided_value = Some(values.to_propose().to_string());
+        values.log_decided(instance);
+        let chosen = (ballot, values.decided_value.unwrap_or_default());
+        return report_chosen(config, transport, total_peers, instance, key, chosen, &accepted.peers, started, (phase1, phase2));
+    }
+
+    eprintln!("No quorum: gave up on {} after {} rounds", instance, config.max_rounds);
+    report_no_quorum(config, my_id, instance, key, &responded, started)
+}
+
+/// Records that the proposer gave up on a slot, in the decision file and as a `failed`
+/// line naming the acceptors that answered at all, and exits with EXIT_NO_QUORUM
+fn report_no_quorum(config: &Config, my_id: u32, instance: Instance, key: Option<&str>, responded: &BTreeSet<String>, started: Instant) -> ! {
+    let failed_at = SystemTime::now();
+    let mut failure = serde_json::json!({
+        "group": instance.group,
+        "slot": instance.slot,
+        "failed": "no quorum",
+        "rounds": config.max_rounds,
+        "responded": responded,
+        "started_ms": unix_millis(failed_at - started.elapsed()),
+        "failed_ms": unix_millis(failed_at),
+    });
+    if let Some(key) = key {
+        failure["key"] = key.into();
+    }
+    record_decision(&decision_file(config, my_id), &failure);
+
+    let mut failed = serde_json::json!({
+        "peer_id": my_id,
+        "action": "failed",
+        "reason": "no quorum",
+        "rounds": config.max_rounds,
+        "group": instance.group,
+        "slot": instance.slot,
+        "responded": responded,
+    });
+    if let Some(key) = key {
+        failed["key"] = key.into();
+    }
+    eprintln!("{}", failed);
+    process::exit(EXIT_NO_QUORUM);
+}
+
+/// --delay-before-prepare and --delay-before-accept: sleeps before a `phase` of `instance`,
+/// logging when the sleep starts and ends so the gap shows up in the trace
+fn pause(delay: Duration, phase: &str, instance: Instance) {
+    if delay.is_zero() {
+        return;
+    }
+    eprintln!("Waiting {} s before {} in {}", delay.as_secs_f64(), phase, instance);
+    thread::sleep(delay);
+    eprintln!("Done waiting before {} in {}", phase, instance);
+}
+
+/// The acceptors a phase sends to: the ones the transport knows are up, as long as they
+/// are enough for a quorum. Otherwise all of them, since a down acceptor may be back by now.
+fn up_acceptors(transport: &impl Transport, acceptors: &[String], quorum: usize) -> Vec<String> {
+    let up = transport.up(acceptors);
+    if up.len() == acceptors.len() || up.len() < quorum {
+        return acceptors.to_vec();
+    }
+    let down: Vec<&str> = acceptors.iter().filter(|addr| !up.contains(addr)).map(|addr| addr.as_str()).collect();
+    eprintln!("Skipping acceptors that are down: {}", down.join(", "));
+    up
+}
+
+/// --failover-ms: an acceptor rejected this proposer for another proposer's higher
+/// promise, so that one is active. This proposer goes passive until its turn comes again.
+fn step_back(failover: &Failover, my_id: u32, group: u32, promised: Option<u64>) {
+    eprintln!(
+        "Proposer {} deactivating in group {}: another proposer holds promise {}",
+        my_id, group, promised.map_or("?".to_string(), |n| n.to_string())
+    );
+    wait_for_turn(failover, my_id, group);
+}
+
+/// --failover-ms: pings the group's other proposers every PING_INTERVAL as a passive
+/// proposer, and becomes the active one once for `window` none of them answered as active
+/// and none listed before this one in the hostsfile answered at all. The earliest proposer
+/// that is up takes over, so two passive proposers don't both activate.
+fn wait_for_turn(failover: &Failover, my_id: u32, group: u32) {
+    failover.active.lock().unwrap().remove(&group);
+    let mut last_seen = Instant::now();
+    while last_seen.elapsed() < failover.window {
+        thread::sleep(PING_INTERVAL);
+        let someone_ahead = failover.peers.iter().enumerate().any(|(i, addr)| match ping(addr, my_id, group) {
+            Some(state) => state == "active" || i < failover.earlier,
+            None => false,
+        });
+        if someone_ahead {
+            last_seen = Instant::now();
+        }
+    }
+    failover.active.lock().unwrap().insert(group);
+    eprintln!(
+        "Proposer {} activating in group {}: no proposer ahead of it answered for {} ms",
+        my_id, group, failover.window.as_millis()
+    );
+}
+
+/// Asks the proposer at `addr` whether it is the active proposer of `group`. Returns its
+/// answer, "active" or "passive", or None if it didn't answer within PING_INTERVAL. Pings
+/// aren't logged, since a passive proposer sends them all the time.
+fn ping(addr: &str, my_id: u32, group: u32) -> Option<String> {
+    let pong = exchange_quietly(addr, &ping_message(my_id, group), PING_INTERVAL)?;
+    (pong.message_type == "pong").then_some(pong.message_value)
+}
+
+/// A ping from proposer `my_id` of `group`, answered by any node with a pong
+fn ping_message(my_id: u32, group: u32) -> PaxosMessage {
+    PaxosMessage {
+        peer_id: my_id,
+        action: "sent".to_string(),
+        message_type: "ping".to_string(),
+        group,
+        ..Default::default()
+    }
+}
+
+/// Sends `msg` to `addr` on a connection of its own and reads the reply, without logging
+/// either or retrying. None if `addr` didn't answer within `timeout` or the reply isn't
+/// a PaxosMessage.
+fn exchange_quietly(addr: &str, msg: &PaxosMessage, timeout: Duration) -> Option<PaxosMessage> {
+    exchange_on(&mut None, addr, msg, timeout)
+}
+
+/// exchange_quietly on `connection`, which is opened first if there is none, and dropped
+/// after a failed exchange so the next one reconnects
+fn exchange_on(connection: &mut Option<BufReader<TcpStream>>, addr: &str, msg: &PaxosMessage, timeout: Duration) -> Option<PaxosMessage> {
+    if connection.is_none() {
+        let socket_addr = addr.to_socket_addrs().ok()?.next()?;
+        let stream = TcpStream::connect_timeout(&socket_addr, timeout).ok()?;
+        stream.set_read_timeout(Some(timeout)).ok()?;
+        stream.set_write_timeout(Some(timeout)).ok()?;
+        *connection = Some(BufReader::new(stream));
+    }
+    let reply = connection.as_mut().and_then(|reader| {
+        writeln!(reader.get_mut(), "{}", json_line(msg)).ok()?;
+        let mut reply = String::new();
+        match reader.read_line(&mut reply) {
+            Ok(n) if n > 0 => serde_json::from_str(reply.trim_end()).ok(),
+            _ => None,
+        }
+    });
+    if reply.is_none() {
+        *connection = None;
+    }
+    reply
+}
+
+/// Records a decided slot in the decision file, then prints the chose line and with
+/// --metrics the slot's timings
+#[allow(clippy::too_many_arguments)]
+fn report_chosen(
+    config: &Config,
+    transport: &impl Transport,
+    total_peers: u32,
+    instance: Instance,
+    key: Option<&str>,
+    (ballot, value): Accepted,
+    acked_by: &[String],
+    started: Instant,
+    (phase1, phase2): (Duration, Duration),
+) -> Accepted {
+    let chosen_at = SystemTime::now();
+    let proposal_num = ballot.number(total_peers);
+    let mut decision = serde_json::json!({
+        "group": instance.group,
+        "slot": instance.slot,
+        "proposal_num": proposal_num,
+        "value": value,
+        "acked_by": acked_by,
+        "started_ms": unix_millis(chosen_at - started.elapsed()),
+        "chosen_ms": unix_millis(chosen_at),
+    });
+    if let Some(key) = key {
+        decision["key"] = key.into();
+    }
+    record_decision(&decision_file(config, ballot.proposer), &decision);
+
+    let chosen_msg = PaxosMessage {
+        peer_id: ballot.proposer,
+        action: "chose".to_string(),
+        message_type: "chose".to_string(),
+        message_value: value,
+        proposal_num,
+        group: instance.group,
+        slot: instance.slot,
+        key: key.map(String::from),
+        ..Default::default()
+    };
+    eprintln!("{}", json_line(&chosen_msg));
+    if config.metrics {
+        print_metrics(instance, phase1, phase2, started.elapsed(), transport.take_round_trips());
+    }
+    (ballot, chosen_msg.message_value)
+}
+
+/// --metrics: one JSON line per chosen slot with the time spent in each phase over all its
+/// rounds, the whole slot including backoff, and each acceptor's average round trip
+fn print_metrics(instance: Instance, phase1: Duration, phase2: Duration, total: Duration, round_trips: BTreeMap<String, Vec<Duration>>) {
+    let per_acceptor: serde_json::Map<String, serde_json::Value> = round_trips
+        .into_iter()
+        .map(|(addr, times)| {
+            let average = times.iter().sum::<Duration>() / times.len() as u32;
+            (addr, millis(average).into())
+        })
+        .collect();
+    let metrics = serde_json::json!({
+        "group": instance.group,
+        "slot": instance.slot,
+        "phase1_ms": millis(phase1),
+        "phase2_ms": millis(phase2),
+        "total_ms": millis(total),
+        "per_acceptor": per_acceptor,
+    });
+    eprintln!("{}", metrics);
+}
+
+/// Where the proposer records its decisions: --out, or chosen_<id>.json
+fn decision_file(config: &Config, my_id: u32) -> String {
+    config.out.clone().unwrap_or_else(|| format!("chosen_{}.json", my_id))
+}
+
+/// Empties the decision file of proposer `my_id` for a new run. The proposer exits if it can't.
+fn start_decision_file(config: &Config, my_id: u32) {
+    let out = decision_file(config, my_id);
+    if let Err(e) = fs::write(&out, "") {
+        eprintln!("Failed to write {}: {}", out, e);
+        process::exit(1);
+    }
+}
+
+/// Appends a decision to `path` as one JSON line and syncs it to disk, so the file holds
+/// every decision the proposer has printed. The proposer exits if it can't.
+fn record_decision(path: &str, decision: &serde_json::Value) {
+    let written = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(format!("{}\n", decision).as_bytes()).and_then(|_| file.sync_all()));
+    if let Err(e) = written {
+        eprintln!("Failed to write {}: {}", path, e);
+        process::exit(1);
+    }
+}
+
+/// Milliseconds since the Unix epoch
+fn unix_millis(t: SystemTime) -> u128 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0)
+}
+
+/// A duration in milliseconds, to the microsecond
+fn millis(d: Duration) -> f64 {
+    (d.as_secs_f64() * 1_000_000.0).round() / 1000.0
+}
+
+/// Accepts connections on --port and handles each on its own thread
+fn serve(listener: TcpListener, node: Arc<Node>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let node = Arc::clone(&node);
+                thread::spawn(move || {
+                    handle_client(stream, &node);
+                });
+            }
+            Err(e) => {
+                eprintln!("Error accepting connection: {}", e);
+            }
+        }
+    }
+}
+
+/// Initializes the application from command-line arguments.
+/// Expected flags: -h <hostsfile>, -v <proposed_value>, -V <file> (proposed value read
+/// from a file), -k <key> (the -v or -V right after it is that key's value, repeatable), -t <delay_time>,
+/// -a <proposal_num>:<value> (acceptor starts out having accepted that proposal),
+/// --max-rounds <n> (proposer retries per slot, default 10),
+/// -n <count> (propose -v in that many slots), -f <file> (propose one line per slot),
+/// --port <port> (default 8889), --timeout-ms <ms> (default 5000),
+/// --connect-timeout-ms <ms> and --read-timeout-ms <ms> (default --timeout-ms), --retries <n> (default 4),
+/// --reply-delay-ms <ms> (acceptor waits before handling each message, for testing),
+/// --backoff-base-ms <ms> (default 1000, 0 disables), --backoff-max-ms <ms> (default 8000),
+/// --quiet-secs <s> (default 30), --result-file <path> (default accepted.json),
+/// --out <path> (proposer's decisions, default chosen_<id>.json),
+/// --distinguished-learner (no value: acceptors report to the first learner of their group only),
+/// --crash-after <prepare_ack|accept_ack> (acceptor exits right after sending that reply, for testing),
+/// --metrics (no value: print timings, off by default so graded output stays the same),
+/// --batch-size <n> (values per slot, default 1), --batch-ms <ms> (default 200),
+/// --client-port <port> (proposer also proposes values clients submit on that port),
+/// --join <acceptor> (new acceptor copies the decided log from it once added to a group),
+/// --failover-ms <ms> (one active proposer per group, the next takes over after <ms> without it),
+/// --poll-ms <ms> (learner also asks the acceptors what they accepted every <ms>),
+/// --pull-only (no value: learner only polls, every --poll-ms or 1000 ms, and ignores learn messages),
+/// --log <path> (appends every message sent and received to <path> as JSON lines),
+/// --drop-rate <p> (proposer loses each message, and each reply, with chance p, for testing),
+/// --delay-ms-max <ms> (proposer holds each message back up to <ms>, for testing),
+/// --seed <n> (seed for --drop-rate and --delay-ms-max, default from the clock),
+/// --heartbeat-ms <ms> (proposer pings its acceptors this often and skips the ones down, default 1000, 0 disables),
+/// --delay-before-prepare <s> and --delay-before-accept <s> (proposer waits that many seconds,
+/// e.g. 0.5, before each prepare or accept phase, to force interleavings),
+/// --quorum <n> (proposer needs n acceptors in each phase instead of a majority, n above half of them),
+/// --unsafe-quorum (no value: allows a --quorum of half the acceptors or fewer)
+fn init(args: Vec<String>) -> Config {
+    // Switches take no value, so they are pulled out before the rest is read in pairs
+    let (switches, args): (Vec<String>, Vec<String>) =
+        args.into_iter().partition(|arg| SWITCHES.contains(&arg.as_str()));
+    
+    let defaults = Config {
+        hostsfile: String::new(),
+        proposed_val: None,
+        keys: Vec::new(),
+        delay_time: None,
+        seed: None,
+        max_rounds: DEFAULT_MAX_ROUNDS,
+        instances: None,
+        values_file: None,
+        timeout: DEFAULT_TIMEOUT,
+        retries: DEFAULT_RETRIES,
+        reply_delay: Duration::ZERO,
+        backoff_base: DEFAULT_BACKOFF_BASE,
+        backoff_max: DEFAULT_BACKOFF_MAX,
+        quiet: DEFAULT_QUIET,
+        result_file: DEFAULT_RESULT_FILE.to_string(),
+        out: None,
+        port: DEFAULT_PORT,
+        connect_timeout: None,
+        read_timeout: None,
+        crash_after: None,
+        batch_size: 1,
+        batch_window: DEFAULT_BATCH_WINDOW,
+        client_port: None,
+        join: None,
+        failover: None,
+        log: None,
+        poll: None,
+        drop_rate: 0.0,
+        delay_max: Duration::ZERO,
+        fault_seed: None,
+        heartbeat: DEFAULT_HEARTBEAT,
+        delay_before_prepare: Duration::ZERO,
+        delay_before_accept: Duration::ZERO,
+        quorum: None,
+        unsafe_quorum: switches.iter().any(|s| s == "--unsafe-quorum"),
+        pull_only: switches.iter().any(|s| s == "--pull-only"),
+        distinguished_learner: switches.iter().any(|s| s == "--distinguished-learner"),
+        metrics: switches.iter().any(|s| s == "--metrics"),
+    };
+    let config = args.chunks(2).fold(defaults, |mut config, pair| {
+        match pair {
+            [key, value] => match key.as_str() {
+                "-h" => config.hostsfile = value.clone(),
+                "-k" if value.is_empty() => {
+                    eprintln!("init error: -k expects a non-empty key");
+                    process::exit(1);
+                }
+                "-k" if config.keys.iter().any(|(key, _)| key == value) => {
+                    eprintln!("init error: -k {} is given twice", value);
+                    process::exit(1);
+                }
+                "-k" => config.keys.push((value.clone(), String::new())),
+                // A -v or -V right after a -k is that key's value
+                "-v" | "-V" if config.keys.last().is_some_and(|(_, pending)| pending.is_empty()) => {
+                    let value = if key == "-v" { value.clone() } else { read_value_file(value) };
+                    if let Some((_, pending)) = config.keys.last_mut() {
+                        *pending = value;
+                    }
+                }
+                "-v" | "-V" if config.proposed_val.is_some() => {
+                    eprintln!("init error: Only one of -v and -V can be given");
+                    process::exit(1);
+                }
+                "-v" => config.proposed_val = Some(value.clone()),
+                "-V" => config.proposed_val = Some(read_value_file(value)),
+                "-t" => config.delay_time = value.parse().ok(),
+                "-a" => config.seed = Some(parse_seed(value)),
+                "-n" => match value.parse() {
+                    Ok(n) if n > 0 => config.instances = Some(n),
+                    _ => {
+                        eprintln!("init error: -n expects a positive number, got {}", value);
+                        process::exit(1);
+                    }
+                },
+                "-f" => config.values_file = Some(value.clone()),
+                "--max-rounds" => match value.parse() {
+                    Ok(n) if n > 0 => config.max_rounds = n,
+                    _ => {
+                        eprintln!("init error: --max-rounds expects a positive number, got {}", value);
+                        process::exit(1);
+                    }
+                },
+                "--timeout-ms" => match value.parse() {
+                    Ok(ms) if ms > 0 => config.timeout = Duration::from_millis(ms),
+                    _ => {
+                        eprintln!("init error: --timeout-ms expects a positive number, got {}", value);
+                        process::exit(1);
+                    }
+                },
+                "--port" => match value.parse() {
+                    Ok(port) if port > 0 => config.port = port,
+                    _ => {
+                        eprintln!("init error: --port expects a port number, got {}", value);
+                        process::exit(1);
+                    }
+                },
+                "--connect-timeout-ms" => match value.parse() {
+                    Ok(ms) if ms > 0 => config.connect_timeout = Some(Duration::from_millis(ms)),
+                    _ => {
+                        eprintln!("init error: --connect-timeout-ms expects a positive number, got {}", value);
+                        process::exit(1);
+                    }
+                },
+                "--read-timeout-ms" => match value.parse() {
+                    Ok(ms) if ms > 0 => config.read_timeout = Some(Duration::from_millis(ms)),
+                    _ => {
+                        eprintln!("init error: --read-timeout-ms expects a positive number, got {}", value);
+                        process::exit(1);
+                    }
+                },
+                "--retries" => match value.parse() {
+                    Ok(n) => config.retries = n,
+                    Err(_) => {
+                        eprintln!("init error: --retries expects a number, got {}", value);
+                        process::exit(1);
+                    }
+                },
+                "--reply-delay-ms" => match value.parse() {
+                    Ok(ms) => config.reply_delay = Duration::from_millis(ms),
+                    Err(_) => {
+                        eprintln!("init error: --reply-delay-ms expects a number, got {}", value);
+                        process::exit(1);
+                    }
+                },
+                "--backoff-base-ms" => match value.parse() {
+                    Ok(ms) => config.backoff_base = Duration::from_millis(ms),
+                    Err(_) => {
+                        eprintln!("init error: --backoff-base-ms expects a number, got {}", value);
+                        process::exit(1);
+                    }
+                },
+                "--backoff-max-ms" => match value.parse() {
+                    Ok(ms) => config.backoff_max = Duration::from_millis(ms),
+                    Err(_) => {
+                        eprintln!("init error: --backoff-max-ms expects a number, got {}", value);
+                        process::exit(1);
+                    }
+                },
+                "--quiet-secs" => match value.parse() {
+                    Ok(secs) => config.quiet = Duration::from_secs(secs),
+                    Err(_) => {
+                        eprintln!("init error: --quiet-secs expects a number, got {}", value);
+                        process::exit(1);
+                    }
+                },
+                "--result-file" => config.result_file = value.clone(),
+                "--out" => config.out = Some(value.clone()),
+                "--join" => config.join = Some(value.clone()),
+                "--log" => config.log = Some(value.clone()),
+                "--failover-ms" => match value.parse() {
+                    Ok(ms) if ms > 0 => config.failover = Some(Duration::from_millis(ms)),
+                    _ => {
+                        eprintln!("init error: --failover-ms expects a positive number, got {}", value);
+                        process::exit(1);
+                    }
+                },
+                "--poll-ms" => match value.parse() {
+                    Ok(ms) if ms > 0 => config.poll = Some(Duration::from_millis(ms)),
+                    _ => {
+                        eprintln!("init error: --poll-ms expects a positive number, got {}", value);
+                        process::exit(1);
+                    }
+                },
+                "--batch-size" => match value.parse() {
+                    Ok(n) if n > 0 => config.batch_size = n,
+                    _ => {
+                        eprintln!("init error: --batch-size expects a positive number, got {}", value);
+                        process::exit(1);
+                    }
+                },
+                "--batch-ms" => match value.parse() {
+                    Ok(ms) => config.batch_window = Duration::from_millis(ms),
+                    Err(_) => {
+                        eprintln!("init error: --batch-ms expects a number, got {}", value);
+                        process::exit(1);
+                    }
+                },
+                "--client-port" => match value.parse() {
+                    Ok(port) if port > 0 => config.client_port = Some(port),
+                    _ => {
+                        eprintln!("init error: --client-port expects a port number, got {}", value);
+                        process::exit(1);
+                    }
+                },
+                "--drop-rate" => match value.parse() {
+                    Ok(p) if (0.0..=1.0).contains(&p) => config.drop_rate = p,
+                    _ => {
+                        eprintln!("init error: --drop-rate expects a number from 0 to 1, got {}", value);
+                        process::exit(1);
+                    }
+                },
+                "--delay-before-prepare" | "--delay-before-accept" => match value.parse::<f64>() {
+                    Ok(secs) if secs.is_finite() && secs >= 0.0 => {
+                        if key == "--delay-before-prepare" {
+                            config.delay_before_prepare = Duration::from_secs_f64(secs);
+                        } else {
+                            config.delay_before_accept = Duration::from_secs_f64(secs);
+                        }
+                    }
+                    _ => {
+                        eprintln!("init error: {} expects seconds, got {}", key, value);
+                        process::exit(1);
+                    }
+                },
+                "--quorum" => match value.parse() {
+                    Ok(n) if n > 0 => config.quorum = Some(n),
+                    _ => {
+                        eprintln!("init error: --quorum expects a positive number, got {}", value);
+                        process::exit(1);
+                    }
+                },
+                "--heartbeat-ms" => match value.parse() {
+                    Ok(ms) => config.heartbeat = Duration::from_millis(ms),
+                    Err(_) => {
+                        eprintln!("init error: --heartbeat-ms expects milliseconds, got {}", value);
+                        process::exit(1);
+                    }
+                },
+                "--delay-ms-max" => match value.parse() {
+                    Ok(ms) => config.delay_max = Duration::from_millis(ms),
+                    Err(_) => {
+                        eprintln!("init error: --delay-ms-max expects milliseconds, got {}", value);
+                        process::exit(1);
+                    }
+                },
+                "--seed" => match value.parse() {
+                    Ok(seed) => config.fault_seed = Some(seed),
+                    Err(_) => {
+                        eprintln!("init error: --seed expects a number, got {}", value);
+                        process::exit(1);
+                    }
+                },
+                "--crash-after" => match value.as_str() {
+                    "prepare_ack" | "accept_ack" => config.crash_after = Some(value.clone()),
+                    _ => {
+                        eprintln!("init error: --crash-after expects prepare_ack or accept_ack, got {}", value);
+                        process::exit(1);
+                    }
+                },
+                other => {
+                    eprintln!("init error: Unknown flag: {}", other);
+                    process::exit(1);
+                }
+            },
+            _ => {
+                eprintln!("init error: Invalid arguments format");
+                process::exit(1);
+            }
+        }
+        config
+    });
+    
+    if config.hostsfile.is_empty() {
+        eprintln!("init error: Missing hostsfile argument (-h)");
+        process::exit(1);
+    }
+    if config.proposed_val.as_deref() == Some("") {
+        eprintln!("init error: The proposed value can't be empty");
+        process::exit(1);
+    }
+    if let Some((key, _)) = config.keys.iter().find(|(_, value)| value.is_empty()) {
+        eprintln!("init error: -k {} needs a non-empty -v or -V right after it", key);
+        process::exit(1);
+    }
+    let other_values = config.proposed_val.is_some() || config.instances.is_some() || config.values_file.is_some();
+    if !config.keys.is_empty() && (other_values || config.client_port.is_some() || config.batch_size > 1 || config.failover.is_some()) {
+        eprintln!("init error: -k can't be combined with -v or -V without a key, -n, -f, --client-port, --batch-size or --failover-ms");
+        process::exit(1);
+    }
+    
+    config
+}
+
+/// Reads the proposed value for -V. The whole file is the value, minus one trailing newline
+fn read_value_file(path: &str) -> String {
+    match fs::read_to_string(path) {
+        Ok(content) => content.strip_suffix('\n').unwrap_or(&content).to_string(),
+        Err(e) => {
+            eprintln!("init error: Error reading {}: {}", path, e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Parses "<proposal_num>:<value>" for -a
+fn parse_seed(arg: &str) -> (u64, String) {
+    match arg.split_once(':') {
+        Some((num, value)) if !value.is_empty() => match num.parse() {
+            Ok(num) => (num, value.to_string()),
+            Err(_) => {
+                eprintln!("init error: Invalid proposal number in -a {}", arg);
+                process::exit(1);
+            }
+        },
+        _ => {
+            eprintln!("init error: -a expects <proposal_num>:<value>, got {}", arg);
+            process::exit(1);
+        }
+    }
+}
+
+/// Phase 1: send prepare to every acceptor and collect the promises
+#[allow(clippy::too_many_arguments)]
+fn prepare_phase(
+    transport: &impl Transport,
+    target_peers: &[String],
+    quorum: usize,
+    value: &str,
+    ballot: Ballot,
+    proposal_num: u64,
+    instance: Instance,
+    key: Option<&str>,
+) -> Prepared {
+    let mut prepared =
+        Prepared { peers: Vec::new(), responded: Vec::new(), rejected: false, highest_promised: None, highest_accepted: None, accepted_later: false, majority_rejected: false };
+    let prepare_msg = PaxosMessage {
+        peer_id: ballot.proposer,
+        action: "sent".to_string(),
+        message_type: "prepare".to_string(),
+        message_value: value.to_string(),
+        proposal_num,
+        ballot: Some(ballot),
+        group: instance.group,
+        slot: instance.slot,
+        key: key.map(String::from),
+        ..Default::default()
+    };
+
+    prepared.majority_rejected = send_to_all(transport, target_peers, &prepare_msg, quorum, "reject_prepare", |addr, result| {
+        if result.is_ok() {
+            prepared.responded.push(addr.clone());
+        }
+        match result {
+            Ok(reply) if reply.message_type == "prepare_ack" => {
+                prepared.peers.push(addr.clone());
+                prepared.accepted_later |= reply.accepted_later;
+                if let (Some(n), Some(value)) = (reply.accepted_proposal, reply.accepted_value) {
+                    if prepared.highest_accepted.as_ref().map_or(true, |(highest, _)| n > *highest) {
+                        prepared.highest_accepted = Some((n, value));
+                    }
+                }
+            }
+            Ok(reply) if reply.message_type == "reject_prepare" => {
+                prepared.rejected = true;
+                prepared.highest_promised = prepared.highest_promised.max(reply.promised);
+            }
+            Ok(reply) if reply.message_type == "error" => eprintln!("Error from {}: {}", addr, reply.message_value),
+            Ok(reply) => eprintln!("Unexpected {} reply from {}", reply.message_type, addr),
+            // Neither a promise nor a rejection: the acceptor is just missing from the quorum
+            Err(e) => eprintln!("No promise from {}: {}", addr, e),
+        }
+    });
+
+    prepared
+}
+
+/// Sends `msg` to every acceptor in `addrs` at once and hands each result to `on_reply`
+/// as it arrives. Once `quorum` of them answered with `reject_type` the round is lost
+/// whatever the others say, so the requests still running are aborted instead of waited
+/// for. Returns whether that happened.
+fn send_to_all(
+    transport: &impl Transport,
+    addrs: &[String],
+    msg: &PaxosMessage,
+    quorum: usize,
+    reject_type: &str,
+    mut on_reply: impl FnMut(&String, Result<PaxosMessage, SendError>),
+) -> bool {
+    let (replies_tx, replies) = mpsc::channel();
+    thread::scope(|scope| {
+        for addr in addrs {
+            let replies_tx = replies_tx.clone();
+            // The phase may have stopped listening already, so a failed send is fine
+            scope.spawn(move || {
+                let _ = replies_tx.send((addr, transport.send_and_receive(addr, msg)));
+            });
+        }
+        drop(replies_tx);
+
+        let mut waiting: Vec<&String> = addrs.iter().collect();
+        let mut rejections = 0;
+        for (addr, result) in replies {
+            waiting.retain(|waiting_for| *waiting_for != addr);
+            if matches!(&result, Ok(reply) if reply.message_type == reject_type) {
+                rejections += 1;
+            }
+            on_reply(addr, result);
+            if rejections >= quorum && !waiting.is_empty() {
+                eprintln!(
+                    "Proposal {} rejected by {} acceptors, not waiting for {}",
+                    msg.proposal_num,
+                    rejections,
+                    waiting.iter().map(|addr| addr.as_str()).collect::<Vec<_>>().join(", ")
+                );
+                for addr in &waiting {
+                    transport.abort(addr);
+                }
+                return true;
+            }
+        }
+        false
+    })
+}
+
+/// Phase 2: ask every acceptor that promised to accept `value` and count the acks
+#[allow(clippy::too_many_arguments)]
+fn accept_phase(
+    transport: &impl Transport,
+    prepared_peers: &[String],
+    quorum: usize,
+    value: &str,
+    ballot: Ballot,
+    proposal_num: u64,
+    instance: Instance,
+    key: Option<&str>,
+) -> AcceptResult {
+    let mut result = AcceptResult { peers: Vec::new(), responded: Vec::new(), rejected: false, highest_promised: None, majority_rejected: false };
+    let accept_msg = PaxosMessage {
+        peer_id: ballot.proposer,
+        action: "sent".to_string(),
+        message_type: "accept".to_string(),
+        message_value: value.to_string(),
+        proposal_num,
+        ballot: Some(ballot),
+        group: instance.group,
+        slot: instance.slot,
+        key: key.map(String::from),
+        ..Default::default()
+    };
+
+    result.majority_rejected = send_to_all(transport, prepared_peers, &accept_msg, quorum, "reject_accept", |addr, reply| {
+        if reply.is_ok() {
+            result.responded.push(addr.clone());
+        }
+        match reply {
+            Ok(reply) if reply.message_type == "accept_ack" => result.peers.push(addr.to_string()),
+            Ok(reply) if reply.message_type == "reject_accept" => {
+                result.rejected = true;
+                result.highest_promised = result.highest_promised.max(reply.promised);
+            }
+            Ok(reply) if reply.message_type == "error" => eprintln!("Error from {}: {}", addr, reply.message_value),
+            Ok(reply) => eprintln!("Unexpected {} reply from {}", reply.message_type, addr),
+            Err(e) => eprintln!("No accept from {}: {}", addr, e),
+        }
+    });
+
+    result
+}
+
+impl TcpTransport {
+    fn new(connect_timeout: Duration, read_timeout: Duration, retries: u32, metrics: bool, events: Option<Arc<EventLog>>) -> Self {
+        TcpTransport {
+            connect_timeout,
+            read_timeout,
+            retries,
+            connections: Mutex::new(HashMap::new()),
+            in_flight: Mutex::new(InFlight::default()),
+            aborted_signal: Condvar::new(),
+            round_trips: metrics.then(|| Mutex::new(BTreeMap::new())),
+            // Ids start from the clock, so a restarted proposer's messages are newer than
+            // the ones it sent before the restart
+            last_message_id: AtomicU64::new(SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_micros() as u64)),
+            events,
+            heartbeat: None,
+        }
+    }
+
+    /// send_and_receive with --retries more attempts after an unreachable or slow acceptor.
+    /// An abort ends it at once, even while waiting to retry.
+    fn send_with_retries(&self, addr: &str, msg: &PaxosMessage) -> Result<PaxosMessage, SendError> {
+        let mut wait = RETRY_BACKOFF;
+        let mut attempt = 0;
+        loop {
+            let started = Instant::now();
+            let result = self.try_send_and_receive(addr, msg);
+            if let (Ok(_), Some(round_trips)) = (&result, &self.round_trips) {
+                round_trips.lock().unwrap().entry(addr.to_string()).or_default().push(started.elapsed());
+            }
+            if result.is_err() && self.in_flight.lock().unwrap().aborted.contains(addr) {
+                return Err(SendError::Aborted);
+            }
+            match result {
+                Err(SendError::Malformed(reply)) => return Err(SendError::Malformed(reply)),
+                Err(e) if attempt < self.retries => {
+                    eprintln!("Attempt {} to {} failed ({}), retrying in {} ms", attempt + 1, addr, e, wait.as_millis());
+                    let in_flight = self.in_flight.lock().unwrap();
+                    let (in_flight, _) = self
+                        .aborted_signal
+                        .wait_timeout_while(in_flight, wait, |in_flight| !in_flight.aborted.contains(addr))
+                        .unwrap();
+                    if in_flight.aborted.contains(addr) {
+                        return Err(SendError::Aborted);
+                    }
+                    wait *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// One attempt of send_and_receive: writes `msg` as one line on the connection to
+    /// `addr`, opening it first if needed, and reads one line back. Connect gets the
+    /// connect timeout, write and read each get the read timeout. The connection goes back
+    /// to the idle ones only after a good reply; on any error it is dropped, since a late
+    /// reply would answer the next message.
+    fn try_send_and_receive(&self, addr: &str, msg: &PaxosMessage) -> Result<PaxosMessage, SendError> {
+        let msg_json = serde_json::to_string(msg).map_err(|e| SendError::Malformed(e.to_string()))?;
+        let idle = self.connections.lock().unwrap().remove(addr);
+        let mut conn = match idle {
+            Some(conn) => conn,
+            None => BufReader::new(self.connect(addr)?),
+        };
+        {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            if in_flight.aborted.contains(addr) {
+                return Err(SendError::Aborted);
+            }
+            in_flight.streams.insert(addr.to_string(), conn.get_ref().try_clone().ok());
+        }
+
+        writeln!(conn.get_mut(), "{}", msg_json).map_err(io_error)?;
+        eprintln!("{}", msg_json);
+        log_event(self.events.as_deref(), "send", addr, &msg_json);
+
+        let mut reply_str = String::new();
+        if conn.read_line(&mut reply_str).map_err(io_error)? == 0 {
+            return Err(SendError::Unreachable(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed")));
+        }
+        let reply_str = reply_str.trim_end();
+        eprintln!("{}", reply_str);
+        log_event(self.events.as_deref(), "recv", addr, reply_str);
+        let reply = serde_json::from_str(reply_str).map_err(|_| SendError::Malformed(reply_str.to_string()))?;
+        self.connections.lock().unwrap().insert(addr.to_string(), conn);
+        Ok(reply)
+    }
+
+    fn connect(&self, addr: &str) -> Result<TcpStream, SendError> {
+        let socket_addr = addr
+            .to_socket_addrs()
+            .map_err(SendError::Unreachable)?
+            .next()
+            .ok_or_else(|| SendError::Unreachable(io::Error::new(io::ErrorKind::NotFound, "no address")))?;
+        let stream = TcpStream::connect_timeout(&socket_addr, self.connect_timeout).map_err(io_error)?;
+        stream.set_read_timeout(Some(self.read_timeout)).map_err(SendError::Unreachable)?;
+        stream.set_write_timeout(Some(self.read_timeout)).map_err(SendError::Unreachable)?;
+        Ok(stream)
+    }
+}
+
+/// Timeouts show up as WouldBlock or TimedOut depending on the platform
+fn io_error(e: io::Error) -> SendError {
+    match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => SendError::TimedOut,
+        _ => SendError::Unreachable(e),
+    }
+}
+
+/// --drop-rate and --delay-ms-max: wraps a proposer's transport and, at random, holds a
+/// message back for up to `delay_max`, loses it before it reaches the acceptor, or loses
+/// the acceptor's reply after the acceptor handled it. The phases see a lost message or
+/// reply as a timeout. Each acceptor gets its own generator, seeded from the seed and its
+/// address, and the messages to one acceptor go one at a time, so a seed injects the same
+/// faults whatever order the phase threads run in.
+struct FaultyTransport<T: Transport> {
+    inner: T,
+    drop_rate: f64,
+    delay_max: Duration,
+    seed: u64,
+    rngs: Mutex<HashMap<String, XorShift>>, // by acceptor address
+}
+
+impl<T: Transport> FaultyTransport<T> {
+    /// Wraps `inner`. Without --seed the seed comes from the clock, and either way it is
+    /// printed so a run can be repeated. Proposers in different groups or on different
+    /// nodes draw different faults from the same seed.
+    fn new(inner: T, config: &Config, my_id: u32, group: u32) -> Self {
+        let seed = config.fault_seed.unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_micros() as u64));
+        if config.drop_rate > 0.0 || !config.delay_max.is_zero() {
+            eprintln!("Injecting faults into proposer {}'s messages in group {} with seed {}", my_id, group, seed);
+        }
+        FaultyTransport {
+            inner,
+            drop_rate: config.drop_rate,
+            delay_max: config.delay_max,
+            seed: seed ^ ((my_id as u64) << 32 | group as u64),
+            rngs: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<T: Transport> Transport for FaultyTransport<T> {
+    fn send_and_receive(&self, addr: &str, msg: &PaxosMessage) -> Result<PaxosMessage, SendError> {
+        if self.drop_rate <= 0.0 && self.delay_max.is_zero() {
+            return self.inner.send_and_receive(addr, msg);
+        }
+        // All three are drawn every time, so one fault doesn't shift the ones after it
+        let (delay, drop_message, drop_reply) = {
+            let mut rngs = self.rngs.lock().unwrap();
+            let rng = rngs.entry(addr.to_string()).or_insert_with(|| {
+                XorShift::from_seed(addr.bytes().fold(self.seed, |h, b| (h ^ b as u64).wrapping_mul(0x100000001b3)))
+            });
+            let delay = Duration::from_millis(rng.next_below(self.delay_max.as_millis() as u64 + 1));
+            (delay, rng.chance(self.drop_rate), rng.chance(self.drop_rate))
+        };
+        if !delay.is_zero() {
+            eprintln!("Injected fault: delaying {} to {} by {} ms", msg.message_type, addr, delay.as_millis());
+            thread::sleep(delay);
+        }
+        if drop_message {
+            eprintln!("Injected fault: dropped {} to {}", msg.message_type, addr);
+            return Err(SendError::TimedOut);
+        }
+        let reply = self.inner.send_and_receive(addr, msg)?;
+        if drop_reply {
+            eprintln!("Injected fault: dropped {} from {}", reply.message_type, addr);
+            return Err(SendError::TimedOut);
+        }
+        Ok(reply)
+    }
+
+    fn take_round_trips(&self) -> BTreeMap<String, Vec<Duration>> {
+        self.inner.take_round_trips()
+    }
+
+    fn abort(&self, addr: &str) {
+        self.inner.abort(addr)
+    }
+
+    fn up(&self, addrs: &[String]) -> Vec<String> {
+        self.inner.up(addrs)
+    }
+}
+
+/// --heartbeat-ms: which acceptors of a proposer's group are up. A thread per acceptor
+/// pings it every interval on a connection of its own and counts it as down while a ping
+/// goes unanswered for the interval, so the phases can leave it out instead of each
+/// waiting for its connect to time out. Pings aren't logged, only the changes.
+struct Heartbeat {
+    my_id: u32,
+    group: u32,
+    interval: Duration,
+    quorum: Option<usize>, // --quorum
+    liveness: Mutex<Liveness>,
+}
+
+struct Liveness {
+    up: BTreeMap<String, bool>, // by acceptor address, up until a ping goes unanswered
+    quorum: bool,               // whether a quorum of them is up
+}
+
+impl Heartbeat {
+    /// Starts pinging `acceptors`, or returns None if --heartbeat-ms is 0
+    fn start(config: &Config, my_id: u32, group: u32, acceptors: &[String]) -> Option<Arc<Heartbeat>> {
+        if config.heartbeat.is_zero() {
+            return None;
+        }
+        let liveness = Liveness { up: BTreeMap::new(), quorum: true };
+        let heartbeat = Arc::new(Heartbeat { my_id, group, interval: config.heartbeat, quorum: config.quorum, liveness: Mutex::new(liveness) });
+        for addr in acceptors {
+            heartbeat.watch(addr);
+        }
+        Some(heartbeat)
+    }
+
+    /// Starts pinging `addr` unless it is pinged already
+    fn watch(self: &Arc<Self>, addr: &str) {
+        match self.liveness.lock().unwrap().up.entry(addr.to_string()) {
+            btree_map::Entry::Occupied(_) => return,
+            btree_map::Entry::Vacant(entry) => entry.insert(true),
+        };
+        let heartbeat = Arc::clone(self);
+        let addr = addr.to_string();
+        thread::spawn(move || {
+            let ping_msg = ping_message(heartbeat.my_id, heartbeat.group);
+            let mut connection = None;
+            loop {
+                let started = Instant::now();
+                let pong = exchange_on(&mut connection, &addr, &ping_msg, heartbeat.interval);
+                heartbeat.mark(&addr, pong.is_some_and(|pong| pong.message_type == "pong"));
+                thread::sleep(heartbeat.interval.saturating_sub(started.elapsed()));
+            }
+        });
+    }
+
+    /// Records whether `addr` answered its last ping, logging it if that changed and
+    /// again if that made a quorum of the group's acceptors possible or impossible
+    fn mark(&self, addr: &str, up: bool) {
+        let mut liveness = self.liveness.lock().unwrap();
+        if liveness.up.insert(addr.to_string(), up) == Some(up) {
+            return;
+        }
+        eprintln!("Acceptor {} {} in group {}", addr, if up { "up" } else { "down" }, self.group);
+        let (up_count, total) = (liveness.up.values().filter(|up| **up).count(), liveness.up.len());
+        let quorum = up_count >= self.quorum.unwrap_or_else(|| majority(total));
+        if quorum != liveness.quorum {
+            liveness.quorum = quorum;
+            eprintln!(
+                "Quorum {} in group {}: {} of {} acceptors up",
+                if quorum { "regained" } else { "lost" }, self.group, up_count, total
+            );
+        }
+    }
+
+    /// The acceptors of `addrs` that answered their last ping. Ones never seen before,
+    /// e.g. added by a reconfiguration, count as up and are pinged from now on.
+    fn up(self: &Arc<Self>, addrs: &[String]) -> Vec<String> {
+        for addr in addrs {
+            self.watch(addr);
+        }
+        let liveness = self.liveness.lock().unwrap();
+        addrs.iter().filter(|addr| liveness.up.get(*addr) != Some(&false)).cloned().collect()
+    }
+}
+
+/// --in-process: the Nodes of every node run in this process, by address. MemoryTransport
+/// and notify_learners hand messages to them in place of TCP.
+#[derive(Default)]
+struct Network {
+    nodes: Mutex<HashMap<String, Arc<Node>>>,
+}
+
+impl Network {
+    /// Hands `line` from `from` to the node at `addr` as if it came on a connection of its
+    /// own, and returns what the node wrote back, empty if nothing. None if no node has
+    /// that address. The node handles the line on the caller's thread.
+    fn deliver(&self, addr: &str, line: &str, from: &str) -> Option<String> {
+        let node = self.nodes.lock().unwrap().get(addr).cloned()?;
+        let mut reply = Vec::new();
+        handle_connection(io::Cursor::new(format!("{}\n", line)), &mut reply, &node, from);
+        Some(String::from_utf8_lossy(&reply).trim_end().to_string())
+    }
+}
+
+/// A proposer's transport with --in-process: each message is handled by the acceptor's
+/// Node right away, on the phase thread that sends it. Nothing is lost or slow unless a
+/// FaultyTransport around it makes it so.
+struct MemoryTransport {
+    network: Arc<Network>,
+    from: String, // the proposer's address, as the acceptors log it
+    last_message_id: AtomicU64,
+    events: Option<Arc<EventLog>>, // --log
+}
+
+impl MemoryTransport {
+    fn new(network: Arc<Network>, from: String, events: Option<Arc<EventLog>>) -> Self {
+        MemoryTransport { network, from, last_message_id: AtomicU64::new(0), events }
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn send_and_receive(&self, addr: &str, msg: &PaxosMessage) -> Result<PaxosMessage, SendError> {
+        let msg = PaxosMessage { message_id: self.last_message_id.fetch_add(1, Ordering::Relaxed) + 1, ..msg.clone() };
+        let msg_json = serde_json::to_string(&msg).map_err(|e| SendError::Malformed(e.to_string()))?;
+        eprintln!("{}", msg_json);
+        log_event(self.events.as_deref(), "send", addr, &msg_json);
+        let reply_str = match self.network.deliver(addr, &msg_json, &self.from) {
+            Some(reply_str) if !reply_str.is_empty() => reply_str,
+            Some(_) => return Err(SendError::Unreachable(io::Error::new(io::ErrorKind::UnexpectedEof, "no reply"))),
+            None => return Err(SendError::Unreachable(io::Error::new(io::ErrorKind::NotFound, "no node with this address"))),
+        };
+        eprintln!("{}", reply_str);
+        log_event(self.events.as_deref(), "recv", addr, &reply_str);
+        serde_json::from_str(&reply_str).map_err(|_| SendError::Malformed(reply_str))
+    }
+}
+
+/// Randomized exponential wait after the failed `attempt` (0 for the first) so dueling
+/// proposers stop preempting each other. The cap starts at --backoff-base-ms and doubles
+/// per failed attempt up to --backoff-max-ms, and the wait is a random point in its upper
+/// half. A proposer that saw a higher proposal (`yielding_to`) doubles it once more so
+/// the other proposer gets a chance to finish.
+fn backoff(config: &Config, rng: &mut XorShift, attempt: u64, yielding_to: Option<u64>) {
+    let doublings = (attempt + yielding_to.map_or(0, |_| 1)).min(16) as u32;
+    let cap = (config.backoff_base.as_millis() as u64 * 2u64.pow(doublings)).min(config.backoff_max.as_millis() as u64);
+    let wait = Duration::from_millis(cap / 2 + rng.next_below(cap - cap / 2 + 1));
+    match yielding_to {
+        Some(p) => eprintln!("Retrying in {} ms (cap {} ms, yielding to proposal {})", wait.as_millis(), cap, p),
+        None => eprintln!("Retrying in {} ms (cap {} ms)", wait.as_millis(), cap),
+    }
+    thread::sleep(wait);
+}
+
+/// Round to retry in after a failed `round`: the next one, or further ahead if a rejection
+/// reported a promise that the next round's ballot would still not exceed
+fn next_round(round: u64, promised: Option<Ballot>, my_id: u32, total_peers: u32) -> u64 {
+    let mut next = no_overflow(round.checked_add(1), format_args!("the round after {}", round));
+    if let Some(promised) = promised {
+        // First round whose ballot is above the promise: the promise's own round if this
+        // proposer's id breaks the tie in its favour, otherwise the one after
+        let above = if my_id > promised.proposer {
+            promised.round
+        } else {
+            no_overflow(promised.round.checked_add(1), format_args!("the round after {}", promised.round))
+        };
+        if above > next {
+            eprintln!("Jumping to round {} above promised proposal {}", above, promised.number(total_peers));
+            next = above;
+        }
+    }
+    next
+}
+
+/// Smallest number of acceptors that is a strict majority
+fn majority(acceptors: usize) -> usize {
+    acceptors / 2 + 1
+}
+
+/// Acceptors a proposer needs in each phase: --quorum, or a majority of `acceptors`
+fn quorum_size(config: &Config, acceptors: usize) -> usize {
+    config.quorum.unwrap_or_else(|| majority(acceptors))
+}
+
+/// Reads the hostsfile and finds this node in it by its host name and `port`
+fn parse_hostfile(hostsfile: &str, port: u16) -> Result<Hosts, String> {
+    let name = hostname::get()
+        .map_err(|e| format!("Failed to get host name: {}", e))?
+        .into_string()
+        .unwrap_or_else(|_| "unknown".to_string());
+    let content = fs::read_to_string(hostsfile).map_err(|e| format!("Error reading {}: {}", hostsfile, e))?;
+    parse_hosts(&content, &name, port).map_err(|e| format!("{}: {}", hostsfile, e))
+}
+
+/// Parses hostsfile `content` for the node `name` listening on `port` and returns its info,
+/// its roles with the peers each role deals with, the number of peers listed, how many
+/// proposers send to the groups this peer accepts for, and the learners of each group this
+/// peer learns for, and the proposers of each group this peer proposes in. Learners and
+/// proposers are listed in hostsfile order, so the first learner is the group's
+/// distinguished learner and the first proposer starts out active with --failover-ms.
+/// Peers are given as `<name>:<port>` addresses. A line may give its port after the name,
+/// e.g. `peer2:9000:acceptor1`, otherwise it uses `port`. This peer's line is the first
+/// with its name and `port`, so several peers can share one host. Its id is its line
+/// number, counting non-empty lines only.
+fn parse_hosts(content: &str, name: &str, port: u16) -> Result<Hosts, String> {
+    let entries = content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(n, line)| parse_entry(line.trim(), port).map_err(|e| format!("line {}: {}", n + 1, e)))
+        .collect::<Result<Vec<HostEntry>, String>>()?;
+    let index = entries
+        .iter()
+        .position(|entry| entry.name == name && entry.port == port)
+        .ok_or_else(|| format!("{} is not listed with port {}", name, port))?;
+    let me = &entries[index];
+    let my_info = UserInfo { name: name.to_string(), id: index as u32 + 1 };
+    let nums_of = |role: Role| -> Vec<u32> {
+        me.roles.iter().filter(|(r, _)| *r == role).map(|(_, num)| *num).collect()
+    };
+    let (proposer_nums, acceptor_nums, learner_nums) =
+        (nums_of(Role::Proposer), nums_of(Role::Acceptor), nums_of(Role::Learner));
+
+    // Proposers send to the acceptors of their group, acceptors report to the learners of
+    // theirs, and learners count the acceptors of their group towards a majority. A node
+    // can hold several roles and may be one of its own peers, e.g. a proposer that is also
+    // one of the acceptors it proposes to. Every role keeps its groups apart.
+    let groups_of = |nums: &[u32], peer_role: Role| -> Groups {
+        nums.iter().map(|num| (*num, peers_with_role(&entries, peer_role, *num))).collect()
+    };
+    let learners_of = |nums: &[u32]| -> Groups {
+        nums.iter().map(|num| (*num, peers_in_file_order(&entries, Role::Learner, *num))).collect()
+    };
+    let mut roles: HashMap<Role, Groups> = HashMap::new();
+    if !proposer_nums.is_empty() {
+        roles.insert(Role::Proposer, groups_of(&proposer_nums, Role::Acceptor));
+    }
+    if !acceptor_nums.is_empty() {
+        roles.insert(Role::Acceptor, learners_of(&acceptor_nums));
+    }
+    if !learner_nums.is_empty() {
+        roles.insert(Role::Learner, groups_of(&learner_nums, Role::Acceptor));
+    }
+
+    let proposers =
+        acceptor_nums.iter().map(|num| (*num, peers_in_file_order(&entries, Role::Proposer, *num).len())).collect();
+
+    let fellow_proposers =
+        proposer_nums.iter().map(|num| (*num, peers_in_file_order(&entries, Role::Proposer, *num))).collect();
+
+    Ok((my_info, roles, entries.len() as u32, proposers, learners_of(&learner_nums), fellow_proposers))
+}
+
+/// Sorted addresses of the peers holding `role` in group `num`
+fn peers_with_role(entries: &[HostEntry], role: Role, num: u32) -> Vec<String> {
+    let mut peers = peers_in_file_order(entries, role, num);
+    peers.sort();
+    peers
+}
+
+/// Addresses of the peers holding `role` in group `num`, in the order they appear in the
+/// hostsfile
+fn peers_in_file_order(entries: &[HostEntry], role: Role, num: u32) -> Vec<String> {
+    entries
+        .iter()
+        .filter(|entry| entry.roles.contains(&(role, num)))
+        .map(|entry| format!("{}:{}", entry.name, entry.port))
+        .collect()
+}
+
+/// Parses a hostsfile line `<name>[:<port>]:<roles>`, taking `default_port` if the line
+/// has no port. Roles are comma-separated `proposer<n>`, `acceptor<n>` or `learner<n>`.
+fn parse_entry(line: &str, default_port: u16) -> Result<HostEntry<'_>, String> {
+    let mut parts = line.splitn(3, ':');
+    let name = parts.next().unwrap_or_default().trim();
+    let (port, roles_str) = match (parts.next(), parts.next()) {
+        (Some(roles_str), None) => (default_port, roles_str),
+        (Some(port), Some(roles_str)) => match port.trim().parse() {
+            Ok(port) if port > 0 => (port, roles_str),
+            _ => return Err(format!("Invalid port {} for {}", port, name)),
+        },
+        _ => return Err(format!("Expected <name>[:<port>]:<roles>, got {}", line)),
+    };
+    if name.is_empty() {
+        return Err(format!("Missing host name in {}", line));
+    }
+    let roles = roles_str.split(',').map(|role| parse_role(role.trim())).collect::<Result<_, _>>()?;
+    Ok(HostEntry { name, port, roles })
+}
+
+/// Parses a role with its group number, e.g. `acceptor2`
+fn parse_role(role: &str) -> Result<(Role, u32), String> {
+    let (kind, num) = if let Some(num) = role.strip_prefix("proposer") {
+        (Role::Proposer, num)
+    } else if let Some(num) = role.strip_prefix("acceptor") {
+        (Role::Acceptor, num)
+    } else if let Some(num) = role.strip_prefix("learner") {
+        (Role::Learner, num)
+    } else {
+        return Err(format!("Unknown role {:?}, expected proposer<n>, acceptor<n> or learner<n>", role));
+    };
+    match num.parse() {
+        Ok(num) => Ok((kind, num)),
+        Err(_) => Err(format!("Group of role {} is not a number", role)),
+    }
+}
+
+/// Serves one connection until the peer closes it. Every line is a JSON message: learn,
+/// forwarded accept_ack and decided messages go to the learner, everything else to the
+/// acceptor, which replies on the same connection. On a node with several roles both
+/// arrive on the same port. Lines that are not valid messages, unknown message types and
+/// messages for groups this node doesn't accept for get an error reply, and the
+/// connection stays open for the next line.
+fn handle_client(stream: TcpStream, node: &Node) {
+    let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+    let writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(e) => {
+            eprintln!("Failed to set up connection: {}", e);
+            return;
+        }
+    };
+    handle_connection(BufReader::new(stream), writer, node, &peer);
+}
+
+/// handle_client on any connection: reads lines from `reader` until it runs out and writes
+/// the replies to `writer`. `peer` is who is on the other end, for the event log.
+fn handle_connection(mut reader: impl BufRead, mut writer: impl Write, node: &Node, peer: &str) {
+    let mut buf = Vec::new();
+    loop {
+        // Read raw bytes so a line that isn't UTF-8 is answered like any other bad line
+        buf.clear();
+        match reader.read_until(b'\n', &mut buf) {
+            Ok(0) => return,
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("Connection lost: {}", e);
+                return;
+            }
+        }
+        let line = String::from_utf8_lossy(&buf);
+        let line = line.trim_end();
+        if line.is_empty() {
+            continue;
+        }
+
+        let msg = match serde_json::from_str::<PaxosMessage>(line) {
+            // Pings come all the time from a passive proposer, and queries from a polling
+            // learner. Neither is logged or keeps the node busy, and nor is a status query.
+            Ok(msg) if matches!(msg.message_type.as_str(), "ping" | "query_accepted" | "status") => {
+                let reply = match msg.message_type.as_str() {
+                    "ping" => pong_message(&msg, node),
+                    "status" => status_message(node),
+                    _ => {
+                        *node.last_query.lock().unwrap() = Some(Instant::now());
+                        handle_query(&msg, node)
+                    }
+                };
+                if writeln!(writer, "{}", json_line(&reply)).is_err() {
+                    return;
+                }
+                continue;
+            }
+            Ok(msg) => {
+                eprintln!("{}", line);
+                log_event(node.events.as_deref(), "recv", peer, line);
+                *node.last_message.lock().unwrap() = Instant::now();
+                msg
+            }
+            Err(e) => {
+                eprintln!("{}", line);
+                log_event(node.events.as_deref(), "recv", peer, line);
+                *node.last_message.lock().unwrap() = Instant::now();
+                eprintln!("Malformed message {:?}: {}", line, e);
+                if !send_reply(&mut writer, &error_message(node.id, format!("malformed message: {}", e), None), node, peer) {
+                    return;
+                }
+                continue;
+            }
+        };
+        if matches!(msg.message_type.as_str(), "learn" | "accept_ack" | "decided") {
+            match node.learner {
+                Some(_) if node.pull_only => eprintln!("Ignoring {} message, --pull-only", msg.message_type),
+                Some(ref learner) => handle_learn(msg, node, learner),
+                None => eprintln!("Ignoring {} message, this node is not a learner", msg.message_type),
+            }
+            continue;
+        }
+        let received = Instant::now();
+        let learners = node.learners.as_ref().and_then(|groups| groups.get(&msg.group));
+        let (reply, repeated) = match (msg.message_type.as_str(), learners) {
+            (_, None) => {
+                let reason = format!("not an acceptor of group {}", msg.group);
+                (error_message(node.id, reason, Some(&msg)), false)
+            }
+            ("chosen", Some(_)) => handle_once(msg, node, handle_chosen),
+            ("state", Some(_)) => (handle_state(msg, node), false),
+            ("prepare" | "accept", Some(_)) => handle_once(msg, node, handle_acceptor),
+            (other, Some(_)) => {
+                let reason = format!("unknown message_type {:?}", other);
+                (error_message(node.id, reason, Some(&msg)), false)
+            }
+        };
+        if reply.message_type == "error" {
+            eprintln!("Rejecting message: {}", reply.message_value);
+        }
+        if !send_reply(&mut writer, &reply, node, peer) {
+            return;
+        }
+        if node.metrics {
+            eprintln!("Replied {} after {} ms", reply.message_type, millis(received.elapsed()));
+        }
+        // A repeated message changed nothing, so there is nothing new to crash after or to
+        // tell the learners
+        if repeated {
+            continue;
+        }
+        // --crash-after: die as if killed right after the reply went out, before telling any learner
+        if node.crash_after.as_deref() == Some(reply.message_type.as_str()) {
+            eprintln!("Crashing after sending {} for proposal {} in {} (--crash-after)", reply.message_type, reply.proposal_num, reply.instance());
+            process::exit(1);
+        }
+
+        // Tell the learners what was just accepted. A distinguished learner gets the
+        // accept_ack itself and passes the decision on to the others.
+        if let (Some(learners), "accept_ack") = (learners, reply.message_type.as_str()) {
+            if node.distinguished_learner {
+                notify_learners(node, &learners[..learners.len().min(1)], &reply);
+            } else {
+                notify_learners(node, learners, &learn_message(node.id, &reply));
+            }
+        }
+    }
+}
+
+/// Runs `handle` on a message unless the acceptor already handled it or a later message
+/// from the same proposer. A proposer that times out waiting for a reply sends the message
+/// again with the same id, and the first copy may have got through, so the acceptor keeps
+/// its last reply to each proposer of each group and sends it again for a repeat instead of
+/// handling the message twice. An older message than the last one gets an error reply; its
+/// sender has moved on and isn't waiting for it. Messages without an id are always handled.
+/// Returns the reply and whether the message was a repeat or an older one.
+fn handle_once(msg: PaxosMessage, node: &Node, handle: fn(PaxosMessage, &Node) -> PaxosMessage) -> (PaxosMessage, bool) {
+    if msg.message_id == 0 {
+        return (handle(msg, node), false);
+    }
+    let last_reply = Arc::clone(node.last_replies.lock().unwrap().entry((msg.peer_id, msg.group)).or_default());
+    // Held while handling, so a repeat arriving meanwhile waits for the first copy's reply
+    let mut last_reply = last_reply.lock().unwrap();
+    match &*last_reply {
+        Some((id, reply)) if *id == msg.message_id => {
+            eprintln!("Message {} from peer {} was already handled, sending the same reply again", id, msg.peer_id);
+            return (reply.clone(), true);
+        }
+        Some((id, _)) if *id > msg.message_id => {
+            let reason = format!("message {} is older than message {} already handled", msg.message_id, id);
+            return (error_message(node.id, reason, Some(&msg)), true);
+        }
+        _ => {}
+    }
+    let id = msg.message_id;
+    let reply = handle(msg, node);
+    *last_reply = Some((id, reply.clone()));
+    (reply, false)
+}
+
+/// The answer to a ping: whether this node is the active proposer of the ping's group
+fn pong_message(ping: &PaxosMessage, node: &Node) -> PaxosMessage {
+    let active = node.active_proposers.lock().unwrap().contains(&ping.group);
+    PaxosMessage {
+        peer_id: node.id,
+        action: "sent".to_string(),
+        message_type: "pong".to_string(),
+        message_value: if active { "active" } else { "passive" }.to_string(),
+        group: ping.group,
+        ..Default::default()
+    }
+}
+
+/// Answers a polling learner's query_accepted with every slot of the group this acceptor
+/// has accepted a value in, as a JSON array of SlotValue in the message_value
+fn handle_query(msg: &PaxosMessage, node: &Node) -> PaxosMessage {
+    if !node.learners.as_ref().is_some_and(|groups| groups.contains_key(&msg.group)) {
+        return error_message(node.id, format!("not an acceptor of group {}", msg.group), Some(msg));
+    }
+    let accepted: Vec<SlotValue> = node
+        .state
+        .lock()
+        .unwrap()
+        .get(&None)
+        .into_iter()
+        .flat_map(|log| log.range(Instance { group: msg.group, slot: 0 }..=Instance { group: msg.group, slot: u64::MAX }))
+        .filter_map(|(instance, s)| {
+            Some(SlotValue { slot: instance.slot, ballot: s.accepted_proposal?, value: s.accepted_value.clone()? })
+        })
+        .collect();
+    PaxosMessage {
+        peer_id: node.id,
+        action: "sent".to_string(),
+        message_type: "accepted_state".to_string(),
+        message_value: serde_json::to_string(&accepted).unwrap_or_default(),
+        group: msg.group,
+        ..Default::default()
+    }
+}
+
+/// Answers a status query with everything this node knows about every slot, as a JSON
+/// object in the message_value: {"node_id":..,"uptime_ms":..,"slots":[SlotStatus, ..]}.
+/// It is built under the state lock, so it is one consistent view, and changes nothing.
+fn status_message(node: &Node) -> PaxosMessage {
+    let slots: Vec<SlotStatus> = {
+        let state = node.state.lock().unwrap();
+        state
+            .iter()
+            .flat_map(|(key, log)| {
+                log.iter().map(move |(instance, s)| SlotStatus {
+                    group: instance.group,
+                    slot: instance.slot,
+                    key: key.clone(),
+                    promised: s.promised_proposal.max(onward_promise(log, *instance)).number(node.total_peers),
+                    accepted_proposal: s.accepted_proposal.map(|ballot| ballot.number(node.total_peers)),
+                    accepted_value: s.accepted_value.clone(),
+                    decided_value: s.decided.as_ref().map(|(_, value)| value.clone()),
+                })
+            })
+            .collect()
+    };
+    let status = serde_json::json!({
+        "node_id": node.id,
+        "uptime_ms": node.started.elapsed().as_millis(),
+        "slots": slots,
+    });
+    PaxosMessage {
+        peer_id: node.id,
+        action: "sent".to_string(),
+        message_type: "status_reply".to_string(),
+        message_value: status.to_string(),
+        ..Default::default()
+    }
+}
+
+/// Writes `reply` to `peer` as one line and logs it. Returns false once the connection is gone
+fn send_reply(writer: &mut impl Write, reply: &PaxosMessage, node: &Node, peer: &str) -> bool {
+    let reply_str = match serde_json::to_string(reply) {
+        Ok(reply_str) => reply_str,
+        Err(e) => {
+            eprintln!("Failed to encode {} reply: {}", reply.message_type, e);
+            return true;
+        }
+    };
+    if let Err(e) = writeln!(writer, "{}", reply_str) {
+        eprintln!("Failed to reply: {}", e);
+        return false;
+    }
+    eprintln!("{}", reply_str);
+    log_event(node.events.as_deref(), "send", peer, &reply_str);
+    true
+}
+
+/// The error reply for a message the node can't handle, with the reason as the value.
+/// It echoes the proposal, group and slot of `about` when the message could be parsed.
+fn error_message(my_id: u32, reason: String, about: Option<&PaxosMessage>) -> PaxosMessage {
+    PaxosMessage {
+        peer_id: my_id,
+        action: "sent".to_string(),
+        message_type: "error".to_string(),
+        message_value: reason,
+        proposal_num: about.map_or(0, |msg| msg.proposal_num),
+        ballot: about.and_then(|msg| msg.ballot),
+        group: about.map_or(0, |msg| msg.group),
+        slot: about.map_or(0, |msg| msg.slot),
+        key: about.and_then(|msg| msg.key.clone()),
+        ..Default::default()
+    }
+}
+
+impl EventLog {
+    /// Opens --log for appending, so a restarted node adds to the events of its earlier run
+    fn open(path: &str, node_id: u32) -> EventLog {
+        match fs::OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => EventLog { node_id, file: Mutex::new(file) },
+            Err(e) => {
+                eprintln!("init error: Failed to open {}: {}", path, e);
+                process::exit(1);
+            }
+        }
+    }
+}
+
+/// With --log, appends one event for `line`, a message sent to or received from `peer`.
+/// `dir` is "send" or "recv". A failed write is reported but doesn't stop the node.
+fn log_event(events: Option<&EventLog>, dir: &str, peer: &str, line: &str) {
+    let Some(events) = events else { return };
+    let msg = serde_json::from_str(line).unwrap_or_else(|_| serde_json::Value::String(line.to_string()));
+    let ts = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_micros() as u64);
+    let event = serde_json::json!({ "ts": ts, "node_id": events.node_id, "dir": dir, "peer": peer, "msg": msg });
+    if let Err(e) = writeln!(events.file.lock().unwrap(), "{}", event) {
+        eprintln!("Failed to write the event log: {}", e);
+    }
+}
+
+/// The JSON line printed for `msg`. Encoding a PaxosMessage doesn't fail in practice, but
+/// if it ever did the log says so instead of the thread panicking
+fn json_line(msg: &PaxosMessage) -> String {
+    serde_json::to_string(msg).unwrap_or_else(|e| format!("Failed to encode {} message: {}", msg.message_type, e))
+}
+
+/// Notes a proposer's chosen announcement and acknowledges it
+fn handle_chosen(msg: PaxosMessage, node: &Node) -> PaxosMessage {
+    eprintln!("Proposer {} chose {} in {}", msg.peer_id, msg.message_value, slot_name(msg.key.as_deref(), msg.instance()));
+    if msg.last {
+        node.finished_proposers.lock().unwrap().insert((msg.group, msg.peer_id));
+    }
+    node.state.lock().unwrap().entry(msg.key.clone()).or_default().entry(msg.instance()).or_default().decided =
+        Some((msg.ballot(node.total_peers), msg.message_value.clone()));
+    if let (Some(source), Some(acceptors)) = (&node.join, parse_reconfiguration(&msg.message_value, node.port).filter(|_| msg.key.is_none())) {
+        if acceptors.contains(&node.address) {
+            copy_decided_log(node, msg.group, source);
+        }
+    }
+    PaxosMessage {
+        peer_id: node.id,
+        action: "sent".to_string(),
+        message_type: "chosen_ack".to_string(),
+        message_value: msg.message_value,
+        proposal_num: msg.proposal_num,
+        ballot: msg.ballot,
+        group: msg.group,
+        slot: msg.slot,
+        key: msg.key.clone(),
+        ..Default::default()
+    }
+}
+
+/// Answers a joining acceptor's state request with every slot of the group this acceptor
+/// knows was decided, as a JSON array of SlotValue in the message_value
+fn handle_state(msg: PaxosMessage, node: &Node) -> PaxosMessage {
+    let decided: Vec<SlotValue> = node
+        .state
+        .lock()
+        .unwrap()
+        .get(&None)
+        .into_iter()
+        .flat_map(|log| log.range(Instance { group: msg.group, slot: 0 }..=Instance { group: msg.group, slot: u64::MAX }))
+        .filter_map(|(instance, s)| {
+            let (ballot, value) = s.decided.clone()?;
+            Some(SlotValue { slot: instance.slot, ballot, value })
+        })
+        .collect();
+    PaxosMessage {
+        peer_id: node.id,
+        action: "sent".to_string(),
+        message_type: "state_reply".to_string(),
+        message_value: serde_json::to_string(&decided).unwrap_or_default(),
+        group: msg.group,
+        ..Default::default()
+    }
+}
+
+/// --join: once a reconfiguration added this acceptor to `group`, copies the slots `source`
+/// knows were decided into its state, as if it had accepted their values. It takes part in
+/// later slots only, so the copied slots just complete its log.
+fn copy_decided_log(node: &Node, group: u32, source: &str) {
+    let request = PaxosMessage {
+        peer_id: node.id,
+        action: "sent".to_string(),
+        message_type: "state".to_string(),
+        group,
+        ..Default::default()
+    };
+    let transport = TcpTransport::new(DEFAULT_TIMEOUT, DEFAULT_TIMEOUT, DEFAULT_RETRIES, false, node.events.clone());
+    let decided: Vec<SlotValue> = match transport.send_and_receive(source, &request) {
+        Ok(reply) if reply.message_type == "state_reply" => match serde_json::from_str(&reply.message_value) {
+            Ok(decided) => decided,
+            Err(e) => {
+                eprintln!("Malformed state from {}: {}", source, e);
+                return;
+            }
+        },
+        Ok(reply) => {
+            eprintln!("Unexpected {} reply from {} to the state request: {}", reply.message_type, source, reply.message_value);
+            return;
+        }
+        Err(e) => {
+            eprintln!("No state from {}: {}", source, e);
+            return;
+        }
+    };
+    let mut logs = node.state.lock().unwrap();
+    let log = logs.entry(None).or_default();
+    for entry in &decided {
+        let s = log.entry(Instance { group, slot: entry.slot }).or_default();
+        if s.decided.is_none() {
+            s.decided = Some((entry.ballot, entry.value.clone()));
+        }
+        if s.accepted_proposal.map_or(true, |ballot| ballot < entry.ballot) {
+            s.promised_proposal = s.promised_proposal.max(entry.ballot);
+            s.accepted_proposal = Some(entry.ballot);
+            s.accepted_value = Some(entry.value.clone());
+        }
+    }
+    let slots: Vec<String> = decided.iter().map(|entry| entry.slot.to_string()).collect();
+    eprintln!("Joined group {}: copied decided slots [{}] from {}", group, slots.join(", "), source);
+}
+
+/// Applies a prepare or accept to this acceptor's state and builds the reply
+fn handle_acceptor(msg: PaxosMessage, node: &Node) -> PaxosMessage {
+    // --reply-delay-ms: act like a slow (or, with a long delay, silent) acceptor
+    if !node.reply_delay.is_zero() {
+        eprintln!("Holding {} for {} ms", msg.message_type, node.reply_delay.as_millis());
+        thread::sleep(node.reply_delay);
+    }
+    let my_id = node.id;
+    let instance = msg.instance();
+    let ballot = msg.ballot(node.total_peers);
+    // The decision, the state change and the reply all come from one hold of the lock, so
+    // a concurrent prepare or accept for the same slot can't change the state in between
+    // and make the reply report a value its reply type didn't decide on
+    let mut logs = node.state.lock().unwrap();
+    let log = logs.entry(msg.key.clone()).or_default();
+    let onward = onward_promise(log, instance);
+    let accepted_later = log
+        .range(Instance { group: instance.group, slot: instance.slot + 1 }..=Instance { group: instance.group, slot: u64::MAX })
+        .any(|(_, s)| s.accepted_value.is_some());
+    let s = log.entry(instance).or_default();
+    s.promised_proposal = s.promised_proposal.max(onward);
+    // A ballot equal to the promise can only come from the proposer it was made to, so
+    // `>=` lets that proposer send its accept after its own prepare, or the same message
+    // again, while any other proposer needs a strictly higher ballot
+    let reply_type = if msg.message_type == "prepare" {
+        if ballot >= s.promised_proposal {
+            s.promised_proposal = ballot;
+            s.promised_onward = ballot;
+            "prepare_ack"
+        } else {
+            "reject_prepare"
+        }
+    } else {
+        // handle_client only passes prepare and accept messages here
+        if ballot >= s.promised_proposal {
+            s.promised_proposal = ballot;
+            s.accepted_proposal = Some(ballot);
+            s.accepted_value = Some(msg.message_value.clone());
+            "accept_ack"
+        } else {
+            "reject_accept"
+        }
+    };
+    if let Some(ref val) = s.accepted_value {
+        eprintln!("State updated: {} accepted_value = {}", slot_name(msg.key.as_deref(), instance), val);
+    }
+
+    let reply_value = if let Some(ref val) = s.accepted_value {
+        val.clone()
+    } else if msg.message_type == "prepare" {
+        msg.message_value.clone()
+    } else {
+        "".to_string()
+    };
+    // A promise reports what was accepted before it, so the proposer can adopt it.
+    // A rejection reports the promise it lost to, so the proposer can outbid it.
+    // It also says whether later slots hold accepted values, since the promise covers them.
+    let (accepted_proposal, accepted_value, accepted_later, promised) = match reply_type {
+        "prepare_ack" => (s.accepted_proposal, s.accepted_value.clone(), accepted_later, None),
+        "reject_prepare" | "reject_accept" => (None, None, false, Some(s.promised_proposal)),
+        _ => (None, None, false, None),
+    };
+    drop(logs);
+
+    PaxosMessage {
+        peer_id: my_id,
+        action: "sent".to_string(),
+        message_type: reply_type.to_string(),
+        message_value: reply_value,
+        proposal_num: msg.proposal_num,
+        ballot: msg.ballot,
+        group: msg.group,
+        slot: msg.slot,
+        key: msg.key.clone(),
+        accepted_proposal,
+        accepted_value,
+        accepted_later,
+        promised,
+        ..Default::default()
+    }
+}
+
+/// The learn message an acceptor sends for its accept_ack
+fn learn_message(my_id: u32, ack: &PaxosMessage) -> PaxosMessage {
+    PaxosMessage {
+        peer_id: my_id,
+        action: "sent".to_string(),
+        message_type: "learn".to_string(),
+        message_value: ack.message_value.clone(),
+        proposal_num: ack.proposal_num,
+        ballot: ack.ballot,
+        group: ack.group,
+        slot: ack.slot,
+        key: ack.key.clone(),
+        ..Default::default()
+    }
+}
+
+/// Highest promise a prepare for `instance` or an earlier slot of its group made for all
+/// later slots. Slots the acceptor hasn't seen yet start out promised to it.
+fn onward_promise(log: &PaxosLog, instance: Instance) -> Ballot {
+    log.range(Instance { group: instance.group, slot: 0 }..=instance).map(|(_, s)| s.promised_onward).max().unwrap_or_default()
+}
+
+/// Sends `msg` to every learner, over one connection per learner that stays open for
+/// later slots and rounds
+fn notify_learners(node: &Node, learners: &[String], msg: &PaxosMessage) {
+    let learn_json = match serde_json::to_string(msg) {
+        Ok(learn_json) => learn_json,
+        Err(e) => {
+            eprintln!("Failed to encode {} message: {}", msg.message_type, e);
+            return;
+        }
+    };
+    if let Some(ref network) = node.network {
+        for addr in learners {
+            eprintln!("{}", learn_json);
+            log_event(node.events.as_deref(), "send", addr, &learn_json);
+            if network.deliver(addr, &learn_json, &node.address).is_none() {
+                eprintln!("Failed to send to learner {}: no node with this address", addr);
+            }
+        }
+        return;
+    }
+    let mut links = node.learner_links.lock().unwrap();
+    for addr in learners {
+        let addr = addr.clone();
+        // An old connection may have been closed by the learner; reconnect once
+        if let Some(mut stream) = links.remove(&addr) {
+            if writeln!(stream, "{}", learn_json).is_ok() {
+                eprintln!("{}", learn_json);
+                log_event(node.events.as_deref(), "send", &addr, &learn_json);
+                links.insert(addr, stream);
+                continue;
+            }
+        }
+        match TcpStream::connect(&addr) {
+            Ok(mut stream) => match writeln!(stream, "{}", learn_json) {
+                Ok(()) => {
+                    eprintln!("{}", learn_json);
+                    log_event(node.events.as_deref(), "send", &addr, &learn_json);
+                    links.insert(addr, stream);
+                }
+                Err(e) => eprintln!("Failed to send to learner {}: {}", addr, e),
+            },
+            Err(e) => {
+                eprintln!("Failed to connect to learner {}: {}", addr, e);
+            }
+        }
+    }
+}
+
+/// --poll-ms: every `interval`, asks each acceptor of the learner's groups for what it has
+/// accepted and counts every slot of the answer like a learn message from that acceptor.
+/// A learner that started late or missed learn messages still learns every slot a
+/// majority accepted. Acceptors named by a decided reconfiguration are asked too.
+fn poll_acceptors(node: &Node, interval: Duration) {
+    let Some(ref learner) = node.learner else { return };
+    loop {
+        thread::sleep(interval);
+        let targets: Vec<(u32, String)> = {
+            let learner = learner.lock().unwrap();
+            let mut targets: Vec<(u32, String)> =
+                learner.acceptors.iter().flat_map(|(group, addrs)| addrs.iter().map(|addr| (*group, addr.clone()))).collect();
+            for (instance, addrs) in &learner.reconfigured {
+                for addr in addrs {
+                    if !targets.contains(&(instance.group, addr.clone())) {
+                        targets.push((instance.group, addr.clone()));
+                    }
+                }
+            }
+            targets
+        };
+        let learned_before = learner.lock().unwrap().learned.len();
+        for (group, addr) in targets {
+            let query = PaxosMessage {
+                peer_id: node.id,
+                action: "sent".to_string(),
+                message_type: "query_accepted".to_string(),
+                group,
+                ..Default::default()
+            };
+            // An acceptor that is down is asked again next time
+            let Some(reply) = exchange_quietly(&addr, &query, interval) else { continue };
+            if reply.message_type != "accepted_state" {
+                eprintln!("Unexpected {} reply from {} to query_accepted: {}", reply.message_type, addr, reply.message_value);
+                continue;
+            }
+            let accepted: Vec<SlotValue> = match serde_json::from_str(&reply.message_value) {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    eprintln!("Malformed accepted state from {}: {}", addr, e);
+                    continue;
+                }
+            };
+            for entry in accepted {
+                let learn = PaxosMessage {
+                    peer_id: reply.peer_id,
+                    action: "sent".to_string(),
+                    message_type: "learn".to_string(),
+                    message_value: entry.value,
+                    proposal_num: entry.ballot.number(node.total_peers),
+                    ballot: Some(entry.ballot),
+                    group,
+                    slot: entry.slot,
+                    ..Default::default()
+                };
+                handle_learn(learn, node, learner);
+            }
+        }
+        let learned = learner.lock().unwrap().learned.len() - learned_before;
+        if learned > 0 {
+            eprintln!("Polling the acceptors learned {} more slot{}", learned, if learned == 1 { "" } else { "s" });
+            // Counts as activity, so the learner doesn't stop in the middle of catching up
+            *node.last_message.lock().unwrap() = Instant::now();
+        }
+    }
+}
+
+/// How many acceptors must report a value in `instance` for the learner to learn it: a
+/// majority of the acceptors named by the last reconfiguration learned in an earlier slot
+/// of the group, or else of the group's acceptors in the hostsfile
+fn learner_quorum(learner: &Learner, instance: Instance) -> Option<usize> {
+    let quorum = *learner.quorums.get(&instance.group)?;
+    let reconfigured = learner.reconfigured.range(Instance { group: instance.group, slot: 0 }..instance).next_back();
+    Some(reconfigured.map_or(quorum, |(_, acceptors)| majority(acceptors.len())))
+}
+
+/// Counts a learn message, or an accept_ack forwarded to a distinguished learner, and prints
+/// the learned value of its instance once a majority of the group's acceptors (see
+/// learner_quorum) reported the same (ballot, value) there. A distinguished learner
+/// then sends decided to the other learners of the group, which take it as learned without
+/// counting.
+fn handle_learn(msg: PaxosMessage, node: &Node, learner: &Mutex<Learner>) {
+    let mut learner = learner.lock().unwrap();
+    let instance = msg.instance();
+    let slot = (msg.key.clone(), instance);
+    if learner.learned.contains_key(&slot) {
+        return;
+    }
+    let Some(quorum) = learner_quorum(&learner, instance) else {
+        eprintln!("Ignoring learn message, this node is not a learner of group {}", msg.group);
+        return;
+    };
+    if msg.message_type != "decided" {
+        let report = (msg.key.clone(), instance, (msg.ballot(node.total_peers), msg.message_value.clone()));
+        let acceptors = learner.reports.entry(report).or_default();
+        acceptors.insert(msg.peer_id);
+        if acceptors.len() < quorum {
+            return;
+        }
+    }
+
+    learner.learned.insert(slot, msg.message_value.clone());
+    // Only the plain slots carry reconfigurations
+    let reconfigured = parse_reconfiguration(&msg.message_value, node.port).filter(|_| msg.key.is_none());
+    if let Some(ref acceptors) = reconfigured {
+        learner.reconfigured.insert(instance, acceptors.clone());
+    }
+    let followers = match learner.followers.get(&msg.group) {
+        Some(followers) if msg.message_type != "decided" => followers.clone(),
+        _ => Vec::new(),
+    };
+    drop(learner);
+    let learned_msg = PaxosMessage {
+        peer_id: node.id,
+        action: "learned".to_string(),
+        message_type: "learned".to_string(),
+        message_value: msg.message_value,
+        proposal_num: msg.proposal_num,
+        group: msg.group,
+        slot: msg.slot,
+        key: msg.key.clone(),
+        ..Default::default()
+    };
+    eprintln!("{}", json_line(&learned_msg));
+    if let Some(acceptors) = reconfigured {
+        eprintln!(
+            "Group {} reconfigured in slot {}: learning later slots from {} of {} acceptors",
+            msg.group, msg.slot, majority(acceptors.len()), acceptors.len()
+        );
+    }
+
+    if !followers.is_empty() {
+        let decided_msg = PaxosMessage { action: "sent".to_string(), message_type: "decided".to_string(), ..learned_msg };
+        notify_learners(node, &followers, &decided_msg);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| format!("{}:8888", name)).collect()
+    }
+
+    /// Each line's configuration, parsed hostsfile and Node, if it has one
+    type Nodes = Vec<(Config, Hosts, Option<Arc<Node>>)>;
+
+    /// The nodes of hostsfile `content` on one Network, built as --in-process builds them,
+    /// line n with the flags in flags[n]
+    fn cluster(content: &str, flags: &[&str]) -> (Arc<Network>, Nodes) {
+        let network = Arc::new(Network::default());
+        let mut nodes = Vec::new();
+        for (n, line) in content.lines().enumerate() {
+            let mut args = vec!["-h".to_string(), "hosts".to_string()];
+            args.extend(flags.get(n).copied().unwrap_or_default().split_whitespace().map(String::from));
+            let config = init(args);
+            let name = line.split(':').next().unwrap();
+            let hosts = parse_hosts(content, name, config.port).unwrap();
+            let events = config.log.as_ref().map(|path| Arc::new(EventLog::open(path, hosts.0.id)));
+            let node = build_node(&config, &hosts, events, Arc::default(), Some(Arc::clone(&network)));
+            if let Some(ref node) = node {
+                network.nodes.lock().unwrap().insert(node.address.clone(), Arc::clone(node));
+            }
+            nodes.push((config, hosts, node));
+        }
+        (network, nodes)
+    }
+
+    /// A file under the temp directory for this test process, removed if it is there already
+    fn temp_path(name: &str) -> String {
+        let path = env::temp_dir().join(format!("hw4_test_{}_{}", process::id(), name));
+        let _ = fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    const SLOT: Instance = Instance { group: 1, slot: 0 };
+
+    /// MemoryTransport that records the acceptors a phase gave up waiting for
+    struct Aborting {
+        inner: MemoryTransport,
+        aborted: Mutex<Vec<String>>,
+    }
+
+    impl Transport for Aborting {
+        fn send_and_receive(&self, addr: &str, msg: &PaxosMessage) -> Result<PaxosMessage, SendError> {
+            self.inner.send_and_receive(addr, msg)
+        }
+
+        fn abort(&self, addr: &str) {
+            self.aborted.lock().unwrap().push(addr.to_string());
+        }
+    }
+
+    /// Two proposers and three acceptors, the last of which holds each message `delay_ms`
+    fn two_proposers(delay_ms: u64) -> (Arc<Network>, MemoryTransport, MemoryTransport, Vec<String>) {
+        let content = "peer1:proposer1\npeer2:proposer1\npeer3:acceptor1\npeer4:acceptor1\npeer5:acceptor1\n";
+        let slow = format!("--reply-delay-ms {}", delay_ms);
+        let (network, nodes) = cluster(content, &["", "", "", "", &slow]);
+        let acceptors = nodes[0].1 .1[&Role::Proposer][&1].clone();
+        let first = MemoryTransport::new(Arc::clone(&network), "peer1:8889".to_string(), None);
+        let second = MemoryTransport::new(Arc::clone(&network), "peer2:8889".to_string(), None);
+        (network, first, second, acceptors)
+    }
+
+    fn prepare(transport: &impl Transport, acceptors: &[String], ballot: Ballot, value: &str) -> Prepared {
+        prepare_phase(transport, acceptors, majority(acceptors.len()), value, ballot, ballot.number(5), SLOT, None)
+    }
+
+    fn accept(transport: &impl Transport, acceptors: &[String], ballot: Ballot, value: &str) -> AcceptResult {
+        accept_phase(transport, acceptors, majority(acceptors.len()), value, ballot, ballot.number(5), SLOT, None)
+    }
+
+    #[test]
+    fn phases_reach_a_quorum() {
+        let (_, first, _, acceptors) = two_proposers(0);
+        let ballot = Ballot { round: 1, proposer: 1 };
+        let prepared = prepare(&first, &acceptors, ballot, "X");
+        assert!(!prepared.rejected && !prepared.majority_rejected);
+        assert_eq!(prepared.peers.len(), 3);
+        assert_eq!(prepared.highest_accepted, None);
+
+        let accepted = accept(&first, &prepared.peers, ballot, "X");
+        assert!(!accepted.rejected && !accepted.majority_rejected);
+        assert_eq!(accepted.peers.len(), 3);
+
+        // A later prepare has to carry the accepted value forward
+        let prepared = prepare(&first, &acceptors, Ballot { round: 2, proposer: 1 }, "Y");
+        assert_eq!(prepared.highest_accepted, Some((ballot, "X".to_string())));
+    }
+
+    #[test]
+    fn phases_rejected_by_a_higher_promise() {
+        let (_, first, second, acceptors) = two_proposers(0);
+        let higher = Ballot { round: 3, proposer: 2 };
+        assert_eq!(prepare(&second, &acceptors, higher, "B").peers.len(), 3);
+
+        let prepared = prepare(&first, &acceptors, Ballot { round: 1, proposer: 1 }, "A");
+        assert!(prepared.rejected && prepared.peers.is_empty());
+        // The third reply may not be waited for once two rejected
+        assert!(prepared.responded.len() >= 2);
+        assert_eq!(prepared.highest_promised, Some(higher));
+
+        let accepted = accept(&first, &acceptors, Ballot { round: 2, proposer: 1 }, "A");
+        assert!(accepted.rejected && accepted.peers.is_empty());
+        assert_eq!(accepted.highest_promised, Some(higher));
+    }
+
+    #[test]
+    fn majority_rejection_aborts_the_slow_acceptor() {
+        let (_, first, second, acceptors) = two_proposers(300);
+        prepare(&second, &acceptors, Ballot { round: 3, proposer: 2 }, "B");
+
+        let first = Aborting { inner: first, aborted: Mutex::new(Vec::new()) };
+        let prepared = prepare(&first, &acceptors, Ballot { round: 1, proposer: 1 }, "A");
+        assert!(prepared.majority_rejected);
+        assert_eq!(*first.aborted.lock().unwrap(), vec!["peer5:8889".to_string()]);
+
+        first.aborted.lock().unwrap().clear();
+        let accepted = accept(&first, &acceptors, Ballot { round: 2, proposer: 1 }, "A");
+        assert!(accepted.majority_rejected);
+        assert_eq!(*first.aborted.lock().unwrap(), vec!["peer5:8889".to_string()]);
+    }
+
+    #[test]
+    fn proposer_with_two_acceptor_groups() {
+        let content = "peer1:proposer1,proposer2\npeer2:acceptor1\npeer3:acceptor1,acceptor2\npeer4:acceptor2\n";
+        let (me, roles, total, _, _, fellows) = parse_hosts(content, "peer1", 8888).unwrap();
+        assert_eq!(me.id, 1);
+        assert_eq!(total, 4);
+        let groups = &roles[&Role::Proposer];
+        assert_eq!(groups[&1], addrs(&["peer2", "peer3"]));
+        assert_eq!(groups[&2], addrs(&["peer3", "peer4"]));
+        assert!(!roles.contains_key(&Role::Acceptor) && !roles.contains_key(&Role::Learner));
+        assert_eq!(fellows[&1], addrs(&["peer1"]));
+        assert_eq!(fellows[&2], addrs(&["peer1"]));
+    }
+
+    #[test]
+    fn acceptor_with_several_proposers() {
+        let content = "peer1:proposer1\npeer2:acceptor1\npeer3:learner1\npeer4:proposer1\npeer5:learner1\n";
+        let (me, roles, _, proposers, _, _) = parse_hosts(content, "peer2", 8888).unwrap();
+        assert_eq!(me.id, 2);
+        assert_eq!(proposers[&1], 2);
+        // The learners an acceptor reports to keep hostsfile order, the first is the distinguished one
+        assert_eq!(roles[&Role::Acceptor][&1], addrs(&["peer3", "peer5"]));
+        assert_eq!(roles.len(), 1);
+    }
+
+    #[test]
+    fn learner_only_node() {
+        let content = "peer1:proposer1\npeer2:acceptor1\npeer3:acceptor1\npeer4:learner1\n";
+        let (me, roles, _, proposers, learners, fellows) = parse_hosts(content, "peer4", 8888).unwrap();
+        assert_eq!(me.id, 4);
+        assert_eq!(roles.len(), 1);
+        assert_eq!(roles[&Role::Learner][&1], addrs(&["peer2", "peer3"]));
+        assert_eq!(learners[&1], addrs(&["peer4"]));
+        assert!(proposers.is_empty() && fellows.is_empty());
+    }
+
+    #[test]
+    fn host_not_listed() {
+        let content = "peer1:proposer1\npeer2:acceptor1\n";
+        let err = parse_hosts(content, "peer9", 8888).err().unwrap();
+        assert_eq!(err, "peer9 is not listed with port 8888");
+        // Listed, but with another port
+        let err = parse_hosts("peer1:9000:proposer1\n", "peer1", 8888).err().unwrap();
+        assert_eq!(err, "peer1 is not listed with port 8888");
+    }
+
+    #[test]
+    fn malformed_role_token() {
+        let content = "peer1:proposer1\n\npeer2:acceptr1\n";
+        let err = parse_hosts(content, "peer1", 8888).err().unwrap();
+        assert!(err.starts_with("line 3: Unknown role \"acceptr1\""), "{}", err);
+        let err = parse_hosts("peer1:proposerX\n", "peer1", 8888).err().unwrap();
+        assert_eq!(err, "line 1: Group of role proposerX is not a number");
+    }
+
+    #[test]
+    fn duplicated_accept_is_learned_once() {
+        let log = temp_path("learner.log");
+        let content = "peer1:proposer1\npeer2:acceptor1\npeer3:learner1\n";
+        let (network, nodes) = cluster(content, &["", "", &format!("--log {}", log)]);
+        let transport = MemoryTransport::new(Arc::clone(&network), "peer1:8889".to_string(), None);
+        let ballot = Ballot { round: 1, proposer: 1 };
+        let msg = PaxosMessage {
+            peer_id: 1,
+            action: "sent".to_string(),
+            message_type: "accept".to_string(),
+            message_value: "X".to_string(),
+            proposal_num: ballot.number(3),
+            ballot: Some(ballot),
+            group: 1,
+            ..Default::default()
+        };
+        let reply = transport.send_and_receive("peer2:8889", &msg).unwrap();
+        assert_eq!(reply.message_type, "accept_ack");
+
+        // The same accept again, as a proposer resends it after a lost reply
+        let resent = serde_json::to_string(&PaxosMessage { message_id: 1, ..msg }).unwrap();
+        let reply_again = network.deliver("peer2:8889", &resent, "peer1:8889").unwrap();
+        assert_eq!(reply_again, json_line(&reply));
+
+        let learner = nodes[2].2.as_ref().unwrap().learner.as_ref().unwrap();
+        assert_eq!(learner.lock().unwrap().learned.get(&(None, SLOT)).map(String::as_str), Some("X"));
+        let learns = fs::read_to_string(&log)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .filter(|event| event["dir"] == "recv" && event["msg"]["message_type"] == "learn")
+            .count();
+        assert_eq!(learns, 1);
+    }
+
+    /// A prepare or accept of `ballot` in `slot` of group 1, without a message id
+    fn ballot_message(message_type: &str, ballot: Ballot, slot: u64) -> PaxosMessage {
+        PaxosMessage {
+            peer_id: ballot.proposer,
+            action: "sent".to_string(),
+            message_type: message_type.to_string(),
+            message_value: format!("v{}", ballot.proposer),
+            proposal_num: ballot.number(4),
+            ballot: Some(ballot),
+            group: 1,
+            slot,
+            ..Default::default()
+        }
+    }
+
+    fn acceptor_node() -> Arc<Node> {
+        let content = "peer1:proposer1\npeer2:proposer1\npeer3:proposer1\npeer4:acceptor1\n";
+        let (_, mut nodes) = cluster(content, &[]);
+        nodes.pop().unwrap().2.unwrap()
+    }
+
+    #[test]
+    fn ballots_order_by_round_then_proposer() {
+        let ballots: Vec<Ballot> = (0..5).flat_map(|round| (1..=4).map(move |proposer| Ballot { round, proposer })).collect();
+        for a in &ballots {
+            assert_eq!(Ballot::from_number(a.number(4), 4), *a);
+            for b in &ballots {
+                assert_eq!(a.cmp(b), (a.round, a.proposer).cmp(&(b.round, b.proposer)));
+                assert_eq!(a.cmp(b), a.number(4).cmp(&b.number(4)));
+                assert_eq!(a == b, a.cmp(b) == std::cmp::Ordering::Equal);
+            }
+            assert!(Ballot::default() < *a);
+        }
+    }
+
+    #[test]
+    fn same_ballot_only_from_the_same_proposer() {
+        let node = acceptor_node();
+        let ballot = Ballot { round: 1, proposer: 2 };
+        let reply = |message_type, ballot| handle_acceptor(ballot_message(message_type, ballot, 0), &node).message_type;
+        assert_eq!(reply("prepare", ballot), "prepare_ack");
+        // The proposer's own accept, and its prepare again, meet the promise with `>=`
+        assert_eq!(reply("accept", ballot), "accept_ack");
+        assert_eq!(reply("prepare", ballot), "prepare_ack");
+        // Another proposer in the same round needs a higher id, so strictly more
+        assert_eq!(reply("prepare", Ballot { round: 1, proposer: 1 }), "reject_prepare");
+        assert_eq!(reply("accept", Ballot { round: 1, proposer: 1 }), "reject_accept");
+        assert_eq!(reply("prepare", Ballot { round: 1, proposer: 3 }), "prepare_ack");
+        assert_eq!(reply("accept", ballot), "reject_accept");
+    }
+
+    #[test]
+    fn acceptor_never_goes_below_its_promise() {
+        for seed in 1..=50 {
+            // A fresh acceptor each time, since a prepare also promises the later slots
+            let node = acceptor_node();
+            let mut rng = XorShift::from_seed(seed);
+            let mut promised = Ballot::default();
+            let mut accepted = None;
+            for _ in 0..40 {
+                let ballot = Ballot { round: rng.next_below(6), proposer: rng.next_below(3) as u32 + 1 };
+                let message_type = if rng.chance(0.5) { "prepare" } else { "accept" };
+                let reply = handle_acceptor(ballot_message(message_type, ballot, 0), &node);
+                let acked = reply.message_type.ends_with("_ack");
+                assert_eq!(acked, ballot >= promised, "{} of {:?} with {:?} promised, seed {}", message_type, ballot, promised, seed);
+                if acked {
+                    promised = ballot;
+                } else {
+                    assert_eq!(reply.promised, Some(promised));
+                }
+                if reply.message_type == "accept_ack" {
+                    assert!(accepted <= Some(ballot));
+                    accepted = Some(ballot);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn next_round_beats_the_reported_promise() {
+        for my_id in 1..=4 {
+            for round in 0..5 {
+                assert_eq!(next_round(round, None, my_id, 4), round + 1);
+                for promised_round in 0..8 {
+                    for proposer in 1..=4 {
+                        let promised = Ballot { round: promised_round, proposer };
+                        let next = next_round(round, Some(promised), my_id, 4);
+                        assert!(next > round);
+                        assert!(Ballot { round: next, proposer: my_id } > promised, "round {} after {:?}", next, promised);
+                        // And it doesn't skip further ahead than it has to
+                        assert!(next == round + 1 || Ballot { round: next - 1, proposer: my_id } <= promised);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Runs every proposer of `nodes` to the end, as --in-process does, each over a
+    /// FaultyTransport that its --seed, --drop-rate and --delay-ms-max configure
+    fn run_proposers(network: &Arc<Network>, nodes: &Nodes) {
+        thread::scope(|scope| {
+            for (config, (user, roles, total_peers, ..), _) in nodes {
+                let Some(groups) = roles.get(&Role::Proposer) else { continue };
+                for (group, acceptors) in groups {
+                    let from = format!("{}:{}", user.name, config.port);
+                    let transport = MemoryTransport::new(Arc::clone(network), from, None);
+                    scope.spawn(move || {
+                        let transport = FaultyTransport::new(transport, config, user.id, *group);
+                        run_proposer(config, &transport, user.id, *group, acceptors, *total_peers, None)
+                    });
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn proposers_agree_on_a_proposed_value() {
+        let content = "peer1:proposer1\npeer2:proposer1\npeer3:acceptor1\npeer4:acceptor1\npeer5:acceptor1\npeer6:learner1\n";
+        // Each seed loses and holds back a different set of messages
+        for seed in 1..=8 {
+            let faults = format!("--seed {} --drop-rate 0.2 --delay-ms-max 2 --backoff-base-ms 1 --backoff-max-ms 16 --max-rounds 200", seed);
+            let outs = [temp_path(&format!("chosen_1_{}.json", seed)), temp_path(&format!("chosen_2_{}.json", seed))];
+            let flags = [format!("-v A --out {} {}", outs[0], faults), format!("-v B --out {} {}", outs[1], faults)];
+            let (network, nodes) = cluster(content, &[&flags[0], &flags[1]]);
+            run_proposers(&network, &nodes);
+
+            // Every value anyone holds for the slot, by who holds it
+            let mut values = Vec::new();
+            for (n, out) in outs.iter().enumerate() {
+                let decision: serde_json::Value = serde_json::from_str(fs::read_to_string(out).unwrap().trim()).unwrap();
+                assert_eq!((decision["group"].as_u64(), decision["slot"].as_u64()), (Some(1), Some(0)));
+                values.push((format!("proposer {}", n + 1), decision["value"].as_str().unwrap().to_string()));
+            }
+            for (_, (user, ..), node) in &nodes {
+                let Some(node) = node else { continue };
+                if let Some((_, ref value)) = node.state.lock().unwrap()[&None].get(&SLOT).and_then(|s| s.decided.clone()) {
+                    values.push((format!("acceptor {}", user.id), value.clone()));
+                }
+                if let Some(ref learner) = node.learner {
+                    let learned = learner.lock().unwrap().learned.get(&(None, SLOT)).cloned();
+                    values.push((format!("learner {}", user.id), learned.expect("the learner learned nothing")));
+                }
+            }
+
+            let chosen = &values[0].1;
+            assert!(chosen == "A" || chosen == "B", "seed {}: {:?} was never proposed", seed, chosen);
+            assert!(values.iter().all(|(_, value)| value == chosen), "seed {}: {:?}", seed, values);
+        }
+    }
+
+    #[test]
+    fn no_accept_acks() {
+        let (network, _, _, acceptors) = two_proposers(0);
+        let config = init(vec!["-h".into(), "hosts".into(), "--drop-rate".into(), "1".into(), "--seed".into(), "1".into()]);
+        let lossy = MemoryTransport::new(network, "peer1:8889".to_string(), None);
+        let lossy = FaultyTransport::new(lossy, &config, 1, 1);
+        let accepted = accept(&lossy, &acceptors, Ballot { round: 1, proposer: 1 }, "A");
+        assert!(accepted.peers.is_empty() && accepted.responded.is_empty() && !accepted.rejected);
+    }
+
+    #[test]
+    fn minority_of_accept_acks() {
+        let (network, first, second, acceptors) = two_proposers(0);
+        // peer3 promised a higher ballot and peer4 is down, so only peer5 accepts
+        prepare(&second, &acceptors[..1], Ballot { round: 3, proposer: 2 }, "B");
+        network.nodes.lock().unwrap().remove("peer4:8889");
+        let accepted = accept(&first, &acceptors, Ballot { round: 1, proposer: 1 }, "A");
+        assert_eq!(accepted.peers, vec!["peer5:8889".to_string()]);
+        assert!(accepted.rejected && accepted.peers.len() < majority(acceptors.len()));
+    }
+
+    #[test]
+    fn majority_of_accept_acks_is_chosen() {
+        let out = temp_path("chosen_majority.json");
+        let content = "peer1:proposer1\npeer2:acceptor1\npeer3:acceptor1\npeer4:acceptor1\n";
+        let (network, nodes) = cluster(content, &[&format!("-v A --out {}", out)]);
+        let (config, (_, roles, ..), _) = &nodes[0];
+        let acceptors = &roles[&Role::Proposer][&1];
+        // peer4 is down, so two of three accept
+        network.nodes.lock().unwrap().remove("peer4:8889");
+        let transport = MemoryTransport::new(Arc::clone(&network), "peer1:8889".to_string(), None);
+
+        let ballot = Ballot { round: 1, proposer: 1 };
+        let accepted = accept(&transport, acceptors, ballot, "A");
+        assert_eq!(accepted.peers.len(), 2);
+        assert!(!accepted.rejected && accepted.peers.len() >= majority(acceptors.len()));
+
+        let (_, chosen) = propose(config, &transport, 1, acceptors, 4, Instance { group: 1, slot: 1 }, None, "A", &mut None, None);
+        assert_eq!(chosen, "A");
+        let decision: serde_json::Value = serde_json::from_str(fs::read_to_string(&out).unwrap().trim()).unwrap();
+        assert_eq!((decision["slot"].as_u64(), decision["value"].as_str()), (Some(1), Some("A")));
+    }
+
+    /// Serves `node` on a free local port the way --port does, over TCP
+    fn serve_locally(node: Arc<Node>) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve(listener, node));
+        TcpStream::connect(addr).unwrap()
+    }
+
+    fn read_reply(reader: &mut impl BufRead) -> PaxosMessage {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[test]
+    fn value_of_4_kb_arrives_whole() {
+        let node = acceptor_node();
+        let mut stream = serve_locally(Arc::clone(&node));
+        let value = "x".repeat(4096);
+        let msg = PaxosMessage { message_value: value.clone(), ..ballot_message("accept", Ballot { round: 1, proposer: 1 }, 0) };
+        writeln!(stream, "{}", json_line(&msg)).unwrap();
+
+        let reply = read_reply(&mut BufReader::new(stream));
+        assert_eq!(reply.message_type, "accept_ack");
+        assert_eq!(reply.message_value, value);
+        let state = node.state.lock().unwrap();
+        assert_eq!(state[&None][&SLOT].accepted_value.as_deref(), Some(value.as_str()));
+    }
+
+    #[test]
+    fn two_messages_in_one_write_are_both_handled() {
+        let node = acceptor_node();
+        let mut stream = serve_locally(Arc::clone(&node));
+        let ballot = Ballot { round: 1, proposer: 2 };
+        let both = format!(
+            "{}\n{}\n",
+            json_line(&ballot_message("prepare", ballot, 0)),
+            json_line(&ballot_message("accept", ballot, 0))
+        );
+        stream.write_all(both.as_bytes()).unwrap();
+
+        let mut reader = BufReader::new(stream);
+        assert_eq!(read_reply(&mut reader).message_type, "prepare_ack");
+        let accepted = read_reply(&mut reader);
+        assert_eq!((accepted.message_type.as_str(), accepted.message_value.as_str()), ("accept_ack", "v2"));
+        assert_eq!(node.state.lock().unwrap()[&None][&SLOT].accepted_proposal, Some(ballot));
+    }
+
+    #[test]
+    fn prepare_ack_round_trips_what_was_accepted() {
+        let node = acceptor_node();
+        let before = Ballot { round: 1, proposer: 1 };
+        handle_acceptor(ballot_message("accept", before, 0), &node);
+        let reply = handle_acceptor(ballot_message("prepare", Ballot { round: 2, proposer: 3 }, 0), &node);
+        assert_eq!(reply.message_type, "prepare_ack");
+
+        let parsed: PaxosMessage = serde_json::from_str(&json_line(&reply)).unwrap();
+        assert_eq!(parsed.accepted_proposal, Some(before));
+        assert_eq!(parsed.accepted_value.as_deref(), Some("v1"));
+        assert_eq!(json_line(&parsed), json_line(&reply));
+
+        // With nothing accepted the fields are left out of the line and read back as None
+        let fresh = handle_acceptor(ballot_message("prepare", Ballot { round: 1, proposer: 1 }, 1), &acceptor_node());
+        let line = json_line(&fresh);
+        assert!(!line.contains("accepted_proposal") && !line.contains("accepted_value"), "{}", line);
+        let parsed: PaxosMessage = serde_json::from_str(&line).unwrap();
+        assert_eq!((parsed.accepted_proposal, parsed.accepted_value), (None, None));
+    }
+
+    #[test]
+    fn old_prepare_ack_without_accepted_fields() {
+        let line = r#"{"peer_id":4,"action":"sent","message_type":"prepare_ack","message_value":"X","proposal_num":6}"#;
+        let parsed: PaxosMessage = serde_json::from_str(line).unwrap();
+        assert_eq!(parsed.message_type, "prepare_ack");
+        assert_eq!((parsed.accepted_proposal, parsed.accepted_value.as_deref()), (None, None));
+        assert_eq!((parsed.ballot, parsed.group, parsed.slot), (None, 0, 0));
+        assert_eq!(parsed.ballot(4), Ballot { round: 1, proposer: 2 });
+    }
+
+    /// Every slot of the acceptor's state, as its status reply lists them
+    fn slots(node: &Node) -> serde_json::Value {
+        serde_json::from_str::<serde_json::Value>(&status_message(node).message_value).unwrap()["slots"].clone()
+    }
+
+    #[test]
+    fn bad_lines_get_an_error_and_leave_the_state_alone() {
+        let content = "peer1:proposer1\npeer2:acceptor1\n";
+        let (network, nodes) = cluster(content, &[]);
+        let node = nodes[1].2.clone().unwrap();
+        let ballot = Ballot { round: 1, proposer: 1 };
+        handle_acceptor(ballot_message("accept", ballot, 0), &node);
+        let before = slots(&node);
+
+        let accept = json_line(&ballot_message("accept", Ballot { round: 2, proposer: 1 }, 0));
+        let unknown = json_line(&PaxosMessage { message_type: "propose".to_string(), ..ballot_message("accept", ballot, 0) });
+        for line in [&accept[..accept.len() / 2], "{\"peer_id\":1,", &unknown] {
+            let reply: PaxosMessage = serde_json::from_str(&network.deliver("peer2:8889", line, "peer1:8889").unwrap()).unwrap();
+            assert_eq!(reply.message_type, "error", "{}", line);
+            assert_eq!(slots(&node), before, "{}", line);
+        }
+
+        // Bytes that aren't even UTF-8, then a valid message on the same connection
+        let mut input = vec![0xff, 0xfe, 0x00, b'{', 0x80, b'\n'];
+        input.extend(format!("{}\n", json_line(&ballot_message("prepare", Ballot { round: 3, proposer: 1 }, 0))).bytes());
+        let mut replies = Vec::new();
+        handle_connection(io::Cursor::new(input), &mut replies, &node, "peer1:8889");
+        let replies: Vec<PaxosMessage> = String::from_utf8(replies).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(replies.iter().map(|reply| reply.message_type.as_str()).collect::<Vec<_>>(), ["error", "prepare_ack"]);
+        assert_eq!(replies[1].accepted_value.as_deref(), Some("v1"));
+    }
+
+    #[test]
+    fn concurrent_prepare_and_accept_replies_are_consistent() {
+        let node = acceptor_node();
+        let mut rng = XorShift::from_seed(7);
+        for slot in 0..200 {
+            // Rounds grow with the slot so the onward promise of an earlier prepare never decides
+            let prepare = Ballot { round: 2 * slot + rng.next_below(2), proposer: 1 };
+            let accept = Ballot { round: 2 * slot + rng.next_below(2), proposer: 2 };
+            let start = std::sync::Barrier::new(2);
+            let (promise, accepted) = thread::scope(|scope| {
+                let promise = scope.spawn(|| {
+                    start.wait();
+                    handle_acceptor(ballot_message("prepare", prepare, slot), &node)
+                });
+                let accepted = scope.spawn(|| {
+                    start.wait();
+                    handle_acceptor(ballot_message("accept", accept, slot), &node)
+                });
+                (promise.join().unwrap(), accepted.join().unwrap())
+            });
+
+            let what = format!("slot {}: prepare {:?} got {:?}, accept {:?} got {:?}", slot, prepare, promise, accept, accepted);
+            match promise.message_type.as_str() {
+                // Whatever the promise reports as accepted has to be the accept's own value,
+                // and only if that accept got through first
+                "prepare_ack" => match (promise.accepted_proposal, promise.accepted_value.as_deref()) {
+                    // Nothing accepted yet, so an accept that was acked came after the promise
+                    (None, None) => assert!(accepted.message_type != "accept_ack" || accept > prepare, "{}", what),
+                    (Some(ballot), Some("v2")) => {
+                        assert!(ballot == accept && accepted.message_type == "accept_ack", "{}", what);
+                        assert_eq!(promise.message_value, "v2", "{}", what);
+                    }
+                    _ => panic!("{}", what),
+                },
+                // Only the accept's ballot can have beaten the prepare
+                "reject_prepare" => assert!(promise.promised == Some(accept) && accept > prepare && accepted.message_type == "accept_ack", "{}", what),
+                _ => panic!("{}", what),
+            }
+            match accepted.message_type.as_str() {
+                "accept_ack" => assert_eq!(accepted.message_value, "v2", "{}", what),
+                "reject_accept" => assert!(accepted.promised == Some(prepare) && prepare > accept && promise.message_type == "prepare_ack", "{}", what),
+                _ => panic!("{}", what),
+            }
+        }
+    }
+
+    #[test]
+    fn adopted_value_is_decided_over_my_value() {
+        let out = temp_path("chosen_adopted.json");
+        let content = "peer1:proposer1\npeer2:proposer1\npeer3:acceptor1\npeer4:acceptor1\npeer5:acceptor1\n";
+        // peer3 already accepted B from proposer 1 in round 0, below proposer 2's first ballot
+        let (network, nodes) = cluster(content, &["", &format!("-v A --out {}", out), "-a 1:B"]);
+        let (config, (_, roles, ..), _) = &nodes[1];
+        let acceptors = &roles[&Role::Proposer][&1];
+        let transport = MemoryTransport::new(Arc::clone(&network), "peer2:8889".to_string(), None);
+
+        let (ballot, chosen) = propose(config, &transport, 2, acceptors, 5, SLOT, None, "A", &mut None, None);
+        assert_eq!(ballot, Ballot { round: 0, proposer: 2 });
+        assert_eq!(chosen, "B");
+        let decision: serde_json::Value = serde_json::from_str(fs::read_to_string(&out).unwrap().trim()).unwrap();
+        assert_eq!(decision["value"].as_str(), Some("B"));
+        for (_, _, node) in &nodes[2..] {
+            let state = node.as_ref().unwrap().state.lock().unwrap();
+            assert_eq!(state[&None][&SLOT].accepted_value.as_deref(), Some("B"));
+        }
+    }
+
+    #[test]
+    fn ballots_past_the_old_u32_limit() {
+        let edge = u32::MAX as u64;
+        let ballots: Vec<Ballot> =
+            (edge - 1..=edge + 1).flat_map(|round| (1..=4).map(move |proposer| Ballot { round, proposer })).collect();
+        for pair in ballots.windows(2) {
+            assert!(pair[0] < pair[1] && pair[0].number(4) < pair[1].number(4), "{:?}", pair);
+        }
+        for ballot in &ballots {
+            assert!(ballot.number(4) > edge);
+            assert_eq!(Ballot::from_number(ballot.number(4), 4), *ballot);
+        }
+        assert_eq!(next_round(edge, Some(Ballot { round: edge + 5, proposer: 4 }), 1, 4), edge + 6);
+
+        // Messages from before the widening carry u32-sized numbers, and still parse
+        let line = format!(
+            r#"{{"peer_id":2,"action":"sent","message_type":"prepare","message_value":"X","proposal_num":{},"round":{},"proposer":2}}"#,
+            edge, edge / 4
+        );
+        let msg: PaxosMessage = serde_json::from_str(&line).unwrap();
+        assert_eq!((msg.proposal_num, msg.ballot), (edge, Some(Ballot { round: edge / 4, proposer: 2 })));
+    }
+
+    #[test]
+    fn no_overflow_stops_the_node() {
+        // Run again as a child process, since the node exits on overflow rather than wrap
+        if env::var_os("HW4_OVERFLOW_CHILD").is_some() {
+            Ballot { round: u64::MAX / 4, proposer: 4 }.number(4);
+            return;
+        }
+        let output = process::Command::new(env::current_exe().unwrap())
+            .args(["tests::no_overflow_stops_the_node", "--exact", "--nocapture"])
+            .env("HW4_OVERFLOW_CHILD", "1")
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(1), "{}", stderr);
+        assert!(stderr.contains("Overflow computing proposal_num"), "{}", stderr);
+        // The largest ballot that fits is still numbered
+        assert_eq!(Ballot { round: u64::MAX / 4 - 1, proposer: 4 }.number(4), (u64::MAX / 4 - 1) * 4 + 4);
+    }
+
+    /// MemoryTransport that counts the messages it sends, by message_type
+    struct Counting {
+        inner: MemoryTransport,
+        sent: Mutex<BTreeMap<String, usize>>,
+    }
+
+    impl Transport for Counting {
+        fn send_and_receive(&self, addr: &str, msg: &PaxosMessage) -> Result<PaxosMessage, SendError> {
+            *self.sent.lock().unwrap().entry(msg.message_type.clone()).or_default() += 1;
+            self.inner.send_and_receive(addr, msg)
+        }
+    }
+
+    /// Decides `slots` slots with one proposer and three acceptors and returns how many
+    /// prepares and accepts it sent, keeping the leadership between slots if `lead`
+    fn messages_for_slots(slots: u64, lead: bool) -> (usize, usize) {
+        let out = temp_path(&format!("chosen_lead_{}.json", lead));
+        let content = "peer1:proposer1\npeer2:acceptor1\npeer3:acceptor1\npeer4:acceptor1\n";
+        let (network, nodes) = cluster(content, &[&format!("-v A --out {}", out)]);
+        let (config, (_, roles, ..), _) = &nodes[0];
+        let acceptors = &roles[&Role::Proposer][&1];
+        let transport = Counting {
+            inner: MemoryTransport::new(network, "peer1:8889".to_string(), None),
+            sent: Mutex::new(BTreeMap::new()),
+        };
+        let mut leader = None;
+        for slot in 0..slots {
+            if !lead {
+                leader = None;
+            }
+            let (_, chosen) = propose(config, &transport, 1, acceptors, 4, Instance { group: 1, slot }, None, "A", &mut leader, None);
+            assert_eq!(chosen, "A");
+        }
+        let sent = transport.sent.lock().unwrap();
+        (sent.get("prepare").copied().unwrap_or(0), sent.get("accept").copied().unwrap_or(0))
+    }
+
+    #[test]
+    fn stable_leader_halves_the_messages_per_slot() {
+        // Without leadership every slot takes both rounds to all three acceptors
+        assert_eq!(messages_for_slots(5, false), (15, 15));
+        // With it only the first slot prepares, the other four go straight to accept
+        let (prepares, accepts) = messages_for_slots(5, true);
+        assert_eq!((prepares, accepts), (3, 15));
+        eprintln!("messages per slot: {} without a leader, {} with one", 30 / 5, (prepares + accepts) as f64 / 5.0);
+    }
 }
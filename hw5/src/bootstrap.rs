@@ -1,30 +1,103 @@
 #[macro_use]
 extern crate lazy_static;
 
-use hostname;
 use std::process;
 use std::net::{TcpListener, TcpStream};
-use std::io::{Read, Write};
+use std::io::{BufRead, BufReader, Read, Write};
 use std::thread;
 use std::sync::{Mutex, mpsc};
+use std::sync::atomic::{AtomicU16, AtomicU64, AtomicUsize, Ordering};
 use std::collections::HashMap;
 
-const TCP_PORT: u16 = 8888;
+const DEFAULT_PORT: u16 = 8888;
+const DEFAULT_PEER_PORT: u16 = 9999;
+const DEFAULT_SUCCESSORS: usize = 2;
+const DEFAULT_HEARTBEAT_MS: u64 = 2000;
+const DEFAULT_MISSES: u64 = 3;
+// A request carries its object's data, so one is read up to this many bytes
+const MAX_REQUEST_BYTES: usize = 1 << 20;
+
+// How many successors each update lists, set with -r
+static SUCCESSORS: AtomicUsize = AtomicUsize::new(DEFAULT_SUCCESSORS);
+// How often each peer is sent a PING, set with -i, 0 for never
+static HEARTBEAT_MS: AtomicU64 = AtomicU64::new(DEFAULT_HEARTBEAT_MS);
+// How many PINGs in a row a peer can leave unanswered before it is taken as failed, set with -k
+static MISSES: AtomicU64 = AtomicU64::new(DEFAULT_MISSES);
+// The port a peer's listener is on when its JOIN doesn't say, set with --peer-port
+static PEER_PORT: AtomicU16 = AtomicU16::new(DEFAULT_PEER_PORT);
+// Numbers each JOIN connection, to tell a rejoined peer's from the one it replaced
+static NEXT_CONN: AtomicU64 = AtomicU64::new(0);
 
 lazy_static! {
     // Global vector holding peer numbers
     static ref PEERS: Mutex<Vec<u64>> = Mutex::new(Vec::new());
-    // Global mapping from peer id to a sender
-    static ref PEER_CONN: Mutex<HashMap<u64, mpsc::Sender<String>>> = Mutex::new(HashMap::new());
+    // Global mapping from peer id to the number of its JOIN connection and a sender on it
+    static ref PEER_CONN: Mutex<HashMap<u64, (u64, mpsc::Sender<String>)>> = Mutex::new(HashMap::new());
+    // Global mapping from peer id to the host:port its listener is on, from its JOIN
+    static ref PEER_ADDRS: Mutex<HashMap<u64, String>> = Mutex::new(HashMap::new());
 }
 
-fn main() -> std::io::Result<()> {
-    // This bootstrap server takes no arguments.
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() != 1 {
-        eprintln!("Bootstrap server takes in no argument");
+// Where the bootstrap server listens and the host name it expects, see init
+struct Config {
+    port: u16,
+    name: String,
+    strict_name: bool,
+}
+
+/// Reads the bootstrap server's command-line arguments, all optional.
+///   -r : How many successors each peer is told about. Defaults to 2.
+///   -i : How many milliseconds apart each peer is sent a PING, 0 for no heartbeats. Defaults to 2000.
+///   -k : How many PINGs in a row a peer can miss before it is removed from the ring. Defaults to 3.
+///   --port : The port to listen on for peers and clients. Defaults to 8888.
+///   --peer-port : The port a peer listens on for requests if its JOIN gives no port=. Defaults to 9999.
+///   --name : The host name the server should be running on, warned about if it isn't.
+///            Defaults to bootstrap.
+///   --strict-name : Takes no value. Exit instead of warning when the host name isn't --name.
+fn init() -> Config {
+    let mut config = Config {
+        port: DEFAULT_PORT,
+        name: "bootstrap".to_string(),
+        strict_name: false,
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(key) = args.next() {
+        if key == "--strict-name" {
+            config.strict_name = true;
+            continue;
+        }
+        let Some(value) = args.next() else {
+            eprintln!("init error: Missing value for {}", key);
+            process::exit(1);
+        };
+        match (key.as_str(), value.parse::<u64>()) {
+            ("-r", Ok(r)) if r > 0 => SUCCESSORS.store(r as usize, Ordering::Relaxed),
+            ("-i", Ok(ms)) => HEARTBEAT_MS.store(ms, Ordering::Relaxed),
+            ("-k", Ok(k)) if k > 0 => MISSES.store(k, Ordering::Relaxed),
+            ("--port", Ok(port)) if port > 0 && port <= u16::MAX as u64 => config.port = port as u16,
+            ("--peer-port", Ok(port)) if port > 0 && port <= u16::MAX as u64 => {
+                PEER_PORT.store(port as u16, Ordering::Relaxed)
+            },
+            ("--name", _) => config.name = value,
+            ("-r" | "-i" | "-k" | "--port" | "--peer-port", _) => {
+                eprintln!("init error: Invalid value for {}: {}", key, value);
+                process::exit(1);
+            },
+            (other, _) => {
+                eprintln!("init error: Unknown flag: {}", other);
+                process::exit(1);
+            }
+        }
+    }
+    // A peer running on the bootstrap server's host couldn't listen on the same port
+    if config.port == PEER_PORT.load(Ordering::Relaxed) {
+        eprintln!("init error: --port and --peer-port are both {}, a peer on this host couldn't listen", config.port);
         process::exit(1);
     }
+    config
+}
+
+fn main() -> std::io::Result<()> {
+    let config = init();
 
     let host = match hostname::get() {
         Ok(name) => name.into_string().unwrap_or_else(|_| "unknown".to_string()),
@@ -34,141 +107,97 @@ fn main() -> std::io::Result<()> {
         }
     };
 
-    if host != "bootstrap" {
-        eprintln!("Error: Hostname is not named bootstrap");
-        process::exit(1);
+    if host != config.name {
+        if config.strict_name {
+            eprintln!("Error: Hostname is {}, not {}", host, config.name);
+            process::exit(1);
+        }
+        println!("Warning: Hostname is {}, not {}", host, config.name);
     }
 
-    let listener = TcpListener::bind(format!("0.0.0.0:{}", TCP_PORT))
+    let listener = TcpListener::bind(format!("0.0.0.0:{}", config.port))
         .expect("Could not bind to address");
 
-    // Hold persistent TCP stream for peer n1
-    let mut n1_stream: Option<TcpStream> = None;
-
     for stream in listener.incoming() {
         let stream = stream?;
-        let mut peek_buf = [0u8; 64];
-        let n = stream.peek(&mut peek_buf)?;
-        let peek_msg = String::from_utf8_lossy(&peek_buf[..n]).to_string();
-
-        if peek_msg.starts_with("JOIN:") {
-            let peer_name = peek_msg.trim_start_matches("JOIN:").trim();
-            if peer_name == "n1" {
-                n1_stream = Some(stream.try_clone()?);
-                let cloned_stream = stream.try_clone()?;
-                thread::spawn(move || {
-                    handle_client(cloned_stream, None);
-                });
-            } else {
-                // It's a JOIN from a peer other than n1.
-                thread::spawn(move || {
-                    handle_client(stream, None);
-                });
-            }
-        } else if peek_msg.starts_with("REQUEST:") {
-            // For REQUEST messages, pass to n1_stream.
-            if let Some(ref n1) = n1_stream {
-                let n1_clone = n1.try_clone()?;
-                thread::spawn(move || {
-                    handle_client(stream, Some(n1_clone));
-                });
-            } else {
-                thread::spawn(move || {
-                    handle_client(stream, None);
-                });
-            }
-        } else {
-            thread::spawn(move || {
-                handle_client(stream, None);
-            });
-        }
+        thread::spawn(move || {
+            handle_client(stream);
+        });
     }
     Ok(())
 }
 
-/// handle_client processes a connection.
-/// If an optional n1_stream is provided, it is used when forwarding a REQUEST message.
-fn handle_client(mut stream: TcpStream, n1_stream: Option<TcpStream>) {
-    let mut buffer = [0u8; 512];
-    match stream.read(&mut buffer) {
+/// handle_client processes a connection, whose first line says what it is for.
+fn handle_client(mut stream: TcpStream) {
+    let mut reader = match stream.try_clone() {
+        Ok(clone) => BufReader::new(clone),
+        Err(e) => {
+            println!("Error cloning stream: {}", e);
+            return;
+        }
+    };
+    let mut message = String::new();
+    match (&mut reader).take(MAX_REQUEST_BYTES as u64).read_line(&mut message) {
         Ok(0) => {
             println!("Connection closed without data.");
-            return;
         },
-        Ok(bytes_read) => {
-            let message = String::from_utf8_lossy(&buffer[..bytes_read]);
-            if message.starts_with("JOIN:") {
-                let peer_str = message.trim_start_matches("JOIN:").trim();
-                if let Some(num_str) = peer_str.strip_prefix('n') {
-                    if let Ok(new_peer) = num_str.parse::<u64>() {
-                        // Create a channel for sending messages to this peer.
-                        let (tx, rx) = mpsc::channel::<String>();
-                        {
-                            let mut conn_map = PEER_CONN.lock().unwrap();
-                            conn_map.insert(new_peer, tx);
-                        }
-                        let mut stream_clone = stream.try_clone().expect("Failed to clone stream");
-                        thread::spawn(move || {
-                            for msg in rx {
-                                if let Err(e) = stream_clone.write_all(msg.as_bytes()) {
-                                    println!("Error sending update to n{}: {}", new_peer, e);
-                                    break;
-                                }
-                            }
-                        });
-                        let (predecessor, successor, updates) = add_peer(new_peer);
-                        let predecessor_str = predecessor.map(|p| format!("n{}", p)).unwrap_or("None".to_string());
-                        let successor_str = successor.map(|s| format!("n{}", s)).unwrap_or("None".to_string());
-                        let reply = format!("JOIN_REPLY: predecessor={}, successor={}\n", predecessor_str, successor_str);
-                        if let Err(e) = stream.write_all(reply.as_bytes()) {
-                            println!("Error sending join reply to n{}: {}", new_peer, e);
-                        }
-                        for (target_peer, update_msg) in updates {
-                            let conn_map = PEER_CONN.lock().unwrap();
-                            if let Some(sender) = conn_map.get(&target_peer) {
-                                let _ = sender.send(format!("{}\n", update_msg));
-                            } else {
-                                println!("No connection found for n{} to send update: {}", target_peer, update_msg);
-                            }
-                        }
-                        loop {
-                            thread::sleep(std::time::Duration::from_secs(10));
+        Ok(_) => {
+            if let Some(joining) = message.strip_prefix("JOIN:") {
+                let Some((new_peer, name, port)) = parse_peer(joining) else {
+                    let _ = stream.write_all(b"ERROR: Invalid peer, expected JOIN:id=<number>,name=<host>,port=<port>\n");
+                    return;
+                };
+                let name = name.unwrap_or_else(|| format!("n{}", new_peer));
+                if name != format!("n{}", new_peer) {
+                    println!("n{} joined from host {}", new_peer, name);
+                }
+                let addr = format!("{}:{}", name, port.unwrap_or_else(|| PEER_PORT.load(Ordering::Relaxed)));
+                // Create a channel for sending messages to this peer.
+                let (tx, rx) = mpsc::channel::<String>();
+                let conn = NEXT_CONN.fetch_add(1, Ordering::Relaxed);
+                {
+                    let mut conn_map = PEER_CONN.lock().unwrap();
+                    conn_map.insert(new_peer, (conn, tx.clone()));
+                }
+                let mut stream_clone = stream.try_clone().expect("Failed to clone stream");
+                thread::spawn(move || {
+                    for msg in rx {
+                        if let Err(e) = stream_clone.write_all(msg.as_bytes()) {
+                            println!("Error sending update to n{}: {}", new_peer, e);
+                            break;
                         }
-                    } else {
-                        let err_msg = "ERROR: Invalid peer number\n";
-                        let _ = stream.write_all(err_msg.as_bytes());
                     }
-                } else {
-                    let err_msg = "ERROR: Peer name must start with 'n'\n";
-                    let _ = stream.write_all(err_msg.as_bytes());
+                });
+                let (predecessor, successor, updates) = add_peer(new_peer, addr);
+                let predecessor_str = predecessor.map(peer_name).unwrap_or("None".to_string());
+                let successor_str = successor.map(peer_name).unwrap_or("None".to_string());
+                let reply = format!("JOIN_REPLY: predecessor={}, successor={}\n", predecessor_str, successor_str);
+                if let Err(e) = stream.write_all(reply.as_bytes()) {
+                    println!("Error sending join reply to n{}: {}", new_peer, e);
                 }
-            } else if message.starts_with("REQUEST:") {
-                if let Some(mut n1) = n1_stream {
-                    if let Err(e) = n1.write_all(message.as_bytes()) {
-                        println!("Error forwarding request to n1: {}", e);
-                        let _ = stream.write_all(b"ERROR: Failed to forward request to peer n1\n");
-                        return;
-                    }
-                    n1.flush().unwrap();
-                    let mut peer_buffer = [0u8; 512];
-                    match n1.read(&mut peer_buffer) {
-                        Ok(0) => {
-                            println!("No response from n1");
-                            let _ = stream.write_all(b"ERROR: No response from peer n1\n");
+                send_updates(updates);
+                watch_peer(&mut reader, new_peer, tx);
+                drop_peer(new_peer, conn);
+                let _ = stream.shutdown(std::net::Shutdown::Both);
+            } else if let Some(leaving) = message.strip_prefix("LEAVE:") {
+                match parse_peer(leaving) {
+                    Some((peer, ..)) => match remove_peer(peer) {
+                        Some(updates) => {
+                            send_updates(updates);
+                            PEER_CONN.lock().unwrap().remove(&peer);
+                            let _ = stream.write_all(b"LEAVE_OK\n");
                         },
-                        Ok(n) => {
-                            let response = String::from_utf8_lossy(&peer_buffer[..n]);
-                            let _ = stream.write_all(response.as_bytes());
-                        },
-                        Err(e) => {
-                            println!("Error reading response from n1: {}", e);
-                            let _ = stream.write_all(b"ERROR: Failed to read response from peer n1\n");
+                        None => {
+                            let _ = stream.write_all(b"ERROR: Peer is not in the ring\n");
                         }
+                    },
+                    None => {
+                        let _ = stream.write_all(b"ERROR: Invalid peer, expected LEAVE:id=<number>\n");
                     }
-                } else {
-                    println!("No n1 stream available for REQUEST forwarding.");
-                    let _ = stream.write_all(b"ERROR: n1 not available\n");
                 }
+            } else if message.starts_with("REQUEST:") {
+                let response = route_request(&message);
+                let _ = stream.write_all(response.as_bytes());
             } else {
                 let err_msg = "ERROR: Unknown message format\n";
                 let _ = stream.write_all(err_msg.as_bytes());
@@ -180,16 +209,188 @@ fn handle_client(mut stream: TcpStream, n1_stream: Option<TcpStream>) {
     }
 }
 
-/// add_peer inserts the new peer into the global PEERS vector and computes its neighbors in a ring.
-fn add_peer(new_peer: u64) -> (Option<u64>, Option<u64>, Vec<(u64, String)>) {
-    let mut updates = Vec::new();
-    let mut peers = PEERS.lock().unwrap();
-    peers.push(new_peer);
-    peers.sort();
+/// parse_peer reads the peer in a JOIN or LEAVE: its id and, for a JOIN, its host name and
+/// the port it listens on, as in `id=5,name=n5,port=9999`. The older form, just the name as
+/// in `n5`, gives the id after the n.
+fn parse_peer(message: &str) -> Option<(u64, Option<String>, Option<u16>)> {
+    let message = message.trim();
+    if !message.starts_with("id=") {
+        return Some((message.strip_prefix('n')?.parse().ok()?, Some(message.to_string()), None));
+    }
+    let mut id = None;
+    let mut name = None;
+    let mut port = None;
+    for field in message.split(',') {
+        match field.trim().split_once('=')? {
+            ("id", value) => id = Some(value.parse().ok()?),
+            ("name", value) => name = Some(value.to_string()),
+            ("port", value) => port = Some(value.parse().ok().filter(|&port| port > 0)?),
+            _ => return None,
+        }
+    }
+    Some((id?, name, port))
+}
+
+/// route_request sends a client's request to the peer that owns its objectID, the first peer
+/// whose id is >= it, wrapping around to the smallest, so the request needs no hops. If that
+/// peer is down, the peers after it are tried in ring order, each told to take the keys of
+/// the ones skipped by naming the owner's predecessor as pred, the way a peer detours around
+/// a dead successor. The request gets the ring's size as its ttl, the most hops it could
+/// need, so one that loops is stopped. Peers are reached at the address they joined with.
+fn route_request(request: &str) -> String {
+    let object_id = request.trim().split(',')
+        .filter_map(|part| part.trim().strip_prefix("objectID="))
+        .find_map(|value| value.trim().parse::<u64>().ok())
+        .unwrap_or(0);
+    let peers = PEERS.lock().unwrap().clone();
+    if peers.is_empty() {
+        return "ERROR: No peers in the ring\n".to_string();
+    }
 
+    let len = peers.len();
+    let owner = peers.iter().position(|&p| p >= object_id).unwrap_or(0);
+    let pred = peers[(owner + len - 1) % len];
+    let request = format!("{}, ttl={}", request.trim(), len);
+    for i in 0..len {
+        let peer = peers[(owner + i) % len];
+        let forwarded = if i == 0 {
+            format!("{}\n", request)
+        } else {
+            println!("n{} is down, trying n{} for objectID={}", peers[(owner + i - 1) % len], peer, object_id);
+            format!("{}, pred={}\n", request, peer_name(pred))
+        };
+        match forward_to_peer(peer, &forwarded) {
+            Ok(response) => return response,
+            Err(e) => println!("Error forwarding request to n{}: {}", peer, e),
+        }
+    }
+    "ERROR: No peer in the ring answered\n".to_string()
+}
+
+/// forward_to_peer sends a request to a peer's listener and reads its reply, which ends when
+/// the peer closes the connection. A peer's listener takes requests until the other end is
+/// done sending, so the write side is shut after the one request.
+fn forward_to_peer(peer: u64, request: &str) -> std::io::Result<String> {
+    let mut peer_stream = TcpStream::connect(peer_addr(peer))?;
+    peer_stream.set_read_timeout(Some(std::time::Duration::from_secs(10)))?;
+    peer_stream.write_all(request.as_bytes())?;
+    peer_stream.shutdown(std::net::Shutdown::Write)?;
+    let mut response = Vec::new();
+    peer_stream.read_to_end(&mut response)?;
+    if response.is_empty() {
+        return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "no response"));
+    }
+    Ok(String::from_utf8_lossy(&response).to_string())
+}
+
+/// watch_peer returns once a peer has failed: its JOIN connection closed, or with heartbeats
+/// on, it left -k PINGs in a row unanswered, which catches a peer that hangs with its
+/// connection still open. Each PING goes out through the peer's sender, and the peer answers
+/// with a PONG line, the only thing it writes on the connection after joining. A line cut off
+/// by a read timeout is finished by the next read.
+fn watch_peer(reader: &mut BufReader<TcpStream>, peer: u64, tx: mpsc::Sender<String>) {
+    let interval = HEARTBEAT_MS.load(Ordering::Relaxed);
+    let misses = MISSES.load(Ordering::Relaxed);
+    let interval = (interval > 0).then(|| std::time::Duration::from_millis(interval));
+    let mut line = String::new();
+    let mut missed = 0;
+    loop {
+        let deadline = interval.map(|interval| {
+            let _ = tx.send("PING\n".to_string());
+            std::time::Instant::now() + interval
+        });
+        let mut answered = false;
+        loop {
+            let left = deadline.map(|d| d.saturating_duration_since(std::time::Instant::now()));
+            if left.is_some_and(|left| left.is_zero()) {
+                break;
+            }
+            let _ = reader.get_ref().set_read_timeout(left);
+            match reader.read_line(&mut line) {
+                Ok(0) => return,
+                Ok(_) => {
+                    answered |= line.trim() == "PONG";
+                    line.clear();
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock
+                       || e.kind() == std::io::ErrorKind::TimedOut => {},
+                Err(_) => return,
+            }
+        }
+        if answered {
+            missed = 0;
+        } else {
+            missed += 1;
+            println!("n{} missed heartbeat {} of {}", peer, missed, misses);
+            if missed >= misses {
+                return;
+            }
+        }
+    }
+}
+
+/// drop_peer takes a failed peer out of the ring and sends its
+/// neighbors their new neighbors. A peer that left with LEAVE is already out, and one that
+/// has rejoined since has a new connection in PEER_CONN, so both are left alone.
+fn drop_peer(peer: u64, conn: u64) {
+    {
+        let mut conn_map = PEER_CONN.lock().unwrap();
+        match conn_map.get(&peer) {
+            Some((current, _)) if *current == conn => {
+                conn_map.remove(&peer);
+            },
+            _ => return,
+        }
+    }
+    println!("Lost n{}, removing it from the ring", peer);
+    if let Some(updates) = remove_peer(peer) {
+        send_updates(updates);
+    }
+}
+
+/// send_updates queues each neighbor update on the target peer's JOIN connection.
+fn send_updates(updates: Vec<(u64, String)>) {
+    let conn_map = PEER_CONN.lock().unwrap();
+    for (target_peer, update_msg) in updates {
+        if let Some((_, sender)) = conn_map.get(&target_peer) {
+            let _ = sender.send(format!("{}\n", update_msg));
+        } else {
+            println!("No connection found for n{} to send update: {}", target_peer, update_msg);
+        }
+    }
+}
+
+/// peer_addr gives the host:port a peer joined with. A peer that isn't known, e.g. one that
+/// left since the ring was read, gets n<id> and --peer-port, which fails like any peer that's gone.
+fn peer_addr(peer: u64) -> String {
+    PEER_ADDRS.lock().unwrap().get(&peer).cloned()
+        .unwrap_or_else(|| format!("n{}:{}", peer, PEER_PORT.load(Ordering::Relaxed)))
+}
+
+/// peer_name is how JOIN_REPLY, neighbor updates and pred= name a peer to the others: its
+/// id and its address, as in n5@n5:9999, so they know its place on the ring and can reach it.
+fn peer_name(peer: u64) -> String {
+    format!("n{}@{}", peer, peer_addr(peer))
+}
+
+fn print_ring(peers: &[u64]) {
     let ring_string = peers.iter().map(|p| format!("n{}", p))
                              .collect::<Vec<String>>().join(" ");
     println!("Ring: [{}]", ring_string);
+}
+
+/// add_peer inserts the new peer into the global PEERS vector, records the address it listens
+/// on, and computes its neighbors in a ring.
+fn add_peer(new_peer: u64, addr: String) -> (Option<u64>, Option<u64>, Vec<(u64, String)>) {
+    let mut updates = Vec::new();
+    let mut peers = PEERS.lock().unwrap();
+    PEER_ADDRS.lock().unwrap().insert(new_peer, addr);
+    // A peer rejoining before its old connection was seen closing is already in the ring
+    if !peers.contains(&new_peer) {
+        peers.push(new_peer);
+    }
+    peers.sort();
+    print_ring(&peers);
 
     let len = peers.len();
     let idx = peers.iter().position(|&x| x == new_peer).unwrap();
@@ -200,17 +401,93 @@ fn add_peer(new_peer: u64) -> (Option<u64>, Option<u64>, Vec<(u64, String)>) {
         return (None, None, updates);
     }
 
-    let get_neighbors = |peer: u64| -> (u64, u64) {
-        let pos = peers.iter().position(|&x| x == peer).unwrap();
-        let pred = if pos == 0 { peers[len - 1] } else { peers[pos - 1] };
-        let succ = if pos == len - 1 { peers[0] } else { peers[pos + 1] };
-        (pred, succ)
-    };
-
-    let affected = vec![predecessor.unwrap(), new_peer, successor.unwrap()];
-    for &p in affected.iter() {
-        let (pred, succ) = get_neighbors(p);
-        updates.push((p, format!("Predecessor: n{}, Successor: n{}", pred, succ)));
+    // The peers up to -r places before the new one now list it among their successors
+    let mut affected: Vec<usize> = before(idx, len);
+    affected.extend([idx, (idx + 1) % len]);
+    for pos in dedup(affected) {
+        updates.push((peers[pos], neighbor_update(&peers, pos)));
     }
     (predecessor, successor, updates)
 }
+
+/// before returns the positions of the peers up to -r places before pos on the ring, the
+/// farthest first, since those list the peer at pos among their successors.
+fn before(pos: usize, len: usize) -> Vec<usize> {
+    let r = SUCCESSORS.load(Ordering::Relaxed).min(len - 1);
+    (1..=r).rev().map(|i| (pos + len - i) % len).collect()
+}
+
+/// dedup drops repeated positions, which come up on rings with fewer peers than -r.
+fn dedup(positions: Vec<usize>) -> Vec<usize> {
+    let mut seen = Vec::new();
+    for pos in positions {
+        if !seen.contains(&pos) {
+            seen.push(pos);
+        }
+    }
+    seen
+}
+
+/// neighbor_update formats the update for the peer at pos: its predecessor, its successor,
+/// and the next -r peers after it, which it falls back on when its successor is down.
+fn neighbor_update(peers: &[u64], pos: usize) -> String {
+    let len = peers.len();
+    let r = SUCCESSORS.load(Ordering::Relaxed).min(len - 1);
+    let successors = (1..=r).map(|i| peer_name(peers[(pos + i) % len]))
+                            .collect::<Vec<String>>().join(" ");
+    format!("Predecessor: {}, Successor: {}, Successors: {}",
+            peer_name(peers[(pos + len - 1) % len]), peer_name(peers[(pos + 1) % len]), successors)
+}
+
+/// remove_peer takes a leaving peer out of the global PEERS vector and computes the updates
+/// for its old predecessor and successor, which become each other's neighbors, and for the
+/// other peers whose successor lists it was on.
+/// Returns None if the peer isn't in the ring.
+fn remove_peer(peer: u64) -> Option<Vec<(u64, String)>> {
+    let mut peers = PEERS.lock().unwrap();
+    let idx = peers.iter().position(|&x| x == peer)?;
+    peers.remove(idx);
+    PEER_ADDRS.lock().unwrap().remove(&peer);
+    print_ring(&peers);
+
+    let len = peers.len();
+    if len == 0 {
+        return Some(Vec::new());
+    }
+    if len == 1 {
+        return Some(vec![(peers[0], "Predecessor: None, Successor: None".to_string())]);
+    }
+    // The old successor now sits at idx (or wrapped to the front), and the peers up to -r
+    // places before it listed the leaving peer among their successors.
+    let succ_idx = idx % len;
+    let mut affected = before(succ_idx, len);
+    affected.push(succ_idx);
+    Some(dedup(affected).into_iter().map(|pos| (peers[pos], neighbor_update(&peers, pos))).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_peer_again_keeps_the_ring_unique() {
+        assert_eq!(add_peer(1, "n1:9999".to_string()), (None, None, Vec::new()));
+        add_peer(5, "foo:9105".to_string());
+
+        // n5 rejoins, on another port, before its old connection is seen closing
+        let (predecessor, successor, updates) = add_peer(5, "foo:9106".to_string());
+        assert_eq!(*PEERS.lock().unwrap(), [1, 5]);
+        assert_eq!((predecessor, successor), (Some(1), Some(1)));
+        assert_eq!(updates, [
+            (1, "Predecessor: n5@foo:9106, Successor: n5@foo:9106, Successors: n5@foo:9106".to_string()),
+            (5, "Predecessor: n1@n1:9999, Successor: n1@n1:9999, Successors: n1@n1:9999".to_string()),
+        ]);
+
+        let (predecessor, successor, updates) = add_peer(10, "n10:9999".to_string());
+        assert_eq!(*PEERS.lock().unwrap(), [1, 5, 10]);
+        assert_eq!((predecessor, successor), (Some(5), Some(1)));
+        let targets: Vec<u64> = updates.iter().map(|(peer, _)| *peer).collect();
+        assert_eq!(targets, [1, 5, 10]);
+        assert_eq!(updates[1].1, "Predecessor: n1@n1:9999, Successor: n10@n10:9999, Successors: n10@n10:9999 n1@n1:9999");
+    }
+}
@@ -1,115 +1,557 @@
-use std::net::TcpStream;
-use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::io::{BufRead, BufReader, Write};
 use std::env;
 use std::process;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-const TCP_PORT: u16 = 8888;
+mod escape;
+use escape::{escape, unescape};
+
+const DEFAULT_BOOTSTRAP_PORT: u16 = 8888;
+const DEFAULT_PEER_PORT: u16 = 9999;
+// The most data --data-size makes, to stay well under the peers' 1 MiB request limit
+const MAX_DATA_SIZE: usize = 256 * 1024;
+const DEFAULT_TIMEOUT_MS: u64 = 10000;
+const DEFAULT_RETRIES: u64 = 3;
+// The wait before the first retry, doubled before each one after it
+const INITIAL_BACKOFF_MS: u64 = 250;
+
+// Exit codes besides 0 for every reply as expected
+const EXIT_MISMATCH: i32 = 1;
+const EXIT_NOT_FOUND: i32 = 2;
+const EXIT_NO_REPLY: i32 = 3;
+
+// What the client sends, from -t or from --op and the flags that go with it
+struct Config {
+    bootstrap: Option<String>,
+    delay_time: Option<u64>,
+    peer: Option<String>,
+    test_case: Option<u64>,
+    op: Option<&'static str>,
+    object_id: Option<u64>,
+    client_id: u64,
+    req_id: u64,
+    repeat: u64,
+    expect: Option<&'static str>,
+    data: Option<String>,
+    timeout: Duration,
+    retries: u64,
+    concurrency: Option<u64>,
+    count: u64,
+    expect_version: Option<u64>,
+    bootstrap_port: u16,
+    peer_port: u16,
+}
+
+// A reply parsed into its kind, e.g. STORED, and its fields
+struct Reply {
+    kind: String,
+    object_id: Option<String>,
+    client_id: Option<String>,
+    peer: Option<String>,
+    data: Option<String>,
+    version: Option<String>,
+}
 
 fn main() -> std::io::Result<()> {
-    let (bootstrap_hostname, delay_time, test_case) = init();
+    let config = init();
 
-    if let Some(delay) = delay_time {
+    if let Some(delay) = config.delay_time {
         thread::sleep(Duration::from_secs(delay));
     }
 
-    // Connect to the bootstrap server.
-    let bootstrap_addr = format!("{}:{}", bootstrap_hostname, TCP_PORT);
-    let mut bs_stream = TcpStream::connect(&bootstrap_addr)?;
+    // Connect to the bootstrap server, which routes the request to the peer that owns the
+    // object, or with -p straight to the peer -p names.
+    let entry_addr = match &config.peer {
+        Some(peer) if peer.contains(':') => peer.clone(),
+        Some(peer) => format!("{}:{}", peer, config.peer_port),
+        None => format!("{}:{}", config.bootstrap.as_deref().unwrap_or_default(), config.bootstrap_port),
+    };
+
+    if let Some(threads) = config.concurrency {
+        process::exit(run_load(&config, &entry_addr, threads));
+    }
+
+    let op = config.op.unwrap_or_default();
+    let object_id = config.object_id.unwrap_or(0);
+    let expect = config.expect.unwrap_or_else(|| expected_reply(op));
 
-    let req_id = 1;
-    let client_id = 3;
+    // Each request goes on its own connection, since the bootstrap server answers one
+    // request per connection
+    let mut mismatches = 0;
+    let mut exit_code = 0;
+    for i in 0..config.repeat {
+        let req_id = config.req_id + i;
+        let request_msg = request_line(req_id, op, object_id, &config);
 
-    // Depending on the test case, set the operation and object ID.
-    let (op, object_id) = match test_case {
-        3 => ("STORE", 9),    // Testcase 3: Store object with ID 3.
-        4 => ("RETRIEVE", 10), // Testcase 4: Retrieve object with ID 3.
-        5 => ("RETRIEVE", 69), // Testcase 5: Attempt to retrieve a non-existent object.
-        _ => {
-            eprintln!("main: Unknown test case argument");
-            process::exit(1);
+        match &config.data {
+            // The data is echoed by its length only, it can run to kilobytes
+            Some(data) if op == "STORE" => println!(
+                "REQUEST: reqID={}, op={}, objectID={}, clientID={}, data=<{} bytes>",
+                req_id, op, object_id, config.client_id, data.len()
+            ),
+            _ => println!("{}", request_msg.trim()),
         }
+
+        let response = match send_with_retries(&entry_addr, &request_msg, op == "LIST", &config) {
+            Ok(response) => response,
+            Err(failure) => {
+                println!("No reply for reqID={} after {} attempts, the last: {}", req_id, config.retries + 1, failure);
+                mismatches += 1;
+                exit_code = exit_code.max(EXIT_NO_REPLY);
+                continue;
+            }
+        };
+
+        if op == "LIST" {
+            // One line per object the peer holds, then the count
+            for line in response.lines().filter(|l| !l.starts_with("OBJ LIST:")) {
+                println!("{}", line);
+            }
+        }
+        let last = response.lines().last().unwrap_or_default();
+        let reply = parse_reply(last);
+        if reply.kind == expect {
+            print_reply(&reply, req_id, last);
+            if let (Some(sent), "RETRIEVED") = (&config.data, reply.kind.as_str()) {
+                let got = reply.data.as_deref().unwrap_or_default();
+                if got == sent {
+                    println!("DATA: {} bytes, matching what was stored", got.len());
+                } else {
+                    println!("Expected {} bytes of data, got {} that don't match", sent.len(), got.len());
+                    mismatches += 1;
+                    exit_code = exit_code.max(EXIT_MISMATCH);
+                }
+            }
+            if let Some(expected) = config.expect_version {
+                let got = reply.version.as_deref().and_then(|v| v.parse().ok());
+                if got != Some(expected) {
+                    println!("Expected version {}, got {}", expected, reply.version.as_deref().unwrap_or("none"));
+                    mismatches += 1;
+                    exit_code = exit_code.max(EXIT_MISMATCH);
+                }
+            }
+        } else {
+            println!("Expected OBJ {}, got: {}", expect, response.trim());
+            mismatches += 1;
+            let code = if reply.kind == "NOT FOUND" { EXIT_NOT_FOUND } else { EXIT_MISMATCH };
+            exit_code = exit_code.max(code);
+        }
+    }
+
+    if config.repeat > 1 {
+        println!("{} of {} replies were OBJ {}", config.repeat - mismatches, config.repeat, expect);
+    }
+    if exit_code > 0 {
+        process::exit(exit_code);
+    }
+    Ok(())
+}
+
+/// The request line for an operation, a STORE carrying --data if there is any.
+fn request_line(req_id: u64, op: &str, object_id: u64, config: &Config) -> String {
+    let data_field = match (op, &config.data) {
+        ("STORE", Some(data)) => format!(", data={}", escape(data)),
+        _ => String::new(),
     };
+    format!(
+        "REQUEST: reqID={}, op={}, objectID={}, clientID={}{}\n",
+        req_id, op, object_id, config.client_id, data_field
+    )
+}
 
-    let request_msg = format!(
-        "REQUEST: reqID={}, op={}, objectID={}, clientID={}\n",
-        req_id, op, object_id, client_id
+/// Runs `threads` threads at once, each storing --count objects of its own and retrieving
+/// each right after storing it, then prints the throughput, the latencies and the failures.
+/// Thread t's objects are --object-id + t * --count onwards, so no two threads share one, and
+/// every request goes on its own connection like any other. Returns the exit code.
+fn run_load(config: &Config, entry_addr: &str, threads: u64) -> i32 {
+    let started = Instant::now();
+    let results: Vec<(Vec<Duration>, u64, i32)> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads).map(|t| scope.spawn(move || {
+            let mut latencies = Vec::new();
+            let mut failures = 0;
+            let mut exit_code = 0;
+            for j in 0..config.count {
+                let object_id = config.object_id.unwrap_or(1) + t * config.count + j;
+                for (k, op) in ["STORE", "RETRIEVE"].into_iter().enumerate() {
+                    let req_id = config.req_id + (t * config.count + j) * 2 + k as u64;
+                    let request = request_line(req_id, op, object_id, config);
+                    let sent = Instant::now();
+                    let result = send_with_retries(entry_addr, &request, false, config);
+                    latencies.push(sent.elapsed());
+                    let code = match result {
+                        Ok(response) if parse_reply(response.trim()).kind == expected_reply(op) => continue,
+                        Ok(response) => {
+                            println!("Thread {}: Expected OBJ {}, got: {}", t, expected_reply(op), response.trim());
+                            EXIT_MISMATCH
+                        },
+                        Err(failure) => {
+                            println!("Thread {}: No reply for reqID={}: {}", t, req_id, failure);
+                            EXIT_NO_REPLY
+                        }
+                    };
+                    failures += 1;
+                    exit_code = exit_code.max(code);
+                }
+            }
+            (latencies, failures, exit_code)
+        })).collect();
+        handles.into_iter().map(|h| h.join().unwrap_or_default()).collect()
+    });
+    let elapsed = started.elapsed();
+
+    let mut latencies: Vec<Duration> = results.iter().flat_map(|(l, _, _)| l.iter().copied()).collect();
+    latencies.sort();
+    let failures: u64 = results.iter().map(|(_, f, _)| f).sum();
+    let exit_code = results.iter().map(|(_, _, c)| *c).max().unwrap_or(0);
+    let percentile = |q: f64| {
+        let i = ((latencies.len().saturating_sub(1)) as f64 * q).round() as usize;
+        latencies.get(i).map_or(0.0, |d| d.as_secs_f64() * 1000.0)
+    };
+    println!(
+        "LOAD: {} threads x {} STORE+RETRIEVE pairs, {} requests in {:.2} s, {:.1} requests/s",
+        threads, config.count, latencies.len(), elapsed.as_secs_f64(),
+        latencies.len() as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
     );
+    println!("LATENCY: p50 {:.1} ms, p95 {:.1} ms, max {:.1} ms", percentile(0.5), percentile(0.95), percentile(1.0));
+    println!("FAILURES: {}", failures);
+    exit_code
+}
 
-    // Send the request message to the bootstrap server.
-    bs_stream.write_all(request_msg.as_bytes())?;
-    println!("{}", request_msg.trim());
-
-    let mut buffer = [0; 512];
-    let bytes_read = bs_stream.read(&mut buffer)?;
-    if bytes_read == 0 {
-        println!("No response received from bootstrap server.");
-        return Ok(());
-    }
-    let response = String::from_utf8_lossy(&buffer[..bytes_read]);
-    
-    // Process the response based on the test case.
-    if test_case == 3 {
-        // Expect a response containing "OBJ STORED".
-        if response.contains("OBJ STORED") {
-            println!("STORED: {}", object_id);
-        } else {
-            println!("Error storing object: {}", response.trim());
+/// Sends a request and reads the reply, trying again up to --retries more times when the
+/// connection fails, nothing comes back within --timeout-ms, or the reply is an ERROR. Each
+/// retry waits twice as long as the one before. The request is sent again unchanged, reqID
+/// included, so a peer that stored it the first time answers from its cache.
+fn send_with_retries(addr: &str, request: &str, list: bool, config: &Config) -> Result<String, String> {
+    let mut backoff = Duration::from_millis(INITIAL_BACKOFF_MS);
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let failure = match send_request(addr, request, list, config.timeout) {
+            Ok(response) if response.is_empty() => "no response".to_string(),
+            Ok(response) if response.starts_with("ERROR") => response.trim().to_string(),
+            Ok(response) => return Ok(response),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => {
+                format!("timed out after {} ms", config.timeout.as_millis())
+            },
+            Err(e) => e.to_string(),
+        };
+        if attempt > config.retries {
+            return Err(failure);
         }
-    } else if test_case == 4 {
-        // Expect a response containing "OBJ RETRIEVED".
-        if response.contains("OBJ RETRIEVED") {
-            println!("RETRIEVED: {}", object_id);
-        } else {
-            println!("Error retrieving object: {}", response.trim());
+        println!(
+            "Attempt {} of {} failed ({}), retrying in {} ms",
+            attempt, config.retries + 1, failure, backoff.as_millis()
+        );
+        thread::sleep(backoff);
+        backoff *= 2;
+    }
+}
+
+/// Sends a request on a new connection and reads the reply, giving up on any step that
+/// takes longer than `timeout`.
+fn send_request(addr: &str, request: &str, list: bool, timeout: Duration) -> std::io::Result<String> {
+    let socket_addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::NotFound, format!("no address for {}", addr))
+    })?;
+    let mut stream = TcpStream::connect_timeout(&socket_addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    stream.write_all(request.as_bytes())?;
+    read_reply(&mut stream, list)
+}
+
+/// Echoes a reply's fields, e.g. "STORED: 9 (clientID 3, reqID 1, peer n10)". A LIST reply
+/// reports its count instead of an object.
+fn print_reply(reply: &Reply, req_id: u64, line: &str) {
+    if reply.kind == "LIST" {
+        let count = field(line, "count").unwrap_or_default();
+        println!("LIST: {} objects (reqID {}, peer {})", count, req_id, reply.peer.as_deref().unwrap_or("?"));
+        return;
+    }
+    let version = reply.version.as_ref().map(|v| format!(", version {}", v)).unwrap_or_default();
+    println!(
+        "{}: {} (clientID {}, reqID {}, peer {}{})",
+        reply.kind,
+        reply.object_id.as_deref().unwrap_or("?"),
+        reply.client_id.as_deref().unwrap_or("?"),
+        req_id,
+        reply.peer.as_deref().unwrap_or("?"),
+        version,
+    );
+    if let Some(data) = &reply.data {
+        println!("DATA: {} bytes", data.len());
+    }
+}
+
+/// The kind of reply each operation should get when it goes through.
+fn expected_reply(op: &str) -> &'static str {
+    match op {
+        "STORE" => "STORED",
+        "RETRIEVE" => "RETRIEVED",
+        "DELETE" => "DELETED",
+        _ => "LIST",
+    }
+}
+
+/// Splits "OBJ STORED: objectID=9, clientID=3, peerID=n10" into its kind and fields. Any
+/// other line, e.g. an ERROR, becomes a reply of kind ERROR.
+fn parse_reply(line: &str) -> Reply {
+    let kind = match line.strip_prefix("OBJ ").and_then(|rest| rest.split_once(':')) {
+        Some((kind, _)) => kind.to_string(),
+        None => "ERROR".to_string(),
+    };
+    Reply {
+        kind,
+        object_id: field(line, "objectID"),
+        client_id: field(line, "clientID"),
+        peer: field(line, "peerID"),
+        data: field(line, "data").map(|data| unescape(&data)),
+        version: field(line, "version"),
+    }
+}
+
+fn field(line: &str, key: &str) -> Option<String> {
+    let (_, fields) = line.split_once(':')?;
+    fields.split(',').find_map(|part| {
+        let (k, v) = part.split_once('=')?;
+        (k.trim() == key).then(|| v.trim().to_string())
+    })
+}
+
+/// Data of a given size for --data-size, the alphabet over and over with a newline every 64
+/// bytes, so a reply that drops or reorders any of it doesn't match.
+fn generated_data(size: usize) -> String {
+    (0..size)
+        .map(|i| if i % 64 == 63 { '\n' } else { (b'a' + (i % 26) as u8) as char })
+        .collect()
+}
+
+/// Reads a reply until its last line has arrived or the other end closes the connection. A
+/// LIST reply spans a line per object and ends with its "OBJ LIST:" line, every other reply is
+/// one line.
+fn read_reply(stream: &mut TcpStream, list: bool) -> std::io::Result<String> {
+    let mut reader = BufReader::new(stream);
+    let mut response = String::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(response);
         }
-    } else if test_case == 5 {
-        // Expect a response containing "OBJ NOT FOUND".
-        if response.contains("OBJ NOT FOUND") {
-            println!("NOT FOUND: {}", object_id);
-        } else {
-            println!("Unexpected response: {}", response.trim());
+        response.push_str(&line);
+        if !list || line.starts_with("OBJ LIST:") {
+            return Ok(response);
         }
     }
-    
-    Ok(())
 }
 
 /// Initializes the application from command-line arguments.
 ///   -b : The hostname of the bootstrap server.
 ///   -d : (Optional) The number of seconds to wait before joining.
-///   -t : Test cases (3 == STORING, 4 == RETRIEVING, 5 == RETRIEVING A NON-EXISTED ITEM)
-fn init() -> (String, Option<u64>, u64) {
+///   -p : (Optional) The hostname of a peer to send the request to instead of the bootstrap server,
+///        or its host:port if it listens on another port than --peer-port.
+///   -t : Test cases, each standing for an --op and --object-id (3 == STORING, 4 == RETRIEVING,
+///        5 == RETRIEVING A NON-EXISTED ITEM, 6 == RETRIEVING THE ITEM STORED BY 3,
+///        7 == STORING AN ID ABOVE EVERY PEER'S, 8 == RETRIEVING THE ITEM STORED BY 7,
+///        9 == RETRIEVING AN ITEM PAST A CRASHED PEER)
+///   --op : Instead of -t, the operation to send: store, retrieve, delete or list. list needs -p
+///          and prints the objects that peer holds.
+///   --object-id : The object ID for --op, all but list need it.
+///   --client-id : (Optional) The client ID to send. Defaults to 3.
+///   --req-id : (Optional) The request ID to send. Defaults to the time in milliseconds, so
+///              each run's are new. A peer answers a STORE with a reqID it has already stored
+///              from the same client with the same reply, without storing it again.
+///   --repeat : (Optional) How many times to send the request, the request ID going up by one
+///              each time. Defaults to 1.
+///   --expect : (Optional) The reply to expect instead of the operation's usual one: stored,
+///              retrieved, deleted, not-found or list. The client exits nonzero on any other.
+///   --data : (Optional) The object's data. A store sends it, a retrieve checks the data that
+///            comes back is the same and exits with 1 if not.
+///   --data-size : (Optional) Instead of --data, generated data of this many bytes, up to 256 KiB.
+///   --expect-version : (Optional) The version the reply to a store or retrieve should report,
+///                      1 for an object stored once and one more for each store after. The
+///                      client exits with 1 on any other.
+///   --timeout-ms : (Optional) How long to wait to connect, send or read a reply before trying
+///                  again. Defaults to 10000.
+///   --retries : (Optional) How many times to try a request again after a timeout, a failed
+///               connection or an ERROR reply, waiting 250 ms before the first retry and twice
+///               as long before each after it. Defaults to 3.
+///   --concurrency : Instead of -t or --op, a load test: this many threads at once, each storing
+///                   and retrieving its own objects, starting at --object-id (1 by default).
+///                   Prints the requests per second, latencies and failures.
+///   --count : (Optional) How many objects each --concurrency thread stores and retrieves.
+///             Defaults to 10.
+///   --bootstrap-port : (Optional) The port the bootstrap server listens on. Defaults to 8888.
+///   --peer-port : (Optional) The port the -p peer listens on, unless -p gives one. Defaults to 9999.
+/// The client exits with 0 when every reply is the expected one, 2 when one is OBJ NOT FOUND
+/// instead, 3 when a request got no reply but timeouts or ERRORs, and 1 for any other mismatch.
+/// With several failures, the highest of these is used.
+fn init() -> Config {
     let args: Vec<String> = env::args().skip(1).collect();
-    let (hostname, delay_time, test_case) = args.chunks(2).fold(
-        (None, None, None),
-        |(hn, dt, objpath), pair| {
-            match pair {
-                [key, value] => match key.as_str() {
-                    "-b" => (Some(value.clone()), dt, objpath),
-                    "-d" => (hn, value.parse().ok(), objpath),
-                    "-t" => (hn, dt, value.parse().ok()),
-                    other => {
-                        eprintln!("init error: Unknown flag: {}", other);
+    let config = Config {
+        bootstrap: None,
+        delay_time: None,
+        peer: None,
+        test_case: None,
+        op: None,
+        object_id: None,
+        client_id: 3,
+        req_id: SystemTime::now().duration_since(UNIX_EPOCH).map_or(1, |t| t.as_millis() as u64),
+        repeat: 1,
+        expect: None,
+        data: None,
+        timeout: Duration::from_millis(DEFAULT_TIMEOUT_MS),
+        retries: DEFAULT_RETRIES,
+        concurrency: None,
+        count: 10,
+        expect_version: None,
+        bootstrap_port: DEFAULT_BOOTSTRAP_PORT,
+        peer_port: DEFAULT_PEER_PORT,
+    };
+    let mut config = args.chunks(2).fold(config, |mut config, pair| {
+        match pair {
+            [key, value] => match key.as_str() {
+                "-b" => config.bootstrap = Some(value.clone()),
+                "-d" => config.delay_time = value.parse().ok(),
+                "-p" => config.peer = Some(value.clone()),
+                "-t" => config.test_case = value.parse().ok(),
+                "--op" => config.op = Some(parse_op(value)),
+                "--object-id" => config.object_id = Some(parse_number(key, value)),
+                "--client-id" => config.client_id = parse_number(key, value),
+                "--req-id" => config.req_id = parse_number(key, value),
+                "--repeat" => config.repeat = parse_number(key, value),
+                "--expect" => config.expect = Some(parse_expect(value)),
+                "--data" => config.data = Some(value.clone()),
+                "--timeout-ms" => match parse_number(key, value) {
+                    0 => {
+                        eprintln!("init error: --timeout-ms has to be above 0");
+                        process::exit(1);
+                    },
+                    ms => config.timeout = Duration::from_millis(ms),
+                },
+                "--retries" => config.retries = parse_number(key, value),
+                "--concurrency" => config.concurrency = Some(parse_number(key, value)),
+                "--count" => config.count = parse_number(key, value),
+                "--expect-version" => config.expect_version = Some(parse_number(key, value)),
+                "--bootstrap-port" => config.bootstrap_port = parse_port(key, value),
+                "--peer-port" => config.peer_port = parse_port(key, value),
+                "--data-size" => {
+                    let size = parse_number(key, value) as usize;
+                    if size > MAX_DATA_SIZE {
+                        eprintln!("init error: --data-size can be at most {}", MAX_DATA_SIZE);
                         process::exit(1);
                     }
+                    config.data = Some(generated_data(size));
                 },
-                _ => {
-                    eprintln!("init error: Invalid arguments format");
+                other => {
+                    eprintln!("init error: Unknown flag: {}", other);
                     process::exit(1);
                 }
+            },
+            _ => {
+                eprintln!("init error: Invalid arguments format");
+                process::exit(1);
             }
-        },
-    );
-    let hostname = hostname.unwrap_or_else(|| {
+        }
+        config
+    });
+    if config.bootstrap.is_none() {
         eprintln!("init error: Missing -b flag for hostname");
         process::exit(1);
-    });
+    }
 
-    let test_case = test_case.unwrap_or_else(|| {
-        eprintln!("init error: Missing -t flag for test cases");
+    match (config.test_case, config.op) {
+        _ if config.concurrency.is_some() => {
+            if config.test_case.is_some() || config.op.is_some() {
+                eprintln!("init error: --concurrency can't be used with -t or --op");
+                process::exit(1);
+            }
+        },
+        (Some(_), Some(_)) => {
+            eprintln!("init error: -t and --op can't be used together");
+            process::exit(1);
+        },
+        (Some(test_case), None) => {
+            // Depending on the test case, set the operation and object ID.
+            let (op, object_id, expect) = match test_case {
+                3 => ("STORE", 9, None),    // Testcase 3: Store object with ID 3.
+                4 => ("RETRIEVE", 10, None), // Testcase 4: Retrieve object with ID 3.
+                5 => ("RETRIEVE", 69, Some("NOT FOUND")), // Testcase 5: Attempt to retrieve a non-existent object.
+                6 => ("RETRIEVE", 9, None),  // Testcase 6: Retrieve the object testcase 3 stored.
+                7 => ("STORE", 1000, None),  // Testcase 7: Store an object with an ID above every peer's.
+                8 => ("RETRIEVE", 1000, None), // Testcase 8: Retrieve the object testcase 7 stored.
+                9 => ("RETRIEVE", 50, None), // Testcase 9: Retrieve an object n50 loaded, past a crashed n10.
+                _ => {
+                    eprintln!("main: Unknown test case argument");
+                    process::exit(1);
+                }
+            };
+            config.op = Some(op);
+            config.object_id = Some(object_id);
+            config.expect = config.expect.or(expect);
+        },
+        (None, Some("LIST")) => {
+            if config.peer.is_none() {
+                eprintln!("init error: --op list needs -p to name the peer to list");
+                process::exit(1);
+            }
+        },
+        (None, Some(_)) => {
+            if config.object_id.is_none() {
+                eprintln!("init error: Missing --object-id flag for --op");
+                process::exit(1);
+            }
+        },
+        (None, None) => {
+            eprintln!("init error: Missing -t or --op flag");
+            process::exit(1);
+        }
+    }
+    config
+}
+
+fn parse_op(value: &str) -> &'static str {
+    match value {
+        "store" => "STORE",
+        "retrieve" => "RETRIEVE",
+        "delete" => "DELETE",
+        "list" => "LIST",
+        other => {
+            eprintln!("init error: Unknown operation for --op: {}", other);
+            process::exit(1);
+        }
+    }
+}
+
+fn parse_expect(value: &str) -> &'static str {
+    match value {
+        "stored" => "STORED",
+        "retrieved" => "RETRIEVED",
+        "deleted" => "DELETED",
+        "not-found" => "NOT FOUND",
+        "list" => "LIST",
+        other => {
+            eprintln!("init error: Unknown reply for --expect: {}", other);
+            process::exit(1);
+        }
+    }
+}
+
+fn parse_number(key: &str, value: &str) -> u64 {
+    value.parse().unwrap_or_else(|_| {
+        eprintln!("init error: {} takes a number, got {}", key, value);
         process::exit(1);
-    });
-    (hostname, delay_time, test_case)
+    })
+}
+
+// A port flag's value, which has to be a port number above 0
+fn parse_port(key: &str, value: &str) -> u16 {
+    match value.parse::<u16>() {
+        Ok(port) if port > 0 => port,
+        _ => {
+            eprintln!("init error: {} takes a port number, got {}", key, value);
+            process::exit(1);
+        }
+    }
 }
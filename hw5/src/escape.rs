@@ -0,0 +1,36 @@
+// Object data goes in a field of a request line and of an object store line, so the
+// characters that separate those are sent as %XX, like a URL. Spaces and tabs are too,
+// since both lines are trimmed before they're split, which would drop them from the ends
+// of the data. Shared by the peer and the client, which each include this file with
+// `mod escape;`.
+
+pub fn escape(data: &str) -> String {
+    let mut escaped = String::with_capacity(data.len());
+    for c in data.chars() {
+        match c {
+            '%' | ',' | '=' | ':' | '\n' | '\r' | ' ' | '\t' => escaped.push_str(&format!("%{:02X}", c as u8)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn unescape(escaped: &str) -> String {
+    let mut data = String::with_capacity(escaped.len());
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        if c == '%' {
+            let hex: String = chars.by_ref().take(2).collect();
+            match u8::from_str_radix(&hex, 16) {
+                Ok(byte) => data.push(byte as char),
+                Err(_) => {
+                    data.push('%');
+                    data.push_str(&hex);
+                }
+            }
+        } else {
+            data.push(c);
+        }
+    }
+    data
+}
@@ -1,32 +1,81 @@
 #[macro_use]
 extern crate lazy_static;
 
-use hostname;
 use serde::{Deserialize, Serialize};
 use std::env;
 use std::process;
-use std::fs;
-use std::net::{TcpStream, TcpListener};
-use std::io::{Read, Write};
+use std::net::{TcpStream, TcpListener, ToSocketAddrs};
+use std::io::{BufRead, BufReader, Read, Write};
 use std::thread;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, AtomicUsize, Ordering};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs::{self, OpenOptions};
 
-const TCP_PORT: u16 = 8888;
-const PEER_PORT: u16 = 9999;
+mod escape;
+use escape::{escape, unescape};
 
-lazy_static! {
-    static ref GLOBAL_PRED: Mutex<Option<String>> = Mutex::new(None);
-}
+const DEFAULT_BOOTSTRAP_PORT: u16 = 8888;
+const DEFAULT_PEER_PORT: u16 = 9999;
+const DEFAULT_REPLICAS: usize = 1;
+// Requests carry objects' data, so one is read up to this many bytes
+const MAX_REQUEST_BYTES: usize = 1 << 20;
+// The hops a request sent straight to a peer may take, the bootstrap server giving the ones
+// it routes the ring's size instead
+const DEFAULT_TTL: u64 = 64;
+// How many STOREs a peer remembers per client
+const RECENT_STORES_PER_CLIENT: usize = 64;
+// How many times a peer tries to connect to a new successor, a second apart
+const CONNECT_ATTEMPTS: u64 = 10;
+// How often the object store file is rewritten from OBJECTS when lines were added to it
+const COMPACT_SECS: u64 = 30;
+// How often a peer checks its successor's predecessor is itself, see stabilize
+const STABILIZE_SECS: u64 = 3;
+
+// How many peers hold each object, the owner and its next REPLICAS - 1 successors, set with -r
+static REPLICAS: AtomicUsize = AtomicUsize::new(DEFAULT_REPLICAS);
+// The port this peer listens on, set with --peer-port and sent in its JOIN
+static PEER_PORT: AtomicU16 = AtomicU16::new(DEFAULT_PEER_PORT);
+// How many of the bootstrap server's neighbor updates are still to be ignored, set with
+// --drop-updates to test that stabilize repairs the ring
+static DROP_UPDATES: AtomicU64 = AtomicU64::new(0);
+// How many more OBJ STORED replies the peer sends before it kills itself, set with
+// --kill-after-stores to test that an acknowledged STORE survives the crash. 0 is never.
+static KILL_AFTER_STORES: AtomicU64 = AtomicU64::new(0);
+// Set while the peer is sending LEAVE, so stabilize on its neighbors doesn't take it back
+static LEAVING: AtomicBool = AtomicBool::new(false);
+// Set by -s, after which the peer answers nothing
+static STALLED: AtomicBool = AtomicBool::new(false);
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 struct Object {
     client_id: u64,
     object_id: u64,
+    // Held for a predecessor that owns the object rather than owned here
+    #[serde(default)]
+    replica: bool,
+    #[serde(default)]
+    data: String,
+    // 1 when first stored, one more each time it is stored again
+    #[serde(default)]
+    version: u64,
 }
 
+// A connection to a neighbor's listener, kept open across requests. Whoever forwards on it
+// holds the lock from sending a request to reading its reply, so replies can't get mixed up.
+type Connection = Arc<Mutex<Option<BufReader<TcpStream>>>>;
+
+// Each neighbor is kept by name even when the connection to it failed, since the name is
+// what places it on the ring. A name is the peer's id and the address it listens on, as in
+// n5@n5:9999, see peer_id and peer_addr. Requests only ever go to the successor, so it is the
+// one neighbor with a connection.
+// The successor list holds the next few peers after this one, the successor first, for
+// routing around a successor that is down.
 struct Neighbors {
-    predecessor: Option<(String, TcpStream)>,
-    successor: Option<(String, TcpStream)>,
+    predecessor: Option<String>,
+    successor: Option<(String, Connection)>,
+    successors: Vec<String>,
 }
 
 impl Neighbors {
@@ -34,16 +83,40 @@ impl Neighbors {
         Neighbors {
             predecessor: None,
             successor: None,
+            successors: Vec::new(),
         }
     }
 }
 
+// The peer's command-line flags, see init
+struct Config {
+    bootstrap: Option<String>,
+    delay_time: Option<u64>,
+    object_store_path: Option<String>,
+    leave_after: Option<u64>,
+    crash_after: Option<u64>,
+    stall_after: Option<u64>,
+    replicas: usize,
+    bootstrap_port: u16,
+    id: Option<u64>,
+}
+
 lazy_static! {
     static ref OBJECTS: Mutex<Vec<Object>> = Mutex::new(Vec::new());
+    // The -o file, which OBJECTS is loaded from and every change to it is appended to
+    static ref OBJECT_STORE_PATH: Mutex<String> = Mutex::new(String::new());
+    // Held while appending to or rewriting the object store file
+    static ref STORE_FILE: Mutex<usize> = Mutex::new(0);
+    // The replies to each client's last few STOREs by reqID, to answer one sent again, e.g.
+    // by a forward that retried, without storing it twice
+    static ref RECENT_STORES: Mutex<HashMap<u64, VecDeque<(u64, String)>>> = Mutex::new(HashMap::new());
+    // This peer's host, as it joined, for the name it gives itself in NOTIFY and pred=
+    static ref MY_HOST: Mutex<String> = Mutex::new(String::new());
 }
 
 fn main() -> std::io::Result<()> {
-    let (bootstrap_hostname, delay_time, object_store_path) = init();
+    let config = init();
+    REPLICAS.store(config.replicas, Ordering::Relaxed);
 
     let local_hostname = hostname::get().unwrap_or_else(|_| {
         eprintln!("main: Unable to get hostname");
@@ -53,9 +126,19 @@ fn main() -> std::io::Result<()> {
         eprintln!("main: Unable to convert hostname to string");
         process::exit(1);
     });
-    let my_id: u64 = my_str.strip_prefix('n')
-                          .and_then(|s| s.parse().ok())
-                          .unwrap_or(0);
+    let my_id: u64 = config.id.or_else(|| peer_id(my_str)).unwrap_or_else(|| {
+        eprintln!("main: The hostname is {}, not n followed by a number like n5, so it gives no peer id. Pass --id instead", my_str);
+        process::exit(1);
+    });
+
+    *MY_HOST.lock().unwrap() = my_str.to_string();
+
+    let bootstrap = config.bootstrap.as_deref().unwrap_or_default();
+    if config.bootstrap_port == PEER_PORT.load(Ordering::Relaxed) && same_host(bootstrap, my_str) {
+        eprintln!("main: --bootstrap-port and --peer-port are both {} and {} is this host, so they can't both listen",
+                  config.bootstrap_port, bootstrap);
+        process::exit(1);
+    }
 
     let neighbors = Arc::new(Mutex::new(Neighbors::new()));
     {
@@ -67,79 +150,312 @@ fn main() -> std::io::Result<()> {
         });
     }
 
-    if let Some(delay) = delay_time {
+    if let Some(delay) = config.delay_time {
         thread::sleep(std::time::Duration::from_secs(delay));
     }
 
-    load_objects_from_file(&object_store_path);
+    load_objects_from_file(config.object_store_path.as_deref().unwrap_or_default());
+    thread::spawn(move || loop {
+        thread::sleep(std::time::Duration::from_secs(COMPACT_SECS));
+        match compact(false) {
+            Ok(Some(count)) => println!("Peer n{}: Rewrote the object store file with {} objects", my_id, count),
+            Ok(None) => {},
+            Err(e) => println!("Peer n{}: Error rewriting the object store file: {}", my_id, e),
+        }
+    });
 
-    let bootstrap_addr = format!("{}:{}", bootstrap_hostname, TCP_PORT);
-    let mut bs_stream = TcpStream::connect(bootstrap_addr)?;
+    let bootstrap_addr = format!("{}:{}", bootstrap, config.bootstrap_port);
+    let mut bs_stream = TcpStream::connect(&bootstrap_addr)?;
 
-    let join_msg = format!("JOIN:{}", my_str);
+    let join_msg = format!("JOIN:id={},name={},port={}\n", my_id, my_str, PEER_PORT.load(Ordering::Relaxed));
     bs_stream.write_all(join_msg.as_bytes())
              .expect("Failed to send JOIN message");
 
-    let mut buffer = [0u8; 512];
+    if let Some(after) = config.leave_after {
+        let nbrs = neighbors.clone();
+        thread::spawn(move || {
+            thread::sleep(std::time::Duration::from_secs(after));
+            leave(&nbrs, &bootstrap_addr, my_id);
+        });
+    }
+
+    // -c stops the peer without a word to anyone, for testing how the ring routes around it
+    if let Some(after) = config.crash_after {
+        thread::spawn(move || {
+            thread::sleep(std::time::Duration::from_secs(after));
+            println!("Peer n{}: Crashing", my_id);
+            process::exit(1);
+        });
+    }
+
+    // -s has the peer hang instead, still connected, for testing the bootstrap's heartbeats
+    if let Some(after) = config.stall_after {
+        thread::spawn(move || {
+            thread::sleep(std::time::Duration::from_secs(after));
+            println!("Peer n{}: Stalling", my_id);
+            STALLED.store(true, Ordering::Relaxed);
+        });
+    }
+
+    {
+        let nbrs = neighbors.clone();
+        thread::spawn(move || loop {
+            thread::sleep(std::time::Duration::from_secs(STABILIZE_SECS));
+            stall_if_stalled();
+            stabilize(&nbrs, my_id);
+        });
+    }
+
+    let reader = BufReader::new(bs_stream.try_clone()?);
+    read_bootstrap_messages(reader, &mut bs_stream, &neighbors, my_id)
+}
+
+// Handles the bootstrap server's messages, one per line, until it closes the connection. A
+// read can hold part of a message or several, e.g. a JOIN_REPLY and the update sent right
+// after it, and each line is handled once it is complete. PONGs go back on `bs_stream`.
+fn read_bootstrap_messages(
+    mut reader: impl BufRead,
+    bs_stream: &mut impl Write,
+    neighbors: &Arc<Mutex<Neighbors>>,
+    my_id: u64,
+) -> std::io::Result<()> {
     loop {
-        match bs_stream.read(&mut buffer) {
-            Ok(0) => {
+        let message = match read_message(&mut reader) {
+            Ok(message) if message.is_empty() => {
                 println!("Bootstrap connection closed.");
-                break;
+                return Ok(());
+            },
+            Ok(message) => message,
+            Err(e) => {
+                println!("Failed to receive data: {}", e);
+                return Ok(());
+            }
+        };
+        let response = message.trim_end();
+        stall_if_stalled();
+        if response == "PING" {
+            // The bootstrap's heartbeat, answered right away
+            bs_stream.write_all(b"PONG\n")?;
+        } else if response.starts_with("JOIN_REPLY:") {
+            if let Some((pred, succ)) = parse_join_reply(response) {
+                update_neighbor(neighbors, my_id, "predecessor", &pred);
+                update_neighbor(neighbors, my_id, "successor", &succ);
+            }
+        } else if response.starts_with("Predecessor:") {
+            if DROP_UPDATES.load(Ordering::Relaxed) > 0 {
+                DROP_UPDATES.fetch_sub(1, Ordering::Relaxed);
+                println!("Peer n{}: Dropping neighbor update: {}", my_id, response);
+                continue;
+            }
+            if let Some((direction, new_peer)) = parse_update(response) {
+                update_neighbor(neighbors, my_id, &direction, &new_peer);
+            }
+        
+            if let Some((direction, new_peer)) = parse_successor(response) {
+                update_neighbor(neighbors, my_id, &direction, &new_peer);
             }
-            Ok(bytes_read) => {
-                let response = String::from_utf8_lossy(&buffer[..bytes_read]).to_string();
-                if response.starts_with("JOIN_REPLY:") {
-                    if let Some((pred, succ)) = parse_join_reply(&response) {
-                        if my_id == 1 {
-                            *GLOBAL_PRED.lock().unwrap() = Some(pred.clone());
-                        }
-                        update_neighbor(&neighbors, my_id, "predecessor", &pred);
-                        update_neighbor(&neighbors, my_id, "successor", &succ);
-                    }
-                } else if response.starts_with("UPDATE:") {
-                    if let Some((direction, new_peer)) = parse_update(&response) {
-                        update_neighbor(&neighbors, my_id, &direction, &new_peer);
-                    }
-                    
-                } else if response.contains("Predecessor:") && response.contains("Successor:") {
-                    if let Some((direction, new_peer)) = parse_update(&response) {
-                        update_neighbor(&neighbors, my_id, &direction, &new_peer);
-                    }
-                    
-                    if let Some((direction, new_peer)) = parse_successor(&response) {
-                        update_neighbor(&neighbors, my_id, &direction, &new_peer);
-                    }
 
-                    print_neighbor_status(&neighbors);
-                } else if response.starts_with("REQUEST:") {
-                    let reply = handle_request(&response, neighbors.clone(), my_id);
-                    bs_stream.write_all(reply.as_bytes()).unwrap();
-                    bs_stream.flush().unwrap();
+            let old_successors = std::mem::replace(
+                &mut neighbors.lock().unwrap().successors,
+                parse_successors(response),
+            );
+            if REPLICAS.load(Ordering::Relaxed) > 1 {
+                let nbrs = neighbors.clone();
+                thread::spawn(move || rereplicate(&nbrs, my_id, &old_successors));
+            }
+
+            print_neighbor_status(neighbors);
+        } else {
+            println!("Peer n{}: Unknown message from bootstrap: {}", my_id, response);
+        }
+    }
+}
+
+// Leaves the ring for -l: hands every object this peer owns to its successor, which owns
+// them once this peer is gone, then has the bootstrap server take it out of the ring and
+// exits. If the successor doesn't take every object, the peer stays so nothing is lost.
+// Replicas stay behind, their owners replicate again once they hear their successors changed.
+fn leave(neighbors: &Arc<Mutex<Neighbors>>, bootstrap_addr: &str, my_id: u64) {
+    let succ = neighbors.lock().unwrap().successor.as_ref().map(|(s, _)| s.clone());
+    let objects: Vec<Object> = OBJECTS.lock().unwrap().iter().filter(|o| !o.replica).cloned().collect();
+    match succ {
+        Some(succ) => {
+            println!("Peer n{}: Leaving, handing {} objects to {}", my_id, objects.len(), succ);
+            for obj in &objects {
+                let reply = send_to(neighbors, &succ, &object_request("HANDOFF", obj), my_id).unwrap_or_else(|e| e);
+                if !reply.starts_with("OBJ STORED") {
+                    println!("Peer n{}: Handoff of objectID={}, clientID={} to {} failed: {}",
+                             my_id, obj.object_id, obj.client_id, succ, reply.trim());
+                    println!("Peer n{}: Staying in the ring", my_id);
+                    return;
                 }
             }
-            Err(e) => {
-                println!("Failed to receive data: {}", e);
-                break;
+        },
+        None => {
+            println!("Peer n{}: Leaving with no successor, dropping {} objects", my_id, objects.len());
+        }
+    }
+
+    LEAVING.store(true, Ordering::Relaxed);
+    let reply = TcpStream::connect(bootstrap_addr).and_then(|mut stream| {
+        stream.write_all(format!("LEAVE:id={}\n", my_id).as_bytes())?;
+        read_message(&mut BufReader::new(stream))
+    });
+    match reply {
+        Ok(reply) if reply.starts_with("LEAVE_OK") => {
+            println!("Peer n{}: Left the ring", my_id);
+            process::exit(0);
+        },
+        Ok(reply) => println!("Peer n{}: Bootstrap refused the leave: {}", my_id, reply.trim()),
+        Err(e) => println!("Peer n{}: Failed to send LEAVE to bootstrap: {}", my_id, e),
+    }
+    LEAVING.store(false, Ordering::Relaxed);
+}
+
+// Runs after every neighbor update when -r is above 1. Replicas of keys this peer owns under
+// its new predecessor become its own, and if its successors or its objects changed, it sends
+// every object it owns to its successors again so each has REPLICAS copies. Peers that are
+// no longer among the successors replicated to drop their replicas, so a later delete
+// doesn't leave one behind.
+fn rereplicate(neighbors: &Arc<Mutex<Neighbors>>, my_id: u64, old_successors: &[String]) {
+    let (pred_id, _) = get_owned_range(neighbors, my_id);
+    let mut promoted = false;
+    let owned: Vec<Object> = {
+        let mut objects = OBJECTS.lock().unwrap();
+        for obj in objects.iter_mut().filter(|o| o.replica && owns(o.object_id, pred_id, my_id)) {
+            println!("Peer n{}: Taking over objectID={}, clientID={} from its replica",
+                     my_id, obj.object_id, obj.client_id);
+            obj.replica = false;
+            promoted = true;
+        }
+        objects.iter().filter(|o| !o.replica).cloned().collect()
+    };
+    let copies = REPLICAS.load(Ordering::Relaxed) - 1;
+    let successors = neighbors.lock().unwrap().successors.clone();
+    let targets: Vec<&String> = successors.iter().take(copies).collect();
+    for dropped in old_successors.iter().take(copies).filter(|s| !targets.contains(s)) {
+        for obj in &owned {
+            unreplicate_on(neighbors, dropped, obj.object_id, obj.client_id, my_id);
+        }
+    }
+    if successors != old_successors || promoted {
+        replicate(&owned, neighbors, my_id);
+    }
+}
+
+// Sends REPLICATE for each object to the first REPLICAS - 1 peers on the successor list.
+// A replica that doesn't make it is logged, the object is still stored here.
+fn replicate(objects: &[Object], neighbors: &Arc<Mutex<Neighbors>>, my_id: u64) {
+    let copies = REPLICAS.load(Ordering::Relaxed) - 1;
+    if copies == 0 || objects.is_empty() {
+        return;
+    }
+    let targets: Vec<String> = neighbors.lock().unwrap().successors.iter().take(copies).cloned().collect();
+    if targets.len() < copies {
+        println!("Peer n{}: Only {} successors known, keeping {} copies instead of {}",
+                 my_id, targets.len(), targets.len() + 1, copies + 1);
+    }
+    for target in &targets {
+        let mut replicated = 0;
+        for obj in objects {
+            match send_to(neighbors, target, &object_request("REPLICATE", obj), my_id) {
+                Ok(reply) if reply.starts_with("OBJ REPLICATED") => replicated += 1,
+                Ok(reply) => println!("Peer n{}: Replicating objectID={}, clientID={} to {} failed: {}",
+                                      my_id, obj.object_id, obj.client_id, target, reply.trim()),
+                Err(e) => println!("Peer n{}: Replicating objectID={}, clientID={} to {} failed: {}",
+                                   my_id, obj.object_id, obj.client_id, target, e.trim()),
             }
         }
+        println!("Peer n{}: Replicated {} objects to {}", my_id, replicated, target);
     }
-    Ok(())
 }
 
+// A request carrying an object to another peer, data included.
+fn object_request(op: &str, obj: &Object) -> String {
+    format!(
+        "REQUEST: reqID=0, op={}, objectID={}, clientID={}, version={}, data={}\n",
+        op, obj.object_id, obj.client_id, obj.version, escape(&obj.data)
+    )
+}
+
+// Reads one newline-terminated message, which can take several reads for a large object,
+// up to MAX_REQUEST_BYTES. It is empty once the other end has closed the connection.
+fn read_message(reader: &mut impl BufRead) -> std::io::Result<String> {
+    let mut message = String::new();
+    reader.take(MAX_REQUEST_BYTES as u64).read_line(&mut message)?;
+    Ok(message)
+}
+
+// Blocks forever once -s has stalled the peer, leaving whatever connection the caller holds open.
+fn stall_if_stalled() {
+    while STALLED.load(Ordering::Relaxed) {
+        thread::sleep(std::time::Duration::from_secs(60));
+    }
+}
+
+// Loads OBJECTS from the -o file, which later changes are appended to, so a restarted peer
+// with the same -o has what it had before. A missing file is created empty.
 fn load_objects_from_file(object_store_path: &str) {
-    match std::fs::read_to_string(object_store_path) {
+    *OBJECT_STORE_PATH.lock().unwrap() = object_store_path.to_string();
+    match fs::read_to_string(object_store_path) {
         Ok(data) => {
-            let mut loaded_objects = Vec::new();
+            let mut loaded_objects: Vec<Object> = Vec::new();
             
-            for line in data.lines() {
-                if let Some(obj) = parse_object_line(line) {
-                    loaded_objects.push(obj);
+            // Every line is written with its newline, so a last line without one was cut off
+            // by a crash while it was written, and was never acknowledged
+            let (complete, cut_off) = data.split_at(data.rfind('\n').map_or(0, |i| i + 1));
+            if !cut_off.trim().is_empty() {
+                println!("Dropping the cut-off last line of {}: {}", object_store_path, cut_off);
+            }
+            let mut bad_lines = 0;
+            for (number, line) in complete.lines().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
                 }
+                // A deleted object is recorded as its line with ::deleted after it, and an
+                // object stored again is kept once, at its highest version
+                let (line, deleted) = match line.trim().strip_suffix("::deleted") {
+                    Some(line) => (line, true),
+                    None => (line, false),
+                };
+                match parse_object_line(line) {
+                    Ok(obj) if deleted => {
+                        loaded_objects.retain(|o| o.object_id != obj.object_id || o.client_id != obj.client_id);
+                    },
+                    Ok(obj) => match loaded_objects.iter_mut().find(|o| o.object_id == obj.object_id && o.client_id == obj.client_id) {
+                        Some(existing) => {
+                            existing.replica &= obj.replica;
+                            if obj.version >= existing.version {
+                                existing.data = obj.data;
+                                existing.version = obj.version;
+                            }
+                        },
+                        None => loaded_objects.push(obj),
+                    },
+                    Err(e) => {
+                        bad_lines += 1;
+                        println!("Skipping line {} of {}: {}", number + 1, object_store_path, e);
+                    }
+                }
+            }
+            if bad_lines > 0 {
+                println!("Skipped {} unreadable lines of {}, loaded {} objects", bad_lines, object_store_path, loaded_objects.len());
             }
             
-            let mut objects = OBJECTS.lock().unwrap();
-            *objects = loaded_objects;
+            *OBJECTS.lock().unwrap() = loaded_objects;
+            // The next line appended would be joined onto a cut-off one, so the file is
+            // rewritten without it first
+            if !cut_off.is_empty() {
+                if let Err(e) = compact(true) {
+                    eprintln!("Unable to rewrite object store file at {}: {}", object_store_path, e);
+                }
+            }
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            println!("Object store file {} doesn't exist, creating it", object_store_path);
+            if let Err(e) = fs::File::create(object_store_path) {
+                eprintln!("Unable to create object store file at {}: {}", object_store_path, e);
+            }
         },
         Err(e) => {
             eprintln!("Unable to read object store file at {}: {}", object_store_path, e);
@@ -147,35 +463,104 @@ fn load_objects_from_file(object_store_path: &str) {
     }
 }
 
-fn parse_object_line(line: &str) -> Option<Object> {
-    let parts: Vec<&str> = line.trim().split("::").collect();
-    if parts.len() != 2 {
-        println!("Invalid object line format: {}", line);
-        return None;
+// Appends a line to the object store file and syncs it to disk before returning, so a STORE
+// or DELETE is only acknowledged once it would survive a crash.
+fn append_record(record: &str) -> std::io::Result<()> {
+    let mut appended = STORE_FILE.lock().unwrap();
+    let path = OBJECT_STORE_PATH.lock().unwrap().clone();
+    let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+    file.write_all(format!("{}\n", record).as_bytes())?;
+    file.flush()?;
+    file.sync_data()?;
+    *appended += 1;
+    Ok(())
+}
+
+// Rewrites the object store file from OBJECTS, a line per object, which drops the lines of
+// deleted objects and old versions. It is written to a temporary file renamed over the old
+// one, so a crash midway leaves the old file whole. Unless forced, a file nothing was
+// appended to since it was last rewritten is left alone. Returns the number of objects
+// written, or None when it was left alone.
+fn compact(force: bool) -> std::io::Result<Option<usize>> {
+    let mut appended = STORE_FILE.lock().unwrap();
+    if *appended == 0 && !force {
+        return Ok(None);
     }
-    
-    match parts[0].parse::<u64>() {
-        Ok(client_id) => {
-            match parts[1].parse::<u64>() {
-                Ok(object_id) => {
-                    Some(Object { client_id, object_id })
-                },
-                Err(e) => {
-                    println!("Error parsing object_id in line {}: {}", line, e);
-                    None
-                }
-            }
-        },
-        Err(e) => {
-            println!("Error parsing client_id in line {}: {}", line, e);
-            None
+    let path = OBJECT_STORE_PATH.lock().unwrap().clone();
+    let lines: Vec<String> = OBJECTS.lock().unwrap().iter().map(|obj| format!("{}\n", object_line(obj))).collect();
+    let temp = format!("{}.tmp", path);
+    let mut file = fs::File::create(&temp)?;
+    file.write_all(lines.concat().as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temp, &path)?;
+    *appended = 0;
+    Ok(Some(lines.len()))
+}
+
+// The object store file line for an object, read back by parse_object_line.
+fn object_line(obj: &Object) -> String {
+    let marker = if obj.replica { "::replica" } else { "" };
+    let version_field = if obj.version > 1 {
+        format!("::version={}", obj.version)
+    } else {
+        String::new()
+    };
+    let data_field = if obj.data.is_empty() {
+        String::new()
+    } else {
+        format!("::data={}", escape(&obj.data))
+    };
+    format!("{}::{}{}{}{}", obj.client_id, obj.object_id, marker, version_field, data_field)
+}
+
+// Why a line of the object store file couldn't be read
+#[derive(Debug)]
+enum ObjectLineError {
+    MissingObjectId,
+    BadClientId(std::num::ParseIntError),
+    BadObjectId(std::num::ParseIntError),
+    UnknownPart(String),
+}
+
+impl fmt::Display for ObjectLineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ObjectLineError::MissingObjectId => write!(f, "no objectID after the clientID"),
+            ObjectLineError::BadClientId(e) => write!(f, "bad clientID: {}", e),
+            ObjectLineError::BadObjectId(e) => write!(f, "bad objectID: {}", e),
+            ObjectLineError::UnknownPart(part) => write!(f, "unknown part {}", part),
+        }
+    }
+}
+
+// An object line is clientID::objectID, with ::replica after it for a replica,
+// ::version=<n> for an object stored more than once and ::data=<escaped data> for an
+// object with data.
+fn parse_object_line(line: &str) -> Result<Object, ObjectLineError> {
+    let parts: Vec<&str> = line.trim().split("::").collect();
+    let mut replica = false;
+    let mut data = String::new();
+    let mut version = 1;
+    for part in parts.iter().skip(2) {
+        if let Some(escaped) = part.strip_prefix("data=") {
+            data = unescape(escaped);
+        } else if let Some(Ok(n)) = part.strip_prefix("version=").map(str::parse) {
+            version = n;
+        } else if *part == "replica" {
+            replica = true;
+        } else {
+            return Err(ObjectLineError::UnknownPart(part.to_string()));
         }
     }
+    let client_id = parts[0].parse::<u64>().map_err(ObjectLineError::BadClientId)?;
+    let object_id = parts.get(1).ok_or(ObjectLineError::MissingObjectId)?
+        .parse::<u64>().map_err(ObjectLineError::BadObjectId)?;
+    Ok(Object { client_id, object_id, replica, data, version })
 }
 
 fn parse_successor(msg: &str) -> Option<(String, String)> {
     let tokens: Vec<&str> = msg.trim().split(',').collect();
-    if tokens.len() != 2 {
+    if tokens.len() < 2 {
         return None;
     }
     let second = tokens[1].trim();
@@ -186,9 +571,18 @@ fn parse_successor(msg: &str) -> Option<(String, String)> {
     None
 }
 
+// The successor list trails the neighbor update as "Successors: n10@n10:9999 n50@n50:9999",
+// and is left out when the peer is alone on the ring.
+fn parse_successors(msg: &str) -> Vec<String> {
+    msg.trim().split(',')
+       .find_map(|token| token.trim().strip_prefix("Successors:"))
+       .map(|list| list.split_whitespace().map(|s| s.to_string()).collect())
+       .unwrap_or_default()
+}
+
 fn parse_update(msg: &str) -> Option<(String, String)> {
     let tokens: Vec<&str> = msg.trim().split(',').collect();
-    if tokens.len() != 2 {
+    if tokens.len() < 2 {
         return None;
     }
     let first = tokens[0].trim();
@@ -202,7 +596,7 @@ fn parse_update(msg: &str) -> Option<(String, String)> {
 
 // Listens for peer connections and handles incoming requests.
 fn peer_listener(neighbors: Arc<Mutex<Neighbors>>, my_id: u64) -> std::io::Result<()> {
-    let listener = TcpListener::bind(format!("0.0.0.0:{}", PEER_PORT))?;
+    let listener = TcpListener::bind(format!("0.0.0.0:{}", PEER_PORT.load(Ordering::Relaxed)))?;
     
     for stream in listener.incoming() {
         match stream {
@@ -218,18 +612,36 @@ fn peer_listener(neighbors: Arc<Mutex<Neighbors>>, my_id: u64) -> std::io::Resul
                         println!("Peer n{}: Warning: Could not set write timeout: {}", thread_my_id, e);
                     }
                     
-                    let mut buf = [0u8; 1024];
-                    match stream.read(&mut buf) {
-                        Ok(n) if n > 0 => {
-                            let msg = String::from_utf8_lossy(&buf[..n]).to_string();
-                            
-                            if msg.starts_with("REQUEST:") {
-                                let response = handle_request(&msg, neighbors_clone, thread_my_id);
+                    // A predecessor keeps its connection open and sends one request after
+                    // another on it, each answered before the next is sent, until it closes
+                    // the connection or leaves it idle past the read timeout
+                    let mut reader = match stream.try_clone() {
+                        Ok(clone) => BufReader::new(clone),
+                        Err(e) => {
+                            println!("Peer n{}: Error cloning stream: {}", thread_my_id, e);
+                            return;
+                        }
+                    };
+                    let mut served = 0;
+                    loop {
+                        stall_if_stalled();
+                        match read_message(&mut reader) {
+                            Ok(msg) if !msg.is_empty() => {
+                                served += 1;
                                 
+                                let response = if msg.starts_with("REQUEST:") {
+                                    handle_request(&msg, neighbors_clone.clone(), thread_my_id)
+                                } else if let Some(reply) = neighbor_message(&msg, &neighbors_clone, thread_my_id) {
+                                    reply
+                                } else {
+                                    println!("Peer n{}: Received unknown message type: {}", thread_my_id, msg.trim());
+                                    continue;
+                                };
+                                    
                                 let mut retry_count = 0;
                                 let max_retries = 3;
                                 let mut success = false;
-                                
+                                    
                                 while retry_count < max_retries && !success {
                                     match stream.write_all(response.as_bytes()) {
                                         Ok(_) => {
@@ -253,23 +665,34 @@ fn peer_listener(neighbors: Arc<Mutex<Neighbors>>, my_id: u64) -> std::io::Resul
                                         }
                                     }
                                 }
-                                
+                                    
                                 if !success {
                                     println!("Peer n{}: Failed to send response after {} attempts", 
                                              thread_my_id, max_retries);
+                                    break;
                                 }
-                            } else {
-                                println!("Peer n{}: Received unknown message type: {}", thread_my_id, msg.trim());
-                            }
-                        },
-                        Ok(_) => {},
-                        Err(e) => {
-                            if e.kind() != std::io::ErrorKind::WouldBlock && 
-                               e.kind() != std::io::ErrorKind::TimedOut {
-                                println!("Peer n{}: Error reading from stream: {}", thread_my_id, e);
+                                // Only this peer's own STOREs count, not the replies it passes back
+                                // for the peers after it
+                                if response.starts_with("OBJ STORED: ")
+                                    && response.contains(&format!("peerID=n{},", thread_my_id))
+                                    && KILL_AFTER_STORES.load(Ordering::Relaxed) > 0
+                                    && KILL_AFTER_STORES.fetch_sub(1, Ordering::Relaxed) == 1 {
+                                    kill_self(thread_my_id);
+                                }
+                            },
+                            Ok(_) => break,
+                            Err(e) => {
+                                if e.kind() != std::io::ErrorKind::WouldBlock && 
+                                   e.kind() != std::io::ErrorKind::TimedOut {
+                                    println!("Peer n{}: Error reading from stream: {}", thread_my_id, e);
+                                }
+                                break;
                             }
                         }
                     }
+                    if served > 1 {
+                        println!("Peer n{}: Served {} requests on one connection", thread_my_id, served);
+                    }
                 });
             },
             Err(e) => {
@@ -283,14 +706,22 @@ fn peer_listener(neighbors: Arc<Mutex<Neighbors>>, my_id: u64) -> std::io::Resul
     Ok(())
 }
 
-// Handles requests using CHORD rule: if object_id ≤ my_id, handle locally; otherwise, forward to successor.
+// Handles requests using the Chord rule: the object's key is its objectID, and the key
+// belongs to its successor on the ring, the first peer whose id is >= the key. A peer
+// handles the keys after its predecessor's id up to its own locally and forwards the
+// rest to its successor, so the same object ends up at the same peer whichever peer the
+// request enters at.
 fn handle_request(request: &str, neighbors: Arc<Mutex<Neighbors>>, my_id: u64) -> String {
     let content = request.trim().strip_prefix("REQUEST:").unwrap_or("");
     let parts: Vec<&str> = content.split(',').collect();
-    let mut req_id = 0;
     let mut op = "";
     let mut object_id = 0;
     let mut client_id = 0;
+    let mut detour_pred = None;
+    let mut data = String::new();
+    let mut ttl = DEFAULT_TTL;
+    let mut req_id = None;
+    let mut version = None;
     
     for part in parts {
         let kv: Vec<&str> = part.split('=').collect();
@@ -298,161 +729,472 @@ fn handle_request(request: &str, neighbors: Arc<Mutex<Neighbors>>, my_id: u64) -
             let key = kv[0].trim();
             let value = kv[1].trim();
             match key {
-                "reqID" => req_id = value.parse().unwrap_or(0),
                 "op" => op = value,
+                "reqID" => req_id = value.parse().ok(),
                 "objectID" => object_id = value.parse().unwrap_or(0),
                 "clientID" => client_id = value.parse().unwrap_or(0),
+                "pred" => detour_pred = peer_id(value),
+                "data" => data = unescape(value),
+                "version" => version = value.parse().ok(),
+                "ttl" => ttl = value.parse().unwrap_or(DEFAULT_TTL),
                 _ => {},
             }
         }
     }
+
+    // Each forward takes one off the ttl, so a request that runs out has gone round the ring
+    // without finding its owner
+    if ttl == 0 {
+        println!("Peer n{}: Dropping objectID={}, its ttl ran out", my_id, object_id);
+        return format!("ERROR: routing loop detected for objectID={}\n", object_id);
+    }
     
-    if object_id <= my_id {
-        if op == "STORE" {
-            let new_object = Object {
+    // A LIST asks this peer alone what it holds, so it is never forwarded
+    if op == "LIST" {
+        let objects = OBJECTS.lock().unwrap();
+        let mut reply = String::new();
+        for obj in objects.iter() {
+            let marker = if obj.replica { "::replica" } else { "" };
+            reply.push_str(&format!("{}::{}{}\n", obj.client_id, obj.object_id, marker));
+        }
+        reply.push_str(&format!("OBJ LIST: count={}, peerID=n{}\n", objects.len(), my_id));
+        return reply;
+    }
+
+    // A HANDOFF comes from a departing predecessor, whose objects are this peer's from now
+    // on, and a REPLICATE or UNREPLICATE from an owner a few places back, so these are handled
+    // here without looking at the ring. A request that was routed around a dead peer names the peer that
+    // sent it as pred, which takes the dead peer's place.
+    let pred_id = detour_pred.or_else(|| get_owned_range(&neighbors, my_id).0);
+    if op == "HANDOFF" || op == "REPLICATE" || op == "UNREPLICATE" || owns(object_id, pred_id, my_id) {
+        if let Some(reply) = req_id.filter(|_| op == "STORE").and_then(|r| recent_store(client_id, r)) {
+            println!("Peer n{}: reqID={} from clientID={} was already stored, answering it again", my_id, req_id.unwrap_or(0), client_id);
+            return reply;
+        }
+        if op == "STORE" || op == "HANDOFF" || op == "REPLICATE" {
+            let replica = op == "REPLICATE";
+            let mut new_object = Object {
                 client_id,
                 object_id,
+                replica,
+                data,
+                version: version.unwrap_or(1),
             };
             
             {
+                // An object already here is kept once, a replica becoming the owned copy, and
+                // takes the data it was stored with last. A STORE of it is a new version, while
+                // a HANDOFF or REPLICATE brings the version the owner has.
                 let mut objects = OBJECTS.lock().unwrap();
-                objects.push(new_object.clone());
-            }
-            
-            {
-                use std::fs::OpenOptions;
-                match OpenOptions::new().append(true).create(true).open("Objects.txt") {
-                    Ok(mut file) => {
-                        use std::io::Write;
-                        if let Err(e) = writeln!(file, "{}::{}", client_id, object_id) {
-                            println!("Peer n{}: Error writing to Objects.txt: {}", my_id, e);
-                            return format!("ERROR: Failed to store object: {}\n", e);
+                match objects.iter_mut().find(|o| o.object_id == object_id && o.client_id == client_id) {
+                    Some(existing) => {
+                        if op == "STORE" {
+                            new_object.version = existing.version + 1;
                         }
+                        existing.replica &= replica;
+                        existing.data = new_object.data.clone();
+                        existing.version = new_object.version;
                     },
-                    Err(e) => {
-                        println!("Peer n{}: Error opening Objects.txt: {}", my_id, e);
-                        return format!("ERROR: Failed to open object store: {}\n", e);
-                    }
+                    None => objects.push(new_object.clone()),
                 }
             }
             
-            format!("OBJ STORED: objectID={}, clientID={}, peerID=n{}\n", object_id, client_id, my_id)
-        } else if op == "RETRIEVE" {
-            let object_exists = {
-                let objects = OBJECTS.lock().unwrap();
-                objects.iter().any(|obj| obj.object_id == object_id && obj.client_id == client_id)
-            };
+            if let Err(e) = append_record(&object_line(&new_object)) {
+                println!("Peer n{}: Error writing to {}: {}", my_id, OBJECT_STORE_PATH.lock().unwrap(), e);
+                return format!("ERROR: Failed to store object: {}\n", e);
+            }
             
-            if object_exists {
-                format!("OBJ RETRIEVED: objectID={}, clientID={}, peerID=n{}\n", object_id, client_id, my_id)
+            if replica {
+                return format!("OBJ REPLICATED: objectID={}, clientID={}, peerID=n{}\n", object_id, client_id, my_id);
+            }
+            let version = new_object.version;
+            replicate(&[new_object], &neighbors, my_id);
+            let reply = format!("OBJ STORED: objectID={}, clientID={}, peerID=n{}, version={}\n", object_id, client_id, my_id, version);
+            if let Some(req_id) = req_id.filter(|_| op == "STORE") {
+                remember_store(client_id, req_id, &reply);
+            }
+            reply
+        } else if op == "RETRIEVE" {
+            if let Some(obj) = find_object(object_id, client_id) {
+                retrieved(&obj, my_id)
             } else {
                 format!("OBJ NOT FOUND: objectID={}, clientID={}, peerID=n{}\n", object_id, client_id, my_id)
             }
+        } else if op == "DELETE" || op == "UNREPLICATE" {
+            match remove_object(object_id, client_id, op == "UNREPLICATE") {
+                Ok(true) => {
+                    if op == "DELETE" {
+                        unreplicate(object_id, client_id, &neighbors, my_id);
+                    }
+                    format!("OBJ DELETED: objectID={}, clientID={}, peerID=n{}\n", object_id, client_id, my_id)
+                },
+                Ok(false) => format!("OBJ NOT FOUND: objectID={}, clientID={}, peerID=n{}\n", object_id, client_id, my_id),
+                Err(e) => {
+                    println!("Peer n{}: Error writing to {}: {}", my_id, OBJECT_STORE_PATH.lock().unwrap(), e);
+                    format!("ERROR: Failed to delete object: {}\n", e)
+                }
+            }
         } else {
             println!("Peer n{}: Unknown operation: {}", my_id, op);
             "ERROR: Unknown operation\n".to_string()
         }
+    } else if let Some(obj) = find_object(object_id, client_id).filter(|_| op == "RETRIEVE") {
+        // Any peer holding the object can answer a RETRIEVE, a replica as well as the owner
+        println!("Peer n{}: Answering for objectID={}, clientID={} from its replica", my_id, object_id, client_id);
+        retrieved(&obj, my_id)
     } else {
-        let succ;
+        let candidates;
         {
             let nbrs = neighbors.lock().unwrap();
             if let Some((s, _)) = &nbrs.successor {
-                succ = s.clone();
+                let mut list = vec![s.clone()];
+                list.extend(nbrs.successors.iter().filter(|p| *p != s).cloned());
+                candidates = list;
             } else {
                 return "ERROR: No successor to forward request\n".to_string();
             }
         }
-        
-        let peer_addr = format!("{}:{}", succ, PEER_PORT);
-        
-        let mut retry_count = 0;
-        let max_retries = 3;
-        let mut response = format!("ERROR: Failed to connect to successor {} after {} attempts\n", succ, max_retries);
-        
-        while retry_count < max_retries {
-            match TcpStream::connect(&peer_addr) {
-                Ok(mut succ_stream) => {
-                    if let Err(e) = succ_stream.set_write_timeout(Some(std::time::Duration::from_secs(10))) {
-                        println!("Peer n{}: Warning: Could not set write timeout: {}", my_id, e);
-                    }
-                    if let Err(e) = succ_stream.set_read_timeout(Some(std::time::Duration::from_secs(10))) {
-                        println!("Peer n{}: Warning: Could not set read timeout: {}", my_id, e);
-                    }
-                    
-                    match succ_stream.write_all(request.as_bytes()) {
-                        Ok(_) => {
-                            match succ_stream.flush() {
-                                Ok(_) => {
-                                    let mut buf = [0u8; 1024];
-                                    match succ_stream.read(&mut buf) {
-                                        Ok(n) if n > 0 => {
-                                            response = String::from_utf8_lossy(&buf[..n]).to_string();
-                                            break;
-                                        },
-                                        Ok(_) => {
-                                            retry_count += 1;
-                                            thread::sleep(std::time::Duration::from_millis(200));
-                                        },
-                                        Err(e) => {
-                                            if e.kind() != std::io::ErrorKind::WouldBlock && 
-                                               e.kind() != std::io::ErrorKind::TimedOut {
-                                                println!("Peer n{}: Error reading from successor: {}", my_id, e);
-                                            } else {
-                                                println!("Peer n{}: Timed out waiting for response from successor", my_id);
-                                            }
-                                            response = format!("ERROR: Failed to read from successor\n");
-                                            retry_count += 1;
-                                            thread::sleep(std::time::Duration::from_millis(200));
+
+        // Past the successor, the request goes to the next peer on the list that answers, which
+        // is told to own the keys of the peers skipped on the way
+        let mut last_error = String::new();
+        for (i, peer) in candidates.iter().enumerate() {
+            if i > 0 {
+                println!("Peer n{}: Successor {} is down, detouring to {}", my_id, candidates[i - 1], peer);
+            }
+            let pred = if i > 0 { Some(my_name(my_id)) } else { None };
+            let request = forwarded(request, pred.as_deref(), ttl - 1);
+            match send_to(&neighbors, peer, &request, my_id) {
+                Ok(reply) => return reply,
+                Err(e) => last_error = e,
+            }
+        }
+        last_error
+    }
+}
+
+// Rewrites a request's ttl and pred fields for the next hop, dropping the pred a previous
+// detour added, since it only holds for the peer right after the detour.
+fn forwarded(request: &str, pred: Option<&str>, ttl: u64) -> String {
+    let mut parts: Vec<String> = request.trim().split(',')
+        .filter(|part| !part.trim().starts_with("pred=") && !part.trim().starts_with("ttl="))
+        .map(|part| part.to_string())
+        .collect();
+    parts.push(format!(" ttl={}", ttl));
+    if let Some(pred) = pred {
+        parts.push(format!(" pred={}", pred));
+    }
+    format!("{}\n", parts.join(","))
+}
+
+// Sends a request to a peer on a connection of its own, closed once the reply is in.
+fn send_to_peer(succ: &str, request: &str, my_id: u64) -> Result<String, String> {
+    exchange(&mut None, succ, request, my_id)
+}
+
+// Sends a request on a neighbor's kept-open connection. If another forward is using it, the
+// request goes on a connection of its own rather than waiting, which could deadlock a
+// request that comes back around the ring to this peer.
+fn send_on(conn: &Connection, succ: &str, request: &str, my_id: u64) -> Result<String, String> {
+    match conn.try_lock() {
+        Ok(mut stream) => exchange(&mut stream, succ, request, my_id),
+        Err(_) => send_to_peer(succ, request, my_id),
+    }
+}
+
+// Sends a request to a peer's listener over `stream`, connecting first if it is None, and
+// returns the reply, trying up to 3 times. A connection that fails is dropped and a new one
+// made for the next try, so a connection the peer closed while idle is replaced. The error
+// is the reply to pass on when the peer can't be reached.
+fn exchange(stream: &mut Option<BufReader<TcpStream>>, succ: &str, request: &str, my_id: u64) -> Result<String, String> {
+    let peer_addr = peer_addr(succ);
+    
+    let mut retry_count = 0;
+    let max_retries = 3;
+    let mut response = format!("ERROR: Failed to connect to successor {} after {} attempts\n", succ, max_retries);
+    
+    while retry_count < max_retries {
+        // A kept-open connection the peer has closed since, e.g. after it sat idle, is
+        // replaced right away without counting as a try
+        let reused = stream.is_some();
+        let connected = match stream.take() {
+            Some(succ_stream) => Ok(succ_stream),
+            None => TcpStream::connect(&peer_addr).map(BufReader::new),
+        };
+        match connected {
+            Ok(mut succ_stream) => {
+                if let Err(e) = succ_stream.get_ref().set_write_timeout(Some(std::time::Duration::from_secs(10))) {
+                    println!("Peer n{}: Warning: Could not set write timeout: {}", my_id, e);
+                }
+                if let Err(e) = succ_stream.get_ref().set_read_timeout(Some(std::time::Duration::from_secs(10))) {
+                    println!("Peer n{}: Warning: Could not set read timeout: {}", my_id, e);
+                }
+                
+                match succ_stream.get_mut().write_all(request.as_bytes()) {
+                    Ok(_) => {
+                        match succ_stream.get_mut().flush() {
+                            Ok(_) => {
+                                match read_message(&mut succ_stream) {
+                                    Ok(reply) if !reply.is_empty() => {
+                                        *stream = Some(succ_stream);
+                                        return Ok(reply);
+                                    },
+                                    Ok(_) if reused => {},
+                                    Ok(_) => {
+                                        retry_count += 1;
+                                        thread::sleep(std::time::Duration::from_millis(200));
+                                    },
+                                    Err(e) => {
+                                        if e.kind() != std::io::ErrorKind::WouldBlock && 
+                                           e.kind() != std::io::ErrorKind::TimedOut {
+                                            println!("Peer n{}: Error reading from successor: {}", my_id, e);
+                                        } else {
+                                            println!("Peer n{}: Timed out waiting for response from successor", my_id);
                                         }
+                                        response = "ERROR: Failed to read from successor\n".to_string();
+                                        retry_count += 1;
+                                        thread::sleep(std::time::Duration::from_millis(200));
                                     }
-                                },
-                                Err(e) => {
-                                    retry_count += 1;
-                                    thread::sleep(std::time::Duration::from_millis(200));
                                 }
+                            },
+                            Err(_) => {
+                                retry_count += 1;
+                                thread::sleep(std::time::Duration::from_millis(200));
                             }
-                        },
-                        Err(e) => {
-                            println!("Peer n{}: Failed to write to successor: {}", my_id, e);
-                            response = format!("ERROR: Failed to write to successor: {}\n", e);
-                            retry_count += 1;
-                            thread::sleep(std::time::Duration::from_millis(200));
                         }
+                    },
+                    Err(e) => {
+                        println!("Peer n{}: Failed to write to successor: {}", my_id, e);
+                        response = format!("ERROR: Failed to write to successor: {}\n", e);
+                        retry_count += 1;
+                        thread::sleep(std::time::Duration::from_millis(200));
                     }
-                },
-                Err(e) => {
-                    if e.kind() != std::io::ErrorKind::TimedOut && 
-                       e.kind() != std::io::ErrorKind::WouldBlock {
-                        println!("Peer n{}: Could not connect to successor at {}: {}", my_id, peer_addr, e);
-                    } else {
-                        println!("Peer n{}: Connection to successor at {} timed out (attempt {})", 
-                                 my_id, peer_addr, retry_count + 1);
-                    }
-                    retry_count += 1;
-                    thread::sleep(std::time::Duration::from_millis(200));
                 }
+            },
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::TimedOut && 
+                   e.kind() != std::io::ErrorKind::WouldBlock {
+                    println!("Peer n{}: Could not connect to successor at {}: {}", my_id, peer_addr, e);
+                } else {
+                    println!("Peer n{}: Connection to successor at {} timed out (attempt {})", 
+                             my_id, peer_addr, retry_count + 1);
+                }
+                retry_count += 1;
+                thread::sleep(std::time::Duration::from_millis(200));
             }
         }
-        
-        response
     }
+    
+    Err(response)
+}
+
+// Drops an object from OBJECTS, or only a replica of it, and records the delete in the
+// object store file, returning whether it was here.
+fn remove_object(object_id: u64, client_id: u64, replica_only: bool) -> std::io::Result<bool> {
+    let removed = {
+        let mut objects = OBJECTS.lock().unwrap();
+        let before = objects.len();
+        objects.retain(|o| o.object_id != object_id || o.client_id != client_id || (replica_only && !o.replica));
+        objects.len() != before
+    };
+    if removed {
+        append_record(&format!("{}::{}::deleted", client_id, object_id))?;
+    }
+    Ok(removed)
+}
+
+// Sends UNREPLICATE for a deleted object to the same successors replicate sends it to.
+fn unreplicate(object_id: u64, client_id: u64, neighbors: &Arc<Mutex<Neighbors>>, my_id: u64) {
+    let copies = REPLICAS.load(Ordering::Relaxed) - 1;
+    let targets: Vec<String> = neighbors.lock().unwrap().successors.iter().take(copies).cloned().collect();
+    for target in &targets {
+        unreplicate_on(neighbors, target, object_id, client_id, my_id);
+    }
+}
+
+// Has a peer drop its replica of an object. A replica that is already gone is fine, a peer
+// that can't be reached is logged.
+fn unreplicate_on(neighbors: &Arc<Mutex<Neighbors>>, target: &str, object_id: u64, client_id: u64, my_id: u64) {
+    let request = format!(
+        "REQUEST: reqID=0, op=UNREPLICATE, objectID={}, clientID={}\n",
+        object_id, client_id
+    );
+    match send_to(neighbors, target, &request, my_id) {
+        Ok(reply) if reply.starts_with("OBJ DELETED") => {
+            println!("Peer n{}: Deleted replica of objectID={}, clientID={} on {}", my_id, object_id, client_id, target);
+        },
+        Ok(reply) if reply.starts_with("OBJ NOT FOUND") => {},
+        Ok(reply) => println!("Peer n{}: Deleting replica of objectID={}, clientID={} on {} failed: {}",
+                              my_id, object_id, client_id, target, reply.trim()),
+        Err(e) => println!("Peer n{}: Deleting replica of objectID={}, clientID={} on {} failed: {}",
+                           my_id, object_id, client_id, target, e.trim()),
+    }
+}
+
+// The reply already sent for a client's STORE with this reqID, if it is a recent one.
+fn recent_store(client_id: u64, req_id: u64) -> Option<String> {
+    let recent = RECENT_STORES.lock().unwrap();
+    recent.get(&client_id)?.iter().find(|(r, _)| *r == req_id).map(|(_, reply)| reply.clone())
+}
+
+fn remember_store(client_id: u64, req_id: u64, reply: &str) {
+    let mut recent = RECENT_STORES.lock().unwrap();
+    let stores = recent.entry(client_id).or_default();
+    if stores.len() == RECENT_STORES_PER_CLIENT {
+        stores.pop_front();
+    }
+    stores.push_back((req_id, reply.to_string()));
+}
+
+fn find_object(object_id: u64, client_id: u64) -> Option<Object> {
+    let objects = OBJECTS.lock().unwrap();
+    objects.iter().find(|obj| obj.object_id == object_id && obj.client_id == client_id).cloned()
+}
+
+// The reply to a RETRIEVE, with the object's data if it has any.
+fn retrieved(obj: &Object, my_id: u64) -> String {
+    let data_field = if obj.data.is_empty() {
+        String::new()
+    } else {
+        format!(", data={}", escape(&obj.data))
+    };
+    format!("OBJ RETRIEVED: objectID={}, clientID={}, peerID=n{}, version={}{}\n",
+            obj.object_id, obj.client_id, my_id, obj.version, data_field)
+}
+
+// Whether `host` names the machine this peer runs on: its own host name, or one that resolves
+// to a loopback address.
+fn same_host(host: &str, my_host: &str) -> bool {
+    host == my_host || (host, 0).to_socket_addrs().is_ok_and(|mut addrs| addrs.any(|a| a.ip().is_loopback()))
+}
+
+// Peer ids come from the names: n5@n5:9999 has id 5, and so does a bare host name n5.
+fn peer_id(name: &str) -> Option<u64> {
+    name.split('@').next()?.strip_prefix('n')?.parse().ok()
+}
+
+// The host:port a peer's name says it listens on. A bare host name, without an address,
+// gets this peer's own --peer-port.
+fn peer_addr(name: &str) -> String {
+    match name.split_once('@') {
+        Some((_, addr)) => addr.to_string(),
+        None => format!("{}:{}", name, PEER_PORT.load(Ordering::Relaxed)),
+    }
+}
+
+// This peer's own name, as the bootstrap server gives it to the others
+fn my_name(my_id: u64) -> String {
+    format!("n{}@{}:{}", my_id, MY_HOST.lock().unwrap(), PEER_PORT.load(Ordering::Relaxed))
+}
+
+// The part of the ring this peer holds, (pred_id, my_id], taken from the predecessor named
+// by the last JOIN_REPLY or update. pred_id is None while the peer is alone on the ring.
+fn get_owned_range(neighbors: &Arc<Mutex<Neighbors>>, my_id: u64) -> (Option<u64>, u64) {
+    let pred_id = neighbors.lock().unwrap().predecessor.as_deref().and_then(peer_id);
+    (pred_id, my_id)
+}
+
+// Whether `key` falls in this peer's part of the ring, after its predecessor's id up to and
+// including its own. The part of the peer with the smallest id wraps around, so it also
+// holds every key above the largest id. A peer without a predecessor is alone on the ring.
+fn owns(key: u64, pred_id: Option<u64>, my_id: u64) -> bool {
+    match pred_id {
+        None => true,
+        Some(pred) if pred < my_id => pred < key && key <= my_id,
+        Some(pred) => key > pred || key <= my_id,
+    }
+}
+
+// Chord's stabilize step, run every STABILIZE_SECS so the ring mends itself when a neighbor
+// update from the bootstrap server is lost. The peer asks its successor for its predecessor,
+// and if that is a peer between the two that answers, takes it as its successor instead.
+// Then it tells its successor about itself, see notified. A successor that can't be reached
+// is left to the bootstrap server's heartbeats.
+fn stabilize(neighbors: &Arc<Mutex<Neighbors>>, my_id: u64) {
+    let Some(mut succ) = neighbors.lock().unwrap().successor.as_ref().map(|(s, _)| s.clone()) else {
+        return;
+    };
+    let Ok(reply) = send_to(neighbors, &succ, "NEIGHBOR_QUERY\n", my_id) else {
+        return;
+    };
+    let reported = reply.trim().strip_prefix("NEIGHBOR_REPLY: predecessor=").unwrap_or("None");
+    if let (Some(id), Some(succ_id)) = (peer_id(reported), peer_id(&succ)) {
+        if id != my_id && between(id, my_id, succ_id) && alive(reported, my_id) {
+            println!("Peer n{}: Stabilize: {}'s predecessor is {}, taking it as successor instead of {}",
+                     my_id, succ, reported, succ);
+            update_neighbor(neighbors, my_id, "successor", reported);
+            let old_successors = {
+                let mut nbrs = neighbors.lock().unwrap();
+                let len = nbrs.successors.len().max(1);
+                let mut successors = vec![reported.to_string()];
+                successors.extend(nbrs.successors.iter().filter(|s| *s != reported).cloned());
+                successors.truncate(len);
+                std::mem::replace(&mut nbrs.successors, successors)
+            };
+            if REPLICAS.load(Ordering::Relaxed) > 1 {
+                rereplicate(neighbors, my_id, &old_successors);
+            }
+            print_neighbor_status(neighbors);
+            succ = reported.to_string();
+        }
+    }
+    if let Err(e) = send_to(neighbors, &succ, &format!("NOTIFY: predecessor={}\n", my_name(my_id)), my_id) {
+        println!("Peer n{}: Stabilize: Couldn't notify {}: {}", my_id, succ, e.trim());
+    }
+}
+
+// Answers the messages stabilize sends, or returns None for any other. NEIGHBOR_QUERY asks for
+// this peer's predecessor, answered with LEAVING instead while it leaves. NOTIFY names a peer
+// that takes this one as its successor, which becomes the predecessor if there is none, it is
+// between the predecessor and this peer, or the predecessor doesn't answer.
+fn neighbor_message(msg: &str, neighbors: &Arc<Mutex<Neighbors>>, my_id: u64) -> Option<String> {
+    let msg = msg.trim();
+    if msg == "NEIGHBOR_QUERY" && LEAVING.load(Ordering::Relaxed) {
+        return Some("LEAVING\n".to_string());
+    }
+    if msg == "NEIGHBOR_QUERY" {
+        let pred = neighbors.lock().unwrap().predecessor.clone().unwrap_or_else(|| "None".to_string());
+        return Some(format!("NEIGHBOR_REPLY: predecessor={}\n", pred));
+    }
+    let candidate = msg.strip_prefix("NOTIFY: predecessor=")?;
+    let candidate_id = peer_id(candidate)?;
+    let pred = neighbors.lock().unwrap().predecessor.clone();
+    let replace = match pred.as_deref() {
+        _ if candidate_id == my_id => false,
+        None => true,
+        Some(pred) if pred == candidate => false,
+        Some(pred) => peer_id(pred).is_none_or(|pred_id| between(candidate_id, pred_id, my_id)) || !alive(pred, my_id),
+    };
+    if replace {
+        println!("Peer n{}: Stabilize: taking {} as predecessor instead of {}",
+                 my_id, candidate, pred.as_deref().unwrap_or("None"));
+        update_neighbor(neighbors, my_id, "predecessor", candidate);
+        if REPLICAS.load(Ordering::Relaxed) > 1 {
+            let old_successors = neighbors.lock().unwrap().successors.clone();
+            let nbrs = neighbors.clone();
+            thread::spawn(move || rereplicate(&nbrs, my_id, &old_successors));
+        }
+        print_neighbor_status(neighbors);
+    }
+    Some("NOTIFY_OK\n".to_string())
+}
+
+// Whether `id` is strictly between `from` and `to` going around the ring from `from`.
+fn between(id: u64, from: u64, to: u64) -> bool {
+    id != to && owns(id, Some(from), to)
+}
+
+// Whether a peer answers a NEIGHBOR_QUERY on a connection of its own and isn't leaving, so
+// stabilize never points the ring at a peer that is gone.
+fn alive(peer: &str, my_id: u64) -> bool {
+    send_to_peer(peer, "NEIGHBOR_QUERY\n", my_id).is_ok_and(|reply| reply.starts_with("NEIGHBOR_REPLY:"))
 }
 
 fn update_neighbor(neighbors: &Arc<Mutex<Neighbors>>, my_id: u64, direction: &str, new_peer: &str) {
     let mut nbrs = neighbors.lock().unwrap();
     match direction {
         "predecessor" => {
-            if my_id == 1 {
-                *GLOBAL_PRED.lock().unwrap() = Some(new_peer.to_string());
-            }
-            if new_peer == "None" {
-                if nbrs.predecessor.is_some() {
-                    println!("Disconnecting old predecessor connection.");
-                }
-                nbrs.predecessor = None;
-            } else {
-                nbrs.predecessor = connect_to_peer(new_peer).map(|stream| (new_peer.to_string(), stream));
-            }
+            nbrs.predecessor = (new_peer != "None").then(|| new_peer.to_string());
         },
         "successor" => {
             if new_peer == "None" {
@@ -460,8 +1202,13 @@ fn update_neighbor(neighbors: &Arc<Mutex<Neighbors>>, my_id: u64, direction: &st
                     println!("Disconnecting old successor connection.");
                 }
                 nbrs.successor = None;
-            } else {
-                nbrs.successor = connect_to_peer(new_peer).map(|stream| (new_peer.to_string(), stream));
+            } else if nbrs.successor.as_ref().is_none_or(|(s, _)| s != new_peer) {
+                // An update naming the same successor keeps its connection
+                let conn = Arc::new(Mutex::new(None));
+                nbrs.successor = Some((new_peer.to_string(), conn.clone()));
+                let nbrs = neighbors.clone();
+                let peer = new_peer.to_string();
+                thread::spawn(move || connect_to_successor(&nbrs, &peer, &conn, my_id));
             }
         },
         _ => {
@@ -473,10 +1220,7 @@ fn update_neighbor(neighbors: &Arc<Mutex<Neighbors>>, my_id: u64, direction: &st
 fn print_neighbor_status(neighbors: &Arc<Mutex<Neighbors>>) {
     let nbrs = neighbors.lock().unwrap();
     
-    let pred_str = match &nbrs.predecessor {
-        Some((peer, _)) => peer.clone(),
-        None => "None".to_string()
-    };
+    let pred_str = nbrs.predecessor.clone().unwrap_or_else(|| "None".to_string());
     
     let succ_str = match &nbrs.successor {
         Some((peer, _)) => peer.clone(),
@@ -486,24 +1230,51 @@ fn print_neighbor_status(neighbors: &Arc<Mutex<Neighbors>>) {
     println!("Predecessor: {}, Successor: {}", pred_str, succ_str);
 }
 
-fn connect_to_peer(peer: &str) -> Option<TcpStream> {
-    let addr = format!("{}:{}", peer, PEER_PORT);
-    match TcpStream::connect(addr) {
-        Ok(stream) => {
-            Some(stream)
-        },
-        Err(_) => {
-            None
+// Opens the connection to a new successor, trying every second while it can't be reached
+// and it is still the successor, up to CONNECT_ATTEMPTS times. Each failure is logged,
+// since until it connects every forward has to open a connection of its own.
+fn connect_to_successor(neighbors: &Arc<Mutex<Neighbors>>, peer: &str, conn: &Connection, my_id: u64) {
+    let addr = peer_addr(peer);
+    for attempt in 1..=CONNECT_ATTEMPTS {
+        let current = neighbors.lock().unwrap().successor.as_ref().is_some_and(|(s, _)| s == peer);
+        if !current {
+            return;
+        }
+        match TcpStream::connect(&addr) {
+            Ok(stream) => {
+                let mut conn = conn.lock().unwrap();
+                // A forward may have connected in the meantime
+                if conn.is_none() {
+                    *conn = Some(BufReader::new(stream));
+                }
+                return;
+            },
+            Err(e) => {
+                println!("Peer n{}: WARNING: Can't reach successor {} (attempt {} of {}): {}",
+                         my_id, peer, attempt, CONNECT_ATTEMPTS, e);
+                thread::sleep(std::time::Duration::from_secs(1));
+            }
         }
     }
+    println!("Peer n{}: WARNING: Gave up connecting to successor {}, forwards will each try on their own", my_id, peer);
 }
 
-fn parse_join_reply(reply: &str) -> Option<(String, String)> {
-    let parts: Vec<&str> = reply.trim().split(':').collect();
-    if parts.len() < 2 {
-        return None;
+// Sends a request to a peer, over the successor's connection if it is the successor.
+fn send_to(neighbors: &Arc<Mutex<Neighbors>>, peer: &str, request: &str, my_id: u64) -> Result<String, String> {
+    let conn = neighbors.lock().unwrap().successor.as_ref()
+        .filter(|(s, _)| s == peer)
+        .map(|(_, conn)| conn.clone());
+    match conn {
+        Some(conn) => send_on(&conn, peer, request, my_id),
+        None => send_to_peer(peer, request, my_id),
     }
-    let content = parts[1].trim();
+}
+
+// Reads "JOIN_REPLY: predecessor=<name>, successor=<name>", only the first colon ending the
+// message type, since the names' addresses have colons of their own.
+fn parse_join_reply(reply: &str) -> Option<(String, String)> {
+    let (_, content) = reply.trim().split_once(':')?;
+    let content = content.trim();
     let tokens: Vec<&str> = content.split(',').collect();
     if tokens.len() != 2 {
         return None;
@@ -513,35 +1284,245 @@ fn parse_join_reply(reply: &str) -> Option<(String, String)> {
     Some((pred, succ))
 }
 
-fn init() -> (String, Option<u64>, String) {
+/// Initializes the peer from command-line arguments.
+///   -b : The hostname of the bootstrap server.
+///   -d : (Optional) The number of seconds to wait before joining.
+///   -o : The object store file to load at startup.
+///   -l : (Optional) The number of seconds after joining to leave the ring again.
+///   -c : (Optional) The number of seconds after joining to crash, without leaving.
+///   -r : (Optional) How many peers hold each object, the owner included. Defaults to 1.
+///   -s : (Optional) The number of seconds after joining to stop answering anyone while
+///        keeping every connection open, like a paused container.
+///   --bootstrap-port : (Optional) The port the bootstrap server listens on. Defaults to 8888.
+///   --peer-port : (Optional) The port this peer listens on, which its JOIN tells the bootstrap
+///                 server, so each peer can have its own. Defaults to 9999.
+///   --drop-updates : (Optional) How many of the bootstrap server's neighbor updates to ignore,
+///                    to test that stabilizing with the neighbors repairs the ring.
+///   --kill-after-stores : (Optional) How many of its own OBJ STORED replies the peer sends
+///                         before it kills itself, to test that the STOREs survive the crash.
+///   --id : (Optional) The peer's id, instead of the one in its hostname, e.g. 5 for n5. Other
+///          peers reach it at its hostname.
+fn init() -> Config {
     let args: Vec<String> = env::args().skip(1).collect();
-    let (hostname, delay_time, object_store_path) = args.chunks(2).fold(
-        (None, None, None),
-        |(hn, dt, objpath), pair| {
-            match pair {
-                [key, value] => match key.as_str() {
-                    "-b" => (Some(value.clone()), dt, objpath),
-                    "-d" => (hn, value.parse().ok(), objpath),
-                    "-o" => (hn, dt, Some(value.clone())),
-                    other => {
-                        eprintln!("init error: Unknown flag: {}", other);
+    let config = Config {
+        bootstrap: None,
+        delay_time: None,
+        object_store_path: None,
+        leave_after: None,
+        crash_after: None,
+        stall_after: None,
+        replicas: DEFAULT_REPLICAS,
+        bootstrap_port: DEFAULT_BOOTSTRAP_PORT,
+        id: None,
+    };
+    let config = args.chunks(2).fold(config, |mut config, pair| {
+        match pair {
+            [key, value] => match key.as_str() {
+                "-b" => config.bootstrap = Some(value.clone()),
+                "-d" => config.delay_time = value.parse().ok(),
+                "-o" => config.object_store_path = Some(value.clone()),
+                "-l" => config.leave_after = value.parse().ok(),
+                "-c" => config.crash_after = value.parse().ok(),
+                "-s" => config.stall_after = value.parse().ok(),
+                "-r" => match value.parse::<usize>() {
+                    Ok(r) if r > 0 => config.replicas = r,
+                    _ => {
+                        eprintln!("init error: -r takes a number of copies of at least 1, got {}", value);
                         process::exit(1);
                     }
                 },
-                _ => {
-                    eprintln!("init error: Invalid arguments format");
+                "--bootstrap-port" => config.bootstrap_port = parse_port(key, value),
+                "--peer-port" => PEER_PORT.store(parse_port(key, value), Ordering::Relaxed),
+                "--drop-updates" => match value.parse() {
+                    Ok(count) => DROP_UPDATES.store(count, Ordering::Relaxed),
+                    Err(_) => {
+                        eprintln!("init error: --drop-updates takes a number, got {}", value);
+                        process::exit(1);
+                    }
+                },
+                "--kill-after-stores" => match value.parse() {
+                    Ok(count) => KILL_AFTER_STORES.store(count, Ordering::Relaxed),
+                    Err(_) => {
+                        eprintln!("init error: --kill-after-stores takes a number, got {}", value);
+                        process::exit(1);
+                    }
+                },
+                "--id" => match value.parse() {
+                    Ok(id) => config.id = Some(id),
+                    Err(_) => {
+                        eprintln!("init error: --id takes a number, got {}", value);
+                        process::exit(1);
+                    }
+                },
+                other => {
+                    eprintln!("init error: Unknown flag: {}", other);
                     process::exit(1);
                 }
+            },
+            _ => {
+                eprintln!("init error: Invalid arguments format");
+                process::exit(1);
             }
-        },
-    );
-    let hostname = hostname.unwrap_or_else(|| {
+        }
+        config
+    });
+    if config.bootstrap.is_none() {
         eprintln!("init error: Missing -b flag for hostname");
         process::exit(1);
-    });
-    let object_store_path = object_store_path.unwrap_or_else(|| {
+    }
+    if config.object_store_path.is_none() {
         eprintln!("init error: Missing -o flag for object store path");
         process::exit(1);
-    });
-    (hostname, delay_time, object_store_path)
-}
\ No newline at end of file
+    }
+    config
+}
+
+// Ends the process the way kill -9 would, without flushing or closing anything, so only what
+// was synced to the object store file is there when the peer restarts
+fn kill_self(my_id: u64) -> ! {
+    println!("Peer n{}: Killing itself", my_id);
+    process::abort();
+}
+
+// A port flag's value, which has to be a port number above 0
+fn parse_port(key: &str, value: &str) -> u16 {
+    match value.parse::<u16>() {
+        Ok(port) if port > 0 => port,
+        _ => {
+            eprintln!("init error: {} takes a port number, got {}", key, value);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // Successors on a port nothing listens on, so connecting to them fails right away
+    const JOIN_REPLY: &str = "JOIN_REPLY: predecessor=n1@127.0.0.1:1, successor=n10@127.0.0.1:1\n";
+    const UPDATE: &str = "Predecessor: n50@127.0.0.1:1, Successor: n10@127.0.0.1:1, Successors: n10@127.0.0.1:1 n50@127.0.0.1:1\n";
+
+    fn read_all(reader: impl BufRead) -> (Arc<Mutex<Neighbors>>, String) {
+        let neighbors = Arc::new(Mutex::new(Neighbors::new()));
+        let mut written = Vec::new();
+        read_bootstrap_messages(reader, &mut written, &neighbors, 5).unwrap();
+        (neighbors, String::from_utf8(written).unwrap())
+    }
+
+    // OBJECTS and the object store path belong to the whole process, so the tests that use
+    // them take turns, each starting from an empty object store file of its own
+    static STORE: Mutex<()> = Mutex::new(());
+
+    fn empty_store(name: &str) -> (std::sync::MutexGuard<'static, ()>, String) {
+        let guard = STORE.lock().unwrap_or_else(|e| e.into_inner());
+        let path = env::temp_dir().join(format!("hw5_{}_{}.txt", name, process::id()));
+        let path = path.to_string_lossy().into_owned();
+        fs::write(&path, "").unwrap();
+        load_objects_from_file(&path);
+        (guard, path)
+    }
+
+    #[test]
+    fn merged_messages_are_each_handled() {
+        // A JOIN_REPLY and the update sent right after it, arriving in one read
+        let (neighbors, written) = read_all(Cursor::new(format!("{}{}PING\n", JOIN_REPLY, UPDATE)));
+        assert_eq!(written, "PONG\n");
+        let nbrs = neighbors.lock().unwrap();
+        assert_eq!(nbrs.predecessor.as_deref(), Some("n50@127.0.0.1:1"));
+        assert_eq!(nbrs.successor.as_ref().map(|(s, _)| s.as_str()), Some("n10@127.0.0.1:1"));
+        assert_eq!(nbrs.successors, ["n10@127.0.0.1:1", "n50@127.0.0.1:1"]);
+    }
+
+    #[test]
+    fn split_messages_are_put_back_together() {
+        // Every read gets 3 bytes, so each line takes many reads to complete
+        let bytes = format!("PING\n{}PING\n", JOIN_REPLY);
+        let (neighbors, written) = read_all(BufReader::with_capacity(3, Cursor::new(bytes)));
+        assert_eq!(written, "PONG\nPONG\n");
+        let nbrs = neighbors.lock().unwrap();
+        assert_eq!(nbrs.predecessor.as_deref(), Some("n1@127.0.0.1:1"));
+        assert_eq!(nbrs.successor.as_ref().map(|(s, _)| s.as_str()), Some("n10@127.0.0.1:1"));
+        assert!(nbrs.successors.is_empty());
+    }
+
+    #[test]
+    fn alone_on_the_ring() {
+        let (neighbors, written) = read_all(Cursor::new("JOIN_REPLY: predecessor=None, successor=None\n"));
+        assert!(written.is_empty());
+        let nbrs = neighbors.lock().unwrap();
+        assert!(nbrs.predecessor.is_none() && nbrs.successor.is_none());
+    }
+
+    #[test]
+    fn data_keeps_the_whitespace_around_it() {
+        let (_store, path) = empty_store("whitespace");
+        let neighbors = Arc::new(Mutex::new(Neighbors::new()));
+        let data = "  two spaces before,\ta tab and a space after\t ";
+        let store = format!("REQUEST: reqID=1, op=STORE, objectID=7, clientID=1, data={}\n", escape(data));
+        assert!(handle_request(&store, neighbors.clone(), 5).starts_with("OBJ STORED"));
+
+        // The RETRIEVE reply, read the way the client reads its fields
+        let reply = handle_request("REQUEST: op=RETRIEVE, objectID=7, clientID=1\n", neighbors, 5);
+        let field = reply.split(',').find_map(|part| part.trim().strip_prefix("data=")).unwrap();
+        assert_eq!(unescape(field.trim()), data);
+
+        // and the object store file, read again as a restarted peer would
+        load_objects_from_file(&path);
+        assert_eq!(find_object(7, 1).unwrap().data, data);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn requests_that_run_out_of_ttl_are_dropped() {
+        // A successor that answers each request it gets the way peer n10 would
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let succ = format!("n10@{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                    let reply = handle_request(&line, Arc::new(Mutex::new(Neighbors::new())), 10);
+                    (&stream).write_all(reply.as_bytes()).unwrap();
+                    line.clear();
+                }
+            }
+        });
+
+        // n5 holds the keys after n10 and up to 5, so objectID=8 is forwarded, with one less ttl
+        let neighbors = Arc::new(Mutex::new(Neighbors::new()));
+        {
+            let mut nbrs = neighbors.lock().unwrap();
+            nbrs.predecessor = Some(succ.clone());
+            nbrs.successor = Some((succ, Arc::new(Mutex::new(None))));
+        }
+        let reply = handle_request("REQUEST: op=RETRIEVE, objectID=8, clientID=2, ttl=1\n", neighbors, 5);
+        assert_eq!(reply, "ERROR: routing loop detected for objectID=8\n");
+        // and one that arrives with none left gets the error right away, even at a peer alone
+        // on the ring, which owns every key
+        let alone = Arc::new(Mutex::new(Neighbors::new()));
+        let reply = handle_request("REQUEST: op=RETRIEVE, objectID=8, clientID=2, ttl=0\n", alone, 5);
+        assert_eq!(reply, "ERROR: routing loop detected for objectID=8\n");
+    }
+
+    #[test]
+    fn a_cut_off_last_line_is_dropped() {
+        let (_store, path) = empty_store("cut_off");
+        // A crash while the STORE of object 4 was being written, before it was acknowledged
+        fs::write(&path, "3::1\n3::2::data=abc\n3::4::da").unwrap();
+        load_objects_from_file(&path);
+        let ids: Vec<u64> = OBJECTS.lock().unwrap().iter().map(|o| o.object_id).collect();
+        assert_eq!(ids, [1, 2]);
+        assert_eq!(find_object(2, 3).unwrap().data, "abc");
+
+        // The file is rewritten without it, so the next line appended starts a line of its own
+        assert!(fs::read_to_string(&path).unwrap().ends_with('\n'));
+        append_record("3::5").unwrap();
+        load_objects_from_file(&path);
+        let ids: Vec<u64> = OBJECTS.lock().unwrap().iter().map(|o| o.object_id).collect();
+        assert_eq!(ids, [1, 2, 5]);
+        fs::remove_file(&path).unwrap();
+    }
+}